@@ -0,0 +1,123 @@
+/*
+ *
+ * This file is part of onizd, copyright ©2020 Solra Bizna.
+ *
+ * onizd is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or (at your option) any later
+ * version.
+ *
+ * onizd is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * onizd. If not, see <https://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Keeps track of failed authentication attempts, and temporarily bans
+//! addresses that fail too often, so that a secret can't be brute-forced by
+//! simply reconnecting over and over.
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::Duration,
+};
+use tokio::time::Instant;
+
+/// No ban lasts longer than this, no matter how many came before it.
+const MAX_BAN: Duration = Duration::from_secs(86400);
+
+struct Record {
+    /// Failures since `first_failure`.
+    failures: u32,
+    first_failure: Option<Instant>,
+    last_failure: Instant,
+    /// How many times this address has been banned. Each ban lasts twice as
+    /// long as the one before.
+    bans: u32,
+    banned_until: Option<Instant>,
+}
+
+pub struct AuthFailures {
+    max_failures: u32,
+    window: Duration,
+    records: Mutex<HashMap<IpAddr, Record>>,
+}
+
+impl AuthFailures {
+    /// `max_failures` failures within `window` gets an address banned. The
+    /// first ban lasts `window`, and each one after that lasts twice as long
+    /// as the last.
+    pub fn new(max_failures: u32, window: Duration) -> AuthFailures {
+        AuthFailures { max_failures, window,
+                       records: Mutex::new(HashMap::new()) }
+    }
+    /// If the given address is currently banned, returns how much longer the
+    /// ban will last.
+    pub fn banned_for(&self, ip: IpAddr) -> Option<Duration> {
+        let records = self.records.lock().unwrap();
+        let until = records.get(&ip)?.banned_until?;
+        let now = Instant::now();
+        if until > now { Some(until - now) } else { None }
+    }
+    /// Records a failed authentication. Returns the length of the new ban if
+    /// this failure got the address banned.
+    pub fn failed(&self, ip: IpAddr) -> Option<Duration> {
+        let now = Instant::now();
+        let mut records = self.records.lock().unwrap();
+        let record = records.entry(ip).or_insert_with(|| Record {
+            failures: 0, first_failure: None, last_failure: now, bans: 0,
+            banned_until: None,
+        });
+        record.last_failure = now;
+        match record.first_failure {
+            Some(x) if now.duration_since(x) < self.window => (),
+            _ => {
+                record.failures = 0;
+                record.first_failure = Some(now);
+            },
+        }
+        record.failures += 1;
+        if record.failures < self.max_failures { return None }
+        let length = self.window.checked_mul(1 << record.bans.min(16))
+            .unwrap_or(MAX_BAN).min(MAX_BAN);
+        record.bans += 1;
+        record.failures = 0;
+        record.first_failure = None;
+        record.banned_until = Some(now + length);
+        Some(length)
+    }
+    /// Forgets past failures from an address that has just authenticated
+    /// successfully.
+    pub fn succeeded(&self, ip: IpAddr) {
+        self.records.lock().unwrap().remove(&ip);
+    }
+    /// Lifts bans that have run out, and forgets about addresses that haven't
+    /// failed in a long time. Returns the addresses whose bans were lifted.
+    pub fn sweep(&self) -> Vec<IpAddr> {
+        let now = Instant::now();
+        let mut lifted = Vec::new();
+        let mut records = self.records.lock().unwrap();
+        for (ip, record) in records.iter_mut() {
+            if record.banned_until.map(|x| x <= now).unwrap_or(false) {
+                record.banned_until = None;
+                lifted.push(*ip);
+            }
+        }
+        // an address that stays out of trouble long enough gets a clean slate
+        // (a previously banned one has to wait longer, so that its next ban
+        // still escalates)
+        let window = self.window;
+        records.retain(|_, record| {
+            let memory = if record.bans > 0 { MAX_BAN } else { window };
+            record.banned_until.is_some()
+                || now.duration_since(record.last_failure) < memory
+        });
+        lifted
+    }
+}
@@ -18,7 +18,12 @@
  */
 
 use lazy_static::lazy_static;
-use std::collections::HashMap;
+use serde::{Deserialize, de::DeserializeOwned};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
 
 lazy_static! {
     static ref ELEMENTS: HashMap<i32, &'static str> = vec![
@@ -180,11 +185,102 @@ lazy_static! {
     ].into_iter().collect();
 }
 
-pub fn get_element_name(id: i32) -> Option<&'static str> {
-    ELEMENTS.get(&id).map(|x| *x)
+lazy_static! {
+    static ref LOADED: RwLock<Option<Arc<NameTables>>> = RwLock::new(None);
+}
+
+/// What state of matter an element is, according to `--element-names`.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ElementState { Solid, Liquid, Gas }
+
+/// One entry in an `--element-names` file: either just a name, or a name and
+/// the element's state of matter.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ElementEntry {
+    Name(String),
+    Properties { name: String, state: ElementState },
+}
+
+/// Element and germ names loaded from `--element-names`/`--germ-names`. These
+/// take precedence over the built-in tables above, so that a server can keep
+/// up with new game versions without being rebuilt.
+#[derive(Debug,Default)]
+pub struct NameTables {
+    elements: HashMap<i32, String>,
+    /// Only the elements whose entries gave a state. There's no built-in
+    /// table of these.
+    element_states: HashMap<i32, ElementState>,
+    germs: HashMap<i32, String>,
+}
+
+impl NameTables {
+    /// Loads whichever tables were given. Each file is a JSON object mapping
+    /// (stringified) ids to names, e.g. `{"-1908044868": "LiquidOxygen"}`.
+    /// In the element table, a name may instead be an object that also gives
+    /// the state, e.g. `{"name": "LiquidOxygen", "state": "liquid"}`.
+    pub fn load(element_path: Option<&str>, germ_path: Option<&str>)
+                -> anyhow::Result<NameTables> {
+        let mut ret = NameTables::default();
+        if let Some(path) = element_path {
+            for (id, entry) in load_table::<ElementEntry>(path)? {
+                match entry {
+                    ElementEntry::Name(name) => {
+                        ret.elements.insert(id, name);
+                    },
+                    ElementEntry::Properties { name, state } => {
+                        ret.elements.insert(id, name);
+                        ret.element_states.insert(id, state);
+                    },
+                }
+            }
+        }
+        if let Some(path) = germ_path {
+            ret.germs = load_table(path)?;
+        }
+        Ok(ret)
+    }
+    /// Makes these tables the ones `get_element_name`, `get_element_state`,
+    /// and `get_germ_name` consult. Done at startup, and again whenever the server reloads.
+    pub fn install(self: Arc<Self>) {
+        *LOADED.write().unwrap() = Some(self);
+    }
 }
 
-pub fn get_germ_name(id: i32) -> Option<&'static str> {
-    GERMS.get(&id).map(|x| *x)
+fn load_table<T: DeserializeOwned>(path: &str)
+                                   -> anyhow::Result<HashMap<i32, T>> {
+    let raw: HashMap<String, T>
+        = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    raw.into_iter().map(|(id, entry)| {
+        match id.parse() {
+            Ok(id) => Ok((id, entry)),
+            Err(_) => Err(anyhow::anyhow!("{}: {:?} is not a valid id",
+                                          path, id)),
+        }
+    }).collect()
 }
 
+fn get_name(id: i32, builtin: &HashMap<i32, &'static str>,
+            loaded: fn(&NameTables) -> &HashMap<i32, String>)
+            -> Option<Cow<'static, str>> {
+    if let Some(tables) = LOADED.read().unwrap().as_ref() {
+        if let Some(x) = loaded(tables).get(&id) {
+            return Some(Cow::Owned(x.clone()))
+        }
+    }
+    builtin.get(&id).map(|x| Cow::Borrowed(*x))
+}
+
+pub fn get_element_name(id: i32) -> Option<Cow<'static, str>> {
+    get_name(id, &ELEMENTS, |x| &x.elements)
+}
+
+pub fn get_germ_name(id: i32) -> Option<Cow<'static, str>> {
+    get_name(id, &GERMS, |x| &x.germs)
+}
+
+/// Returns the element's state of matter, if the loaded element table says.
+pub fn get_element_state(id: i32) -> Option<ElementState> {
+    LOADED.read().unwrap().as_ref()?.element_states.get(&id).copied()
+}
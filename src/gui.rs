@@ -21,7 +21,8 @@ use std::{
     rc::{Rc,Weak},
     cell::RefCell,
     {thread, thread::JoinHandle},
-    time::Duration,
+    sync::{Arc, atomic::Ordering},
+    time::{Duration, Instant},
 };
 use tokio::sync::mpsc;
 
@@ -35,21 +36,25 @@ use gtk::{
     CheckButton,
     Entry, EntryBuilder,
     InputPurpose,
-    LabelBuilder,
+    Label, LabelBuilder,
     Orientation,
     PolicyType,
     ScrolledWindowBuilder,
     SeparatorBuilder,
     TextView, TextViewBuilder, TextBuffer,
 };
+#[cfg(feature = "auth")]
+use gtk::{FileChooserAction, FileChooserButton};
 use gio::prelude::*;
 use glib;
-use crate::{Invocation, Outputter};
+use crate::{Invocation, MapLimits, Outputter, Shared};
 
 /// The maximum number of bytes that the log is allowed to grow to.
 const MAX_LOG_SIZE: i32 = 1_000_000; // this is a lot, okay
 /// The number of lines to kill every time we truncate the log.
 const LOG_TRUNC_LINES: i32 = 500;
+/// How often to refresh the status bar while the server is running.
+const STATUS_INTERVAL: Duration = Duration::from_secs(1);
 
 /// Contains all the actual logic for the GUI.
 struct Controller {
@@ -59,17 +64,33 @@ struct Controller {
     ping_checkbox: CheckButton,
     ping_field: Entry,
     verbose_checkbox: CheckButton,
+    offset_checkbox: CheckButton,
+    object_size_checkbox: CheckButton,
+    object_size_field: Entry,
     save_checkbox: CheckButton,
     save_field: Entry,
+    #[cfg(feature = "auth")]
+    auth_checkbox: CheckButton,
+    #[cfg(feature = "auth")]
+    auth_chooser: FileChooserButton,
     start_button: Button,
     stop_button: Button,
     output_view: TextView,
+    tiles_label: Label,
+    clients_label: Label,
+    save_status_label: Label,
     server_thread: Option<JoinHandle<()>>,
     terminator: Option<mpsc::Sender<()>>,
     server_canary: Option<mpsc::Receiver<()>>,
     self_ref: Option<Weak<RefCell<Controller>>>,
     log_tx: mpsc::UnboundedSender<String>,
     log_rx: mpsc::UnboundedReceiver<String>,
+    /// The running server hands us its `Shared` through here, once it's done
+    /// starting up.
+    shared_tx: mpsc::UnboundedSender<Arc<Shared>>,
+    shared_rx: mpsc::UnboundedReceiver<Arc<Shared>>,
+    shared: Option<Arc<Shared>>,
+    last_status: Option<Instant>,
 }
 
 impl Controller {
@@ -79,18 +100,37 @@ impl Controller {
                ping_checkbox: CheckButton,
                ping_field: Entry,
                verbose_checkbox: CheckButton,
+               offset_checkbox: CheckButton,
+               object_size_checkbox: CheckButton,
+               object_size_field: Entry,
                save_checkbox: CheckButton,
                save_field: Entry,
+               #[cfg(feature = "auth")]
+               auth_checkbox: CheckButton,
+               #[cfg(feature = "auth")]
+               auth_chooser: FileChooserButton,
                start_button: Button,
                stop_button: Button,
-               output_view: TextView) -> Rc<RefCell<Controller>> {
+               output_view: TextView,
+               tiles_label: Label,
+               clients_label: Label,
+               save_status_label: Label) -> Rc<RefCell<Controller>> {
         let (log_tx, log_rx) = mpsc::unbounded_channel();
+        let (shared_tx, shared_rx) = mpsc::unbounded_channel();
         let ret = Rc::new(RefCell::new(Controller {
             _window, listen_checkbox, listen_field, ping_checkbox, ping_field,
-            output_view, verbose_checkbox, save_checkbox, save_field,
-            start_button, stop_button,
+            output_view, verbose_checkbox, offset_checkbox,
+            object_size_checkbox, object_size_field, save_checkbox,
+            save_field,
+            #[cfg(feature = "auth")]
+            auth_checkbox,
+            #[cfg(feature = "auth")]
+            auth_chooser,
+            start_button, stop_button, tiles_label, clients_label,
+            save_status_label,
             server_thread: None, terminator: None, server_canary: None,
-            self_ref: None, log_tx, log_rx,
+            self_ref: None, log_tx, log_rx, shared_tx, shared_rx,
+            shared: None, last_status: None,
         }));
         let rc = ret.clone();
         let mut me = rc.borrow_mut();
@@ -100,6 +140,15 @@ impl Controller {
         let rc = ret.clone();
         me.ping_checkbox.connect_clicked(move |_| rc.borrow_mut().update_sensitive());
         let rc = ret.clone();
+        me.object_size_checkbox.connect_clicked(move |_| rc.borrow_mut().update_sensitive());
+        let rc = ret.clone();
+        me.save_checkbox.connect_clicked(move |_| rc.borrow_mut().update_sensitive());
+        #[cfg(feature = "auth")]
+        {
+            let rc = ret.clone();
+            me.auth_checkbox.connect_clicked(move |_| rc.borrow_mut().update_sensitive());
+        }
+        let rc = ret.clone();
         me.start_button.connect_clicked(move |_| rc.borrow_mut().start_server());
         let rc = ret.clone();
         me.stop_button.connect_clicked(move |_| rc.borrow_mut().stop_server());
@@ -111,10 +160,18 @@ impl Controller {
                 self.listen_checkbox.set_sensitive(true);
                 self.ping_checkbox.set_sensitive(true);
                 self.verbose_checkbox.set_sensitive(true);
+                self.offset_checkbox.set_sensitive(true);
+                self.object_size_checkbox.set_sensitive(true);
                 self.save_checkbox.set_sensitive(true);
                 self.listen_field.set_sensitive(self.listen_checkbox.get_active());
                 self.ping_field.set_sensitive(self.ping_checkbox.get_active());
+                self.object_size_field.set_sensitive(self.object_size_checkbox.get_active());
                 self.save_field.set_sensitive(self.save_checkbox.get_active());
+                #[cfg(feature = "auth")]
+                {
+                    self.auth_checkbox.set_sensitive(true);
+                    self.auth_chooser.set_sensitive(self.auth_checkbox.get_active());
+                }
                 self.start_button.set_sensitive(true);
                 self.stop_button.set_sensitive(false);
             },
@@ -122,10 +179,18 @@ impl Controller {
                 self.listen_checkbox.set_sensitive(false);
                 self.ping_checkbox.set_sensitive(false);
                 self.verbose_checkbox.set_sensitive(false);
+                self.offset_checkbox.set_sensitive(false);
+                self.object_size_checkbox.set_sensitive(false);
                 self.save_checkbox.set_sensitive(false);
                 self.listen_field.set_sensitive(false);
                 self.ping_field.set_sensitive(false);
+                self.object_size_field.set_sensitive(false);
                 self.save_field.set_sensitive(false);
+                #[cfg(feature = "auth")]
+                {
+                    self.auth_checkbox.set_sensitive(false);
+                    self.auth_chooser.set_sensitive(false);
+                }
                 self.start_button.set_sensitive(false);
                 self.stop_button.set_sensitive(true);
             },
@@ -145,11 +210,13 @@ impl Controller {
         let termination_tx_clone = termination_tx.clone();
         let (canary_tx, canary_rx) = mpsc::channel(1);
         let log_tx = self.log_tx.clone();
+        let shared_tx = self.shared_tx.clone();
         let neu = thread::Builder::new().name("onizd server thread".to_owned())
             .spawn(move || {
                 let canary_tx = canary_tx;
                 crate::true_main(invocation, termination_tx_clone,
-                                 termination_rx, Outputter::Channel(log_tx));
+                                 termination_rx, Outputter::channel(log_tx),
+                                 Some(shared_tx));
                 std::mem::drop(canary_tx); // explicit but unnecessary
             });
         match neu {
@@ -181,7 +248,7 @@ impl Controller {
     /// the server thread stuff, call `update_sensitive`, and return false.
     ///
     /// Also reads the `log_tx` channel and appends any outputted log data to
-    /// the output view.
+    /// the output view, and keeps the status bar up to date.
     fn check_server_status(&mut self) -> bool {
         let ret =
         if self.server_thread.is_none() || self.server_canary.is_none() {
@@ -198,15 +265,52 @@ impl Controller {
         while let Ok(str) = self.log_rx.try_recv() {
             self.append_text(&str);
         }
+        while let Ok(shared) = self.shared_rx.try_recv() {
+            self.shared = Some(shared);
+            self.last_status = None;
+        }
         if ret == false {
             self.server_thread = None;
             self.terminator = None;
             self.server_canary = None;
+            self.shared = None;
             self.update_sensitive();
             self.append_text("Server is no longer running.");
+            self.update_status();
+        }
+        else if self.last_status.map(|x| x.elapsed() >= STATUS_INTERVAL)
+            .unwrap_or(true) {
+            self.update_status();
         }
         ret
     }
+    /// Fills in the status bar from the running server's `Shared`, or blanks
+    /// it if there isn't one.
+    fn update_status(&mut self) {
+        let shared = match &self.shared {
+            None => {
+                self.tiles_label.set_text("");
+                self.clients_label.set_text("");
+                self.save_status_label.set_text("");
+                return
+            },
+            Some(x) => x,
+        };
+        // the write lock is only held while loading or saving; rather than
+        // freeze the GUI, just try again next time
+        let (tiles, pending) = match shared.map.try_read() {
+            Ok(map) => (map.occupied_tile_count(), map.is_dirty()),
+            Err(_) => return,
+        };
+        self.last_status = Some(Instant::now());
+        self.tiles_label.set_text(&format!("Occupied tiles: {}", tiles));
+        self.clients_label.set_text(&format!(
+            "Clients: {}", shared.connections.load(Ordering::Relaxed)));
+        self.save_status_label.set_text(
+            if shared.invocation.save_file.is_none() { "Not saving" }
+            else if pending { "Save pending" }
+            else { "Saved" });
+    }
     fn append_text(&mut self, text: &str) {
         let buffer = self.output_view.get_buffer().unwrap();
         if buffer.get_char_count() + text.len() as i32 > MAX_LOG_SIZE {
@@ -230,17 +334,17 @@ impl Controller {
             else if !text.ends_with(".json") { Some(text.to_owned() + ".json")}
             else { Some(text.to_owned()) }
         } else { None };
-        let listen_addr = if self.listen_checkbox.get_active() {
+        let listen_addrs = if self.listen_checkbox.get_active() {
             let gtext = self.listen_field.get_text();
             let text = gtext.as_str();
-            if text == "" { None }
+            if text == "" { Vec::new() }
             else {
                 match text.parse::<std::net::SocketAddr>() {
-                    Ok(_) => Some(text.to_owned()),
+                    Ok(_) => vec![text.to_owned()],
                     Err(_) => return Err("Invalid listen address.".to_owned()),
                 }
             }
-        } else { None };
+        } else { Vec::new() };
         let ping_interval = if self.ping_checkbox.get_active() {
             let gtext = self.ping_field.get_text();
             let text = gtext.as_str();
@@ -253,8 +357,33 @@ impl Controller {
             }
         } else { None };
         let verbosity = if self.verbose_checkbox.get_active() { 1 } else { 0 };
-        Ok(Invocation { listen_addr, ping_interval, verbosity, save_file,
-                        offset_mode: false, auth_file: None })
+        let offset = if self.offset_checkbox.get_active() {
+            Some(crate::OFFSET_MODE_OFFSET)
+        } else { None };
+        let mut map_limits = MapLimits::default();
+        if self.object_size_checkbox.get_active() {
+            let gtext = self.object_size_field.get_text();
+            let text = gtext.as_str();
+            if text != "" {
+                match text.parse::<usize>() {
+                    Ok(x) if x >= 1 && x <= crate::MAX_OBJECT_SIZE_LIMIT =>
+                        map_limits.max_object_size = x,
+                    _ => return Err("Invalid maximum object size.".to_owned()),
+                }
+            }
+        }
+        #[cfg(feature = "auth")]
+        let auth_file = if self.auth_checkbox.get_active() {
+            match self.auth_chooser.get_filename() {
+                Some(path) => Some(path.to_string_lossy().into_owned()),
+                None => return Err("No secret file chosen.".to_owned()),
+            }
+        } else { None };
+        #[cfg(not(feature = "auth"))]
+        let auth_file = None;
+        Ok(Invocation { listen_addrs, ping_interval, verbosity, offset,
+                        save_file, auth_file, map_limits,
+                        ..Default::default() })
     }
 }
 
@@ -299,6 +428,24 @@ pub fn go() {
             .label("Output more information").halign(Align::Start).build();
         little_box.add(&verbose_checkbox);
         little_box.add(&verbose_label);
+        let offset_checkbox = CheckButton::new();
+        let offset_label = LabelBuilder::new()
+            .label("Offset mode").halign(Align::Start).build();
+        little_box.add(&offset_checkbox);
+        little_box.add(&offset_label);
+        big_box.add(&little_box);
+        // Row #1½: limits
+        let little_box = BoxBuilder::new().spacing(SPACING).build();
+        let object_size_checkbox = CheckButton::new();
+        let object_size_label = LabelBuilder::new()
+            .label("Max object size:").halign(Align::Start).build();
+        let object_size_field = EntryBuilder::new().sensitive(false)
+            .placeholder_text(&crate::MAX_OBJECT_SIZE.to_string())
+            .width_request(80).input_purpose(InputPurpose::Number)
+            .max_length(4).build();
+        little_box.add(&object_size_checkbox);
+        little_box.add(&object_size_label);
+        little_box.add(&object_size_field);
         big_box.add(&little_box);
         // Row #2: saving-related things
         let little_box = BoxBuilder::new().spacing(SPACING).build();
@@ -312,6 +459,23 @@ pub fn go() {
         little_box.add(&save_label);
         little_box.add(&save_field);
         big_box.add(&little_box);
+        // Row #2½: authentication
+        #[cfg(feature = "auth")]
+        let (auth_checkbox, auth_chooser) = {
+            let little_box = BoxBuilder::new().spacing(SPACING).build();
+            let auth_checkbox = CheckButton::new();
+            let auth_label = LabelBuilder::new().label("Secret file:")
+                .halign(Align::Start).build();
+            let auth_chooser = FileChooserButton::new("Choose Secret File",
+                                                      FileChooserAction::Open);
+            auth_chooser.set_hexpand(true);
+            auth_chooser.set_sensitive(false);
+            little_box.add(&auth_checkbox);
+            little_box.add(&auth_label);
+            little_box.add(&auth_chooser);
+            big_box.add(&little_box);
+            (auth_checkbox, auth_chooser)
+        };
         // Row #3: buttons!
         let button_box = BoxBuilder::new().halign(Align::End).spacing(SPACING)
             .build();
@@ -338,12 +502,29 @@ pub fn go() {
                                 0.0, true, 0.0, 1.0);
         });
         big_box.add(&scroller);
+        // Row #6: status bar
+        let status_box = BoxBuilder::new().spacing(SPACING * 2).build();
+        let tiles_label = LabelBuilder::new().halign(Align::Start).build();
+        let clients_label = LabelBuilder::new().halign(Align::Start).build();
+        let save_status_label = LabelBuilder::new().halign(Align::Start)
+            .build();
+        status_box.add(&tiles_label);
+        status_box.add(&clients_label);
+        status_box.add(&save_status_label);
+        big_box.add(&status_box);
         window.add(&big_box);
         window.show_all();
         // Controller will keep track of itself
         Controller::new(window, listen_checkbox, listen_field, ping_checkbox,
-                        ping_field, verbose_checkbox, save_checkbox,
-                        save_field, start_button, stop_button, output_view);
+                        ping_field, verbose_checkbox, offset_checkbox,
+                        object_size_checkbox, object_size_field,
+                        save_checkbox, save_field,
+                        #[cfg(feature = "auth")]
+                        auth_checkbox,
+                        #[cfg(feature = "auth")]
+                        auth_chooser,
+                        start_button, stop_button, output_view, tiles_label,
+                        clients_label, save_status_label);
     });
     application.run(&[]);
 }
@@ -19,15 +19,184 @@
 
 use std::time::Duration;
 use std::convert::TryInto;
+use std::str::FromStr;
+use serde::Deserialize;
+
+use crate::{MapLimits, SaveFormat, DuplicateIdentity, DEFAULT_LOG_MAX_SIZE,
+            DEFAULT_COMPRESSION_LEVEL, MAX_OBJECT_SIZE_LIMIT,
+            MAX_CHUNKED_OBJECT_SIZE_LIMIT,
+            DEFAULT_MAX_MESSAGE_BYTES};
+
+pub const DEFAULT_AUTH_MAX_FAILURES: u32 = 5;
+pub const DEFAULT_AUTH_BAN_WINDOW: Duration = Duration::from_secs(300);
+/// The offset that `--offset-mode` stands for.
+pub const OFFSET_MODE_OFFSET: (i32, i32, i32) = (0, 1, 0);
+/// The longest a timeout or interval option can be, in seconds: a year. Any
+/// longer and adding it to the current time might overflow.
+const MAX_TIMER_SECS: u64 = 365 * 24 * 60 * 60;
 
 #[derive(Debug,Clone)]
 pub struct Invocation {
-    pub listen_addr: Option<String>,
+    /// Addresses to listen on. If empty, `DEFAULT_ADDR_AND_PORT` is used.
+    pub listen_addrs: Vec<String>,
+    /// Addresses to listen on for WebSocket clients, as well as the ordinary
+    /// ones.
+    pub ws_listen_addrs: Vec<String>,
+    pub listen_proxy_protocol: bool,
+    /// Make IPv6 listeners accept IPv4 connections too, whatever the OS's
+    /// default is.
+    pub dual_stack: bool,
+    /// If an address to listen on is in use, keep trying to bind it for this
+    /// long before giving up.
+    pub bind_retry: Option<Duration>,
     pub auth_file: Option<String>,
+    /// A directory of per-identity secret files, as an alternative to
+    /// `auth_file`. Never set at the same time as `auth_file`.
+    pub auth_dir: Option<String>,
+    /// PEM files with the certificate chain and private key to use for TLS.
+    /// Either both are given or neither is.
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    /// This many authentication failures from one address within
+    /// `auth_ban_window` gets that address banned for a while.
+    pub auth_max_failures: u32,
+    pub auth_ban_window: Duration,
+    /// What to do when an `auth_dir` identity connects while it's already
+    /// connected.
+    pub duplicate_identity: DuplicateIdentity,
     pub save_file: Option<String>,
-    pub offset_mode: bool,
+    /// The format to save the map in. If not given, it's picked based on the
+    /// name of `save_file`.
+    pub save_format: Option<SaveFormat>,
+    /// Gzip the save file, whatever its name. (It's gzipped anyway if its
+    /// name ends in `.gz`.)
+    pub compress_save: bool,
+    /// Refuse every message that would add to the map, and never save it.
+    pub readonly: bool,
+    /// Added to the point of every `recv_*` request, and of every
+    /// registration whose name doesn't end in "Sender". Senders are
+    /// registered at the opposite offset. Useful for testing with only one
+    /// world.
+    pub offset: Option<(i32, i32, i32)>,
+    /// For points sent without a `z`, take the z layer from this many of the
+    /// top bits of `y` (0 for none). This happens before `offset` is applied,
+    /// so an offset moves a point between layers, and never spills its `y`
+    /// into the layer bits.
+    pub z_from_y_bits: u32,
+    /// Refuse to let clients send to or register anything at a point with any
+    /// coordinate below `min_coord` or above `max_coord`. The point checked
+    /// is the one the client gave, before `offset` is applied.
+    pub min_coord: Option<(i32, i32, i32)>,
+    pub max_coord: Option<(i32, i32, i32)>,
     pub verbosity: u32,
+    /// Don't log clients connecting, authenticating, and disconnecting, only
+    /// errors (and the usual startup and shutdown messages). Never set along
+    /// with a nonzero `verbosity`.
+    pub quiet: bool,
+    /// Log the events that `verbosity` asks for as JSON objects, one per
+    /// line, instead of as prose.
+    pub log_json: bool,
     pub ping_interval: Option<Duration>,
+    pub autosave_interval: Option<Duration>,
+    /// Save the map once it has gone this long without changing, if it has
+    /// changed since the last save.
+    pub save_interval_on_change: Option<Duration>,
+    /// JSON files mapping element and germ ids to names, for logging.
+    pub element_names: Option<String>,
+    pub germ_names: Option<String>,
+    /// File listing the building identifiers clients may register.
+    pub building_list: Option<String>,
+    /// Disconnect clients we haven't heard anything from in this long.
+    pub idle_timeout: Option<Duration>,
+    pub metrics_addr: Option<String>,
+    pub max_messages_per_second: Option<u32>,
+    /// Turn away new connections while this many clients are connected.
+    pub max_connections: Option<usize>,
+    /// Fraction of each point's stored energy that is lost every second.
+    pub energy_decay_rate: Option<f64>,
+    /// Periodically even out the temperatures of the packets stored at each
+    /// point.
+    pub thermal_mixing: bool,
+    /// Half-life, in seconds, of the germs in stored packets.
+    pub germ_decay: Option<f64>,
+    /// Throw away objects that have been stored for this long without being
+    /// received.
+    pub object_ttl: Option<Duration>,
+    /// If given, log to this file instead of to stderr.
+    pub log_file: Option<String>,
+    pub log_max_size: u64,
+    /// Log to the local syslog daemon instead of to stderr.
+    pub syslog: bool,
+    /// zlib level (0-9) for clients that ask for compression.
+    pub compression_level: u32,
+    /// Refuse to compress, even for clients that ask for it.
+    pub no_compression: bool,
+    /// The longest message (after decompression) we'll accept from a client.
+    pub max_message_bytes: usize,
+    pub map_limits: MapLimits,
+}
+
+impl Default for Invocation {
+    fn default() -> Invocation {
+        Invocation {
+            listen_addrs: Vec::new(),
+            ws_listen_addrs: Vec::new(),
+            listen_proxy_protocol: false,
+            dual_stack: false,
+            bind_retry: None,
+            auth_file: None,
+            auth_dir: None,
+            tls_cert: None,
+            tls_key: None,
+            auth_max_failures: DEFAULT_AUTH_MAX_FAILURES,
+            auth_ban_window: DEFAULT_AUTH_BAN_WINDOW,
+            duplicate_identity: DuplicateIdentity::Allow,
+            save_file: None,
+            save_format: None,
+            compress_save: false,
+            readonly: false,
+            offset: None,
+            z_from_y_bits: 0,
+            min_coord: None,
+            max_coord: None,
+            verbosity: 0,
+            quiet: false,
+            log_json: false,
+            ping_interval: None,
+            autosave_interval: None,
+            save_interval_on_change: None,
+            element_names: None,
+            germ_names: None,
+            building_list: None,
+            idle_timeout: None,
+            metrics_addr: None,
+            max_messages_per_second: None,
+            max_connections: None,
+            energy_decay_rate: None,
+            thermal_mixing: false,
+            germ_decay: None,
+            object_ttl: None,
+            log_file: None,
+            log_max_size: DEFAULT_LOG_MAX_SIZE,
+            syslog: false,
+            compression_level: DEFAULT_COMPRESSION_LEVEL,
+            no_compression: false,
+            max_message_bytes: DEFAULT_MAX_MESSAGE_BYTES,
+            map_limits: MapLimits::default(),
+        }
+    }
+}
+
+impl Invocation {
+    /// Returns the format the map should be saved to the given path in.
+    pub fn save_format_for(&self, path: &str) -> SaveFormat {
+        self.save_format.unwrap_or_else(|| SaveFormat::for_path(path))
+    }
+    /// Returns `true` if the map should be gzipped when saved to the given
+    /// path.
+    pub fn save_compressed_for(&self, path: &str) -> bool {
+        self.compress_save || path.ends_with(".gz")
+    }
 }
 
 fn print_usage(program: &str, opts: getopts::Options) {
@@ -42,13 +211,75 @@ Usage: {} [options]\
 pub fn get_invocation() -> Option<Invocation> {
     let args: Vec<String> = std::env::args().collect();
     let mut opts = getopts::Options::new();
-    opts.optopt("l", "listen-on", "Specify address and port to listen on.", "ADDR:PORT (default 0.0.0.0:5496)");
-    opts.optflag("o", "offset-mode", "Add 1 to Y coordinate of all consumers; useful for single-world testing.");
+    opts.optopt("c", "config", "Read settings from a TOML file. Options given on the command line override the ones in the file.", "FILE");
+    opts.optmulti("l", "listen-on", "Specify address and port to listen on. Can be given more than once, to listen on several addresses.", "ADDR:PORT (default 0.0.0.0:5496)");
+    #[cfg(feature = "websocket")]
+    opts.optmulti("", "ws-listen", "Also listen on this address and port for WebSocket clients, such as ones running in a web browser. Each text message is one protocol message. Can be given more than once.", "ADDR:PORT");
+    opts.optflag("", "listen-proxy-protocol", "Expect every connection to begin with a PROXY protocol (v1 or v2) header, as sent by HAProxy and similar proxies, and use the client address it contains. Connections without a valid header are rejected.");
+    opts.optflag("", "dual-stack", "Make every IPv6 address listened on (such as [::]:5496) accept IPv4 connections as well, instead of leaving it up to the operating system.");
+    opts.optopt("", "bind-retry", "If an address to listen on is already in use (say, by a server that was just restarted), keep trying for this long before giving up. At most 31536000 (a year). (default 0, give up right away)", "SECONDS");
+    opts.optflag("o", "offset-mode", "Add 1 to the Y coordinate of everything but senders (see --offset); useful for single-world testing. Same as --offset 0,1,0.");
+    opts.optopt("", "offset", "Add this to the coordinates of every recv_* request and every registration, except that buildings whose names end in \"Sender\" have it subtracted instead; useful for single-world testing.", "X,Y,Z");
+    opts.optopt("", "z-from-y-bits", "For clients that don't send a Z coordinate, take the Z layer from this many of the top bits of the Y coordinate, up to 16. (These clients also hear about registrations on other layers this way.) --offset and --offset-mode apply after the layer is taken out. (default 0, meaning every such point is on layer 0)", "N");
+    opts.optopt("", "min-coord", "Refuse to let clients send anything to, or register anything at, a point with any coordinate below this one's. Useful to keep a buggy client from scattering things all over the map. (default unlimited)", "X,Y,Z");
+    opts.optopt("", "max-coord", "Refuse to let clients send anything to, or register anything at, a point with any coordinate above this one's. (default unlimited)", "X,Y,Z");
     opts.optflagmulti("v", "verbose", "Print information every time something happens (lots!). Specify twice to print every received packet.");
+    opts.optflag("q", "quiet", "Don't print anything when clients connect, authenticate, or disconnect. Errors are still printed.");
     #[cfg(feature = "auth")]
     opts.optopt("a", "auth-file", "Specify the shared secret file to use for authentication. If absent, authentication will not be used.", "FILE");
-    opts.optopt("s", "save-file", "Specify a JSON file in which to save and restore the map state.", "FILE");
+    #[cfg(feature = "auth")]
+    opts.optopt("", "auth-max-failures", "Ban an address after it fails to authenticate this many times within --auth-ban-window. (default 5)", "COUNT");
+    #[cfg(feature = "auth")]
+    opts.optopt("", "auth-ban-window", "The window for --auth-max-failures, and the length of an address's first ban. Each later ban of the same address lasts twice as long as the last. (default 300)", "SECONDS");
+    #[cfg(feature = "auth")]
+    opts.optopt("", "auth-dir", "Authenticate each client against its own secret file, named after the identity the client gives in its hello, in this directory. Use instead of --auth-file.", "DIR");
+    #[cfg(feature = "auth")]
+    opts.optopt("", "duplicate-identity", "What to do when an --auth-dir identity connects while it already has a connection: \"allow\" both connections, \"replace\" the old one (which is hung up on, and its buildings unregistered), or \"reject\" the new one. (default allow)", "POLICY");
+    #[cfg(feature = "tls")]
+    opts.optopt("", "tls-cert", "Require clients to connect using TLS, with the certificate chain in this PEM file. Requires --tls-key.", "FILE");
+    #[cfg(feature = "tls")]
+    opts.optopt("", "tls-key", "The private key (PEM, PKCS #8 or RSA) that goes with --tls-cert.", "FILE");
+    opts.optopt("s", "save-file", "Specify a file in which to save and restore the map state.", "FILE");
+    opts.optopt("", "save-format", "Save the map as \"json\" or \"binary\". Binary saves are smaller and faster, which matters for very large maps. Either format can be loaded regardless. (default: binary if the save file's name ends in .bin or .bin.gz, json otherwise)", "FORMAT");
+    opts.optflag("", "compress-save", "Gzip the map when saving it, even if the save file's name doesn't end in .gz. (Names that do end in .gz are always gzipped.) Compressed and uncompressed saves can both be loaded regardless.");
+    opts.optflag("", "readonly", "Load the map, but refuse to let clients add to it or register anything, and never save it. Useful for poking at a copy of a saved map.");
+    opts.optopt("", "autosave-interval", "Also save the map this often, instead of only when the server shuts down. At most 31536000 (a year). Requires --save-file.", "SECONDS");
+    opts.optopt("", "save-interval-on-change", "Also save the map once it has gone this long without changing, if it has changed since it was last saved. Unlike --autosave-interval, an idle server never rewrites the file. At most 31536000 (a year). Requires --save-file.", "SECONDS");
+    opts.optflag("", "log-json", "Log the events that -v asks for as JSON objects, one per line, instead of as prose. Other log messages are unaffected.");
+    opts.optopt("", "log-file", "Append log output to this file instead of printing it. If the file can't be opened, logs go to stderr instead.", "FILE");
+    #[cfg(all(unix, feature = "syslog"))]
+    opts.optflag("", "syslog", "Send log output to the local syslog daemon instead of printing it. Errors are logged as warnings, everything else as info. If syslog can't be reached, logs go to stderr instead.");
+    opts.optopt("", "log-max-size", "Once the log file would grow past this size, rename it to FILE.1 (FILE.1 to FILE.2, and so on) and start a new one. (default 10000000)", "BYTES");
+    opts.optopt("", "compression-level", "How hard to try when compressing data for clients that ask for compression, from 0 (not at all) to 9 (as hard as possible). Our messages are small, so high levels gain little. (default 6)", "LEVEL");
+    opts.optflag("", "no-compression", "Don't compress anything, even for clients that ask for it. Clients that ask will be told that no compression types are supported.");
+    opts.optopt("", "max-message-bytes", "Disconnect any client that sends a message longer than this, not counting compression. Must be at least 1024. (default 10000)", "BYTES");
     opts.optopt("p", "ping-interval", "Send a \"ping\" message to each client roughly this often. This can help deal with broken NAT routers that aggressively drop idle connections.", "SECONDS");
+    opts.optopt("", "element-names", "Load element names (for logging) from this JSON file, which maps ids to names. These supplement the built-in names. A name may instead be given as {\"name\": NAME, \"state\": \"solid\"/\"liquid\"/\"gas\"}, and then packets of that element sent as the wrong phase are refused.", "FILE");
+    opts.optopt("", "germ-names", "Load germ names (for logging) from this JSON file, which maps ids to names. These supplement the built-in names.", "FILE");
+    opts.optopt("", "building-list", "Only allow clients to register the buildings listed in this file (one identifier per line). By default, anything goes.", "FILE");
+    opts.optopt("", "idle-timeout", "Disconnect a client if it hasn't sent anything for this long. Use with --ping-interval (set shorter than this), so that clients that are merely quiet answer the pings and stay connected. At most 31536000 (a year).", "SECONDS");
+    opts.optopt("", "metrics-addr", "Also serve Prometheus-style metrics over HTTP on this address and port.", "ADDR:PORT");
+    opts.optopt("", "max-messages-per-second", "Limit how many messages each client can have processed per second. Messages beyond the limit are delayed, not dropped. Pings are exempt.", "N");
+    opts.optopt("", "max-connections", "Limit how many clients can be connected at once. Anyone who connects while the server is full is told so and disconnected.", "N");
+    opts.optopt("", "energy-decay-rate", "Lose this fraction (between 0 and 1) of the energy stored at each point every second, as transmission loss. By default, stored energy never decays.", "FRACTION");
+    opts.optflag("", "thermal-mixing", "Let gas or liquid packets stored at the same point exchange heat, as if they were sharing a tile, so that they soon reach the same temperature. By default, packets come out at the temperature they went in.");
+    opts.optopt("", "germ-decay", "Let the germs in stored packets die off, with half of them dying every this many seconds. Nothing else about the packets changes. By default, packets come out with as many germs as they went in with.", "SECONDS");
+    opts.optopt("", "object-ttl", "Throw away objects that have been stored for this long without anyone receiving them. (For a stack of identical objects, this counts from when the newest one arrived.) By default, objects wait forever.", "SECONDS");
+    opts.optopt("", "max-energy", "Maximum number of joules that can be stored at one point. (default 10000)", "JOULES");
+    opts.optopt("", "max-packets", "Maximum number of gas or liquid packets that can be stored at one point. (default 10)", "COUNT");
+    opts.optopt("", "gas-stack", "Maximum mass, in kg, of one gas packet. Only change this if your game is modded to change it too. (default 1)", "KG");
+    opts.optopt("", "liquid-stack", "Maximum mass, in kg, of one liquid packet. Only change this if your game is modded to change it too. (default 10)", "KG");
+    opts.optopt("", "max-objects", "Maximum number of objects that can be stored at one point. (default 3)", "COUNT");
+    opts.optopt("", "max-object-size", "Maximum size, in bytes, of one object sent whole. Objects any bigger are rejected, unless they're sent in pieces. Can't be more than 7168. (default 4096)", "BYTES");
+    opts.optopt("", "max-chunked-object-size", "Maximum size, in bytes, of one object sent in pieces. Objects bigger than this and --max-object-size are left out when loading a saved map. Can't be more than 16777216. (default 65536)", "BYTES");
+    opts.optopt("", "max-registrations", "Maximum number of buildings one client can register at one point. (default 7)", "COUNT");
+    opts.optopt("", "max-total-registrations", "Maximum number of buildings that can be registered at one point, by all clients together. (Each client is still limited by --max-registrations.)", "COUNT");
+    opts.optopt("", "max-total-objects", "Maximum number of objects that can be stored on the whole map at once. Objects sent while the map is full are rejected.", "COUNT");
+    opts.optopt("", "max-total-object-bytes", "Maximum number of bytes of objects that can be stored on the whole map at once.", "BYTES");
+    opts.optopt("", "max-tiles", "Maximum number of points on the map that can have something stored at them at once. Once reached, only points that already have something stored can accept more.", "COUNT");
+    opts.optflag("", "stack-objects", "Let objects that are identical byte-for-byte share one of a point's object slots, instead of each taking its own. Only use this if your clients are okay with it.");
+    opts.optopt("", "energy-spill-to", "Instead of handing back energy that doesn't fit at a point, spill it into the point this far away (for example, \"0,1,0\" for the point above), and from there onward, through up to 16 points. Whatever still doesn't fit is handed back.", "X,Y,Z");
+    opts.optflag("", "mass-audit", "Keep count of all the gas and liquid mass that goes into and out of the map, check every minute that it all adds up, and let clients ask for the totals with mass_audit. For hunting down bugs that lose or create mass; it slows things down a little.");
     opts.optflag("?", "help", "Print this help string.");
     let matches = match opts.parse(&args[1..]) {
         Ok(x) => x,
@@ -60,28 +291,736 @@ pub fn get_invocation() -> Option<Invocation> {
     };
     if matches.opt_present("?") || !matches.free.is_empty() {
         print_usage(&args[0], opts);
-        None
+        return None
     }
-    else {
-        Some(Invocation {
-            listen_addr: matches.opt_str("l"),
-            offset_mode: matches.opt_present("o"),
-            verbosity: matches.opt_count("v").try_into().expect("ridiculous \
-                                                                 -v count"),
-            auth_file: if cfg!(feature = "auth") { matches.opt_str("a") }
-            else { None },
-            save_file: matches.opt_str("s"),
-            ping_interval: match matches.opt_str("p") {
-                None => None,
-                Some(x) => match x.parse() {
-                    Ok(x) if x > 0 && x < 999 => Some(Duration::new(x, 0)),
-                    _ => {
-                        eprintln!("Invalid ping interval, should be between 1 and 999");
-                        print_usage(&args[0], opts);
-                        return None
-                    }
-                }
+    let mut invocation = match matches.opt_str("c") {
+        None => Invocation::default(),
+        Some(path) => match from_config_file(&path) {
+            Ok(x) => x,
+            Err(x) => {
+                eprintln!("{}: {}", path, x);
+                return None
+            },
+        },
+    };
+    if apply_options(&matches, &mut invocation).is_err() {
+        print_usage(&args[0], opts);
+        return None
+    }
+    Some(invocation)
+}
+
+/// Overrides the settings in `invocation` with any that were given on the
+/// command line. Prints a complaint and returns `Err` if any of them were
+/// invalid.
+fn apply_options(matches: &getopts::Matches, invocation: &mut Invocation)
+                 -> Result<(), ()> {
+    let listen_addrs = matches.opt_strs("l");
+    if !listen_addrs.is_empty() { invocation.listen_addrs = listen_addrs }
+    #[cfg(feature = "websocket")]
+    {
+        let ws_listen_addrs = matches.opt_strs("ws-listen");
+        if !ws_listen_addrs.is_empty() {
+            invocation.ws_listen_addrs = ws_listen_addrs
+        }
+    }
+    // (a flag can only turn something on, not off)
+    if matches.opt_present("listen-proxy-protocol") {
+        invocation.listen_proxy_protocol = true;
+    }
+    if matches.opt_present("dual-stack") { invocation.dual_stack = true }
+    if let Some(x) = parse_opt(matches, "bind-retry", check_bind_retry)? {
+        invocation.bind_retry = x;
+    }
+    if matches.opt_present("o") {
+        if matches.opt_present("offset") {
+            eprintln!("--offset-mode and --offset can't be used together");
+            return Err(())
+        }
+        invocation.offset = Some(OFFSET_MODE_OFFSET);
+    }
+    if let Some(x) = parse_opt(matches, "offset", check_offset)? {
+        invocation.offset = Some(x);
+    }
+    if let Some(x) = parse_opt(matches, "z-from-y-bits", check_z_bits)? {
+        invocation.z_from_y_bits = x;
+    }
+    if let Some(x) = parse_opt(matches, "min-coord", check_offset)? {
+        invocation.min_coord = Some(x);
+    }
+    if let Some(x) = parse_opt(matches, "max-coord", check_offset)? {
+        invocation.max_coord = Some(x);
+    }
+    if let (Some(min), Some(max))
+        = (invocation.min_coord, invocation.max_coord) {
+        if min.0 > max.0 || min.1 > max.1 || min.2 > max.2 {
+            eprintln!("--min-coord can't be above --max-coord");
+            return Err(())
+        }
+    }
+    if matches.opt_present("readonly") { invocation.readonly = true }
+    if matches.opt_present("log-json") { invocation.log_json = true }
+    if matches.opt_present("v") {
+        if matches.opt_present("quiet") {
+            eprintln!("--verbose and --quiet can't be used together");
+            return Err(())
+        }
+        invocation.verbosity = matches.opt_count("v").try_into()
+            .expect("ridiculous -v count");
+        invocation.quiet = false;
+    }
+    if matches.opt_present("quiet") {
+        invocation.quiet = true;
+        invocation.verbosity = 0;
+    }
+    #[cfg(feature = "auth")]
+    {
+        // (whichever of these is on the command line beats the config file)
+        if let Some(x) = matches.opt_str("a") {
+            if matches.opt_present("auth-dir") {
+                eprintln!("--auth-file and --auth-dir can't be used together");
+                return Err(())
+            }
+            invocation.auth_file = Some(x);
+            invocation.auth_dir = None;
+        }
+        if let Some(x) = matches.opt_str("auth-dir") {
+            invocation.auth_dir = Some(x);
+            invocation.auth_file = None;
+        }
+        if let Some(x) = parse_opt(matches, "auth-max-failures",
+                                   check_auth_max_failures)? {
+            invocation.auth_max_failures = x;
+        }
+        if let Some(x) = parse_opt(matches, "auth-ban-window",
+                                   check_auth_ban_window)? {
+            invocation.auth_ban_window = x;
+        }
+        if let Some(x) = parse_opt(matches, "duplicate-identity",
+                                   check_duplicate_identity)? {
+            invocation.duplicate_identity = x;
+        }
+    }
+    #[cfg(feature = "tls")]
+    {
+        if let Some(x) = matches.opt_str("tls-cert") {
+            invocation.tls_cert = Some(x);
+        }
+        if let Some(x) = matches.opt_str("tls-key") {
+            invocation.tls_key = Some(x);
+        }
+        if invocation.tls_cert.is_some() != invocation.tls_key.is_some() {
+            eprintln!("--tls-cert and --tls-key must be given together");
+            return Err(())
+        }
+    }
+    if let Some(x) = matches.opt_str("s") { invocation.save_file = Some(x) }
+    if let Some(x) = parse_opt(matches, "save-format", check_save_format)? {
+        invocation.save_format = Some(x);
+    }
+    if matches.opt_present("compress-save") {
+        invocation.compress_save = true;
+    }
+    if let Some(x) = matches.opt_str("log-file") {
+        invocation.log_file = Some(x);
+    }
+    #[cfg(all(unix, feature = "syslog"))]
+    {
+        if matches.opt_present("syslog") {
+            invocation.syslog = true;
+        }
+        if invocation.syslog && invocation.log_file.is_some() {
+            eprintln!("--syslog and --log-file can't be used together");
+            return Err(())
+        }
+    }
+    if let Some(x) = parse_opt(matches, "log-max-size", check_log_size)? {
+        invocation.log_max_size = x;
+    }
+    if let Some(x) = parse_opt(matches, "compression-level",
+                               check_compression_level)? {
+        invocation.compression_level = x;
+    }
+    if matches.opt_present("no-compression") {
+        invocation.no_compression = true
+    }
+    if let Some(x) = parse_opt(matches, "max-message-bytes",
+                               check_message_bytes)? {
+        invocation.max_message_bytes = x;
+    }
+    if let Some(x) = parse_opt(matches, "ping-interval",
+                               check_ping_interval)? {
+        invocation.ping_interval = Some(x);
+    }
+    if let Some(x) = parse_opt(matches, "autosave-interval",
+                               check_autosave_interval)? {
+        invocation.autosave_interval = Some(x);
+    }
+    if let Some(x) = parse_opt(matches, "save-interval-on-change",
+                               check_autosave_interval)? {
+        invocation.save_interval_on_change = Some(x);
+    }
+    if let Some(x) = matches.opt_str("element-names") {
+        invocation.element_names = Some(x);
+    }
+    if let Some(x) = matches.opt_str("germ-names") {
+        invocation.germ_names = Some(x);
+    }
+    if let Some(x) = matches.opt_str("building-list") {
+        invocation.building_list = Some(x);
+    }
+    if let Some(x) = parse_opt(matches, "idle-timeout",
+                               check_idle_timeout)? {
+        invocation.idle_timeout = Some(x);
+    }
+    if let Some(x) = matches.opt_str("metrics-addr") {
+        invocation.metrics_addr = Some(x);
+    }
+    if let Some(x) = parse_opt(matches, "max-messages-per-second",
+                               check_message_rate)? {
+        invocation.max_messages_per_second = Some(x);
+    }
+    if let Some(x) = parse_opt(matches, "max-connections", check_nonzero)? {
+        invocation.max_connections = Some(x);
+    }
+    if let Some(x) = parse_opt(matches, "energy-decay-rate",
+                               check_decay_rate)? {
+        invocation.energy_decay_rate = Some(x);
+    }
+    if matches.opt_present("thermal-mixing") {
+        invocation.thermal_mixing = true;
+    }
+    if let Some(x) = parse_opt(matches, "germ-decay", check_half_life)? {
+        invocation.germ_decay = Some(x);
+    }
+    if let Some(x) = parse_opt(matches, "object-ttl", check_object_ttl)? {
+        invocation.object_ttl = Some(x);
+    }
+    let map_limits = &mut invocation.map_limits;
+    if let Some(x) = parse_opt(matches, "max-energy", Ok)? {
+        map_limits.max_stored_energy = x;
+    }
+    if let Some(x) = parse_opt(matches, "max-packets", check_nonzero)? {
+        map_limits.max_stored_packets = x;
+    }
+    if let Some(x) = parse_opt(matches, "gas-stack", check_stack)? {
+        map_limits.phase_limits.gas_stack = x;
+    }
+    if let Some(x) = parse_opt(matches, "liquid-stack", check_stack)? {
+        map_limits.phase_limits.liquid_stack = x;
+    }
+    if let Some(x) = parse_opt(matches, "max-objects", check_nonzero)? {
+        map_limits.max_stored_objects = x;
+    }
+    if let Some(x) = parse_opt(matches, "max-object-size",
+                               check_object_size)? {
+        map_limits.max_object_size = x;
+    }
+    if let Some(x) = parse_opt(matches, "max-chunked-object-size",
+                               check_chunked_object_size)? {
+        map_limits.max_chunked_object_size = x;
+    }
+    if let Some(x) = parse_opt(matches, "max-registrations", check_nonzero)? {
+        map_limits.max_registrations = x;
+    }
+    if let Some(x) = parse_opt(matches, "max-total-registrations",
+                               check_nonzero)? {
+        map_limits.max_total_registrations = Some(x);
+    }
+    if let Some(x) = parse_opt(matches, "max-total-objects", Ok)? {
+        map_limits.max_total_objects = Some(x);
+    }
+    if let Some(x) = parse_opt(matches, "max-total-object-bytes", Ok)? {
+        map_limits.max_total_object_bytes = Some(x);
+    }
+    if let Some(x) = parse_opt(matches, "max-tiles", check_nonzero)? {
+        map_limits.max_tiles = Some(x);
+    }
+    if let Some(x) = parse_opt(matches, "energy-spill-to", check_spill)? {
+        map_limits.energy_spill_to = Some(x);
+    }
+    if matches.opt_present("stack-objects") {
+        map_limits.stack_objects = true;
+    }
+    if matches.opt_present("mass-audit") {
+        map_limits.mass_audit = true;
+    }
+    Ok(())
+}
+
+/// Parses and checks the value of an option, if it was given. Prints a
+/// complaint and returns `Err` if it was given but wasn't valid.
+fn parse_opt<T: FromStr, U>(matches: &getopts::Matches, name: &str,
+                            check: impl Fn(T) -> Result<U, String>)
+                            -> Result<Option<U>, ()> {
+    match matches.opt_str(name) {
+        None => Ok(None),
+        Some(x) => match x.parse().map_err(|_| format!("{:?}", x))
+            .and_then(check) {
+                Ok(x) => Ok(Some(x)),
+                Err(x) => {
+                    eprintln!("Invalid value for --{}: {}", name, x);
+                    Err(())
+                },
             },
-        })
+    }
+}
+
+fn check_ping_interval(x: u64) -> Result<Duration, String> {
+    if x > 0 && x < 999 { Ok(Duration::new(x, 0)) }
+    else { Err("should be between 1 and 999".to_owned()) }
+}
+
+fn check_autosave_interval(x: u64) -> Result<Duration, String> {
+    if x > 0 && x <= MAX_TIMER_SECS { Ok(Duration::new(x, 0)) }
+    else { Err(format!("should be between 1 and {}", MAX_TIMER_SECS)) }
+}
+
+fn check_bind_retry(x: u64) -> Result<Option<Duration>, String> {
+    if x <= MAX_TIMER_SECS {
+        Ok(Some(Duration::from_secs(x)).filter(|_| x > 0))
+    }
+    else { Err(format!("should be at most {}", MAX_TIMER_SECS)) }
+}
+
+fn check_idle_timeout(x: u64) -> Result<Duration, String> {
+    if x > 0 && x <= MAX_TIMER_SECS { Ok(Duration::new(x, 0)) }
+    else { Err(format!("should be between 1 and {}", MAX_TIMER_SECS)) }
+}
+
+fn check_object_ttl(x: u64) -> Result<Duration, String> {
+    if x > 0 { Ok(Duration::new(x, 0)) }
+    else { Err("should be at least 1".to_owned()) }
+}
+
+fn check_auth_max_failures(x: u32) -> Result<u32, String> {
+    if x > 0 { Ok(x) }
+    else { Err("should be at least 1".to_owned()) }
+}
+
+fn check_auth_ban_window(x: u64) -> Result<Duration, String> {
+    if x > 0 { Ok(Duration::new(x, 0)) }
+    else { Err("should be at least 1".to_owned()) }
+}
+
+fn check_message_rate(x: u32) -> Result<u32, String> {
+    if x > 0 { Ok(x) }
+    else { Err("should be at least 1".to_owned()) }
+}
+
+fn check_decay_rate(x: f64) -> Result<f64, String> {
+    if x > 0.0 && x <= 1.0 { Ok(x) }
+    else { Err("should be greater than 0 and at most 1".to_owned()) }
+}
+
+fn check_half_life(x: f64) -> Result<f64, String> {
+    if x > 0.0 && x.is_finite() { Ok(x) }
+    else { Err("should be a positive number of seconds".to_owned()) }
+}
+
+fn check_log_size(x: u64) -> Result<u64, String> {
+    if x >= 1024 { Ok(x) }
+    else { Err("should be at least 1024".to_owned()) }
+}
+
+fn check_compression_level(x: u32) -> Result<u32, String> {
+    if x <= 9 { Ok(x) }
+    else { Err("should be between 0 and 9".to_owned()) }
+}
+
+fn check_message_bytes(x: usize) -> Result<usize, String> {
+    if x >= 1024 { Ok(x) }
+    else { Err("should be at least 1024".to_owned()) }
+}
+
+fn check_save_format(x: String) -> Result<SaveFormat, String> {
+    match x.as_str() {
+        "json" => Ok(SaveFormat::Json),
+        "binary" => Ok(SaveFormat::Binary),
+        _ => Err("should be \"json\" or \"binary\"".to_owned()),
+    }
+}
+
+fn check_duplicate_identity(x: String) -> Result<DuplicateIdentity, String> {
+    match x.as_str() {
+        "allow" => Ok(DuplicateIdentity::Allow),
+        "replace" => Ok(DuplicateIdentity::Replace),
+        "reject" => Ok(DuplicateIdentity::Reject),
+        _ => Err("should be \"allow\", \"replace\", or \"reject\""
+                 .to_owned()),
+    }
+}
+
+fn check_z_bits(x: u32) -> Result<u32, String> {
+    if x <= 16 { Ok(x) }
+    else { Err("should be between 0 and 16".to_owned()) }
+}
+
+fn check_offset(x: String) -> Result<(i32, i32, i32), String> {
+    let complaint = || "should be three integers separated by commas, like \
+                        \"0,1,0\"".to_owned();
+    let mut parts = x.split(',')
+        .map(|x| x.trim().parse::<i32>().map_err(|_| complaint()));
+    match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(x), Some(y), Some(z), None) => Ok((x?, y?, z?)),
+        _ => Err(complaint()),
+    }
+}
+
+fn check_spill(x: String) -> Result<(i32, i32, i32), String> {
+    let offset = check_offset(x)?;
+    if offset != (0, 0, 0) { Ok(offset) }
+    else { Err("can't be 0,0,0; energy can't spill into the same \
+                point".to_owned()) }
+}
+
+fn check_object_size(x: usize) -> Result<usize, String> {
+    if x > 0 && x <= MAX_OBJECT_SIZE_LIMIT { Ok(x) }
+    else { Err(format!("should be between 1 and {}", MAX_OBJECT_SIZE_LIMIT)) }
+}
+
+fn check_chunked_object_size(x: usize) -> Result<usize, String> {
+    if x > 0 && x <= MAX_CHUNKED_OBJECT_SIZE_LIMIT { Ok(x) }
+    else {
+        Err(format!("should be between 1 and {}",
+                    MAX_CHUNKED_OBJECT_SIZE_LIMIT))
+    }
+}
+
+fn check_stack(x: f32) -> Result<f32, String> {
+    if x > 0.0 && x.is_finite() { Ok(x) }
+    else { Err("should be a positive number of kg".to_owned()) }
+}
+
+fn check_nonzero(x: usize) -> Result<usize, String> {
+    if x > 0 { Ok(x) }
+    else { Err("must not be zero".to_owned()) }
+}
+
+/// The contents of a config file, as given to `--config`. The keys are the
+/// same as the long command line options, with `_` in place of `-`. Anything
+/// left out keeps its default. A flag's key is a `bool`, except for `verbose`,
+/// which is how many times `-v` would have been given.
+#[derive(Deserialize,Default)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    listen_on: Option<Vec<String>>,
+    ws_listen: Option<Vec<String>>,
+    listen_proxy_protocol: Option<bool>,
+    dual_stack: Option<bool>,
+    bind_retry: Option<u64>,
+    offset_mode: Option<bool>,
+    offset: Option<String>,
+    z_from_y_bits: Option<u32>,
+    min_coord: Option<String>,
+    max_coord: Option<String>,
+    readonly: Option<bool>,
+    verbose: Option<u32>,
+    quiet: Option<bool>,
+    log_json: Option<bool>,
+    auth_file: Option<String>,
+    auth_dir: Option<String>,
+    auth_max_failures: Option<u32>,
+    tls_cert: Option<String>,
+    tls_key: Option<String>,
+    auth_ban_window: Option<u64>,
+    duplicate_identity: Option<String>,
+    save_file: Option<String>,
+    save_format: Option<String>,
+    compress_save: Option<bool>,
+    autosave_interval: Option<u64>,
+    save_interval_on_change: Option<u64>,
+    ping_interval: Option<u64>,
+    idle_timeout: Option<u64>,
+    element_names: Option<String>,
+    germ_names: Option<String>,
+    building_list: Option<String>,
+    metrics_addr: Option<String>,
+    max_messages_per_second: Option<u32>,
+    max_connections: Option<usize>,
+    energy_decay_rate: Option<f64>,
+    thermal_mixing: Option<bool>,
+    germ_decay: Option<f64>,
+    object_ttl: Option<u64>,
+    log_file: Option<String>,
+    log_max_size: Option<u64>,
+    syslog: Option<bool>,
+    compression_level: Option<u32>,
+    no_compression: Option<bool>,
+    max_message_bytes: Option<usize>,
+    max_energy: Option<u32>,
+    max_packets: Option<usize>,
+    gas_stack: Option<f32>,
+    liquid_stack: Option<f32>,
+    max_objects: Option<usize>,
+    max_object_size: Option<usize>,
+    max_chunked_object_size: Option<usize>,
+    max_registrations: Option<usize>,
+    max_total_registrations: Option<usize>,
+    max_total_objects: Option<usize>,
+    max_total_object_bytes: Option<usize>,
+    max_tiles: Option<usize>,
+    energy_spill_to: Option<String>,
+    stack_objects: Option<bool>,
+    mass_audit: Option<bool>,
+}
+
+/// Reads an `Invocation` from a TOML config file. Settings the file doesn't
+/// mention are left at their defaults.
+pub fn from_config_file(path: &str) -> Result<Invocation, String> {
+    let text = std::fs::read_to_string(path).map_err(|x| x.to_string())?;
+    let file: ConfigFile = toml::from_str(&text).map_err(|x| x.to_string())?;
+    if file.auth_file.is_some() && !cfg!(feature = "auth") {
+        return Err("auth_file was given, but this server was built without \
+                    authentication support".to_owned())
+    }
+    if file.auth_dir.is_some() && !cfg!(feature = "auth") {
+        return Err("auth_dir was given, but this server was built without \
+                    authentication support".to_owned())
+    }
+    if (file.tls_cert.is_some() || file.tls_key.is_some())
+    && !cfg!(feature = "tls") {
+        return Err("tls_cert/tls_key were given, but this server was built \
+                    without TLS support".to_owned())
+    }
+    if file.ws_listen.is_some() && !cfg!(feature = "websocket") {
+        return Err("ws_listen was given, but this server was built without \
+                    WebSocket support".to_owned())
+    }
+    if file.syslog == Some(true) && !cfg!(all(unix, feature = "syslog")) {
+        return Err("syslog was given, but this server was built without \
+                    syslog support".to_owned())
+    }
+    if file.syslog == Some(true) && file.log_file.is_some() {
+        return Err("syslog and log_file can't be used together".to_owned())
+    }
+    if file.offset_mode == Some(true) && file.offset.is_some() {
+        return Err("offset_mode and offset can't be used together".to_owned())
+    }
+    if file.auth_file.is_some() && file.auth_dir.is_some() {
+        return Err("auth_file and auth_dir can't be used together"
+                   .to_owned())
+    }
+    if file.quiet == Some(true) && file.verbose.unwrap_or(0) > 0 {
+        return Err("quiet and verbose can't be used together".to_owned())
+    }
+    let mut ret = Invocation {
+        listen_addrs: file.listen_on.unwrap_or_default(),
+        ws_listen_addrs: file.ws_listen.unwrap_or_default(),
+        listen_proxy_protocol: file.listen_proxy_protocol.unwrap_or(false),
+        dual_stack: file.dual_stack.unwrap_or(false),
+        bind_retry: check_key(file.bind_retry, "bind_retry",
+                              check_bind_retry)?.flatten(),
+        offset: match file.offset_mode {
+            Some(true) => Some(OFFSET_MODE_OFFSET),
+            _ => check_key(file.offset, "offset", check_offset)?,
+        },
+        z_from_y_bits: check_key(file.z_from_y_bits, "z_from_y_bits",
+                                 check_z_bits)?.unwrap_or(0),
+        min_coord: check_key(file.min_coord, "min_coord", check_offset)?,
+        max_coord: check_key(file.max_coord, "max_coord", check_offset)?,
+        readonly: file.readonly.unwrap_or(false),
+        verbosity: file.verbose.unwrap_or(0),
+        quiet: file.quiet.unwrap_or(false),
+        log_json: file.log_json.unwrap_or(false),
+        auth_file: file.auth_file,
+        auth_dir: file.auth_dir,
+        tls_cert: file.tls_cert,
+        tls_key: file.tls_key,
+        auth_max_failures: check_key(file.auth_max_failures,
+                                     "auth_max_failures",
+                                     check_auth_max_failures)?
+            .unwrap_or(DEFAULT_AUTH_MAX_FAILURES),
+        auth_ban_window: check_key(file.auth_ban_window, "auth_ban_window",
+                                   check_auth_ban_window)?
+            .unwrap_or(DEFAULT_AUTH_BAN_WINDOW),
+        duplicate_identity: check_key(file.duplicate_identity,
+                                      "duplicate_identity",
+                                      check_duplicate_identity)?
+            .unwrap_or(DuplicateIdentity::Allow),
+        save_file: file.save_file,
+        save_format: check_key(file.save_format, "save_format",
+                               check_save_format)?,
+        compress_save: file.compress_save.unwrap_or(false),
+        ping_interval: check_key(file.ping_interval, "ping_interval",
+                                 check_ping_interval)?,
+        autosave_interval: check_key(file.autosave_interval,
+                                     "autosave_interval",
+                                     check_autosave_interval)?,
+        save_interval_on_change: check_key(file.save_interval_on_change,
+                                           "save_interval_on_change",
+                                           check_autosave_interval)?,
+        element_names: file.element_names,
+        germ_names: file.germ_names,
+        building_list: file.building_list,
+        idle_timeout: check_key(file.idle_timeout, "idle_timeout",
+                                check_idle_timeout)?,
+        metrics_addr: file.metrics_addr,
+        max_messages_per_second: check_key(file.max_messages_per_second,
+                                           "max_messages_per_second",
+                                           check_message_rate)?,
+        max_connections: check_key(file.max_connections, "max_connections",
+                                   check_nonzero)?,
+        energy_decay_rate: check_key(file.energy_decay_rate,
+                                     "energy_decay_rate", check_decay_rate)?,
+        thermal_mixing: file.thermal_mixing.unwrap_or(false),
+        germ_decay: check_key(file.germ_decay, "germ_decay", check_half_life)?,
+        object_ttl: check_key(file.object_ttl, "object_ttl",
+                              check_object_ttl)?,
+        log_file: file.log_file,
+        log_max_size: check_key(file.log_max_size, "log_max_size",
+                                check_log_size)?
+            .unwrap_or(DEFAULT_LOG_MAX_SIZE),
+        syslog: file.syslog.unwrap_or(false),
+        compression_level: check_key(file.compression_level,
+                                     "compression_level",
+                                     check_compression_level)?
+            .unwrap_or(DEFAULT_COMPRESSION_LEVEL),
+        no_compression: file.no_compression.unwrap_or(false),
+        max_message_bytes: check_key(file.max_message_bytes,
+                                     "max_message_bytes",
+                                     check_message_bytes)?
+            .unwrap_or(DEFAULT_MAX_MESSAGE_BYTES),
+        map_limits: MapLimits::default(),
+    };
+    let map_limits = &mut ret.map_limits;
+    if let Some(x) = file.max_energy { map_limits.max_stored_energy = x }
+    if let Some(x) = check_key(file.max_packets, "max_packets",
+                               check_nonzero)? {
+        map_limits.max_stored_packets = x;
+    }
+    if let Some(x) = check_key(file.gas_stack, "gas_stack", check_stack)? {
+        map_limits.phase_limits.gas_stack = x;
+    }
+    if let Some(x) = check_key(file.liquid_stack, "liquid_stack",
+                               check_stack)? {
+        map_limits.phase_limits.liquid_stack = x;
+    }
+    if let Some(x) = check_key(file.max_objects, "max_objects",
+                               check_nonzero)? {
+        map_limits.max_stored_objects = x;
+    }
+    if let Some(x) = check_key(file.max_object_size, "max_object_size",
+                               check_object_size)? {
+        map_limits.max_object_size = x;
+    }
+    if let Some(x) = check_key(file.max_chunked_object_size,
+                               "max_chunked_object_size",
+                               check_chunked_object_size)? {
+        map_limits.max_chunked_object_size = x;
+    }
+    if let Some(x) = check_key(file.max_registrations, "max_registrations",
+                               check_nonzero)? {
+        map_limits.max_registrations = x;
+    }
+    map_limits.max_total_registrations
+        = check_key(file.max_total_registrations, "max_total_registrations",
+                    check_nonzero)?;
+    map_limits.max_total_objects = file.max_total_objects;
+    map_limits.max_total_object_bytes = file.max_total_object_bytes;
+    map_limits.max_tiles = check_key(file.max_tiles, "max_tiles",
+                                     check_nonzero)?;
+    map_limits.energy_spill_to = check_key(file.energy_spill_to,
+                                           "energy_spill_to", check_spill)?;
+    map_limits.stack_objects = file.stack_objects.unwrap_or(false);
+    map_limits.mass_audit = file.mass_audit.unwrap_or(false);
+    Ok(ret)
+}
+
+/// Checks the value of a config file key, if it was given.
+fn check_key<T, U>(value: Option<T>, name: &str,
+                   check: impl Fn(T) -> Result<U, String>)
+                   -> Result<Option<U>, String> {
+    match value {
+        None => Ok(None),
+        Some(x) => check(x).map(Some)
+            .map_err(|x| format!("invalid value for {}: {}", name, x)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn idle_timeout_is_bounded() {
+        assert!(check_idle_timeout(0).is_err());
+        assert_eq!(check_idle_timeout(1), Ok(Duration::from_secs(1)));
+        assert_eq!(check_idle_timeout(MAX_TIMER_SECS),
+                   Ok(Duration::from_secs(MAX_TIMER_SECS)));
+        assert!(check_idle_timeout(MAX_TIMER_SECS + 1).is_err());
+        assert!(check_idle_timeout(u64::MAX).is_err());
+    }
+
+    #[test]
+    fn bind_retry_is_bounded() {
+        assert_eq!(check_bind_retry(0), Ok(None));
+        assert_eq!(check_bind_retry(MAX_TIMER_SECS),
+                   Ok(Some(Duration::from_secs(MAX_TIMER_SECS))));
+        assert!(check_bind_retry(MAX_TIMER_SECS + 1).is_err());
+        assert!(check_bind_retry(u64::MAX).is_err());
+    }
+
+    #[test]
+    fn autosave_interval_is_bounded() {
+        assert!(check_autosave_interval(0).is_err());
+        assert_eq!(check_autosave_interval(MAX_TIMER_SECS),
+                   Ok(Duration::from_secs(MAX_TIMER_SECS)));
+        assert!(check_autosave_interval(MAX_TIMER_SECS + 1).is_err());
+        assert!(check_autosave_interval(u64::MAX).is_err());
+    }
+
+    #[test]
+    fn offsets_are_three_integers() {
+        assert_eq!(check_offset("0,1,0".to_owned()), Ok((0, 1, 0)));
+        assert_eq!(check_offset(" -2, 3 ,0".to_owned()), Ok((-2, 3, 0)));
+        assert!(check_offset("0,1".to_owned()).is_err());
+        assert!(check_offset("0,1,0,0".to_owned()).is_err());
+        assert!(check_offset("0,one,0".to_owned()).is_err());
+    }
+
+    /// Writes `text` to a scratch file and loads it as a config file.
+    fn load_config(name: &str, text: &str) -> Result<Invocation, String> {
+        let path = std::env::temp_dir()
+            .join(format!("onizd-test-{}-{}.toml", std::process::id(), name));
+        std::fs::write(&path, text).unwrap();
+        let ret = from_config_file(path.to_str().unwrap());
+        let _ = std::fs::remove_file(&path);
+        ret
+    }
+
+    #[test]
+    fn config_file_timers() {
+        let invocation = load_config("timers", "autosave_interval = 60\n\
+                                                save_interval_on_change = 5\n")
+            .unwrap();
+        assert_eq!(invocation.autosave_interval, Some(Duration::from_secs(60)));
+        assert_eq!(invocation.save_interval_on_change,
+                   Some(Duration::from_secs(5)));
+        assert!(load_config("big_autosave", &format!(
+            "autosave_interval = {}\n", MAX_TIMER_SECS + 1)).is_err());
+        assert!(load_config("big_on_change", &format!(
+            "save_interval_on_change = {}\n", MAX_TIMER_SECS + 1)).is_err());
+    }
+
+    #[test]
+    fn config_file_offsets() {
+        assert_eq!(load_config("offset_mode", "offset_mode = true\n")
+                   .unwrap().offset, Some(OFFSET_MODE_OFFSET));
+        assert_eq!(load_config("offset", "offset = \"0,-2,1\"\n")
+                   .unwrap().offset, Some((0, -2, 1)));
+        assert_eq!(load_config("no_offset", "offset_mode = false\n")
+                   .unwrap().offset, None);
+        assert!(load_config("both_offsets", "offset_mode = true\n\
+                                             offset = \"0,1,0\"\n").is_err());
+    }
+
+    #[test]
+    fn config_file_verbose_is_a_count() {
+        assert_eq!(load_config("verbose", "verbose = 2\n").unwrap().verbosity,
+                   2);
+        assert!(load_config("verbosity", "verbosity = 2\n").is_err());
+        assert!(load_config("quiet_verbose", "verbose = 1\nquiet = true\n")
+                .is_err());
     }
 }
@@ -26,26 +26,31 @@
 )]
 
 use std::{
+    collections::{HashMap, HashSet},
     convert::{TryFrom,TryInto},
-    net::SocketAddr,
-    sync::{Arc,Mutex},
+    net::{IpAddr, SocketAddr},
+    sync::{Arc,Mutex,RwLock,atomic::{AtomicBool,AtomicUsize,Ordering}},
     time::Duration,
     fmt::Write,
     fs,
 };
 #[cfg(feature = "auth")]
-use std::io::SeekFrom;
+use std::{
+    io::SeekFrom,
+    path::{Path, PathBuf},
+};
 use tokio::{
     net::{TcpListener, TcpStream},
     stream::StreamExt,
-    sync::mpsc,
-    time::{timeout,interval},
+    sync::{broadcast, mpsc, oneshot},
+    time::{timeout,interval,delay_for,delay_until,Instant,Interval},
 };
 #[cfg(feature = "auth")]
 use tokio::{
     io::AsyncReadExt,
     fs::File,
 };
+use tokio::io::AsyncWriteExt;
 use futures::sink::SinkExt;
 use tokio_util::codec;
 use bytes::{BytesMut, buf::{Buf, BufMut}};
@@ -61,6 +66,8 @@ mod point;
 pub use point::*;
 mod map;
 pub use map::*;
+mod savefile;
+pub use savefile::SaveFormat;
 mod mat;
 pub use mat::*;
 mod elemap;
@@ -68,9 +75,22 @@ pub use elemap::*;
 mod wrapped;
 pub use wrapped::*;
 mod mit_zlib;
-pub use mit_zlib::{MitZlibReader, MitZlibWriter};
+pub use mit_zlib::{MitZlibReader, MitZlibWriter, DEFAULT_COMPRESSION_LEVEL};
 mod outputter;
 pub use outputter::*;
+mod proxy;
+mod metrics;
+pub use metrics::{Metrics, ClientStats};
+mod ratelimit;
+use ratelimit::RateLimiter;
+mod sessions;
+pub use sessions::{Sessions, DuplicateIdentity};
+#[cfg(feature = "tls")]
+mod tls;
+#[cfg(feature = "auth")]
+mod authban;
+#[cfg(feature = "auth")]
+use authban::AuthFailures;
 
 #[cfg(feature = "gui")]
 mod gui;
@@ -80,69 +100,870 @@ pub const DEFAULT_ADDR_AND_PORT: &str = "0.0.0.0:5496";
 pub const AUTH_BYTE_SIZE: usize = 5496;
 #[cfg(feature = "auth")]
 pub const NUM_CHALLENGES: usize = 3;
+/// How often we check for authentication bans that have run out.
+#[cfg(feature = "auth")]
+const AUTH_BAN_SWEEP_INTERVAL: Duration = Duration::from_secs(10);
 /// The list of version numbers this version of the server will support.
-pub const SUPPORTED_VERSIONS: &[i64] = &[0, 1, 2];
-/// The maximum size an opaque object is allowed to be. This reflects the raw
-/// binary size.
+///
+/// What changes from version to version:
+///
+/// - Version 0: like version 1, but the client crashes if it gets a
+///   `handshake_error`.
+/// - Versions 1 and 2: handled identically, except that only version 2 may
+///   send the object messages. (Version 2 only exists to keep new clients
+///   from sending objects to old servers.) These clients don't know
+///   about the z coordinate, so they only hear about registrations at z = 0,
+///   and responses don't include `z`. They also don't understand `error` or
+///   `server_closing` messages; where a newer client would get an `error`,
+///   these get disconnected, and on shutdown they're just hung up on.
+/// - Version 3: adds the messages listed in `MESSAGE_TYPES`, `z` in
+///   every response that has `x` and `y`, `error` responses (which also
+///   answer malformed messages), `server_closing`, and `server_info` right
+///   after `auth_ok`. A version 3 client may also put `"batch_registrations":
+///   true` in its `hello`, to get the registrations that already exist in
+///   `registrations` messages instead of one `registered` message each.
+///   If a version 3 client reads so slowly that it misses registration
+///   events, it gets a `registrations_reset` message, meaning it should
+///   forget every registration it's heard about, followed by all the current
+///   ones (batched or not, as above). An older client is disconnected
+///   instead.
+///
+/// In every version, a request may carry a `cookie`, which is echoed in its
+/// response if it's a string, number, or boolean, and/or an `id`, which is
+/// echoed verbatim in every response to it, whatever JSON it is (`null`,
+/// objects, and arrays included).
+pub const SUPPORTED_VERSIONS: &[i64] = &[0, 1, 2, 3];
+
+/// The first protocol version that knows about z coordinates, `error`
+/// messages, and `server_closing`.
+const Z_AWARE_VERSION: i64 = 3;
+/// The default maximum size an opaque object is allowed to be. This reflects
+/// the raw binary size.
 pub const MAX_OBJECT_SIZE: usize = 4096;
-/// The maximum number of characters an opaque object small enough to store can
-/// take up when Base64 encoded.
-pub const MAX_OBJECT_ENCODED_SIZE: usize = (MAX_OBJECT_SIZE + 2) * 4 / 3;
+/// The longest message a client may send us, unless `--max-message-bytes`
+/// says otherwise.
+pub const DEFAULT_MAX_MESSAGE_BYTES: usize = 10000;
+/// The most `--max-object-size` may be set to. Any bigger, and a message
+/// carrying one Base64 encoded object would be over the 10000 bytes that
+/// clients are willing to decode.
+pub const MAX_OBJECT_SIZE_LIMIT: usize = 7168;
+
+/// The default most bytes an object sent in pieces (see `begin_object`) may
+/// be.
+pub const DEFAULT_MAX_CHUNKED_OBJECT_SIZE: usize = 65536;
+/// The most `--max-chunked-object-size` may be set to. Each client can have
+/// `MAX_OBJECT_TRANSFERS` objects this big on the go at once.
+pub const MAX_CHUNKED_OBJECT_SIZE_LIMIT: usize = 16 * 1024 * 1024;
+/// How many bytes of an object go in each `got_object_chunk` (see
+/// `begin_recv_object`). The same as the default `--max-object-size`, so that
+/// every chunk fits in a message as easily as a whole object would.
+pub const OBJECT_CHUNK_SIZE: usize = MAX_OBJECT_SIZE;
+
+/// Returns the maximum number of characters an opaque object of up to `size`
+/// bytes can take up when Base64 encoded, or `None` if that's too many to
+/// count.
+pub fn max_encoded_size(size: usize) -> Option<usize> {
+    size.checked_add(2)?.checked_mul(4).map(|x| x / 3)
+}
+/// The maximum number of points and boxes one client can `subscribe` to.
+pub const MAX_SUBSCRIPTIONS: usize = 64;
+/// The maximum number of chunked object sends and receives (see
+/// `begin_object` and `begin_recv_object`) one client can have going at once.
+pub const MAX_OBJECT_TRANSFERS: usize = 4;
+/// The maximum number of operations in one `bulk_send` message.
+pub const MAX_BULK_OPS: usize = 100;
+/// The most points (occupied or not) one `query_region` may cover.
+pub const MAX_REGION_POINTS: u64 = 65536;
+/// The longest string (in bytes) we'll take in a message field, such as the
+/// `what` of a registration. This is 5464, the length of a `MAX_OBJECT_SIZE`
+/// object once Base64 encoded (as `max_encoded_size` works it out), which is
+/// what the very first versions of the server allowed any string to be. It's
+/// far longer than any real building or element name.
+pub const MAX_STRING_BYTES: usize = (MAX_OBJECT_SIZE + 2) * 4 / 3;
+/// The deepest that arrays and objects may be nested in a message. No real
+/// message comes close; this is to turn away pathological ones before they
+/// get anywhere near the parser. They get a `nested_too_deep` error.
+pub const MAX_MESSAGE_DEPTH: usize = 32;
+/// The compression types a client may ask for in its `hello`.
+pub const SUPPORTED_COMPRESSION_TYPES: &[&str] = &["Zlib", "Gzip"];
 /// Suffix to add to a filename when making a backup.
 pub const BACKUP_SUFFIX: &str = "~";
 /// Suffix to add to a filename when writing.
 pub const TEMP_SUFFIX: &str = "^";
+/// How long to wait for clients to finish up and disconnect when the server is
+/// shutting down.
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long a connecting client gets for each step of connecting: the PROXY
+/// header, the TLS and WebSocket handshakes, and our own handshake and
+/// authentication. See `connecting_step`.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait between attempts to bind an address that's in use, when
+/// `--bind-retry` is given.
+pub const BIND_RETRY_DELAY: Duration = Duration::from_millis(500);
+/// How often stored energy decays, when `--energy-decay-rate` is given.
+pub const ENERGY_DECAY_INTERVAL: Duration = Duration::from_secs(1);
+/// Roughly how many bytes of tiles to put in each `map_dump` response. Clients
+/// (like us) refuse messages much over 10000 bytes, so this leaves some room.
+/// A single tile bigger than this gets a response all to itself.
+pub const MAP_DUMP_CHUNK_SIZE: usize = 8000;
+/// How often stored packets exchange heat, when `--thermal-mixing` is given.
+pub const THERMAL_MIXING_INTERVAL: Duration = Duration::from_secs(1);
+/// How often the germs in stored packets die off, when `--germ-decay` is
+/// given.
+pub const GERM_DECAY_INTERVAL: Duration = Duration::from_secs(1);
+/// How often to throw away objects that have been stored too long, when
+/// `--object-ttl` is given.
+pub const OBJECT_EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+/// How often to check whether the map has changed, when
+/// `--save-interval-on-change` is given.
+pub const SAVE_ON_CHANGE_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How often to check that the packet mass on the map adds up, when
+/// `--mass-audit` is given.
+pub const MASS_AUDIT_INTERVAL: Duration = Duration::from_secs(60);
 
 pub type ClientID = u64;
 
-#[derive(Debug,PartialEq,Eq,Serialize,Deserialize)]
-pub enum CompressionType { Zlib }
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Serialize,Deserialize)]
+pub enum CompressionType { Zlib, Gzip }
+
+/// Everything the server's tasks share with one another.
+pub struct Shared {
+    pub invocation: Invocation,
+    /// The map does its own, finer-grained locking, so almost everything
+    /// only needs the read lock. The write lock is for loading and saving,
+    /// which need the whole map to hold still.
+    pub map: RwLock<Map>,
+    pub metrics: Metrics,
+    /// How many connections are open right now, for `--max-connections`.
+    pub connections: AtomicUsize,
+    /// Set once the server has started shutting down. Clients that leave
+    /// after that keep their identified registrations, so they get saved.
+    pub shutting_down: AtomicBool,
+    /// If given, the only building identifiers clients may `register`.
+    /// Replaced when the server is told to reload.
+    pub building_list: RwLock<Option<HashSet<String>>>,
+    /// Which connection each `--auth-dir` identity is using.
+    pub sessions: Sessions,
+    /// Every connected client, so that they can be kicked.
+    pub clients: Mutex<HashMap<ClientID, ConnectedClient>>,
+    #[cfg(feature = "auth")]
+    pub auth_failures: AuthFailures,
+    /// Present if `--tls-cert` and `--tls-key` were given.
+    #[cfg(feature = "tls")]
+    pub tls: Option<tokio_rustls::TlsAcceptor>,
+}
+
+impl Shared {
+    /// Sets up everything for a server with an empty map and nobody connected.
+    fn new(invocation: Invocation,
+           building_list: Option<HashSet<String>>,
+           #[cfg(feature = "tls")] tls: Option<tokio_rustls::TlsAcceptor>)
+           -> Shared {
+        Shared {
+            map: RwLock::new(Map::new(invocation.map_limits.clone())),
+            metrics: Metrics::new(),
+            connections: AtomicUsize::new(0),
+            shutting_down: AtomicBool::new(false),
+            building_list: RwLock::new(building_list),
+            sessions: Sessions::new(invocation.duplicate_identity),
+            clients: Mutex::new(HashMap::new()),
+            #[cfg(feature = "auth")]
+            auth_failures: AuthFailures::new(invocation.auth_max_failures,
+                                             invocation.auth_ban_window),
+            #[cfg(feature = "tls")]
+            tls,
+            invocation,
+        }
+    }
+}
+
+/// Reads a `--building-list` file: one building identifier per line. Blank
+/// lines, and lines starting with `#`, are ignored.
+fn load_building_list(path: &str) -> std::io::Result<HashSet<String>> {
+    Ok(fs::read_to_string(path)?.lines().map(str::trim)
+       .filter(|x| !x.is_empty() && !x.starts_with('#'))
+       .map(str::to_owned).collect())
+}
 
 fn errorize(err: &str) -> std::io::Error {
     std::io::Error::new(std::io::ErrorKind::Other, err)
 }
 
+/// Makes an error for a message that has a field that doesn't make sense.
+/// Errors of this kind (which is also what `serde_json` gives for a field of
+/// the wrong type) get an `error` response, instead of getting the client
+/// disconnected.
+fn malformed(err: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, err)
+}
+
 fn expect_int<T: TryFrom<i64>>(val: &Value) -> std::io::Result<T> {
     match val {
         Value::Number(x) if x.is_i64() => match val.as_i64().unwrap().try_into() {
             Ok(x) => Ok(x),
-            Err(_) => Err(errorize("Number out of range")),
+            Err(_) => Err(malformed("Number out of range")),
         },
-        _ => Err(errorize("Needed a number, got something else"))
+        _ => Err(malformed("Needed a number, got something else"))
+    }
+}
+
+/// Like `expect_int`, but a missing value is taken to be zero. Used for the `z`
+/// coordinate, which older clients don't send.
+fn expect_int_or_zero<T: TryFrom<i64> + Default>(val: &Value)
+                                                 -> std::io::Result<T> {
+    match val {
+        Value::Null => Ok(T::default()),
+        _ => expect_int(val),
     }
 }
 
-fn expect_string(val: &Value) -> std::io::Result<&str> {
+/// Reads an amount of energy. Without the `float_energy` feature, this is the
+/// same as `expect_int`.
+#[cfg(not(feature = "float_energy"))]
+fn expect_joules(val: &Value) -> std::io::Result<Joules> {
+    expect_int(val)
+}
+
+/// Reads an amount of energy. With the `float_energy` feature, fractions are
+/// allowed, but negative, infinite, and NaN amounts are not.
+#[cfg(feature = "float_energy")]
+fn expect_joules(val: &Value) -> std::io::Result<Joules> {
     match val {
-        Value::String(ref x) => {
-            if x.len() > 5464 { Err(errorize("String was too long")) }
-            else { Ok(x) }
+        Value::Number(x) => match x.as_f64() {
+            Some(x) if x.is_finite() && x >= 0.0 => Ok(x),
+            _ => Err(malformed("Number out of range")),
+        },
+        _ => Err(malformed("Needed a number, got something else"))
+    }
+}
+
+/// A field of a message that didn't make sense, when we can say which one.
+/// This rides inside an `InvalidData` error (like the ones from `malformed`),
+/// and makes the `error` response more specific than `bad_message`.
+#[derive(Debug)]
+struct BadField {
+    what: &'static str,
+    field: &'static str,
+    reason: &'static str,
+}
+
+impl std::fmt::Display for BadField {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(fmt, "{} {}", self.field, self.reason)
+    }
+}
+
+impl std::error::Error for BadField {}
+
+/// Makes an `InvalidData` error carrying a `BadField`.
+fn bad_field(what: &'static str, field: &'static str, reason: &'static str)
+             -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData,
+                        BadField { what, field, reason })
+}
+
+/// What a `bad_amount` error says an amount has to be.
+#[cfg(not(feature = "float_energy"))]
+const AMOUNT_RANGE: &str = "must be a whole number from 0 to 4294967295";
+/// What a `bad_amount` error says an amount has to be.
+#[cfg(feature = "float_energy")]
+const AMOUNT_RANGE: &str = "must be a finite number, at least 0";
+
+/// Reads an amount of energy out of the given field of a message. One that's
+/// negative, too big, or not a number at all gets a `bad_amount` error naming
+/// the field.
+fn expect_amount(message: &Value, field: &'static str)
+                 -> std::io::Result<Joules> {
+    expect_joules(&message[field])
+        .map_err(|_| bad_field("bad_amount", field, AMOUNT_RANGE))
+}
+
+/// Checks that a packet's element is in the phase the client says it is, if
+/// the element table knows (see `MatPacket::is_element_in_phase`). One that
+/// isn't gets a `wrong_phase` error.
+fn check_element_phase(packet: &MatPacket, phase: Phase)
+                       -> std::io::Result<()> {
+    if packet.is_element_in_phase(phase) { return Ok(()) }
+    Err(bad_field("wrong_phase", "phase",
+                  "doesn't match the packet's element"))
+}
+
+/// Reads a string out of the given field of a message. One longer than
+/// `MAX_STRING_BYTES` gets a `string_too_long` error naming the field.
+fn expect_string<'a>(message: &'a Value, field: &'static str)
+                     -> std::io::Result<&'a str> {
+    match &message[field] {
+        Value::String(ref x) if x.len() > MAX_STRING_BYTES =>
+            Err(bad_field("string_too_long", field,
+                          "is longer than max_string_bytes")),
+        Value::String(ref x) => Ok(x),
+        _ => Err(malformed("Needed a string, got something else")),
+    }
+}
+
+/// Works out how far a registration is shifted by `--offset`. Senders (whose
+/// names end in "Sender") are shifted the opposite way. Every `recv_*` request
+/// is shifted by `recv_offset`, so anything else is shifted that way too;
+/// otherwise, a consumer like a battery would be registered at one point and
+/// drain another.
+fn register_maybe_offset(what: &str, recv_offset: (i32, i32, i32))
+                         -> (i32, i32, i32) {
+    if what.ends_with("Sender") {
+        let (x, y, z) = recv_offset;
+        (x.wrapping_neg(), y.wrapping_neg(), z.wrapping_neg())
+    }
+    else { recv_offset }
+}
+
+/// Every type of message a client can send us, and the protocol version that
+/// introduced it. Keep this in sync with the big `match` in `inner_client`!
+///
+/// If a client that negotiated an older version sends one anyway, it isn't
+/// processed; we'd only be sending back a response it can't understand. A
+/// client new enough to understand `error` gets an `unsupported_in_version`
+/// one. Anything older is just ignored.
+const MESSAGE_TYPES: &[(&str, i64)] = &[
+    ("ping", 0), ("pong", 0), ("send_joules", 0), ("recv_joules", 0),
+    ("send_packet", 0), ("recv_packet", 0), ("register", 0),
+    ("unregister", 0),
+    ("send_object", 2), ("recv_object", 2),
+    ("query_tile", 3), ("bulk_send", 3), ("clear_tile", 3), ("subscribe", 3),
+    ("unsubscribe", 3), ("dump_map", 3), ("transfer", 3),
+    ("capabilities", 3), ("reset_map", 3), ("kick", 3), ("mass_audit", 3),
+    ("query_region", 3), ("save_now", 3), ("move_registration", 3),
+    ("begin_object", 3), ("object_chunk", 3), ("end_object", 3),
+    ("begin_recv_object", 3), ("recv_object_chunk", 3),
+];
+
+/// Returns the protocol version that introduced a given type of message (see
+/// `MESSAGE_TYPES`). Types we've never heard of give 0, so that they get
+/// treated as unknown rather than as too new.
+fn message_min_version(typ: &str) -> i64 {
+    MESSAGE_TYPES.iter().find(|x| x.0 == typ).map(|x| x.1).unwrap_or(0)
+}
+
+/// Returns `true` if clients have to authenticate.
+fn auth_enabled(invocation: &Invocation) -> bool {
+    #[cfg(feature = "auth")]
+    { invocation.auth_file.is_some() || invocation.auth_dir.is_some() }
+    #[cfg(not(feature = "auth"))]
+    { let _ = invocation; false }
+}
+
+/// Returns `true` if administrative requests (see `is_admin_message`) may be
+/// used. When authentication is available, it has to be turned on, so that
+/// not just anyone can wipe the map or kick people.
+fn admin_allowed(invocation: &Invocation) -> bool {
+    !cfg!(feature = "auth") || auth_enabled(invocation)
+}
+
+/// Returns `true` if a given type of message is administrative, and should
+/// only be allowed if `admin_allowed`.
+fn is_admin_message(typ: &str) -> bool {
+    match typ {
+        "reset_map" | "kick" | "save_now" => true,
+        _ => false,
+    }
+}
+
+/// Returns the compression types clients may ask for, which is none of them
+/// with `--no-compression`.
+fn compression_types(invocation: &Invocation) -> &'static [&'static str] {
+    if invocation.no_compression { &[] } else { SUPPORTED_COMPRESSION_TYPES }
+}
+
+/// Returns `true` if a given type of message puts something into the map (or
+/// registers something on it), and should be refused in `--readonly` mode.
+/// Receiving things is still allowed; a read-only map is never saved, so no
+/// harm is done.
+fn message_mutates(typ: &str) -> bool {
+    match typ {
+        "send_joules" | "send_packet" | "send_object" | "register"
+            | "unregister" | "clear_tile" | "bulk_send" | "transfer"
+            | "reset_map" | "move_registration" | "begin_object"
+            | "object_chunk" | "end_object" => true,
+        _ => false,
+    }
+}
+
+/// Reads a point given as an object with `x`, `y`, and (optionally) `z` keys.
+fn expect_point(val: &Value, z_bits: u32) -> std::io::Result<Point> {
+    client_point(expect_int(&val["x"])?, expect_int(&val["y"])?, &val["z"],
+                 z_bits)
+}
+
+/// Makes a point out of the coordinates a client sent. If it left out `z`,
+/// the z layer comes out of the top `z_bits` bits of `y` (see
+/// `--z-from-y-bits`).
+fn client_point(x: i32, y: i32, z: &Value, z_bits: u32)
+                -> std::io::Result<Point> {
+    match z {
+        Value::Null => Ok(Point::unpack_z(x, y, z_bits)),
+        _ => Ok(Point::new(x, y, expect_int(z)?)),
+    }
+}
+
+/// Whether a point a client gave is within `--min-coord` and `--max-coord`.
+fn in_bounds(invocation: &Invocation, point: Point) -> bool {
+    let coords = (point.get_x(), point.get_y(), point.get_z());
+    let above = |min: (i32, i32, i32)|
+        coords.0 >= min.0 && coords.1 >= min.1 && coords.2 >= min.2;
+    let below = |max: (i32, i32, i32)|
+        coords.0 <= max.0 && coords.1 <= max.1 && coords.2 <= max.2;
+    invocation.min_coord.map(above).unwrap_or(true)
+        && invocation.max_coord.map(below).unwrap_or(true)
+}
+
+/// Finds the first point that a message would send something to, or register
+/// something at, that's out of bounds (see `in_bounds`). Points that can't be
+/// parsed are skipped here; the message's own handler will complain about
+/// them.
+fn out_of_bounds_point(invocation: &Invocation, typ: &str, message: &Value)
+                       -> Option<Point> {
+    if invocation.min_coord.is_none() && invocation.max_coord.is_none() {
+        return None
+    }
+    let z_bits = invocation.z_from_y_bits;
+    let points: Vec<&Value> = match typ {
+        "send_joules" | "send_packet" | "send_object" | "register"
+            | "begin_object" => vec![message],
+        "transfer" | "move_registration" => vec![&message["to"]],
+        "bulk_send" => match message["ops"].as_array() {
+            Some(ops) => ops.iter().take(MAX_BULK_OPS).collect(),
+            None => return None,
         },
-        _ => Err(errorize("Needed a string, got something else")),
+        _ => return None,
+    };
+    points.into_iter()
+        .filter_map(|x| expect_point(x, z_bits).ok())
+        .find(|x| !in_bounds(invocation, *x))
+}
+
+/// Decodes and size-checks an opaque object sent by a client. Invalid Base64
+/// (or not a string at all) is a protocol error (the outer `Err`), but an
+/// object that's merely bigger than `max_size` is one we can turn away
+/// politely: the inner `Err` gives the `reason` to put in the response.
+fn decode_object(val: &Value, max_size: usize)
+                 -> std::io::Result<Result<Vec<u8>, &'static str>> {
+    // (not `expect_string`, which would treat a long string as an error)
+    let base64_object = match val {
+        Value::String(ref x) => x,
+        _ => return Err(malformed("Needed a string, got something else")),
+    };
+    // (don't bother decoding something that can only be too big)
+    if max_encoded_size(max_size).map(|x| base64_object.len() > x)
+    .unwrap_or(false) {
+        return Ok(Err("too_large"))
+    }
+    let raw_object = match base64::decode(base64_object) {
+        Ok(x) => x,
+        Err(_) => return Err(malformed("Received object was invalid Base64"))
+    };
+    if raw_object.len() > max_size {
+        return Ok(Err("too_large"))
+    }
+    Ok(Ok(raw_object))
+}
+
+/// One operation out of a `bulk_send` message.
+enum BulkOp {
+    Joules(Point, Joules),
+    Packet(Point, MatPacket, Phase),
+    Object(Point, Vec<u8>),
+}
+
+impl BulkOp {
+    /// Parses one element of a `bulk_send` message's `ops` array. These look
+    /// just like the corresponding standalone messages, minus the cookie.
+    fn parse(op: &Value, limits: &MapLimits, z_bits: u32)
+             -> std::io::Result<BulkOp> {
+        let point = expect_point(op, z_bits)?;
+        match op["type"].as_str() {
+            Some("send_joules") =>
+                Ok(BulkOp::Joules(point, expect_amount(op, "joules")?)),
+            Some("send_packet") => {
+                let packet: MatPacket
+                    = serde_json::from_value(op["packet"].clone())?;
+                let phase = serde_json::from_value(op["phase"].clone())?;
+                packet.validate(phase, &limits.phase_limits)
+                    .map_err(malformed)?;
+                check_element_phase(&packet, phase)?;
+                Ok(BulkOp::Packet(point, packet, phase))
+            },
+            Some("send_object") => {
+                let object = decode_object(&op["object"],
+                                           limits.max_object_size)?
+                    .map_err(|_| malformed("Received object was too many \
+                                            bytes long"))?;
+                Ok(BulkOp::Object(point, object))
+            },
+            _ => Err(malformed("Unknown operation type in bulk_send")),
+        }
+    }
+}
+
+/// An object a client is sending or receiving in pieces.
+enum ObjectTransfer {
+    /// Being sent, from its `begin_object` until its `end_object`.
+    Sending {
+        point: Point,
+        /// The coordinates the client gave in its `begin_object`, to echo
+        /// back in the `sent_object`.
+        x: i32, y: i32, z: i32,
+        /// How many bytes the client said the object would be.
+        length: usize,
+        /// What's arrived so far. Never longer than `length`.
+        data: Vec<u8>,
+    },
+    /// Being received, from its `begin_recv_object` until its last
+    /// `recv_object_chunk`. It's already been taken out of the map.
+    Receiving {
+        object: Vec<u8>,
+        /// How many bytes of it have been sent so far.
+        sent: usize,
+    },
+}
+
+/// Something a client asked to get `tile_changed` messages about.
+#[derive(Debug,PartialEq)]
+enum Subscription {
+    Point(Point),
+    /// All the points between two corners, inclusive.
+    Box(Point, Point),
+}
+
+impl Subscription {
+    /// Parses the subscriptions out of a `subscribe` or `unsubscribe` message.
+    /// That's either a `points` array of `{x, y, z}` objects, or the corners
+    /// of a box, given as `min_x`, `min_y`, `min_z`, `max_x`, `max_y`, and
+    /// `max_z`. (As usual, the `z`s can be left out.)
+    fn parse_all(message: &Value, z_bits: u32)
+                 -> std::io::Result<Vec<Subscription>> {
+        match &message["points"] {
+            Value::Array(points) => points.iter().map(|point| {
+                Ok(Subscription::Point(expect_point(point, z_bits)?))
+            }).collect(),
+            Value::Null => {
+                let min = client_point(expect_int(&message["min_x"])?,
+                                       expect_int(&message["min_y"])?,
+                                       &message["min_z"], z_bits)?;
+                let max = client_point(expect_int(&message["max_x"])?,
+                                       expect_int(&message["max_y"])?,
+                                       &message["max_z"], z_bits)?;
+                Ok(vec![Subscription::Box(min, max)])
+            },
+            _ => Err(malformed("Needed an array of points, got something \
+                                else")),
+        }
+    }
+    fn contains(&self, loc: Point) -> bool {
+        let (min, max) = self.bounds();
+        loc.is_within(min, max)
+    }
+    /// Returns the corners of the box this covers (which, for a single point,
+    /// are both that point).
+    fn bounds(&self) -> (Point, Point) {
+        match self {
+            Subscription::Point(point) => (*point, *point),
+            Subscription::Box(min, max) => (*min, *max),
+        }
+    }
+    /// Tells the map which tiles we want to hear about, so that it doesn't
+    /// queue up changes to any others for us.
+    fn watch_all(subscriptions: &[Subscription], events: &EventReceiver) {
+        events.watch(subscriptions.iter().map(Subscription::bounds).collect());
+    }
+}
+
+/// Returns `true` if a client-supplied identity is safe to use as a file name
+/// inside `--auth-dir`.
+#[cfg(feature = "auth")]
+fn is_valid_identity(name: &str) -> bool {
+    !name.is_empty() && name.len() <= 64 && !name.starts_with('.')
+        && name.chars().all(|c| c.is_ascii_alphanumeric()
+                            || c == '-' || c == '_' || c == '.')
+}
+
+/// Tells a client that failed authentication so, and ends the connection. The
+/// failure counts against the client's address, and may get it banned.
+#[cfg(feature = "auth")]
+async fn refuse_auth(client: &mut Client, out: &mut Outputter,
+                     shared: &Shared, ip: IpAddr) -> std::io::Result<()> {
+    if let Some(length) = shared.auth_failures.failed(ip) {
+        writeln!(out, "{} BANNED for {} seconds after too many failed \
+                       authentications", ip, length.as_secs()).unwrap();
+    }
+    send_response(client,
+                  json!({
+                      "type": "auth_bad"
+                  }), &Value::Null).await?;
+    client.flush().await?;
+    Ok(())
+}
+
+/// Waits for the next tick of an optional interval. If there's no interval,
+/// this never completes, so a `select!` arm using it never fires.
+async fn next_tick(interval: &mut Option<Interval>) {
+    match interval {
+        Some(x) => { x.tick().await; },
+        None => futures::future::pending().await,
+    }
+}
+
+/// Waits for word that this connection has been replaced (see
+/// `Sessions::claim`). Like `next_tick`, never completes if there's nothing to
+/// wait for, including if the word can no longer come.
+async fn next_replacement(replaced: &mut Option<oneshot::Receiver<()>>) {
+    if let Some(x) = replaced {
+        if x.await.is_ok() { return }
+        // (a finished receiver mustn't be waited on again)
+        *replaced = None;
+    }
+    futures::future::pending().await
+}
+
+/// Makes a `registered`/`unregistered` message for a client speaking the given
+/// protocol version. Returns `None` if the point can't be expressed in that
+/// version (i.e. it's off the z = 0 plane, the client doesn't know about z,
+/// and the layer can't be packed into `y` with `z_bits` bits).
+fn registration_message(typ: &str, loc: Point, what: &str,
+                        proto_version: i64, z_bits: u32) -> Option<Value> {
+    let y = if proto_version < Z_AWARE_VERSION { loc.packed_y(z_bits)? }
+    else { loc.get_y() };
+    Some(with_z(json!({
+        "type": typ,
+        "x": loc.get_x(),
+        "y": y,
+        "what": what,
+    }), loc.get_z(), proto_version))
+}
+
+/// Makes the messages that tell a client speaking the given protocol version
+/// about a building that moved (see `Map::move_registration`). That's one
+/// `moved` message, or, for a client too old to know about those, an
+/// `unregistered` and a `registered` (either of which may be left out, as
+/// with `registration_message`).
+fn moved_messages(from: Point, to: Point, what: &str, proto_version: i64,
+                  z_bits: u32) -> Vec<Value> {
+    if proto_version >= Z_AWARE_VERSION {
+        let point = |loc: Point| json!({
+            "x": loc.get_x(),
+            "y": loc.get_y(),
+            "z": loc.get_z(),
+        });
+        return vec![json!({
+            "type": "moved",
+            "from": point(from),
+            "to": point(to),
+            "what": what,
+        })]
+    }
+    registration_message("unregistered", from, what, proto_version, z_bits)
+        .into_iter()
+        .chain(registration_message("registered", to, what, proto_version,
+                                    z_bits))
+        .collect()
+}
+
+/// Sends a batch of registrations as `registrations` messages, in chunks of
+/// about `MAP_DUMP_CHUNK_SIZE` bytes. The last one has `done` set. Only for
+/// clients new enough to know about z.
+async fn send_registrations(socket: &mut Client,
+                            registrations: Vec<(Point, String)>)
+                            -> std::io::Result<()> {
+    let mut chunk = Vec::new();
+    let mut chunk_size = 0;
+    for (loc, what) in registrations.into_iter() {
+        let registration = json!({
+            "x": loc.get_x(),
+            "y": loc.get_y(),
+            "z": loc.get_z(),
+            "what": what,
+        });
+        // (+1 for the comma)
+        let size = registration.to_string().len() + 1;
+        if !chunk.is_empty() && chunk_size + size > MAP_DUMP_CHUNK_SIZE {
+            send_response(socket,
+                          json!({
+                              "type": "registrations",
+                              "registrations": std::mem::take(&mut chunk),
+                              "done": false,
+                          }), &Value::Null).await?;
+            chunk_size = 0;
+        }
+        chunk_size += size;
+        chunk.push(registration);
     }
+    send_response(socket,
+                  json!({
+                      "type": "registrations",
+                      "registrations": chunk,
+                      "done": true,
+                  }), &Value::Null).await
+}
+
+/// Adds `z` to a response, if the client is new enough to expect it.
+fn with_z(mut response: Value, z: i32, proto_version: i64) -> Value {
+    if proto_version >= Z_AWARE_VERSION { response["z"] = json!(z) }
+    response
+}
+
+/// In offset mode, adds the point a `recv_*` request was actually resolved to
+/// (the one the log shows) to its response, as `resolved_x`, `resolved_y`,
+/// and, if the client is new enough, `resolved_z`.
+fn with_resolved(mut response: Value, point: Point, offset_mode: bool,
+                 proto_version: i64) -> Value {
+    if offset_mode {
+        response["resolved_x"] = json!(point.get_x());
+        response["resolved_y"] = json!(point.get_y());
+        if proto_version >= Z_AWARE_VERSION {
+            response["resolved_z"] = json!(point.get_z());
+        }
+    }
+    response
+}
+
+/// Sends an `error` response to a client that understands them. Older clients
+/// would choke on one, so they get disconnected instead, which is how we've
+/// always dealt with requests we can't honor.
+async fn send_error(socket: &mut Client, proto_version: i64, error: Value,
+                    cookie: &Value) -> std::io::Result<()> {
+    if proto_version >= Z_AWARE_VERSION {
+        send_response(socket, error, cookie).await
+    }
+    else { Err(refusal(&error)) }
 }
 
-fn register_maybe_offset(what: &str, recv_offset: i32) -> i32 {
-    if what.ends_with("Recver") { recv_offset }
-    else if what.ends_with("Sender") { -recv_offset }
-    else { 0 }
+/// The error that an older client gets disconnected with, in place of the
+/// given `error` response.
+fn refusal(error: &Value) -> std::io::Error {
+    errorize(&format!("refused a request ({})",
+                      error["what"].as_str().unwrap_or("error")))
 }
 
-async fn send_response(socket: &mut Client, mut json: Value,
+async fn send_response(socket: &mut Client, json: Value,
                        cookie: &Value) -> std::io::Result<()>
 {
+    socket.send(with_cookie(json, cookie)).await
+}
+
+/// Adds the cookie from a request to its response. (Cookies that are objects
+/// or arrays aren't echoed.)
+fn with_cookie(mut json: Value, cookie: &Value) -> Value {
     // TODO: debug_assert that there's a "type" key
     match cookie {
         Value::Null | Value::Object(_) | Value::Array(_) => (),
         x => json["cookie"] = x.clone(),
     }
-    socket.send(json).await
+    json
+}
+
+/// Adds the `id` from a request, if it has one, to a response to it. Unlike a
+/// cookie, any JSON value is echoed as is.
+fn with_id(mut json: Value, request: &Value) -> Value {
+    if let Some(id) = request.get("id") {
+        json["id"] = id.clone();
+    }
+    json
+}
+
+/// Like `send_response`, but for `handle_message`, which only collects the
+/// responses for someone else to send.
+fn respond(responses: &mut Vec<Value>, json: Value, cookie: &Value) {
+    responses.push(with_cookie(json, cookie))
+}
+
+/// Like `send_error`, but for `handle_message`.
+fn respond_error(responses: &mut Vec<Value>, proto_version: i64, error: Value,
+                 cookie: &Value) -> std::io::Result<()> {
+    if proto_version >= Z_AWARE_VERSION {
+        respond(responses, error, cookie);
+        Ok(())
+    }
+    else { Err(refusal(&error)) }
+}
+
+/// Logs one of the per-client events that `--verbose` reports. Normally
+/// that's a line of prose, but with `--log-json` it's `fields` (which must be
+/// an object), plus the peer, `action`, and the point involved if there is
+/// one.
+fn log_event(out: &mut Outputter, log_json: bool, peer: &str, action: &str,
+             point: Option<Point>, mut fields: Value,
+             prose: impl std::fmt::Display) {
+    if log_json {
+        fields["peer"] = json!(peer);
+        fields["action"] = json!(action);
+        if let Some(point) = point {
+            fields["x"] = json!(point.get_x());
+            fields["y"] = json!(point.get_y());
+            fields["z"] = json!(point.get_z());
+        }
+        out.write_json(fields);
+    }
+    else {
+        writeln!(out, "  {} {}", peer, prose).unwrap();
+    }
 }
 
 pub struct MessageCoder {
     verbosity: u32,
+    log_json: bool,
+    /// Who's on the other end, for `--log-json`.
+    peer: String,
     out: Outputter,
+    stats: Arc<ClientStats>,
+    /// The longest message we'll accept. The coder sits on top of any
+    /// decompression, so this counts the message as sent, not the bytes on
+    /// the wire.
+    max_message_bytes: usize,
+}
+/// Returns `true` if the given JSON nests arrays and objects more than `max`
+/// deep. Brackets inside strings don't count. (Whether it's valid JSON at all
+/// is for the parser to decide.)
+fn nested_too_deep(json: &[u8], max: usize) -> bool {
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    for &b in json {
+        if in_string {
+            if escaped { escaped = false }
+            else if b == b'\\' { escaped = true }
+            else if b == b'"' { in_string = false }
+            continue
+        }
+        match b {
+            b'"' => in_string = true,
+            b'[' | b'{' => {
+                depth += 1;
+                if depth > max { return true }
+            },
+            b']' | b'}' => depth = depth.saturating_sub(1),
+            _ => (),
+        }
+    }
+    false
 }
+
+/// The error the `MessageCoder` gives for a message nested more than
+/// `MAX_MESSAGE_DEPTH` deep. The message has already been skipped over by
+/// then, so unlike the coder's other errors, this one isn't worth hanging up
+/// over.
+#[derive(Debug)]
+struct NestedTooDeep;
+
+impl std::fmt::Display for NestedTooDeep {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(fmt, "message nested more than {} deep", MAX_MESSAGE_DEPTH)
+    }
+}
+
+impl std::error::Error for NestedTooDeep {}
+
+/// Returns `true` if the given error is a `NestedTooDeep`.
+fn is_nested_too_deep(err: &std::io::Error) -> bool {
+    err.get_ref().map(|x| x.is::<NestedTooDeep>()).unwrap_or(false)
+}
+
 impl codec::Decoder for MessageCoder {
     type Item = Value;
     type Error = std::io::Error;
@@ -152,16 +973,32 @@ impl codec::Decoder for MessageCoder {
         }
         for n in 0 .. src.len() {
             if src[n] == b'\n' {
+                // (it may all have arrived at once)
+                if n > self.max_message_bytes {
+                    return Err(errorize("Improbably long message"));
+                }
                 let splat = src.split_to(n+1);
                 let as_utf8 = match std::str::from_utf8(&splat[..]) {
                     Ok(x) => x,
                     Err(_) => return Err(errorize("Received invalid UTF-8")),
                 };
+                if nested_too_deep(&splat[..], MAX_MESSAGE_DEPTH) {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData, NestedTooDeep))
+                }
                 match serde_json::from_str(as_utf8) {
                     Err(_) => return Err(errorize("Received invalid JSON")),
                     Ok(x) => match x {
                         Value::Object(_) => {
-                            if self.verbosity >= 2 {
+                            self.stats.message_received();
+                            if self.verbosity >= 2 && self.log_json {
+                                self.out.write_json(json!({
+                                    "peer": self.peer,
+                                    "action": "received",
+                                    "message": x,
+                                }));
+                            }
+                            else if self.verbosity >= 2 {
                                 writeln!(self.out, "    → {}", x).unwrap();
                             }
                             return Ok(Some(x))
@@ -171,7 +1008,7 @@ impl codec::Decoder for MessageCoder {
                 }
             }
         }
-        if src.len() > 10000 {
+        if src.len() > self.max_message_bytes {
             return Err(errorize("Improbably long message"));
         }
         Ok(None)
@@ -182,7 +1019,15 @@ impl codec::Encoder<Value> for MessageCoder {
     fn encode(&mut self, json: Value, dst: &mut BytesMut)
               -> std::io::Result<()> {
         let s = json.to_string();
-        if self.verbosity >= 2 {
+        self.stats.message_sent();
+        if self.verbosity >= 2 && self.log_json {
+            self.out.write_json(json!({
+                "peer": self.peer,
+                "action": "sent",
+                "message": json,
+            }));
+        }
+        else if self.verbosity >= 2 {
             writeln!(self.out, "    ← {}", s).unwrap();
         }
         let b = s.as_bytes();
@@ -194,25 +1039,39 @@ impl codec::Encoder<Value> for MessageCoder {
 }
 type Client = codec::Framed<WrappedSocket, MessageCoder>;
 
+#[cfg_attr(not(feature = "auth"), allow(unused_variables))]
 async fn inner_client(out: &mut Outputter,
-                      verbosity: u32,
-                      ping_interval: Option<Duration>,
-                      offset_mode: bool,
-                      auth_file: Option<String>,
-                      map: &Arc<Mutex<Map>>,
-                      socket: TcpStream,
-                      peer: &SocketAddr,
-                      client_id: ClientID)
+                      shared: &Shared,
+                      socket: Transport,
+                      peer: &mut String,
+                      owner: &mut Option<String>,
+                      ip: IpAddr,
+                      client_id: ClientID,
+                      shutdown: &mut broadcast::Receiver<()>,
+                      #[cfg_attr(not(feature = "auth"), allow(unused))]
+                      gone: oneshot::Receiver<()>,
+                      #[cfg_attr(not(feature = "auth"), allow(unused))]
+                      hang_up: oneshot::Sender<()>)
                       -> std::io::Result<()> {
-    socket.set_nodelay(true)?;
+    let invocation = &shared.invocation;
+    let map = &shared.map;
+    let verbosity = invocation.verbosity;
+    let log_json = invocation.log_json;
+    let stats = socket.stats().clone();
+    // (a WebSocket already has message boundaries, which compression would
+    // get in the way of)
+    let supported_compression: &[&str] = if socket.is_websocket() { &[] }
+    else { compression_types(invocation) };
     let mut client = codec::Framed::new(socket, MessageCoder {
-        verbosity, out: out.clone()
+        verbosity, log_json, peer: peer.clone(), out: out.clone(),
+        stats: stats.clone(), max_message_bytes: invocation.max_message_bytes,
     });
-    let recv_offset_y = if offset_mode { 1 } else { 0 };
+    let z_bits = invocation.z_from_y_bits;
     // make sure our client talks the right protocol at us
     // TODO: make the timeout duration configurable
-    let message = match timeout(Duration::from_secs(10), client.next()).await {
-        Err(_) => return Err(errorize("timed out")),
+    let message = match connecting_step(shutdown, "handshake", client.next())
+    .await {
+        Err(x) => return Err(errorize(&x)),
         // o_O
         Ok(None) | Ok(Some(Err(_))) =>
             return Err(errorize("invalid handshake")),
@@ -223,24 +1082,34 @@ async fn inner_client(out: &mut Outputter,
         Value::String(ref x) if x == "hello" => (),
         _ => return Err(errorize("no \"hello\" in handshake")),
     }
+    // a client on a stable network can ask us not to bother pinging it
+    let wants_ping = message["ping"].as_bool() != Some(false);
+    // and a client that would rather not hear about every registration
+    // separately when it connects can ask for them all at once
+    let batch_registrations = message["batch_registrations"].as_bool()
+        == Some(true);
+    // (only meaningful with `--auth-dir`)
+    #[cfg(feature = "auth")]
+    let identity = message["identity"].as_str().map(str::to_owned);
     let compression_type = match serde_json::from_value
         ::<Option<CompressionType>>(message["compression"].clone()) {
-            Ok(x) => x,
-            Err(_) => {
-                let mut client = wrap_client(client, None).await?;
+            Ok(x) if x.is_none() || !supported_compression.is_empty() => x,
+            _ => {
+                let mut client = wrap_client(client, None, 0).await?;
                 let _ = send_response(&mut client,
                                       json!({
                                           "type": "handshake_error",
                                           "what": "compression_type_unknown",
                                           "supported_compression_types":
-                                            ["Zlib"],
+                                            supported_compression,
                                       }), &Value::Null).await;
                 let _ = client.flush().await;
-                return Err(errorize("client requested an unknown compression \
-                                     type"))
+                return Err(errorize("client requested an unsupported \
+                                     compression type"))
             },
         };
-    let mut client = wrap_client(client, compression_type).await?;
+    let mut client = wrap_client(client, compression_type,
+                                 invocation.compression_level).await?;
     match message["proto"] {
         Value::String(ref x) if x == "oniz" => (),
         _ => {
@@ -255,7 +1124,7 @@ async fn inner_client(out: &mut Outputter,
             return Err(errorize("handshake is for wrong protocol"));
         }
     }
-    let (_proto_version, _may_send_handshake_error) = {
+    let (proto_version, _may_send_handshake_error) = {
         let proto_version = match &message["version"] {
             Value::Number(x) => match x.as_i64() {
                 Some(x) => Some(x),
@@ -266,16 +1135,16 @@ async fn inner_client(out: &mut Outputter,
         let result = match proto_version {
             // Like version 1, except the client will crash if we send
             // `handshake_error`
-            Some(0) => Ok((2, false)),
-            // Current version... sort of.
-            // We support current versions identically. We would accept a
-            // `send_object` message from a version 1 (or even 0) client, for
-            // example. The main reason to bump the version number to 2 after
-            // adding the object messages was to stop new clients (that support
-            // `send_object` et. al.) from trying to send objects to old
-            // servers (that will crash with an unfriendly message if they
-            // receive one).
-            Some(1) | Some(2) => Ok((2, true)),
+            Some(0) => Ok((0, false)),
+            // Version 2 only adds the object messages. The main reason to
+            // bump the version number to 2 after adding them was to stop new
+            // clients (that support `send_object` et. al.) from trying to
+            // send objects to old servers (that will crash with an unfriendly
+            // message if they receive one).
+            Some(x @ 1..=2) => Ok((x, true)),
+            // Current version. Adds the message types that `MESSAGE_TYPES`
+            // says are new in version 3.
+            Some(3) => Ok((3, true)),
             // Older versions
             Some(x) if x < 0 => Err(("version_too_old", "client is too old")),
             // Newer versions
@@ -301,9 +1170,37 @@ async fn inner_client(out: &mut Outputter,
             Ok(x) => x,
         }
     };
+    // (completes if a later connection with our identity replaces us)
+    #[cfg_attr(not(feature = "auth"), allow(unused_mut))]
+    let mut replaced: Option<oneshot::Receiver<()>> = None;
+    #[cfg(feature = "auth")]
+    let auth_path = if let Some(path) = &invocation.auth_file {
+        Some(PathBuf::from(path))
+    }
+    else if let Some(dir) = &invocation.auth_dir {
+        match identity.as_deref().filter(|x| is_valid_identity(x)) {
+            Some(name) => Some(Path::new(dir).join(name)),
+            None => {
+                writeln!(out, "  {} AUTHENTICATION FAILED (no valid identity \
+                               given)", peer).unwrap();
+                return refuse_auth(&mut client, out, shared, ip).await
+            },
+        }
+    }
+    else { None };
     #[cfg(feature = "auth")]
-    if let Some(path) = auth_file {
-        let mut file = File::open(path).await?;
+    if let Some(path) = &auth_path {
+        let mut file = match File::open(path).await {
+            Ok(x) => x,
+            Err(x) if x.kind() == std::io::ErrorKind::NotFound
+                && invocation.auth_dir.is_some() => {
+                writeln!(out, "  {} AUTHENTICATION FAILED (unknown identity \
+                               {:?})", peer, identity.unwrap_or_default())
+                    .unwrap();
+                return refuse_auth(&mut client, out, shared, ip).await
+            },
+            Err(x) => return Err(x),
+        };
         let metadata = file.metadata().await?;
         let len = metadata.len();
         if len == 0 {
@@ -336,9 +1233,11 @@ async fn inner_client(out: &mut Outputter,
             }
             let calculated_hash = lsx::sha256::hash(&buf[..]);
             let calculated_hash = base64::encode(&calculated_hash[..]);
-            let message = match client.next().await {
-                Some(x) => x?,
-                None => return Ok(()),
+            let message = match connecting_step(shutdown, "authentication",
+                                                client.next()).await {
+                Err(x) => return Err(errorize(&x)),
+                Ok(Some(x)) => x?,
+                Ok(None) => return Ok(()),
             };
             if let Value::String(typ) = &message["type"] {
                 match typ.as_str() {
@@ -367,280 +1266,321 @@ async fn inner_client(out: &mut Outputter,
                 writeln!(out, "    WARNING!!! Passed {}/{} auths!", ok_auths,
                           NUM_CHALLENGES).unwrap();
             }
-            send_response(&mut client,
-                          json!({
-                              "type": "auth_bad"
-                          }), &Value::Null).await?;
-            client.flush().await?;
-            return Ok(())
+            return refuse_auth(&mut client, out, shared, ip).await
         }
         else {
-            writeln!(out, "  {} AUTHENTICATED", peer).unwrap();
+            shared.auth_failures.succeeded(ip);
+            if let Some(name) = identity.filter(|_| invocation.auth_dir
+                                                .is_some()) {
+                match shared.sessions.claim(&name, client_id, gone, hang_up)
+                .await {
+                    Ok(x) => replaced = x,
+                    Err(()) => {
+                        writeln!(out, "  {} REFUSED (identity {:?} is \
+                                       already connected)", peer, name)
+                            .unwrap();
+                        send_error(&mut client, proto_version,
+                                   json!({
+                                       "type": "error",
+                                       "what": "identity_in_use",
+                                   }), &Value::Null).await?;
+                        client.flush().await?;
+                        return Ok(())
+                    },
+                }
+                // from now on, say who this is whenever we mention them
+                *peer = format!("{}@{}", name, peer);
+                *owner = Some(name);
+            }
+            if !invocation.quiet {
+                writeln!(out, "  {} AUTHENTICATED", peer).unwrap();
+            }
         }
     }
-    else {
+    else if !invocation.quiet {
         writeln!(out, "  {} AUTHENTICATED (no auth needed)", peer).unwrap();
     }
-    #[cfg(not(feature = "auth"))]
-    std::mem::drop(auth_file); // normally dropped by the above
+    let peer = &*peer;
+    let owner = owner.as_deref();
+    client.codec_mut().peer = peer.clone();
     send_response(&mut client,
                   json!({
                       "type": "auth_ok"
                   }), &Value::Null).await?;
-    let mut registrations = map.lock().unwrap().get_registrations();
-    // send all registrations before our first flush
-    while let Ok((polarity, loc, what)) = registrations.try_recv() {
-        let typ = if polarity { "registered" } else { "unregistered"};
+    if proto_version >= Z_AWARE_VERSION {
+        // what a client needs to know to pace itself
+        let limits = &invocation.map_limits;
         send_response(&mut client,
                       json!({
-                          "type": typ,
-                          "x": loc.get_x(),
-                          "y": loc.get_y(),
-                          "what": what,
+                          "type": "server_info",
+                          "version": proto_version,
+                          "offset_mode": invocation.offset.is_some(),
+                          "offset": invocation.offset.map(|(x, y, z)| [x, y, z]),
+                          "min_coord":
+                            invocation.min_coord.map(|(x, y, z)| [x, y, z]),
+                          "max_coord":
+                            invocation.max_coord.map(|(x, y, z)| [x, y, z]),
+                          "supported_compression_types":
+                            supported_compression,
+                          "max_energy": limits.max_stored_energy,
+                          "max_packets": limits.max_stored_packets,
+                          "max_gas_packet_mass":
+                            limits.phase_limits.gas_stack,
+                          "max_liquid_packet_mass":
+                            limits.phase_limits.liquid_stack,
+                          "max_objects": limits.max_stored_objects,
+                          "max_object_size": limits.max_object_size,
+                          "max_registrations": limits.max_registrations,
+                          "max_total_registrations":
+                            limits.max_total_registrations,
+                          "max_bulk_ops": MAX_BULK_OPS,
+                          "max_message_bytes": invocation.max_message_bytes,
+                          "max_string_bytes": MAX_STRING_BYTES,
+                          "max_subscriptions": MAX_SUBSCRIPTIONS,
+                          "max_object_transfers": MAX_OBJECT_TRANSFERS,
+                          "max_chunked_object_size":
+                            limits.max_chunked_object_size,
+                          "object_chunk_size": OBJECT_CHUNK_SIZE,
+                          "max_region_points": MAX_REGION_POINTS,
+                          "object_ttl":
+                            invocation.object_ttl.map(|x| x.as_secs()),
+                          "occupied_tiles":
+                            map.read().unwrap().occupied_tile_count(),
                       }), &Value::Null).await?;
     }
+    let mut events = if batch_registrations
+    && proto_version >= Z_AWARE_VERSION {
+        let (registrations, events) = map.read().unwrap()
+            .get_registrations_and_events();
+        send_registrations(&mut client, registrations).await?;
+        events
+    }
+    else { map.read().unwrap().get_events() };
+    // send all registrations before our first flush (we aren't subscribed to
+    // any tiles yet, so those events can be skipped)
+    while let Some(event) = events.try_recv() {
+        let message = match event {
+            MapEvent::Registered(loc, what) =>
+                registration_message("registered", loc, &what, proto_version,
+                                     z_bits),
+            MapEvent::Unregistered(loc, what) =>
+                registration_message("unregistered", loc, &what,
+                                     proto_version, z_bits),
+            MapEvent::Moved(from, to, what) => {
+                for message in moved_messages(from, to, &what, proto_version,
+                                              z_bits) {
+                    send_response(&mut client, message, &Value::Null).await?;
+                }
+                continue
+            },
+            MapEvent::TileChanged(_) => continue,
+            // (caught up with in the main loop)
+            MapEvent::Overflowed => break,
+        };
+        let message = match message { Some(x) => x, None => continue };
+        send_response(&mut client, message, &Value::Null).await?;
+    }
     client.flush().await?;
-    // if there's no ping interval specified, ping once per day... since I
-    // can't figure out how to make an optional future while using `select!`...
-    let mut ping = interval(ping_interval.unwrap_or_else(|| Duration::new(86400,0)));
+    let mut subscriptions: Vec<Subscription> = Vec::new();
+    // chunked object sends and receives in progress, by transfer ID; any
+    // that are still here when the client goes away are simply forgotten
+    let mut transfers: HashMap<u64, ObjectTransfer> = HashMap::new();
+    // no ping interval (or a client that doesn't want pings) means no pings
+    let mut ping = invocation.ping_interval.filter(|_| wants_ping)
+        .map(interval);
+    // each ping's cookie is when it was sent, in microseconds since this,
+    // so that the pong that echoes it back tells us the round trip time
+    let ping_epoch = Instant::now();
+    let mut rate_limiter = invocation.max_messages_per_second
+        .map(RateLimiter::new);
+    let mut throttled = false;
+    // (same trick as above: no idle timeout means a very long one)
+    let idle_timeout = invocation.idle_timeout
+        .unwrap_or_else(|| Duration::new(86400*365,0));
+    let mut last_heard = Instant::now();
     loop {
         tokio::select! {
-            _ = ping.tick() => {
+            _ = shutdown.recv() => {
+                // let the client know this is deliberate, then hang up
+                if proto_version >= Z_AWARE_VERSION {
+                    send_response(&mut client,
+                                  json!({
+                                      "type": "server_closing",
+                                  }), &Value::Null).await?;
+                    client.flush().await?;
+                }
+                return Ok(())
+            },
+            _ = next_replacement(&mut replaced) => {
+                // the same identity connected again, and gets to take over
+                writeln!(out, "  {} REPLACED (same identity connected again)",
+                         peer).unwrap();
+                if proto_version >= Z_AWARE_VERSION {
+                    send_response(&mut client,
+                                  json!({
+                                      "type": "replaced",
+                                  }), &Value::Null).await?;
+                    client.flush().await?;
+                }
+                return Ok(())
+            },
+            _ = next_tick(&mut ping) => {
+                let sent_at = ping_epoch.elapsed().as_micros() as u64;
                 send_response(&mut client,
                               json!({
                                   "type": "ping",
-                              }), &Value::Null).await?;
+                              }), &json!(sent_at)).await?;
                 client.flush().await?;
             },
-            Some((polarity, loc, what)) = registrations.next() => {
-                let typ = if polarity { "registered" } else { "unregistered"};
-                send_response(&mut client,
-                              json!({
-                                  "type": typ,
-                                  "x": loc.get_x(),
-                                  "y": loc.get_y(),
-                                  "what": what,
-                              }), &Value::Null).await?;
+            _ = delay_until(last_heard + idle_timeout) => {
+                return Err(errorize("idle timeout (client stopped \
+                                     responding)"))
+            },
+            Some(event) = events.recv() => {
+                // the map stops queueing tile changes for a client that falls
+                // too far behind
+                let dropped = events.take_dropped();
+                if dropped > 0 && verbosity >= 1 {
+                    log_event(out, log_json, peer, "dropped_tile_changes",
+                              None, json!({"count": dropped}),
+                              format_args!("is reading too slowly, dropped \
+                                            {} tile change notifications",
+                                           dropped));
+                }
+                let message = match event {
+                    MapEvent::Registered(loc, what) =>
+                        registration_message("registered", loc, &what,
+                                             proto_version, z_bits),
+                    MapEvent::Unregistered(loc, what) =>
+                        registration_message("unregistered", loc, &what,
+                                             proto_version, z_bits),
+                    MapEvent::Moved(from, to, what) => {
+                        for message in moved_messages(from, to, &what,
+                                                      proto_version, z_bits) {
+                            send_response(&mut client, message, &Value::Null)
+                                .await?;
+                        }
+                        client.flush().await?;
+                        continue
+                    },
+                    MapEvent::TileChanged(loc) => {
+                        if !subscriptions.iter().any(|x| x.contains(loc)) {
+                            continue
+                        }
+                        let state = map.read().unwrap().peek_tile(loc);
+                        Some(json!({
+                            "type": "tile_changed",
+                            "x": loc.get_x(),
+                            "y": loc.get_y(),
+                            "z": loc.get_z(),
+                            "joules": state.joules,
+                            "gas_packets": state.gas_packets,
+                            "liquid_packets": state.liquid_packets,
+                            "object_count": state.object_count,
+                        }))
+                    },
+                    MapEvent::Overflowed => {
+                        // it missed some registration events, so it has to
+                        // start over
+                        if proto_version < Z_AWARE_VERSION {
+                            // (it can't be told to forget what it has)
+                            return Err(errorize("fell too far behind on \
+                                                 registrations"))
+                        }
+                        let registrations = map.read().unwrap()
+                            .resync_events(&mut events);
+                        if verbosity >= 1 {
+                            log_event(out, log_json, peer, "resync", None,
+                                      json!({"registrations":
+                                             registrations.len()}),
+                                      format_args!("fell too far behind on \
+                                                    registrations, sending \
+                                                    all {} again",
+                                                   registrations.len()));
+                        }
+                        send_response(&mut client,
+                                      json!({
+                                          "type": "registrations_reset",
+                                      }), &Value::Null).await?;
+                        if batch_registrations {
+                            send_registrations(&mut client, registrations)
+                                .await?;
+                        }
+                        else {
+                            for (loc, what) in registrations.into_iter() {
+                                let message = registration_message(
+                                    "registered", loc, &what, proto_version,
+                                    z_bits);
+                                if let Some(message) = message {
+                                    send_response(&mut client, message,
+                                                  &Value::Null).await?;
+                                }
+                            }
+                        }
+                        client.flush().await?;
+                        continue
+                    },
+                };
+                let message = match message { Some(x) => x, None => continue };
+                send_response(&mut client, message, &Value::Null).await?;
                 client.flush().await?;
             },
             message = client.next() => {
                 let message = match message {
+                    Some(Err(x)) if is_nested_too_deep(&x) => {
+                        last_heard = Instant::now();
+                        send_error(&mut client, proto_version, json!({
+                            "type": "error",
+                            "what": "nested_too_deep",
+                            "max_depth": MAX_MESSAGE_DEPTH,
+                        }), &Value::Null).await?;
+                        client.flush().await?;
+                        if verbosity >= 1 {
+                            log_event(out, log_json, peer, "bad_message", None,
+                                      json!({"reason": x.to_string()}),
+                                      format_args!("sent a bad message: {}",
+                                                   x));
+                        }
+                        continue
+                    },
                     Some(x) => x?,
                     None => return Ok(()),
                 };
+                last_heard = Instant::now();
                 if let Value::String(typ) = &message["type"] {
-                    match typ.as_str() {
-                        "ping" => {
-                            send_response(&mut client,
-                                          json!({
-                                              "type": "pong",
-                                          }), &message["cookie"]).await?;
-                        },
-                        "pong" => {},
-                        "send_joules" => {
-                            let x = expect_int(&message["x"])?;
-                            let y = expect_int(&message["y"])?;
-                            let joules = expect_int(&message["joules"])?;
-                            let point = Point::new(x, y);
-                            let spare = map.lock().unwrap().add_joules(point, joules);
-                            send_response(&mut client,
-                                          json!({
-                                              "type": "sent_joules",
-                                              "x": x,
-                                              "y": y,
-                                              "spare": spare
-                                          }), &message["cookie"]).await?;
-                            if verbosity >= 1 {
-                                if spare > 0 {
-                                    writeln!(out, "  {} sent {}J to {} ({}J \
-                                                   spared)",
-                                             peer, joules, point, spare)
-                                        .unwrap();
-                                }
-                                else {
-                                    writeln!(out, "  {} sent {}J to {}",
-                                              peer, joules, point).unwrap();
-                                }
-                            }
-                        },
-                        "recv_joules" => {
-                            let x = expect_int(&message["x"])?;
-                            let y = expect_int::<i32>(&message["y"])?;
-                            let max_joules = expect_int(&message["max_joules"])?;
-                            let point = Point::new(x, y + recv_offset_y);
-                            let joules = map.lock().unwrap().sub_joules(point,
-                                                                        max_joules);
-                            send_response(&mut client,
-                                          json!({
-                                              "type": "got_joules",
-                                              "x": x,
-                                              "y": y,
-                                              "joules": joules,
-                                          }), &message["cookie"]).await?;
-                            if verbosity >= 1 {
-                                writeln!(out, "  {} wanted up to {}J from {} \
-                                               ({}J gotten)",
-                                         peer, max_joules, point, joules)
-                                    .unwrap();
-                            }
-                        },
-                        "send_packet" => {
-                            let x = expect_int(&message["x"])?;
-                            let y = expect_int(&message["y"])?;
-                            let packet: MatPacket = serde_json::from_value(message["packet"].clone())?;
-                            let phase = serde_json::from_value(message["phase"].clone())?;
-                            if packet.is_oversized(phase) {
-                                return Err(errorize("Received `MatPacket` had too \
-                                                     much mass"))
-                            }
-                            let point = Point::new(x, y);
-                            let accepted = map.lock().unwrap()
-                                .add_packet(point, &packet, phase);
-                            send_response(&mut client,
-                                          json!({
-                                              "type": "sent_packet",
-                                              "x": x,
-                                              "y": y,
-                                              "accepted": accepted
-                                          }), &message["cookie"]).await?;
-                            if verbosity >= 1 {
-                                if accepted {
-                                    writeln!(out, "  {} put {} {} in {}",
-                                             peer, phase, packet, point)
-                                        .unwrap();
-                                }
-                                else {
-                                    writeln!(out, "  {} put {} {} in {} \
-                                                   (rejected!)",
-                                             peer, phase, packet, point)
-                                        .unwrap();
-                                }
-                            }
-                        },
-                        "recv_packet" => {
-                            let x = expect_int(&message["x"])?;
-                            let y = expect_int::<i32>(&message["y"])?;
-                            let phase = serde_json::from_value(message["phase"].clone())?;
-                            let point = Point::new(x, y + recv_offset_y);
-                            let packet = map.lock().unwrap().pop_packet(point, phase);
-                            send_response(&mut client,
-                                          json!({
-                                              "type": "got_packet",
-                                              "x": x,
-                                              "y": y,
-                                              "phase": phase,
-                                              "packet": packet,
-                                          }), &message["cookie"]).await?;
-                            if verbosity >= 1 {
-                                match packet {
-                                    Some(packet) =>
-                                        writeln!(out, "  {} sunk {} from {} \
-                                                   (got {})",
-                                                  peer, phase, point, packet),
-                                    None =>
-                                        writeln!(out, "  {} sunk {} from {} \
-                                                   (got nothing)",
-                                                  peer, phase, point),
-                                }.unwrap();
-                            }
-                        },
-                        "send_object" => {
-                            let x = expect_int(&message["x"])?;
-                            let y = expect_int(&message["y"])?;
-                            let base64_object = expect_string(&message["object"])?;
-                            if base64_object.len() > MAX_OBJECT_ENCODED_SIZE {
-                                return Err(errorize("Received object was too \
-                                                     many bytes long"))
-                            }
-                            let raw_object = match base64::decode(base64_object) {
-                                Ok(x) => x,
-                                Err(_) =>
-                                    return Err(errorize("Received object was \
-                                                         invalid Base64"))
-                            };
-                            if raw_object.len() > MAX_OBJECT_SIZE {
-                                return Err(errorize("Received object was too \
-                                                     many bytes long"))
-                            }
-                            let point = Point::new(x, y);
-                            let accepted = map.lock().unwrap()
-                                .add_object(point, raw_object);
-                            send_response(&mut client,
-                                          json!({
-                                              "type": "sent_object",
-                                              "x": x,
-                                              "y": y,
-                                              "accepted": accepted
-                                          }), &message["cookie"]).await?;
-                            if verbosity >= 1 {
-                                if accepted {
-                                    writeln!(out, "  {} put an object in {}",
-                                             peer, point)
-                                        .unwrap();
-                                }
-                                else {
-                                    writeln!(out, "  {} put an object in {} \
-                                                   (rejected!)",
-                                             peer, point)
-                                        .unwrap();
-                                }
-                            }
-                        },
-                        "recv_object" => {
-                            let x = expect_int(&message["x"])?;
-                            let y = expect_int::<i32>(&message["y"])?;
-                            let point = Point::new(x, y + recv_offset_y);
-                            let object = map.lock().unwrap().pop_object(point)
-                                .map(base64::encode);
-                            send_response(&mut client,
-                                          json!({
-                                              "type": "got_object",
-                                              "x": x,
-                                              "y": y,
-                                              "object": object,
-                                          }), &message["cookie"]).await?;
-                            if verbosity >= 1 {
-                                match object {
-                                    Some(_) =>
-                                        writeln!(out, "  {} sunk an object \
-                                                       from {} (got one)",
-                                                  peer, point),
-                                    None =>
-                                        writeln!(out, "  {} sunk an object \
-                                                       from {} (got nothing)",
-                                                 peer, point),
-                                }.unwrap();
-                            }
-                        },
-                        "register" => {
-                            let x = expect_int(&message["x"])?;
-                            let y = expect_int::<i32>(&message["y"])?;
-                            let what = expect_string(&message["what"])?;
-                            let point = Point::new(x, y + register_maybe_offset(what, recv_offset_y));
-                            if !map.lock().unwrap().register(point, client_id,
-                                                             what.to_owned()) {
-                                return Err(errorize("Registered too many buildings at \
-                                                     the same point"))
-                            }
-                            if verbosity >= 1 {
-                                writeln!(out, "  {} registered a {:?} at {}",
-                                          peer, what, point).unwrap();
-                            }
-                        },
-                        "unregister" => {
-                            let x = expect_int(&message["x"])?;
-                            let y = expect_int::<i32>(&message["y"])?;
-                            let what = expect_string(&message["what"])?;
-                            let point = Point::new(x, y + register_maybe_offset(what, recv_offset_y));
-                            map.lock().unwrap().unregister(point, client_id, what);
-                            if verbosity >= 1 {
-                                writeln!(out, "  {} unregistered a {:?} at {}",
-                                          peer, what, point).unwrap();
-                            }
-                        },
-                        x => return Err(errorize(&format!("Received a message \
-                                                           with unknown type: \
-                                                           {:?}", x)))
+                    if typ == "pong" && verbosity >= 1 {
+                        let sent_at = message["cookie"].as_u64()
+                            .map(|x| ping_epoch + Duration::from_micros(x));
+                        // (a cookie from the future isn't one of ours)
+                        if let Some(latency) = sent_at
+                        .and_then(|x| last_heard.checked_duration_since(x)) {
+                            let ms = latency.as_secs_f64() * 1000.0;
+                            log_event(out, log_json, peer, "ping", None,
+                                      json!({"latency_ms": ms}),
+                                      format_args!("round trip time {:.1}ms",
+                                                   ms));
+                        }
+                    }
+                    // keepalives don't count against the limit
+                    if let Some(rate_limiter) = rate_limiter.as_mut()
+                    .filter(|_| typ != "ping" && typ != "pong") {
+                        let was_throttled = throttled;
+                        throttled = rate_limiter.take().await;
+                        if throttled && !was_throttled && verbosity >= 1 {
+                            log_event(out, log_json, peer, "throttled", None,
+                                      json!({}),
+                                      "is sending too many messages, \
+                                       throttling");
+                        }
+                    }
+                    let mut cx = ClientContext {
+                        out, shared, peer, owner, client_id, proto_version,
+                        stats: &stats, subscriptions: &mut subscriptions,
+                        events: &events, transfers: &mut transfers,
+                    };
+                    for response in answer_message(&mut cx, typ,
+                                                   &message)? {
+                        client.send(response).await?;
                     }
                     client.flush().await?;
                 }
@@ -653,69 +1593,2076 @@ async fn inner_client(out: &mut Outputter,
     }
 }
 
-async fn client(mut out: Outputter,
-                verbosity: u32, ping_interval: Option<Duration>,
-                offset_mode: bool, auth_file: Option<String>,
-                map: Arc<Mutex<Map>>, socket: TcpStream, peer: SocketAddr,
-                client_id: ClientID) {
-    match inner_client(&mut out, verbosity, ping_interval, offset_mode,
-                       auth_file, &map, socket, &peer, client_id)
-    .await {
-        Ok(()) =>
-            writeln!(out, "  {} DISCONNECTED", peer),
+/// Puts an object a client sent into the map, counting it if it's accepted.
+/// Returns whether it was.
+fn put_object(out: &mut Outputter, shared: &Shared, stats: &ClientStats,
+              point: Point, object: Vec<u8>) -> bool {
+    let (accepted, budget_warning) = {
+        let map = shared.map.read().unwrap();
+        (map.add_object(point, object), map.take_object_budget_warning())
+    };
+    if accepted {
+        shared.metrics.object_sent();
+        stats.object_sent();
+    }
+    if budget_warning {
+        writeln!(out, "The global object limit has been reached. Objects \
+                       will be rejected until some are received.").unwrap();
+    }
+    accepted
+}
+
+/// Checks that a client can start a new chunked object transfer with the given
+/// ID (see `begin_object` and `begin_recv_object`).
+fn check_new_transfer(transfers: &HashMap<u64, ObjectTransfer>, id: u64)
+                      -> std::io::Result<()> {
+    if transfers.contains_key(&id) {
+        return Err(bad_field("transfer_in_use", "transfer",
+                             "is already in progress"))
+    }
+    if transfers.len() >= MAX_OBJECT_TRANSFERS {
+        return Err(bad_field("too_many_transfers", "transfer",
+                             "would be more than max_object_transfers"))
+    }
+    Ok(())
+}
+
+/// Everything about the client that sent a message that `handle_message` (and
+/// the handlers it calls) might need.
+struct ClientContext<'a> {
+    out: &'a mut Outputter,
+    shared: &'a Shared,
+    peer: &'a str,
+    /// The identity it authenticated with, if any.
+    owner: Option<&'a str>,
+    client_id: ClientID,
+    proto_version: i64,
+    stats: &'a ClientStats,
+    subscriptions: &'a mut Vec<Subscription>,
+    /// Where our map events come from. Told about any change to
+    /// `subscriptions`.
+    events: &'a EventReceiver,
+    /// Chunked object sends and receives in progress, by transfer ID.
+    transfers: &'a mut HashMap<u64, ObjectTransfer>,
+}
+
+impl<'a> ClientContext<'a> {
+    fn invocation(&self) -> &'a Invocation { &self.shared.invocation }
+    /// Returns `true` if what the client does should be logged.
+    fn verbose(&self) -> bool { self.invocation().verbosity >= 1 }
+    /// Logs something the client did (see `log_event`). Most of what a
+    /// client does is only logged if `verbose`.
+    fn log(&mut self, action: &str, point: Option<Point>, fields: Value,
+           prose: impl std::fmt::Display) {
+        log_event(self.out, self.invocation().log_json, self.peer, action,
+                  point, fields, prose)
+    }
+    /// The point that a message with the given `x` and `y` (and its own `z`,
+    /// if any) is aimed at, before any offset.
+    fn point(&self, x: i32, y: i32, message: &Value)
+             -> std::io::Result<Point> {
+        client_point(x, y, &message["z"], self.invocation().z_from_y_bits)
+    }
+    /// How far `recv_*` requests are shifted by `--offset`.
+    fn recv_offset(&self) -> (i32, i32, i32) {
+        self.invocation().offset.unwrap_or((0, 0, 0))
+    }
+    fn offset_mode(&self) -> bool { self.invocation().offset.is_some() }
+}
+
+/// Handles one message the way `inner_client` does, returning everything to
+/// send back. Unlike with `handle_message`, a message that didn't make sense
+/// gets an `error` response instead of an error, and every response carries
+/// the `id` of the message, if it had one.
+fn answer_message(cx: &mut ClientContext, typ: &str, message: &Value)
+                  -> std::io::Result<Vec<Value>> {
+    let responses = match handle_message(cx, typ, message) {
+        Ok(responses) => responses,
+        // a message that didn't make sense isn't worth hanging up over
+        Err(x) if x.kind() == std::io::ErrorKind::InvalidData => {
+            let mut error = json!({
+                "type": "error",
+                "what": "bad_message",
+                "message_type": typ,
+                "reason": x.to_string(),
+            });
+            if let Some(bad) = x.get_ref()
+            .and_then(|x| x.downcast_ref::<BadField>()) {
+                error["what"] = json!(bad.what);
+                error["field"] = json!(bad.field);
+            }
+            let mut responses = Vec::new();
+            respond_error(&mut responses, cx.proto_version, error,
+                          &message["cookie"])?;
+            if cx.shared.invocation.verbosity >= 1 {
+                log_event(cx.out, cx.shared.invocation.log_json, cx.peer,
+                          "bad_message", None,
+                          json!({"message_type": typ,
+                                 "reason": x.to_string()}),
+                          format_args!("sent a bad {} message: {}", typ, x));
+            }
+            responses
+        },
+        Err(x) => return Err(x),
+    };
+    Ok(responses.into_iter().map(|x| with_id(x, message)).collect())
+}
+
+/// Handles one message from a client that's finished its handshake, and
+/// returns the responses to send back, in order. It doesn't need a connection
+/// to work with. (`answer_message` does the rest of what `inner_client` does
+/// with a message.)
+///
+/// Messages that get past the checks that apply to every message (protocol
+/// version, `--readonly`, and so on) go to a `handle_*` function of their own.
+///
+/// An `InvalidData` error means the message didn't make sense, and deserves
+/// an `error` response, which `answer_message` gives it. Any other error
+/// means the client should be disconnected.
+fn handle_message(cx: &mut ClientContext, typ: &str, message: &Value)
+                  -> std::io::Result<Vec<Value>> {
+    let invocation = cx.invocation();
+    let out_of_bounds = out_of_bounds_point(invocation, typ, message);
+    let mut responses = Vec::new();
+    match typ {
+        x if message_min_version(x) > cx.proto_version
+        && cx.proto_version < Z_AWARE_VERSION => {
+            // it can't understand an error, or any response we could give it,
+            // so pretend we never saw it
+            if cx.verbose() {
+                cx.log("ignored", None, json!({"message_type": x}),
+                       format_args!("sent a {} message, which is too new for \
+                                     its version; ignored", x));
+            }
+        },
+        x if message_min_version(x) > cx.proto_version => {
+            respond_error(&mut responses, cx.proto_version,
+                          json!({
+                              "type": "error",
+                              "what": "unsupported_in_version",
+                              "message_type": x,
+                              "version": cx.proto_version,
+                          }), &message["cookie"])?;
+        },
+        x if invocation.readonly && message_mutates(x) => {
+            respond_error(&mut responses, cx.proto_version,
+                          json!({
+                              "type": "error",
+                              "what": "readonly",
+                              "message_type": x,
+                          }), &message["cookie"])?;
+        },
+        // Administrative, and drastic, so unlike `clear_tile` these aren't
+        // allowed when anyone could send them.
+        x if is_admin_message(x) && !admin_allowed(invocation) => {
+            respond_error(&mut responses, cx.proto_version,
+                          json!({
+                              "type": "error",
+                              "what": "auth_required",
+                              "message_type": x,
+                          }), &message["cookie"])?;
+        },
+        // A client bug can send wild coordinates; don't let it scatter things
+        // all over the map.
+        x if out_of_bounds.is_some() => {
+            let point = out_of_bounds.unwrap();
+            if cx.verbose() {
+                cx.log(x, Some(point),
+                       json!({"accepted": false, "reason": "out_of_bounds"}),
+                       format_args!("sent a {} message aimed at {}, which is \
+                                     out of bounds (rejected!)", x, point));
+            }
+            respond_error(&mut responses, cx.proto_version,
+                          json!({
+                              "type": "error",
+                              "what": "out_of_bounds",
+                              "message_type": x,
+                              "x": point.get_x(),
+                              "y": point.get_y(),
+                              "z": point.get_z(),
+                          }), &message["cookie"])?;
+        },
+        "ping" => return handle_ping(message),
+        "pong" => {},
+        "capabilities" => return handle_capabilities(cx, message),
+        "send_joules" => return handle_send_joules(cx, message),
+        "recv_joules" => return handle_recv_joules(cx, message),
+        "send_packet" => return handle_send_packet(cx, message),
+        "recv_packet" => return handle_recv_packet(cx, message),
+        "send_object" => return handle_send_object(cx, message),
+        "begin_object" => return handle_begin_object(cx, message),
+        "object_chunk" => return handle_object_chunk(cx, message),
+        "end_object" => return handle_end_object(cx, message),
+        "begin_recv_object" => return handle_begin_recv_object(cx, message),
+        "recv_object_chunk" => return handle_recv_object_chunk(cx, message),
+        "recv_object" => return handle_recv_object(cx, message),
+        "query_tile" => return handle_query_tile(cx, message),
+        "query_region" => return handle_query_region(cx, message),
+        "dump_map" => return handle_dump_map(cx, message),
+        "subscribe" => return handle_subscribe(cx, message),
+        "unsubscribe" => return handle_unsubscribe(cx, message),
+        "clear_tile" => return handle_clear_tile(cx, message),
+        "reset_map" => return handle_reset_map(cx, message),
+        "save_now" => return handle_save_now(cx, message),
+        "mass_audit" => return handle_mass_audit(cx, message),
+        "kick" => return handle_kick(cx, message),
+        "transfer" => return handle_transfer(cx, message),
+        "bulk_send" => return handle_bulk_send(cx, message),
+        "register" => return handle_register(cx, message),
+        "unregister" => return handle_unregister(cx, message),
+        "move_registration" => return handle_move_registration(cx, message),
+        x => return Err(errorize(&format!("Received a message with unknown \
+                                           type: {:?}", x)))
+    }
+    Ok(responses)
+}
+
+/// Handles `ping`, which just gets a `pong` back.
+fn handle_ping(message: &Value) -> std::io::Result<Vec<Value>> {
+    let mut responses = Vec::new();
+    respond(&mut responses,
+            json!({
+                "type": "pong",
+            }), &message["cookie"]);
+    Ok(responses)
+}
+
+/// Handles `capabilities`: tells the client what it's allowed to do.
+fn handle_capabilities(cx: &mut ClientContext, message: &Value)
+                       -> std::io::Result<Vec<Value>> {
+    let invocation = cx.invocation();
+    let mut responses = Vec::new();
+    // only what this client could actually use
+    let message_types: Vec<&str> = MESSAGE_TYPES
+        .iter()
+        .filter(|x| x.1 <= cx.proto_version)
+        .map(|x| x.0)
+        .filter(|x| !(invocation.readonly && message_mutates(x)))
+        .filter(|x| !is_admin_message(x) || admin_allowed(invocation))
+        .filter(|x| *x != "mass_audit" || invocation.map_limits.mass_audit)
+        .collect();
+    respond(&mut responses,
+            json!({
+                "type": "capabilities",
+                "version": cx.proto_version,
+                "message_types": message_types,
+                "auth": auth_enabled(invocation),
+                "supported_compression_types": compression_types(invocation),
+                "readonly": invocation.readonly,
+                "offset_mode": invocation.offset.is_some(),
+            }), &message["cookie"]);
+    Ok(responses)
+}
+
+/// Handles `send_joules`: puts energy into the map, and says how much of it
+/// didn't fit.
+fn handle_send_joules(cx: &mut ClientContext, message: &Value)
+                      -> std::io::Result<Vec<Value>> {
+    let mut responses = Vec::new();
+    let x = expect_int(&message["x"])?;
+    let y = expect_int(&message["y"])?;
+    let z = expect_int_or_zero(&message["z"])?;
+    let joules = expect_amount(message, "joules")?;
+    let point = cx.point(x, y, message)?;
+    let spare = cx.shared.map.read().unwrap().add_joules(point, joules);
+    cx.shared.metrics.joules_sent(joules - spare);
+    cx.stats.joules_sent(joules - spare);
+    respond(&mut responses,
+            with_z(json!({
+                       "type": "sent_joules",
+                       "x": x,
+                       "y": y,
+                       "spare": spare
+                   }), z, cx.proto_version),
+            &message["cookie"]);
+    if cx.verbose() {
+        cx.log("send_joules", Some(point),
+               json!({"amount": joules, "spare": spare}),
+               if spare > 0 as Joules {
+                   format!("sent {}J to {} ({}J spared)", joules, point, spare)
+               }
+               else { format!("sent {}J to {}", joules, point) });
+    }
+    Ok(responses)
+}
+
+/// Handles `recv_joules`: takes energy out of the map.
+fn handle_recv_joules(cx: &mut ClientContext, message: &Value)
+                      -> std::io::Result<Vec<Value>> {
+    let mut responses = Vec::new();
+    let x = expect_int(&message["x"])?;
+    let y = expect_int::<i32>(&message["y"])?;
+    let z = expect_int_or_zero(&message["z"])?;
+    let max_joules = expect_amount(message, "max_joules")?;
+    let point = cx.point(x, y, message)?.offset_by(cx.recv_offset());
+    let joules = cx.shared.map.read().unwrap().sub_joules(point, max_joules);
+    cx.shared.metrics.joules_received(joules);
+    cx.stats.joules_received(joules);
+    let response = with_z(json!({
+                              "type": "got_joules",
+                              "x": x,
+                              "y": y,
+                              "joules": joules,
+                          }), z, cx.proto_version);
+    respond(&mut responses,
+            with_resolved(response, point, cx.offset_mode(),
+                          cx.proto_version),
+            &message["cookie"]);
+    if cx.verbose() {
+        cx.log("recv_joules", Some(point),
+               json!({"max_amount": max_joules, "amount": joules}),
+               format_args!("wanted up to {}J from {} ({}J gotten)",
+                            max_joules, point, joules));
+    }
+    Ok(responses)
+}
+
+/// Handles `send_packet`: puts a gas or liquid packet into the map, if there's
+/// room.
+fn handle_send_packet(cx: &mut ClientContext, message: &Value)
+                      -> std::io::Result<Vec<Value>> {
+    let mut responses = Vec::new();
+    let x = expect_int(&message["x"])?;
+    let y = expect_int(&message["y"])?;
+    let z = expect_int_or_zero(&message["z"])?;
+    let packet: MatPacket = serde_json::from_value(message["packet"].clone())?;
+    let phase = serde_json::from_value(message["phase"].clone())?;
+    let point = cx.point(x, y, message)?;
+    let phase_limits = &cx.invocation().map_limits.phase_limits;
+    if let Err(err) = packet.validate(phase, phase_limits) {
+        // too much mass is something a client could plausibly get wrong, and
+        // gets an ordinary refusal; nonsense gets an error
+        if !packet.is_oversized(phase, phase_limits) {
+            return Err(malformed(err))
+        }
+        respond(&mut responses,
+                with_z(json!({
+                           "type": "sent_packet",
+                           "x": x,
+                           "y": y,
+                           "accepted": false,
+                           "spare": packet.get_mass(),
+                           "reason": "too_large",
+                       }), z, cx.proto_version),
+                &message["cookie"]);
+        if cx.verbose() {
+            cx.log("send_packet", Some(point),
+                   json!({"phase": phase,
+                          "packet": packet,
+                          "accepted": false,
+                          "reason": "too_large"}),
+                   format_args!("put an oversized {} {} in {} (rejected!)",
+                                phase, packet, point));
+        }
+        return Ok(responses)
+    }
+    check_element_phase(&packet, phase)?;
+    let (spare, why) = cx.shared.map.read().unwrap()
+        .add_packet(point, &packet, phase);
+    if spare < packet.get_mass() {
+        cx.shared.metrics.packet_sent(phase);
+        cx.stats.packet_sent();
+    }
+    // (older clients only look at `accepted`, and keep the whole packet
+    // unless it's true)
+    let mut response = json!({
+        "type": "sent_packet",
+        "x": x,
+        "y": y,
+        "accepted": spare == 0.0,
+        "spare": spare,
+    });
+    if let Some(why) = why {
+        response["reason"] = json!(why.as_str());
+    }
+    respond(&mut responses, with_z(response, z, cx.proto_version),
+            &message["cookie"]);
+    if cx.verbose() {
+        let prose = if spare == 0.0 {
+            format!("put {} {} in {}", phase, packet, point)
+        }
+        else if spare < packet.get_mass() {
+            format!("put {} {} in {} ({:.2}kg spared)",
+                    phase, packet, point, spare)
+        }
+        else {
+            format!("put {} {} in {} (rejected!)", phase, packet, point)
+        };
+        cx.log("send_packet", Some(point),
+               json!({"phase": phase,
+                      "packet": packet,
+                      "accepted": spare == 0.0,
+                      "spare": spare,
+                      "reason": why.map(|x| x.as_str())}),
+               prose);
+    }
+    Ok(responses)
+}
+
+/// Handles `recv_packet`: takes a gas or liquid packet out of the map.
+fn handle_recv_packet(cx: &mut ClientContext, message: &Value)
+                      -> std::io::Result<Vec<Value>> {
+    let mut responses = Vec::new();
+    let x = expect_int(&message["x"])?;
+    let y = expect_int::<i32>(&message["y"])?;
+    let z = expect_int_or_zero(&message["z"])?;
+    let phase = serde_json::from_value(message["phase"].clone())?;
+    let point = cx.point(x, y, message)?.offset_by(cx.recv_offset());
+    let packet = cx.shared.map.read().unwrap().pop_packet(point, phase);
+    if packet.is_some() {
+        cx.shared.metrics.packet_received(phase);
+        cx.stats.packet_received();
+    }
+    let response = with_z(json!({
+                              "type": "got_packet",
+                              "x": x,
+                              "y": y,
+                              "phase": phase,
+                              "packet": packet,
+                          }), z, cx.proto_version);
+    respond(&mut responses,
+            with_resolved(response, point, cx.offset_mode(),
+                          cx.proto_version),
+            &message["cookie"]);
+    if cx.verbose() {
+        cx.log("recv_packet", Some(point),
+               json!({"phase": phase, "packet": packet}),
+               match packet {
+                   Some(packet) =>
+                       format!("sunk {} from {} (got {})",
+                               phase, point, packet),
+                   None =>
+                       format!("sunk {} from {} (got nothing)", phase, point),
+               });
+    }
+    Ok(responses)
+}
+
+/// Handles `send_object`: puts an opaque object into the map, if there's room.
+fn handle_send_object(cx: &mut ClientContext, message: &Value)
+                      -> std::io::Result<Vec<Value>> {
+    let mut responses = Vec::new();
+    let x = expect_int(&message["x"])?;
+    let y = expect_int(&message["y"])?;
+    let z = expect_int_or_zero(&message["z"])?;
+    let raw_object = decode_object(&message["object"],
+                                   cx.invocation().map_limits
+                                   .max_object_size)?;
+    let point = cx.point(x, y, message)?;
+    let raw_object = match raw_object {
+        Ok(x) => x,
+        Err(reason) => {
+            respond(&mut responses,
+                    with_z(json!({
+                               "type": "sent_object",
+                               "x": x,
+                               "y": y,
+                               "accepted": false,
+                               "reason": reason,
+                           }), z, cx.proto_version),
+                    &message["cookie"]);
+            if cx.verbose() {
+                cx.log("send_object", Some(point),
+                       json!({"accepted": false, "reason": reason}),
+                       format_args!("put an oversized object in {} \
+                                     (rejected!)", point));
+            }
+            return Ok(responses)
+        },
+    };
+    let accepted = put_object(cx.out, cx.shared, cx.stats, point, raw_object);
+    respond(&mut responses,
+            with_z(json!({
+                       "type": "sent_object",
+                       "x": x,
+                       "y": y,
+                       "accepted": accepted
+                   }), z, cx.proto_version),
+            &message["cookie"]);
+    if cx.verbose() {
+        cx.log("send_object", Some(point), json!({"accepted": accepted}),
+               if accepted { format!("put an object in {}", point) }
+               else { format!("put an object in {} (rejected!)", point) });
+    }
+    Ok(responses)
+}
+
+/// Handles `begin_object`, which starts a chunked object send.
+fn handle_begin_object(cx: &mut ClientContext, message: &Value)
+                       -> std::io::Result<Vec<Value>> {
+    let mut responses = Vec::new();
+    let x = expect_int(&message["x"])?;
+    let y = expect_int(&message["y"])?;
+    let z = expect_int_or_zero(&message["z"])?;
+    let point = cx.point(x, y, message)?;
+    let id = expect_int::<u64>(&message["transfer"])?;
+    let length = expect_int::<usize>(&message["length"])?;
+    if length > cx.invocation().map_limits.max_chunked_object_size {
+        return Err(bad_field("object_too_large", "length",
+                             "is more than max_chunked_object_size"))
+    }
+    check_new_transfer(cx.transfers, id)?;
+    cx.transfers.insert(id, ObjectTransfer::Sending {
+        point, x, y, z, length, data: Vec::with_capacity(length),
+    });
+    respond(&mut responses,
+            json!({
+                "type": "object_begun",
+                "transfer": id,
+            }), &message["cookie"]);
+    Ok(responses)
+}
+
+/// Handles `object_chunk`: adds to a chunked object send in progress.
+fn handle_object_chunk(cx: &mut ClientContext, message: &Value)
+                       -> std::io::Result<Vec<Value>> {
+    let mut responses = Vec::new();
+    let id = expect_int::<u64>(&message["transfer"])?;
+    let (length, data) = match cx.transfers.get_mut(&id) {
+        Some(ObjectTransfer::Sending { length, data, .. }) => (*length, data),
+        _ => return Err(bad_field("unknown_transfer", "transfer",
+                                  "isn't a send in progress")),
+    };
+    // (not `expect_string`, for the same reason as `decode_object`)
+    let chunk = match &message["data"] {
+        Value::String(ref x) => base64::decode(x)
+            .map_err(|_| malformed("Received object chunk was invalid \
+                                    Base64"))?,
+        _ => return Err(malformed("Needed a string, got something else")),
+    };
+    if chunk.len() > length - data.len() {
+        // there's no sense in letting it carry on
+        cx.transfers.remove(&id);
+        return Err(bad_field("object_too_large", "data",
+                             "goes past the length given in begin_object"))
+    }
+    data.extend_from_slice(&chunk);
+    respond(&mut responses,
+            json!({
+                "type": "object_chunk_ok",
+                "transfer": id,
+                "received": data.len(),
+            }), &message["cookie"]);
+    Ok(responses)
+}
+
+/// Handles `end_object`: puts a chunked object into the map, once all of it
+/// has arrived.
+fn handle_end_object(cx: &mut ClientContext, message: &Value)
+                     -> std::io::Result<Vec<Value>> {
+    let mut responses = Vec::new();
+    let id = expect_int::<u64>(&message["transfer"])?;
+    let (point, x, y, z, data) = match cx.transfers.remove(&id) {
+        Some(ObjectTransfer::Sending { point, x, y, z, length, data }) => {
+            if data.len() < length {
+                return Err(bad_field("transfer_incomplete", "transfer",
+                                     "is missing some of its data"))
+            }
+            (point, x, y, z, data)
+        },
+        other => {
+            // (a receive in progress carries on regardless)
+            if let Some(other) = other { cx.transfers.insert(id, other); }
+            return Err(bad_field("unknown_transfer", "transfer",
+                                 "isn't a send in progress"))
+        },
+    };
+    let accepted = put_object(cx.out, cx.shared, cx.stats, point, data);
+    respond(&mut responses,
+            with_z(json!({
+                       "type": "sent_object",
+                       "x": x,
+                       "y": y,
+                       "transfer": id,
+                       "accepted": accepted,
+                   }), z, cx.proto_version),
+            &message["cookie"]);
+    if cx.verbose() {
+        cx.log("send_object", Some(point),
+               json!({"accepted": accepted, "transfer": id}),
+               if accepted {
+                   format!("put an object in {} (in pieces)", point)
+               }
+               else {
+                   format!("put an object in {} (in pieces, rejected!)",
+                           point)
+               });
+    }
+    Ok(responses)
+}
+
+/// Handles `begin_recv_object`, which takes an opaque object out of the map
+/// to be received in pieces, with `recv_object_chunk`. (If the client goes
+/// away before it has all the pieces, the object is lost, the same as if it
+/// had gone away right after a `recv_object`.)
+fn handle_begin_recv_object(cx: &mut ClientContext, message: &Value)
+                            -> std::io::Result<Vec<Value>> {
+    let mut responses = Vec::new();
+    let x = expect_int(&message["x"])?;
+    let y = expect_int::<i32>(&message["y"])?;
+    let z = expect_int_or_zero(&message["z"])?;
+    let point = cx.point(x, y, message)?.offset_by(cx.recv_offset());
+    let id = expect_int::<u64>(&message["transfer"])?;
+    check_new_transfer(cx.transfers, id)?;
+    let object = cx.shared.map.read().unwrap().pop_object(point);
+    let length = object.as_ref().map(Vec::len);
+    if let Some(object) = object {
+        cx.shared.metrics.object_received();
+        cx.stats.object_received();
+        cx.transfers.insert(id, ObjectTransfer::Receiving { object, sent: 0 });
+    }
+    let response = with_z(json!({
+                              "type": "recv_object_begun",
+                              "x": x,
+                              "y": y,
+                              "transfer": id,
+                              "length": length,
+                          }), z, cx.proto_version);
+    respond(&mut responses,
+            with_resolved(response, point, cx.offset_mode(),
+                          cx.proto_version),
+            &message["cookie"]);
+    if cx.verbose() {
+        cx.log("recv_object", Some(point),
+               json!({"got": length.is_some(), "transfer": id}),
+               format_args!("sunk an object from {} (in pieces, {})",
+                            point,
+                            if length.is_some() { "got one" }
+                            else { "got nothing" }));
+    }
+    Ok(responses)
+}
+
+/// Handles `recv_object_chunk`: sends the next piece of an object being
+/// received, up to `OBJECT_CHUNK_SIZE` bytes. The last piece ends the
+/// transfer.
+fn handle_recv_object_chunk(cx: &mut ClientContext, message: &Value)
+                            -> std::io::Result<Vec<Value>> {
+    let mut responses = Vec::new();
+    let id = expect_int::<u64>(&message["transfer"])?;
+    let (object, sent) = match cx.transfers.get_mut(&id) {
+        Some(ObjectTransfer::Receiving { object, sent }) => (object, sent),
+        _ => return Err(bad_field("unknown_transfer", "transfer",
+                                  "isn't a receive in progress")),
+    };
+    let start = *sent;
+    let end = object.len().min(start + OBJECT_CHUNK_SIZE);
+    let data = base64::encode(&object[start .. end]);
+    *sent = end;
+    let done = end == object.len();
+    if done { cx.transfers.remove(&id); }
+    respond(&mut responses,
+            json!({
+                "type": "got_object_chunk",
+                "transfer": id,
+                "offset": start,
+                "data": data,
+                "done": done,
+            }), &message["cookie"]);
+    Ok(responses)
+}
+
+/// Handles `recv_object`: takes an opaque object out of the map.
+fn handle_recv_object(cx: &mut ClientContext, message: &Value)
+                      -> std::io::Result<Vec<Value>> {
+    let mut responses = Vec::new();
+    let x = expect_int(&message["x"])?;
+    let y = expect_int::<i32>(&message["y"])?;
+    let z = expect_int_or_zero(&message["z"])?;
+    let point = cx.point(x, y, message)?.offset_by(cx.recv_offset());
+    let object = cx.shared.map.read().unwrap().pop_object(point)
+        .map(base64::encode);
+    if object.is_some() {
+        cx.shared.metrics.object_received();
+        cx.stats.object_received();
+    }
+    let response = with_z(json!({
+                              "type": "got_object",
+                              "x": x,
+                              "y": y,
+                              "object": object,
+                          }), z, cx.proto_version);
+    respond(&mut responses,
+            with_resolved(response, point, cx.offset_mode(),
+                          cx.proto_version),
+            &message["cookie"]);
+    if cx.verbose() {
+        cx.log("recv_object", Some(point), json!({"got": object.is_some()}),
+               format_args!("sunk an object from {} ({})", point,
+                            if object.is_some() { "got one" }
+                            else { "got nothing" }));
+    }
+    Ok(responses)
+}
+
+/// Handles `query_tile`: tells the client what's stored at a point, without
+/// taking anything.
+fn handle_query_tile(cx: &mut ClientContext, message: &Value)
+                     -> std::io::Result<Vec<Value>> {
+    let mut responses = Vec::new();
+    let x = expect_int(&message["x"])?;
+    let y = expect_int(&message["y"])?;
+    let z = expect_int_or_zero::<i32>(&message["z"])?;
+    let point = cx.point(x, y, message)?;
+    let state = cx.shared.map.read().unwrap().peek_tile(point);
+    respond(&mut responses,
+            json!({
+                "type": "tile_state",
+                "x": x,
+                "y": y,
+                "z": z,
+                "joules": state.joules,
+                "gas_packets": state.gas_packets,
+                "liquid_packets": state.liquid_packets,
+                "object_count": state.object_count,
+            }), &message["cookie"]);
+    if cx.verbose() {
+        cx.log("query_tile", Some(point), json!({}),
+               format_args!("queried {}", point));
+    }
+    Ok(responses)
+}
+
+/// Handles `query_region`: like `query_tile`, but for every occupied point in
+/// a box.
+fn handle_query_region(cx: &mut ClientContext, message: &Value)
+                       -> std::io::Result<Vec<Value>> {
+    let z_bits = cx.invocation().z_from_y_bits;
+    let mut responses = Vec::new();
+    let min = client_point(expect_int(&message["min_x"])?,
+                           expect_int(&message["min_y"])?,
+                           &message["min_z"], z_bits)?;
+    let max = client_point(expect_int(&message["max_x"])?,
+                           expect_int(&message["max_y"])?,
+                           &message["max_z"], z_bits)?;
+    // (a box with its corners the wrong way around is empty)
+    let extent = |min: i32, max: i32|
+        (max as i64 - min as i64 + 1).max(0) as u64;
+    let points = extent(min.get_x(), max.get_x())
+        .saturating_mul(extent(min.get_y(), max.get_y()))
+        .saturating_mul(extent(min.get_z(), max.get_z()));
+    if points > MAX_REGION_POINTS {
+        respond_error(&mut responses, cx.proto_version,
+                      json!({
+                          "type": "error",
+                          "what": "region_too_big",
+                          "points": points,
+                          "max_points": MAX_REGION_POINTS,
+                      }), &message["cookie"])?;
+        return Ok(responses)
+    }
+    let tiles = cx.shared.map.read().unwrap().query_region(min, max);
+    let tile_count = tiles.len();
+    let mut chunk = Vec::new();
+    let mut chunk_size = 0;
+    for (point, state) in tiles.into_iter() {
+        let tile = json!({
+            "x": point.get_x(),
+            "y": point.get_y(),
+            "z": point.get_z(),
+            "joules": state.joules,
+            "gas_packets": state.gas_packets,
+            "liquid_packets": state.liquid_packets,
+            "object_count": state.object_count,
+        });
+        // (+1 for the comma)
+        let size = tile.to_string().len() + 1;
+        if !chunk.is_empty() && chunk_size + size > MAP_DUMP_CHUNK_SIZE {
+            respond(&mut responses,
+                    json!({
+                        "type": "region_state",
+                        "tiles": std::mem::take(&mut chunk),
+                        "done": false,
+                    }), &message["cookie"]);
+            chunk_size = 0;
+        }
+        chunk_size += size;
+        chunk.push(tile);
+    }
+    respond(&mut responses,
+            json!({
+                "type": "region_state",
+                "tiles": chunk,
+                "done": true,
+            }), &message["cookie"]);
+    if cx.verbose() {
+        cx.log("query_region", None,
+               json!({"min": min.to_string(),
+                      "max": max.to_string(),
+                      "tiles": tile_count}),
+               format_args!("queried {} to {} ({} tiles)",
+                            min, max, tile_count));
+    }
+    Ok(responses)
+}
+
+/// Handles `dump_map`: sends the whole map back, a chunk of tiles at a time.
+fn handle_dump_map(cx: &mut ClientContext, message: &Value)
+                   -> std::io::Result<Vec<Value>> {
+    let mut responses = Vec::new();
+    // take a snapshot, and send it after letting go of the map
+    let tiles = cx.shared.map.read().unwrap().to_json()?;
+    let tile_count = tiles.len();
+    let mut chunk = serde_json::Map::new();
+    let mut chunk_size = 0;
+    for (point, tile) in tiles.into_iter() {
+        // (+4 for the quotes, colon, and comma)
+        let size = point.len() + tile.to_string().len() + 4;
+        if !chunk.is_empty() && chunk_size + size > MAP_DUMP_CHUNK_SIZE {
+            respond(&mut responses,
+                    json!({
+                        "type": "map_dump",
+                        "tiles": std::mem::take(&mut chunk),
+                        "done": false,
+                    }), &message["cookie"]);
+            chunk_size = 0;
+        }
+        chunk_size += size;
+        chunk.insert(point, tile);
+    }
+    respond(&mut responses,
+            json!({
+                "type": "map_dump",
+                "tiles": chunk,
+                "done": true,
+            }), &message["cookie"]);
+    if cx.verbose() {
+        cx.log("dump_map", None, json!({"tiles": tile_count}),
+               format_args!("dumped the map ({} tiles)", tile_count));
+    }
+    Ok(responses)
+}
+
+/// Handles `subscribe`: starts sending `tile_changed` messages about some
+/// points.
+fn handle_subscribe(cx: &mut ClientContext, message: &Value)
+                    -> std::io::Result<Vec<Value>> {
+    let mut responses = Vec::new();
+    let new = Subscription::parse_all(message, cx.invocation().z_from_y_bits)?;
+    if cx.subscriptions.len() + new.len() > MAX_SUBSCRIPTIONS {
+        return Err(malformed("Subscribed to too many points"))
+    }
+    cx.subscriptions.extend(new);
+    Subscription::watch_all(cx.subscriptions, cx.events);
+    let count = cx.subscriptions.len();
+    respond(&mut responses,
+            json!({
+                "type": "subscribed",
+                "count": count,
+            }), &message["cookie"]);
+    if cx.verbose() {
+        cx.log("subscribe", None, json!({"subscriptions": count}),
+               format_args!("now has {} subscriptions", count));
+    }
+    Ok(responses)
+}
+
+/// Handles `unsubscribe`: stops sending `tile_changed` messages about some
+/// points (or all of them).
+fn handle_unsubscribe(cx: &mut ClientContext, message: &Value)
+                      -> std::io::Result<Vec<Value>> {
+    let mut responses = Vec::new();
+    // with no points or box, unsubscribe from everything
+    if message["points"].is_null() && message["min_x"].is_null() {
+        cx.subscriptions.clear();
+    }
+    else {
+        let old = Subscription::parse_all(message,
+                                          cx.invocation().z_from_y_bits)?;
+        cx.subscriptions.retain(|x| !old.contains(x));
+    }
+    Subscription::watch_all(cx.subscriptions, cx.events);
+    let count = cx.subscriptions.len();
+    respond(&mut responses,
+            json!({
+                "type": "unsubscribed",
+                "count": count,
+            }), &message["cookie"]);
+    if cx.verbose() {
+        cx.log("unsubscribe", None, json!({"subscriptions": count}),
+               format_args!("now has {} subscriptions", count));
+    }
+    Ok(responses)
+}
+
+/// Handles `clear_tile`: throws away everything stored at a point.
+fn handle_clear_tile(cx: &mut ClientContext, message: &Value)
+                     -> std::io::Result<Vec<Value>> {
+    let mut responses = Vec::new();
+    // Administrative. We don't need to check anything here: if authentication
+    // is enabled, every client that got this far has passed it.
+    let x = expect_int(&message["x"])?;
+    let y = expect_int(&message["y"])?;
+    let z = expect_int_or_zero::<i32>(&message["z"])?;
+    let point = cx.point(x, y, message)?;
+    let removed = cx.shared.map.read().unwrap().clear_tile(point);
+    respond(&mut responses,
+            json!({
+                "type": "cleared_tile",
+                "x": x,
+                "y": y,
+                "z": z,
+                "joules": removed.joules,
+                "gas_packets": removed.gas_packets,
+                "liquid_packets": removed.liquid_packets,
+                "object_count": removed.object_count,
+            }), &message["cookie"]);
+    cx.log("clear_tile", Some(point),
+           json!({"amount": removed.joules,
+                  "gas_packets": removed.gas_packets.len(),
+                  "liquid_packets": removed.liquid_packets.len(),
+                  "objects": removed.object_count}),
+           format_args!("cleared {} (removed {}J, {} gas packets, {} liquid \
+                         packets, {} objects)",
+                        point, removed.joules, removed.gas_packets.len(),
+                        removed.liquid_packets.len(), removed.object_count));
+    Ok(responses)
+}
+
+/// Handles `reset_map`: throws away everything stored anywhere.
+fn handle_reset_map(cx: &mut ClientContext, message: &Value)
+                    -> std::io::Result<Vec<Value>> {
+    let mut responses = Vec::new();
+    let (tile_count, registration_count) = cx.shared.map.read().unwrap()
+        .reset();
+    respond(&mut responses,
+            json!({
+                "type": "map_reset",
+                "tiles": tile_count,
+                "registrations": registration_count,
+            }), &message["cookie"]);
+    cx.log("reset_map", None,
+           json!({"tiles": tile_count,
+                  "registrations": registration_count}),
+           format_args!("RESET THE MAP (removed {} tiles and {} \
+                         registrations)", tile_count, registration_count));
+    Ok(responses)
+}
+
+/// Handles `save_now`: saves the map right away, instead of waiting for the
+/// next autosave.
+fn handle_save_now(cx: &mut ClientContext, message: &Value)
+                   -> std::io::Result<Vec<Value>> {
+    let invocation = cx.invocation();
+    let mut responses = Vec::new();
+    // (a read-only server never saves, even when asked nicely)
+    let path = match &invocation.save_file {
+        Some(x) if !invocation.readonly => x,
+        _ => {
+            respond_error(&mut responses, cx.proto_version,
+                          json!({
+                              "type": "error",
+                              "what": "saving_disabled",
+                          }), &message["cookie"])?;
+            return Ok(responses)
+        },
+    };
+    match save_map(&cx.shared.map, path, invocation, cx.out) {
+        Ok(()) => {
+            respond(&mut responses, json!({"type": "saved"}),
+                    &message["cookie"]);
+            cx.log("save_now", None, json!({}), "saved the map");
+        },
+        Err(x) => {
+            respond_error(&mut responses, cx.proto_version,
+                          json!({
+                              "type": "error",
+                              "what": "save_failed",
+                              "reason": x,
+                          }), &message["cookie"])?;
+        },
+    }
+    Ok(responses)
+}
+
+/// Handles `mass_audit`: reports how much mass has gone into and out of the
+/// map.
+fn handle_mass_audit(cx: &mut ClientContext, message: &Value)
+                     -> std::io::Result<Vec<Value>> {
+    let mut responses = Vec::new();
+    let audit = match cx.shared.map.read().unwrap().mass_audit() {
+        Some(x) => x,
+        None => {
+            respond_error(&mut responses, cx.proto_version,
+                          json!({
+                              "type": "error",
+                              "what": "mass_audit_disabled",
+                          }), &message["cookie"])?;
+            return Ok(responses)
+        },
+    };
+    respond(&mut responses,
+            json!({
+                "type": "mass_audit",
+                "gas": audit.gas,
+                "liquid": audit.liquid,
+                "leaking": audit.gas.is_leaking()
+                    || audit.liquid.is_leaking(),
+            }), &message["cookie"]);
+    Ok(responses)
+}
+
+/// Handles `kick`: disconnects other clients.
+fn handle_kick(cx: &mut ClientContext, message: &Value)
+               -> std::io::Result<Vec<Value>> {
+    let mut responses = Vec::new();
+    // by client ID, or by address (with or without the port)
+    let target = match (&message["client_id"], &message["peer"]) {
+        (Value::Null, Value::String(x)) => match x.parse() {
+            Ok(addr) => KickTarget::Address(addr),
+            Err(_) => KickTarget::Ip(x.parse().map_err(|_| {
+                malformed("kick with a nonsense peer address")
+            })?),
+        },
+        (x, Value::Null) => KickTarget::Client(expect_int(x)?),
+        _ => return Err(malformed("kick needs exactly one of client_id and \
+                                   peer")),
+    };
+    let kicked = kick_clients(cx.shared, &target);
+    respond(&mut responses,
+            json!({
+                "type": "kicked",
+                "found": !kicked.is_empty(),
+            }), &message["cookie"]);
+    cx.log("kick", None,
+           json!({"target": target.to_string(), "kicked": kicked.len()}),
+           format_args!("KICKED {} ({} client(s))", target, kicked.len()));
+    Ok(responses)
+}
+
+/// Handles `transfer`: moves things from one point to another, all at once.
+fn handle_transfer(cx: &mut ClientContext, message: &Value)
+                   -> std::io::Result<Vec<Value>> {
+    let z_bits = cx.invocation().z_from_y_bits;
+    let mut responses = Vec::new();
+    // `from` is where something is received from, so it gets the offset like
+    // a `recv_*` would
+    let from = expect_point(&message["from"], z_bits)?
+        .offset_by(cx.recv_offset());
+    let to = expect_point(&message["to"], z_bits)?;
+    let kind = expect_string(message, "kind")?;
+    let count = if message["count"].is_null() { 1 }
+    else { expect_int::<usize>(&message["count"])? };
+    if count > MAX_BULK_OPS {
+        return Err(malformed("Transferred too many things at once"))
+    }
+    let amount = match kind {
+        "joules" => Some(expect_amount(message, "amount")?),
+        _ => None,
+    };
+    let phase: Option<Phase> = match kind {
+        "packet" => Some(serde_json::from_value(message["phase"].clone())?),
+        _ => None,
+    };
+    // the write lock keeps everyone else out, so that nobody sees anything
+    // halfway moved
+    let result = {
+        let map = cx.shared.map.write().unwrap();
+        match (kind, amount, phase) {
+            ("joules", Some(amount), _) => json!({
+                "moved": map.transfer_joules(from, to, amount),
+            }),
+            ("packet", _, Some(phase)) => {
+                let mut moved = 0;
+                let mut mass = 0.0;
+                for _ in 0 .. count {
+                    let (m, left) = map.transfer_packet(from, to, phase);
+                    if m > 0.0 {
+                        moved += 1;
+                        mass += m;
+                    }
+                    if m == 0.0 || left > 0.0 { break }
+                }
+                json!({"moved": moved, "mass": mass})
+            },
+            ("object", _, _) => {
+                let moved = (0 .. count)
+                    .take_while(|_| map.transfer_object(from, to))
+                    .count();
+                json!({"moved": moved})
+            },
+            _ => return Err(malformed("Unknown kind of transfer")),
+        }
+    };
+    let mut response = result;
+    response["type"] = json!("transferred");
+    response["kind"] = json!(kind);
+    respond(&mut responses, response, &message["cookie"]);
+    if cx.verbose() {
+        cx.log("transfer", None,
+               json!({"kind": kind,
+                      "from": from.as_string(),
+                      "to": to.as_string()}),
+               format_args!("transferred {} from {} to {}", kind, from, to));
+    }
+    Ok(responses)
+}
+
+/// Handles `bulk_send`: does a batch of sends all at once.
+fn handle_bulk_send(cx: &mut ClientContext, message: &Value)
+                    -> std::io::Result<Vec<Value>> {
+    let invocation = cx.invocation();
+    let metrics = &cx.shared.metrics;
+    let mut responses = Vec::new();
+    let ops = match message["ops"].as_array() {
+        Some(x) => x,
+        None => return Err(malformed("bulk_send without an ops array")),
+    };
+    // parse everything first, so that a bad operation rejects the whole batch
+    let parsed = if ops.len() > MAX_BULK_OPS {
+        Err(errorize("too many operations"))
+    }
+    else {
+        ops.iter()
+            .map(|x| BulkOp::parse(x, &invocation.map_limits,
+                                   invocation.z_from_y_bits))
+            .collect()
+    };
+    let parsed: Vec<BulkOp> = match parsed {
+        Ok(x) => x,
+        Err(x) => {
+            respond_error(&mut responses, cx.proto_version,
+                          json!({
+                              "type": "error",
+                              "what": "bulk_send_rejected",
+                              "reason": x.to_string(),
+                          }), &message["cookie"])?;
+            return Ok(responses)
+        },
+    };
+    let mut results = Vec::with_capacity(parsed.len());
+    let budget_warning = {
+        let map = cx.shared.map.read().unwrap();
+        for op in parsed {
+            results.push(match op {
+                BulkOp::Joules(point, joules) => {
+                    let spare = map.add_joules(point, joules);
+                    metrics.joules_sent(joules - spare);
+                    cx.stats.joules_sent(joules - spare);
+                    json!({"spare": spare})
+                },
+                BulkOp::Packet(point, packet, phase) => {
+                    let (spare, why) = map.add_packet(point, &packet, phase);
+                    if spare < packet.get_mass() {
+                        metrics.packet_sent(phase);
+                        cx.stats.packet_sent();
+                    }
+                    let mut result = json!({"accepted": spare == 0.0,
+                                            "spare": spare});
+                    if let Some(why) = why {
+                        result["reason"] = json!(why.as_str());
+                    }
+                    result
+                },
+                BulkOp::Object(point, raw_object) => {
+                    let accepted = map.add_object(point, raw_object);
+                    if accepted {
+                        metrics.object_sent();
+                        cx.stats.object_sent();
+                    }
+                    json!({"accepted": accepted})
+                },
+            });
+        }
+        map.take_object_budget_warning()
+    };
+    if budget_warning {
+        writeln!(cx.out, "The global object limit has been reached. Objects \
+                          will be rejected until some are received.").unwrap();
+    }
+    let count = results.len();
+    respond(&mut responses,
+            json!({
+                "type": "bulk_sent",
+                "results": results,
+            }), &message["cookie"]);
+    if cx.verbose() {
+        cx.log("bulk_send", None, json!({"operations": count}),
+               format_args!("sent a batch of {} operations", count));
+    }
+    Ok(responses)
+}
+
+/// Handles `register`: tells everyone that a building is at a point.
+fn handle_register(cx: &mut ClientContext, message: &Value)
+                   -> std::io::Result<Vec<Value>> {
+    let mut responses = Vec::new();
+    let x = expect_int(&message["x"])?;
+    let y = expect_int::<i32>(&message["y"])?;
+    let what = expect_string(message, "what")?;
+    let point = cx.point(x, y, message)?
+        .offset_by(register_maybe_offset(what, cx.recv_offset()));
+    let known = cx.shared.building_list.read().unwrap()
+        .as_ref().map(|x| x.contains(what))
+        .unwrap_or(true);
+    if !known {
+        if cx.verbose() {
+            cx.log("register", Some(point),
+                   json!({"building": what,
+                          "accepted": false,
+                          "reason": "unknown_building"}),
+                   format_args!("tried to register an unknown {:?} at {}",
+                                what, point));
+        }
+        respond_error(&mut responses, cx.proto_version,
+                      json!({
+                          "type": "error",
+                          "what": "unknown_building",
+                          "building": what,
+                      }), &message["cookie"])?;
+        return Ok(responses)
+    }
+    if let Err(why) = cx.shared.map.read().unwrap()
+    .register(point, cx.client_id, cx.owner, what.to_owned()) {
+        if cx.verbose() {
+            cx.log("register", Some(point),
+                   json!({"building": what,
+                          "accepted": false,
+                          "reason": why.as_str()}),
+                   format_args!("tried to register a {:?} at {}, but there \
+                                 were too many ({})",
+                                what, point, why.as_str()));
+        }
+        respond_error(&mut responses, cx.proto_version,
+                      json!({
+                          "type": "error",
+                          "what": "too_many_registrations",
+                          "reason": why.as_str(),
+                          "building": what,
+                      }), &message["cookie"])?;
+        return Ok(responses)
+    }
+    if cx.verbose() {
+        cx.log("register", Some(point),
+               json!({"building": what, "accepted": true}),
+               format_args!("registered a {:?} at {}", what, point));
+    }
+    Ok(responses)
+}
+
+/// Handles `unregister`: tells everyone that a building is gone.
+fn handle_unregister(cx: &mut ClientContext, message: &Value)
+                     -> std::io::Result<Vec<Value>> {
+    let x = expect_int(&message["x"])?;
+    let y = expect_int::<i32>(&message["y"])?;
+    let what = expect_string(message, "what")?;
+    let point = cx.point(x, y, message)?
+        .offset_by(register_maybe_offset(what, cx.recv_offset()));
+    cx.shared.map.read().unwrap()
+        .unregister(point, cx.client_id, cx.owner, what);
+    if cx.verbose() {
+        cx.log("unregister", Some(point), json!({"building": what}),
+               format_args!("unregistered a {:?} at {}", what, point));
+    }
+    Ok(Vec::new())
+}
+
+/// Handles `move_registration`: moves a building from one point to another in
+/// one step.
+fn handle_move_registration(cx: &mut ClientContext, message: &Value)
+                            -> std::io::Result<Vec<Value>> {
+    let z_bits = cx.invocation().z_from_y_bits;
+    let mut responses = Vec::new();
+    let what = expect_string(message, "what")?;
+    let offset = register_maybe_offset(what, cx.recv_offset());
+    let from = expect_point(&message["from"], z_bits)?.offset_by(offset);
+    let to = expect_point(&message["to"], z_bits)?.offset_by(offset);
+    if let Err(why) = cx.shared.map.read().unwrap()
+    .move_registration(from, to, cx.client_id, cx.owner, what) {
+        if cx.verbose() {
+            cx.log("move_registration", Some(to),
+                   json!({"building": what,
+                          "from": from.as_string(),
+                          "accepted": false,
+                          "reason": why.as_str()}),
+                   format_args!("tried to move a {:?} from {} to {} ({})",
+                                what, from, to, why.as_str()));
+        }
+        let error = match why {
+            RegistrationRefusal::NotRegistered => json!({
+                "type": "error",
+                "what": "not_registered",
+                "building": what,
+            }),
+            _ => json!({
+                "type": "error",
+                "what": "too_many_registrations",
+                "reason": why.as_str(),
+                "building": what,
+            }),
+        };
+        respond_error(&mut responses, cx.proto_version, error,
+                      &message["cookie"])?;
+        return Ok(responses)
+    }
+    if cx.verbose() {
+        cx.log("move_registration", Some(to),
+               json!({"building": what,
+                      "from": from.as_string(),
+                      "accepted": true}),
+               format_args!("moved a {:?} from {} to {}", what, from, to));
+    }
+    Ok(responses)
+}
+
+/// One of the connections counted in `Shared::connections`. The count goes
+/// back down when this is dropped.
+struct ConnectionSlot(Arc<Shared>);
+
+impl ConnectionSlot {
+    /// Takes a slot, unless the server already has `--max-connections`
+    /// clients.
+    fn take(shared: &Arc<Shared>) -> Option<ConnectionSlot> {
+        let count = shared.connections.fetch_add(1, Ordering::Relaxed);
+        let slot = ConnectionSlot(shared.clone());
+        match shared.invocation.max_connections {
+            Some(max) if count >= max => None, // (drops `slot`)
+            _ => Some(slot),
+        }
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// One of the clients in `Shared::clients`.
+pub struct ConnectedClient {
+    /// Where it's connected from (after `--listen-proxy-protocol`, if any).
+    peer: SocketAddr,
+    /// Tells its task to hang up. Gone once it's been used.
+    kick: Option<oneshot::Sender<()>>,
+}
+
+/// A client's entry in `Shared::clients`. The entry is removed when this is
+/// dropped.
+struct KickHandle(Arc<Shared>, ClientID);
+
+impl KickHandle {
+    /// Adds a client to `Shared::clients`. Returns the receiver that tells it
+    /// when it's been kicked.
+    fn add(shared: &Arc<Shared>, client_id: ClientID, peer: SocketAddr)
+           -> (KickHandle, oneshot::Receiver<()>) {
+        let (tx, rx) = oneshot::channel();
+        shared.clients.lock().unwrap().insert(client_id, ConnectedClient {
+            peer, kick: Some(tx),
+        });
+        (KickHandle(shared.clone(), client_id), rx)
+    }
+    /// Updates where the client is connected from, once we know for sure.
+    fn set_peer(&self, peer: SocketAddr) {
+        if let Some(x) = self.0.clients.lock().unwrap().get_mut(&self.1) {
+            x.peer = peer;
+        }
+    }
+}
+
+impl Drop for KickHandle {
+    fn drop(&mut self) {
+        self.0.clients.lock().unwrap().remove(&self.1);
+    }
+}
+
+/// Who a `kick` request is aimed at.
+enum KickTarget {
+    Client(ClientID),
+    Address(SocketAddr),
+    /// Every client connected from this address.
+    Ip(IpAddr),
+}
+
+impl std::fmt::Display for KickTarget {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            KickTarget::Client(x) => write!(fmt, "client {}", x),
+            KickTarget::Address(x) => write!(fmt, "{}", x),
+            KickTarget::Ip(x) => write!(fmt, "everyone from {}", x),
+        }
+    }
+}
+
+/// Tells every client that matches `target` to hang up. Returns the IDs of
+/// the clients that matched.
+fn kick_clients(shared: &Shared, target: &KickTarget) -> Vec<ClientID> {
+    let mut ret = Vec::new();
+    for (&client_id, client) in shared.clients.lock().unwrap().iter_mut() {
+        let matches = match target {
+            KickTarget::Client(x) => client_id == *x,
+            KickTarget::Address(x) => client.peer == *x,
+            KickTarget::Ip(x) => client.peer.ip() == *x,
+        };
+        if !matches { continue }
+        // (it might have been kicked already, and not be gone yet)
+        if let Some(kick) = client.kick.take() { let _ = kick.send(()); }
+        ret.push(client_id);
+    }
+    ret
+}
+
+/// Waits for one step of a client's connecting (see `HANDSHAKE_TIMEOUT`),
+/// giving up if it takes too long, or as soon as the server starts shutting
+/// down. (A client that hasn't finished connecting has nothing to lose by
+/// being hung up on, and shouldn't hold up the shutdown past
+/// `DRAIN_TIMEOUT`.) The error says what we were waiting for, and why we
+/// stopped.
+async fn connecting_step<T>(shutdown: &mut broadcast::Receiver<()>,
+                            waiting_for: &str,
+                            step: impl std::future::Future<Output=T>)
+                            -> Result<T, String> {
+    tokio::select! {
+        x = timeout(HANDSHAKE_TIMEOUT, step) => x.map_err(|_| {
+            format!("timed out waiting for {}", waiting_for)
+        }),
+        _ = shutdown.recv() => Err(format!("server shut down while waiting \
+                                            for {}", waiting_for)),
+    }
+}
+
+/// Tells someone who connected while the server was full that it's full, and
+/// hangs up on them.
+async fn refuse_full(mut socket: TcpStream, peer: SocketAddr,
+                     mut out: Outputter, verbosity: u32) {
+    if verbosity >= 1 {
+        writeln!(out, "{} REFUSED (server full)", peer).unwrap();
+    }
+    let _ = timeout(Duration::from_secs(10),
+                    socket.write_all(b"{\"type\":\"handshake_error\",\
+                                       \"what\":\"server_full\"}\n")).await;
+}
+
+/// Handles one client from start to finish. `_drain` isn't used for anything;
+/// it's just held until the client is finished, so that `server_loop` can tell
+/// when all the clients are gone.
+#[cfg_attr(not(feature = "websocket"), allow(unused_variables))]
+async fn client(mut out: Outputter, shared: Arc<Shared>,
+                mut socket: TcpStream, mut peer: SocketAddr, websocket: bool,
+                client_id: ClientID, mut shutdown: broadcast::Receiver<()>,
+                _slot: ConnectionSlot, _drain: mpsc::Sender<()>,
+                kick_handle: KickHandle,
+                mut kicked: oneshot::Receiver<()>) {
+    // (errors are printed regardless)
+    let quiet = shared.invocation.quiet;
+    if shared.invocation.listen_proxy_protocol {
+        // find out who's really on the other end before doing anything else
+        match connecting_step(&mut shutdown, "PROXY header",
+                              proxy::read_proxy_header(&mut socket)).await {
+            Ok(Ok(Some(real_peer))) => {
+                if !quiet {
+                    writeln!(out, "{} CONNECTED (via proxy {})", real_peer,
+                             peer).unwrap();
+                }
+                peer = real_peer;
+                kick_handle.set_peer(peer);
+            },
+            Ok(Ok(None)) => if !quiet {
+                writeln!(out, "{} CONNECTED (proxy gave no address)", peer)
+                    .unwrap();
+            },
+            Ok(Err(x)) => {
+                writeln!(out, "{} ERROR: {}", peer, x).unwrap();
+                return
+            },
+            Err(x) => {
+                writeln!(out, "{} ERROR: {}", peer, x).unwrap();
+                return
+            },
+        }
+    }
+    else if !quiet {
+        writeln!(out, "{} CONNECTED", peer).unwrap();
+    }
+    #[cfg(feature = "auth")]
+    if let Some(remaining) = shared.auth_failures.banned_for(peer.ip()) {
+        if shared.invocation.verbosity >= 1 {
+            writeln!(out, "  {} REFUSED (banned for {} more seconds)", peer,
+                     remaining.as_secs()).unwrap();
+        }
+        return
+    }
+    let stats = Arc::new(ClientStats::new());
+    if let Err(x) = socket.set_nodelay(true) {
+        writeln!(out, "  {} ERROR: {}", peer, x).unwrap();
+        return
+    }
+    #[cfg(feature = "tls")]
+    let socket = match &shared.tls {
+        None => Transport::plain(socket, stats.clone()),
+        Some(acceptor) => {
+            // a plaintext client gets told, in plaintext, what went wrong
+            let mut first = [0; 1];
+            match connecting_step(&mut shutdown, "TLS handshake",
+                                  socket.peek(&mut first)).await {
+                Ok(Ok(1)) if first[0] == tls::TLS_HANDSHAKE_RECORD => (),
+                Ok(Ok(1)) => {
+                    writeln!(out, "  {} ERROR: sent plaintext to a TLS port",
+                             peer).unwrap();
+                    let _ = socket.write_all(b"{\"type\":\"handshake_error\",\
+                                               \"what\":\"tls_required\"}\n")
+                        .await;
+                    return
+                },
+                Ok(Ok(_)) => return,
+                Ok(Err(x)) => {
+                    writeln!(out, "  {} ERROR: {}", peer, x).unwrap();
+                    return
+                },
+                Err(x) => {
+                    writeln!(out, "  {} ERROR: {}", peer, x).unwrap();
+                    return
+                },
+            }
+            match connecting_step(&mut shutdown, "TLS handshake",
+                                  acceptor.accept(socket)).await {
+                Ok(Ok(x)) => Transport::tls(x, stats.clone()),
+                Ok(Err(x)) => {
+                    writeln!(out, "  {} ERROR: TLS handshake failed: {}",
+                             peer, x).unwrap();
+                    return
+                },
+                Err(x) => {
+                    writeln!(out, "  {} ERROR: {}", peer, x).unwrap();
+                    return
+                },
+            }
+        },
+    };
+    #[cfg(not(feature = "tls"))]
+    let socket = Transport::plain(socket, stats.clone());
+    #[cfg(feature = "websocket")]
+    let socket = if !websocket { socket } else {
+        let config = websocket_config(shared.invocation.max_message_bytes);
+        match connecting_step(&mut shutdown, "WebSocket handshake",
+                              tokio_tungstenite::accept_async_with_config(
+                                  socket, Some(config))).await {
+            Ok(Ok(x)) => Transport::websocket(x),
+            Ok(Err(x)) => {
+                writeln!(out, "  {} ERROR: WebSocket handshake failed: {}",
+                         peer, x).unwrap();
+                return
+            },
+            Err(x) => {
+                writeln!(out, "  {} ERROR: {}", peer, x).unwrap();
+                return
+            },
+        }
+    };
+    shared.metrics.client_connected();
+    let ip = peer.ip();
+    // (becomes "identity@address" once an `--auth-dir` client authenticates)
+    let mut peer = peer.to_string();
+    // (the identity it authenticated with, if any)
+    let mut owner = None;
+    // (dropped once we've cleaned up after ourselves; see `Sessions::claim`)
+    let (gone, gone_rx) = oneshot::channel::<()>();
+    // (for a replaced connection that doesn't leave when asked)
+    let (hang_up, mut hung_up) = oneshot::channel::<()>();
+    let result = tokio::select! {
+        x = inner_client(&mut out, &shared, socket, &mut peer, &mut owner,
+                         ip, client_id, &mut shutdown, gone_rx, hang_up) => x,
+        // (hangs up on them right away, whatever they were doing)
+        Ok(()) = &mut kicked => Err(errorize("kicked by an administrator")),
+        Ok(()) = &mut hung_up => Err(errorize("replaced, and took too long \
+                                               to leave")),
+    };
+    match result {
+        Ok(()) => if !quiet {
+            writeln!(out, "  {} DISCONNECTED", peer).unwrap();
+        },
         Err(x) => {
             if cfg!(debug_assertions) {
                 writeln!(out, "  {} ERROR: {:?}", peer, x)
             }
             else {
                 writeln!(out, "  {} ERROR: {}", peer, x)
+            }.unwrap();
+        }
+    }
+    if !quiet {
+        writeln!(out, "  {} totals: {}", peer, stats.summary()).unwrap();
+    }
+    if let Some(compression) = stats.compression() {
+        if shared.invocation.verbosity >= 1 {
+            writeln!(out, "  {} compression: {}", peer,
+                     compression.summary()).unwrap();
+        }
+        shared.metrics.add_compression(compression);
+    }
+    // (tile subscriptions live in `inner_client`, so they're already gone,
+    // and the map forgets our event receiver the next time it sends one)
+    shared.map.read().unwrap().unregister_all(
+        client_id, owner.as_deref(),
+        shared.shutting_down.load(Ordering::Relaxed));
+    if let Some(identity) = owner.as_deref() {
+        shared.sessions.release(identity, client_id);
+    }
+    drop(gone);
+    shared.metrics.client_disconnected();
+}
+
+/// Saves the map to the given path. The new save is written to a temporary
+/// file first, and only renamed into place (after backing up the previous
+/// save) once it has been written successfully, so a failed save never
+/// clobbers a good one.
+///
+/// The format, and whether to compress, come from `invocation`. The map is
+/// only locked while it's being copied into memory, not while the file is
+/// being written.
+///
+/// Errors are logged to `out`, and the same text is returned.
+fn save_map(map: &RwLock<Map>, path: &str, invocation: &Invocation,
+            out: &mut Outputter) -> Result<(), String> {
+    let temp_path = path.to_owned() + TEMP_SUFFIX;
+    // (nothing can change the map while we hold the write lock, so this count
+    // goes with exactly what we save)
+    let (change_count, snapshot) = {
+        let map = map.write().unwrap();
+        (map.change_count(), map.snapshot(invocation.save_format_for(path)))
+    };
+    let result = snapshot.and_then(|snapshot| {
+        write_save(&temp_path, &snapshot, invocation.save_compressed_for(path))
+    });
+    let error = match result {
+        Ok(_) => {
+            let backup_path = path.to_owned() + BACKUP_SUFFIX;
+            match fs::rename(path, &backup_path) {
+                Ok(_) => (),
+                Err(x) if x.kind() == std::io::ErrorKind::NotFound
+                    => (),
+                Err(x) => writeln!(out, "Error backing up map file: \
+                                         {}", x).unwrap(),
+            }
+            match fs::rename(&temp_path, path) {
+                Ok(_) => {
+                    map.read().unwrap().mark_saved(change_count);
+                    return Ok(())
+                },
+                Err(x) => format!("Error moving new map file into place: {}",
+                                  x),
             }
+        },
+        Err(x) => format!("Error while saving map: {}", x),
+    };
+    writeln!(out, "{}", error).unwrap();
+    Err(error)
+}
+
+/// Binds a listener to the given address. With `dual_stack`, an IPv6 address
+/// is bound with `IPV6_V6ONLY` turned off, so that it takes IPv4 connections
+/// too. Also returns a description of which address families it serves.
+async fn bind_listener(addr: &str, dual_stack: bool)
+                       -> std::io::Result<(TcpListener, &'static str)> {
+    let mut last_err = None;
+    for addr in tokio::net::lookup_host(addr).await? {
+        let result = match addr {
+            SocketAddr::V4(_) => TcpListener::bind(addr).await
+                .map(|x| (x, "IPv4")),
+            SocketAddr::V6(_) if !dual_stack => TcpListener::bind(addr).await
+                .map(|x| (x, "IPv6, and maybe IPv4, depending on the OS")),
+            SocketAddr::V6(_) => bind_dual_stack(addr),
+        };
+        match result {
+            Ok(x) => return Ok(x),
+            Err(x) => last_err = Some(x),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| errorize("address didn't resolve to \
+                                             anything")))
+}
+
+/// Like `bind_listener`, but if the address is in use, keeps trying until
+/// `retry` has passed (if given).
+async fn bind_listener_retrying(addr: &str, dual_stack: bool,
+                                retry: Option<Duration>, out: &mut Outputter)
+                                -> std::io::Result<(TcpListener,
+                                                    &'static str)> {
+    let give_up = retry.map(|x| Instant::now() + x);
+    loop {
+        match bind_listener(addr, dual_stack).await {
+            Err(x) if x.kind() == std::io::ErrorKind::AddrInUse
+                && give_up.map(|t| Instant::now() < t).unwrap_or(false) => {
+                writeln!(out, "{} is in use, trying again...", addr).unwrap();
+                delay_for(BIND_RETRY_DELAY).await;
+            },
+            x => return x,
         }
-    }.unwrap();
-    map.lock().unwrap().unregister_all(client_id);
+    }
+}
+
+fn bind_dual_stack(addr: SocketAddr)
+                   -> std::io::Result<(TcpListener, &'static str)> {
+    use socket2::{Socket, Domain, Type, Protocol};
+    let socket = Socket::new(Domain::ipv6(), Type::stream(),
+                             Some(Protocol::tcp()))?;
+    socket.set_only_v6(false)?;
+    // (`TcpListener::bind` does this too)
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    // some systems refuse to turn it off without saying so
+    let family = if socket.only_v6()? { "IPv6 only" }
+    else { "IPv4 and IPv6" };
+    Ok((TcpListener::from_std(socket.into_tcp_listener())?, family))
 }
 
-async fn server_loop(invocation: Invocation, out: &mut Outputter,
-                     map: Arc<Mutex<Map>>)
+/// Accepts connections until `shutdown` fires, then waits (up to
+/// `DRAIN_TIMEOUT`) for the connected clients to finish.
+async fn server_loop(shared: Arc<Shared>, out: &mut Outputter,
+                     shutdown: broadcast::Sender<()>)
                      -> anyhow::Result<()> {
-    let listen_addr = invocation.listen_addr
-        .unwrap_or_else(|| DEFAULT_ADDR_AND_PORT.to_owned());
-    let mut listener = TcpListener::bind(&listen_addr).await?;
+    let invocation = &shared.invocation;
+    let mut listeners = Vec::new();
+    let mut families = Vec::new();
+    if invocation.listen_addrs.is_empty() {
+        let (listener, family)
+            = bind_listener_retrying(DEFAULT_ADDR_AND_PORT,
+                                     invocation.dual_stack,
+                                     invocation.bind_retry, out).await?;
+        listeners.push(listener);
+        families.push(family);
+    }
+    for listen_addr in invocation.listen_addrs.iter() {
+        let (listener, family)
+            = bind_listener_retrying(listen_addr, invocation.dual_stack,
+                                     invocation.bind_retry, out).await?;
+        listeners.push(listener);
+        families.push(family);
+    }
+    // (which of the listeners are for WebSocket clients)
+    #[cfg_attr(not(feature = "websocket"), allow(unused_mut))]
+    let mut websocket = vec![false; listeners.len()];
+    #[cfg(feature = "websocket")]
+    for listen_addr in invocation.ws_listen_addrs.iter() {
+        let (listener, family)
+            = bind_listener_retrying(listen_addr, invocation.dual_stack,
+                                     invocation.bind_retry, out).await?;
+        listeners.push(listener);
+        families.push(family);
+        websocket.push(true);
+    }
+    for ((listener, family), websocket) in listeners.iter()
+    .zip(families.iter()).zip(websocket.iter()) {
+        writeln!(out, "Listening on {} ({}{}).", listener.local_addr()?,
+                 family, if *websocket { ", WebSocket" } else { "" })
+            .unwrap();
+    }
     let mut next_client_id: ClientID = 0;
+    if let Some(metrics_addr) = &invocation.metrics_addr {
+        let metrics_listener = TcpListener::bind(metrics_addr).await?;
+        writeln!(out, "Serving metrics on {}.", metrics_addr).unwrap();
+        tokio::spawn(metrics::serve_metrics(metrics_listener, shared.clone(),
+                                            out.clone()));
+    }
+    if let (Some(path), Some(period), false)
+    = (invocation.save_file.clone(), invocation.autosave_interval,
+       invocation.readonly) {
+        let shared = shared.clone();
+        let mut out = out.clone();
+        tokio::spawn(async move {
+            let mut ticker = interval(period);
+            ticker.tick().await; // the first tick completes immediately
+            loop {
+                ticker.tick().await;
+                if save_map(&shared.map, &path, &shared.invocation, &mut out)
+                    .is_ok()
+                && shared.invocation.verbosity >= 1 {
+                    writeln!(out, "Map autosaved.").unwrap();
+                }
+            }
+        });
+    }
+    if let (Some(path), Some(quiet_period), false)
+    = (invocation.save_file.clone(), invocation.save_interval_on_change,
+       invocation.readonly) {
+        let shared = shared.clone();
+        let mut out = out.clone();
+        tokio::spawn(async move {
+            let mut ticker = interval(SAVE_ON_CHANGE_POLL_INTERVAL);
+            let mut last_count = shared.map.read().unwrap().change_count();
+            let mut last_change = Instant::now();
+            loop {
+                ticker.tick().await;
+                let (count, dirty) = {
+                    let map = shared.map.read().unwrap();
+                    (map.change_count(), map.is_dirty())
+                };
+                if count != last_count {
+                    last_count = count;
+                    last_change = Instant::now();
+                }
+                else if dirty && last_change.elapsed() >= quiet_period
+                && save_map(&shared.map, &path, &shared.invocation, &mut out)
+                    .is_ok()
+                && shared.invocation.verbosity >= 1 {
+                    writeln!(out, "Map saved after changes.").unwrap();
+                }
+            }
+        });
+    }
+    #[cfg(feature = "auth")]
+    if auth_enabled(invocation) {
+        let shared = shared.clone();
+        let mut out = out.clone();
+        tokio::spawn(async move {
+            let mut ticker = interval(AUTH_BAN_SWEEP_INTERVAL);
+            loop {
+                ticker.tick().await;
+                for ip in shared.auth_failures.sweep() {
+                    writeln!(out, "{} ban expired", ip).unwrap();
+                }
+            }
+        });
+    }
+    if let Some(rate) = invocation.energy_decay_rate
+    .filter(|_| !invocation.readonly) {
+        let shared = shared.clone();
+        let factor = 1.0 - rate * ENERGY_DECAY_INTERVAL.as_secs_f64();
+        tokio::spawn(async move {
+            let mut ticker = interval(ENERGY_DECAY_INTERVAL);
+            ticker.tick().await; // the first tick completes immediately
+            loop {
+                ticker.tick().await;
+                shared.map.read().unwrap().decay_energy(factor);
+            }
+        });
+    }
+    if invocation.thermal_mixing && !invocation.readonly {
+        let shared = shared.clone();
+        tokio::spawn(async move {
+            let mut ticker = interval(THERMAL_MIXING_INTERVAL);
+            ticker.tick().await; // the first tick completes immediately
+            loop {
+                ticker.tick().await;
+                shared.map.read().unwrap().mix_packet_temperatures();
+            }
+        });
+    }
+    if let Some(half_life) = invocation.germ_decay
+    .filter(|_| !invocation.readonly) {
+        let shared = shared.clone();
+        let factor = 0.5f64.powf(GERM_DECAY_INTERVAL.as_secs_f64()
+                                 / half_life);
+        tokio::spawn(async move {
+            let mut ticker = interval(GERM_DECAY_INTERVAL);
+            ticker.tick().await; // the first tick completes immediately
+            loop {
+                ticker.tick().await;
+                shared.map.read().unwrap().decay_germs(factor);
+            }
+        });
+    }
+    if let Some(ttl) = invocation.object_ttl.filter(|_| !invocation.readonly) {
+        let shared = shared.clone();
+        let mut out = out.clone();
+        tokio::spawn(async move {
+            let mut ticker = interval(OBJECT_EXPIRY_INTERVAL);
+            ticker.tick().await; // the first tick completes immediately
+            loop {
+                ticker.tick().await;
+                let expired = shared.map.read().unwrap().expire_objects(ttl);
+                if expired > 0 && shared.invocation.verbosity >= 1 {
+                    writeln!(out, "{} object(s) expired after {} seconds",
+                             expired, ttl.as_secs()).unwrap();
+                }
+            }
+        });
+    }
+    if invocation.map_limits.mass_audit {
+        let shared = shared.clone();
+        let mut out = out.clone();
+        tokio::spawn(async move {
+            let mut ticker = interval(MASS_AUDIT_INTERVAL);
+            ticker.tick().await; // the first tick completes immediately
+            loop {
+                ticker.tick().await;
+                let audit = match shared.map.read().unwrap().mass_audit() {
+                    Some(x) => x,
+                    None => continue,
+                };
+                for (name, phase) in &[("gas", audit.gas),
+                                       ("liquid", audit.liquid)] {
+                    if !phase.is_leaking() { continue }
+                    writeln!(out, "WARNING: {}kg of {} is unaccounted for \
+                                   ({}kg added, {}kg removed, {}kg stored)",
+                             phase.unaccounted, name, phase.added,
+                             phase.removed, phase.stored).unwrap();
+                }
+            }
+        });
+    }
     writeln!(out, "Startup complete. Listening for connections.").unwrap();
+    let (drain_tx, mut drain_rx) = mpsc::channel::<()>(1);
+    let mut shutdown_rx = shutdown.subscribe();
     loop {
-        let (socket, peer) = listener.accept().await?;
-        writeln!(out, "{} CONNECTED", peer).unwrap();
-        let map_clone = map.clone();
-        let verbosity = invocation.verbosity;
-        let offset_mode = invocation.offset_mode;
-        let auth_file = invocation.auth_file.clone();
-        let client_id = next_client_id;
-        let ping_interval = invocation.ping_interval;
-        next_client_id = next_client_id.checked_add(1) // :)
-            .expect("Can't have more than 2^64 clients in one session!");
-        tokio::spawn(client(out.clone(), verbosity, ping_interval, offset_mode,
-                            auth_file, map_clone, socket, peer,
-                            client_id));
+        tokio::select! {
+            (accepted, index, _) = futures::future::select_all(
+                listeners.iter_mut().map(|x| Box::pin(x.accept()))) => {
+                let (socket, peer) = accepted?;
+                let slot = match ConnectionSlot::take(&shared) {
+                    Some(x) => x,
+                    None => {
+                        tokio::spawn(refuse_full(socket, peer, out.clone(),
+                                                 invocation.verbosity));
+                        continue
+                    },
+                };
+                let client_id = next_client_id;
+                next_client_id = next_client_id.checked_add(1) // :)
+                    .expect("Can't have more than 2^64 clients in one \
+                             session!");
+                let (kick_handle, kicked)
+                    = KickHandle::add(&shared, client_id, peer);
+                tokio::spawn(client(out.clone(), shared.clone(),
+                                    socket, peer, websocket[index],
+                                    client_id, shutdown.subscribe(), slot,
+                                    drain_tx.clone(), kick_handle, kicked));
+            },
+            _ = shutdown_rx.recv() => break,
+        }
+    }
+    // every client holds a clone of `drain_tx`; once they're all gone, `recv`
+    // will return `None`
+    std::mem::drop(drain_tx);
+    if timeout(DRAIN_TIMEOUT, drain_rx.recv()).await.is_err() {
+        writeln!(out, "Some clients didn't disconnect in time.").unwrap();
+    }
+    Ok(())
+}
+
+/// Treats SIGTERM, which is how systemd, Docker, and `kill` ask us to stop,
+/// the same as a Ctrl-C.
+#[cfg(unix)]
+async fn terminate_on_sigterm(mut termination_tx: mpsc::Sender<()>,
+                              mut out: Outputter) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut sigterm = match signal(SignalKind::terminate()) {
+        Ok(x) => x,
+        Err(x) => {
+            writeln!(out, "WARNING: Unable to catch SIGTERM: {}", x).unwrap();
+            return
+        },
+    };
+    sigterm.recv().await;
+    let _ = termination_tx.try_send(());
+}
+
+/// Reloads the name tables and the building list from the files they came
+/// from when we get a SIGHUP. If one can't be loaded, the old one stays.
+///
+/// Nothing needs to be done for authentication: secret files are read
+/// afresh for every handshake, so a changed secret applies to the next
+/// client that connects. Clients that have already authenticated stay
+/// connected either way.
+#[cfg(unix)]
+async fn reload_on_sighup(shared: Arc<Shared>, mut out: Outputter) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut sighup = match signal(SignalKind::hangup()) {
+        Ok(x) => x,
+        Err(x) => {
+            writeln!(out, "WARNING: Unable to catch SIGHUP: {}", x).unwrap();
+            return
+        },
+    };
+    let invocation = &shared.invocation;
+    while let Some(()) = sighup.recv().await {
+        writeln!(out, "Reloading...").unwrap();
+        if invocation.element_names.is_some()
+        || invocation.germ_names.is_some() {
+            match NameTables::load(invocation.element_names.as_deref(),
+                                   invocation.germ_names.as_deref()) {
+                Ok(x) => {
+                    Arc::new(x).install();
+                    writeln!(out, "Reloaded the name tables.").unwrap();
+                },
+                Err(x) => writeln!(out, "Unable to reload the name tables, \
+                                         keeping the old ones: {}", x)
+                    .unwrap(),
+            }
+        }
+        if let Some(path) = &invocation.building_list {
+            match load_building_list(path) {
+                Ok(x) => {
+                    writeln!(out, "Reloaded {} building identifiers.",
+                             x.len()).unwrap();
+                    *shared.building_list.write().unwrap() = Some(x);
+                },
+                Err(x) => writeln!(out, "Unable to reload the building list, \
+                                         keeping the old one: {}", x)
+                    .unwrap(),
+            }
+        }
     }
 }
 
+/// `shared_tx`, if given, is sent the server's `Shared` once it has finished
+/// starting up, so that the GUI can keep an eye on it.
 fn true_main(invocation: Invocation,
              mut termination_tx: mpsc::Sender<()>,
              mut termination_rx: mpsc::Receiver<()>,
-             mut out: Outputter) {
+             mut out: Outputter,
+             shared_tx: Option<mpsc::UnboundedSender<Arc<Shared>>>) {
     writeln!(out, "\n\nServer starting up...").unwrap();
     let mut runtime = tokio::runtime::Builder::new()
         .basic_scheduler().enable_all().build().unwrap();
     let mut out_clone = out.clone();
-    let map = Arc::new(Mutex::new(Map::new()));
-    match invocation.save_file {
+    #[cfg(unix)]
+    runtime.spawn(terminate_on_sigterm(termination_tx.clone(), out.clone()));
+    if invocation.element_names.is_some() || invocation.germ_names.is_some() {
+        match NameTables::load(invocation.element_names.as_deref(),
+                               invocation.germ_names.as_deref()) {
+            Ok(x) => Arc::new(x).install(),
+            Err(x) => {
+                writeln!(out, "Unable to load the name tables: {}", x)
+                    .unwrap();
+                return
+            },
+        }
+    }
+    #[cfg(feature = "tls")]
+    let tls = match (&invocation.tls_cert, &invocation.tls_key) {
+        (Some(cert), Some(key)) => match tls::load_acceptor(cert, key) {
+            Ok(x) => Some(x),
+            Err(x) => {
+                writeln!(out, "Unable to set up TLS: {}", x).unwrap();
+                return
+            },
+        },
+        _ => None,
+    };
+    let building_list = match &invocation.building_list {
+        None => None,
+        Some(path) => match load_building_list(path) {
+            Ok(x) => {
+                writeln!(out, "Loaded {} building identifiers.", x.len())
+                    .unwrap();
+                Some(x)
+            },
+            Err(x) => {
+                writeln!(out, "Unable to load the building list: {}", x)
+                    .unwrap();
+                return
+            },
+        },
+    };
+    let shared = Arc::new(Shared::new(invocation, building_list,
+                                      #[cfg(feature = "tls")] tls));
+    match shared.invocation.save_file {
         None => (),
         Some(ref path) => {
-            let mut map = map.lock().unwrap();
+            let mut map = shared.map.write().unwrap();
             match map.try_load(path)
             .or_else(|_| map.try_load(&(path.to_owned() + BACKUP_SUFFIX))) {
-                Ok(_) => writeln!(out, "Successfully loaded the map."),
+                Ok(report) => {
+                    // what we just loaded is already saved, of course
+                    map.mark_saved(map.change_count());
+                    if report.skipped() > 0 {
+                        writeln!(out, "WARNING: Parts of the saved map didn't \
+                                       make sense, and were left out: {}",
+                                 report).unwrap();
+                    }
+                    writeln!(out, "Successfully loaded the map.")
+                },
                 Err(x) => {
                     map.clear();
                     if x.kind() == std::io::ErrorKind::NotFound {
@@ -731,10 +3678,17 @@ fn true_main(invocation: Invocation,
             }.unwrap()
         },
     }
-    let map_clone = map.clone();
-    let invocation_clone = invocation.clone();
-    runtime.spawn(async move {
-        match server_loop(invocation_clone, &mut out_clone, map_clone).await {
+    #[cfg(unix)]
+    runtime.spawn(reload_on_sighup(shared.clone(), out.clone()));
+    if let Some(shared_tx) = shared_tx {
+        let _ = shared_tx.send(shared.clone());
+    }
+    let shared_clone = shared.clone();
+    let (shutdown_tx, _) = broadcast::channel(1);
+    let shutdown_tx_clone = shutdown_tx.clone();
+    let server = runtime.spawn(async move {
+        match server_loop(shared_clone, &mut out_clone,
+                          shutdown_tx_clone).await {
             Ok(_) => (),
             Err(x) => {
                 writeln!(out_clone, "\n\nError! {}", x).unwrap();
@@ -744,32 +3698,29 @@ fn true_main(invocation: Invocation,
         let _ = termination_tx.try_send(());
     });
     runtime.block_on(async {
-        termination_rx.recv().await.unwrap()
+        termination_rx.recv().await.unwrap();
+        writeln!(out, "\n\nServer closing down...").unwrap();
+        // give the clients a chance to finish what they're doing
+        shared.shutting_down.store(true, Ordering::Relaxed);
+        let _ = shutdown_tx.send(());
+        let _ = server.await;
     });
-    writeln!(out, "\n\nServer closing down...").unwrap();
-    match invocation.save_file {
+    if shared.invocation.verbosity >= 1 {
+        if let Some(compression) = shared.metrics.compression() {
+            writeln!(out, "Compression for all clients: {}",
+                     compression.summary()).unwrap();
+        }
+    }
+    match shared.invocation.save_file {
         None => (),
+        Some(_) if shared.invocation.readonly => {
+            writeln!(out, "Read-only mode, not saving the map.").unwrap();
+        },
         Some(ref path) => {
-            let temp_path = path.to_owned() + TEMP_SUFFIX;
-            match map.lock().unwrap().try_save(&temp_path) {
-                Ok(_) => {
-                    let backup_path = path.to_owned() + BACKUP_SUFFIX;
-                    match fs::rename(path, &backup_path) {
-                        Ok(_) => (),
-                        Err(x) if x.kind() == std::io::ErrorKind::NotFound
-                            => (),
-                        Err(x) => writeln!(out, "Error backing up map file: \
-                                                 {}", x).unwrap(),
-                    }
-                    match fs::rename(&temp_path, path) {
-                        Ok(_) => writeln!(out, "Map saved successfully."),
-                        Err(x) => writeln!(out, "Error moving new map file \
-                                                 into place: {}", x),
-                    }
-                },
-                Err(x) =>
-                    writeln!(out, "Error while saving map: {}", x),
-            }.unwrap();
+            if save_map(&shared.map, path, &shared.invocation, &mut out)
+            .is_ok() {
+                writeln!(out, "Map saved successfully.").unwrap();
+            }
         }
     }
 }
@@ -792,5 +3743,791 @@ fn main() {
     ctrlc::set_handler(move || {
         let _ = termination_tx_clone.try_send(());
     }).unwrap();
-    true_main(invocation, termination_tx, termination_rx, Outputter::Stderr);
+    let out = match &invocation.log_file {
+        None => Outputter::stderr(),
+        Some(path) => match Outputter::file(path, invocation.log_max_size) {
+            Ok(x) => x,
+            Err(x) => {
+                let mut out = Outputter::stderr();
+                writeln!(out, "WARNING: Unable to open log file {:?}: {}\n\
+                               Logging to stderr instead.", path, x).unwrap();
+                out
+            },
+        },
+    };
+    #[cfg(all(unix, feature = "syslog"))]
+    let out = if !invocation.syslog { out } else {
+        match Outputter::syslog() {
+            Ok(x) => x,
+            Err(x) => {
+                let mut out = out;
+                writeln!(out, "WARNING: Unable to connect to syslog: {}\n\
+                               Logging to stderr instead.", x).unwrap();
+                out
+            },
+        }
+    };
+    true_main(invocation, termination_tx, termination_rx, out, None);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Stands in for a connected client, handing messages straight to
+    /// `handle_message`.
+    struct TestClient {
+        shared: Shared,
+        out: Outputter,
+        // (kept so that the log doesn't go nowhere)
+        _log: mpsc::UnboundedReceiver<String>,
+        stats: ClientStats,
+        subscriptions: Vec<Subscription>,
+        events: EventReceiver,
+        transfers: HashMap<u64, ObjectTransfer>,
+        proto_version: i64,
+    }
+
+    impl TestClient {
+        fn new() -> TestClient { TestClient::with(Invocation::default()) }
+        fn with(invocation: Invocation) -> TestClient {
+            let (tx, rx) = mpsc::unbounded_channel();
+            let shared = Shared::new(invocation, None,
+                                     #[cfg(feature = "tls")] None);
+            let events = shared.map.read().unwrap().get_events();
+            TestClient {
+                shared,
+                out: Outputter::channel(tx), _log: rx,
+                stats: ClientStats::new(),
+                subscriptions: Vec::new(), events, transfers: HashMap::new(),
+                proto_version: 3,
+            }
+        }
+        fn send(&mut self, message: Value) -> std::io::Result<Vec<Value>> {
+            let typ = message["type"].as_str().unwrap().to_owned();
+            answer_message(&mut ClientContext {
+                out: &mut self.out, shared: &self.shared, peer: "test",
+                owner: None, client_id: 1,
+                proto_version: self.proto_version, stats: &self.stats,
+                subscriptions: &mut self.subscriptions,
+                events: &self.events, transfers: &mut self.transfers,
+            }, &typ, &message)
+        }
+        /// Sends a message that should get exactly one response, and returns
+        /// it.
+        fn req(&mut self, message: Value) -> Value {
+            let mut responses = self.send(message).unwrap();
+            assert_eq!(responses.len(), 1, "{:?}", responses);
+            responses.remove(0)
+        }
+        /// Sends a message that should be refused as not making sense, and
+        /// returns the `error` it gets.
+        fn refusal(&mut self, message: Value) -> Value {
+            let typ = message["type"].clone();
+            let error = self.req(message);
+            assert_eq!(error["type"], "error", "{}", error);
+            assert_eq!(error["message_type"], typ);
+            assert!(error["reason"].is_string(), "{}", error);
+            error
+        }
+        /// Like `refusal`, but only returns the `what` of the `error`.
+        fn refused(&mut self, message: Value) -> String {
+            self.refusal(message)["what"].as_str().unwrap().to_owned()
+        }
+    }
+
+    fn packet(element: i32, mass: f32) -> Value {
+        json!({"element": element, "mass": mass, "temperature": 300.0,
+               "germs": null})
+    }
+
+    #[test]
+    fn registrations_are_offset_like_their_requests() {
+        let mut client = TestClient::with(Invocation {
+            offset: Some((1, 2, 3)),
+            ..Invocation::default()
+        });
+        for what in &["Battery", "WirelessRecver", "WirelessSender"] {
+            client.send(json!({"type": "register", "x": 0, "y": 0, "z": 0,
+                               "what": what})).unwrap();
+        }
+        let (registrations, _) = client.shared.map.read().unwrap()
+            .get_registrations_and_events();
+        let registered = |what: &str| registrations.iter()
+            .find(|(_, x)| x == what)
+            .map(|(point, _)| json!([point.get_x(), point.get_y(),
+                                     point.get_z()]))
+            .unwrap();
+        let response = client.req(json!({"type": "recv_joules",
+                                         "x": 0, "y": 0, "z": 0,
+                                         "max_joules": 5}));
+        let resolved = json!([response["resolved_x"], response["resolved_y"],
+                              response["resolved_z"]]);
+        assert_eq!(registered("Battery"), resolved);
+        assert_eq!(registered("WirelessRecver"), resolved);
+        assert_ne!(registered("WirelessSender"), resolved);
+    }
+
+    #[test]
+    fn consumers_are_offset() {
+        let mut client = TestClient::with(Invocation {
+            offset: Some((1, 2, 3)),
+            ..Invocation::default()
+        });
+        client.send(json!({"type": "send_packet", "x": 1, "y": 2, "z": 3,
+                           "phase": "Gas", "packet": packet(5, 0.5)}))
+            .unwrap();
+        let recv = |x: i64, y: i64, z: i64| json!({
+            "type": "recv_packet", "x": x, "y": y, "z": z, "phase": "Gas"});
+        assert!(client.req(recv(1, 2, 3))["packet"].is_null());
+        assert_eq!(client.req(recv(0, 0, 0))["packet"]["element"], 5);
+    }
+
+    #[test]
+    fn ping_gets_pong_with_cookie() {
+        let mut client = TestClient::new();
+        assert_eq!(client.req(json!({"type": "ping", "cookie": 7})),
+                   json!({"type": "pong", "cookie": 7}));
+    }
+
+    #[test]
+    fn ids_are_echoed_verbatim() {
+        let mut client = TestClient::new();
+        for id in [json!(null), json!(true), json!(7), json!(-1.5),
+                   json!("abc"), json!([1, "two", [3]]),
+                   json!({"seq": 4, "tags": ["a", {"b": null}]})].iter() {
+            let response = client.req(json!({"type": "ping", "id": id,
+                                             "cookie": id}));
+            assert_eq!(response.get("id"), Some(id));
+            // (the cookie stays the way it was, for old clients)
+            if id.is_object() || id.is_array() || id.is_null() {
+                assert!(response.get("cookie").is_none());
+            }
+            else { assert_eq!(&response["cookie"], id) }
+        }
+        assert!(client.req(json!({"type": "ping"})).get("id").is_none());
+        // errors carry it too, whether from a refusal or a bad message
+        let mut readonly = TestClient::with(Invocation {
+            readonly: true,
+            ..Invocation::default()
+        });
+        let error = readonly.req(json!({"type": "send_joules", "x": 0,
+                                        "y": 0, "joules": 5,
+                                        "id": {"seq": 5}}));
+        assert_eq!(error["what"], "readonly");
+        assert_eq!(error["id"], json!({"seq": 5}));
+        let error = client.refusal(json!({"type": "send_joules", "x": 0,
+                                          "id": [6, "x"]}));
+        assert_eq!(error["id"], json!([6, "x"]));
+    }
+
+    #[test]
+    fn pong_gets_nothing() {
+        let mut client = TestClient::new();
+        assert!(client.send(json!({"type": "pong"})).unwrap().is_empty());
+    }
+
+    #[test]
+    fn unknown_type_disconnects() {
+        let mut client = TestClient::new();
+        let err = client.send(json!({"type": "bogus"})).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::Other);
+    }
+
+    #[test]
+    fn too_new_is_unsupported_or_ignored() {
+        let mut client = TestClient::new();
+        client.proto_version = 2;
+        assert!(client.send(json!({"type": "query_tile", "x": 0, "y": 0}))
+                .unwrap().is_empty());
+        client.proto_version = 3;
+        assert_eq!(client.req(json!({"type": "query_tile", "x": 0,
+                                     "y": 0}))["type"], "tile_state");
+    }
+
+    #[test]
+    fn readonly_refuses_mutations() {
+        let mut client = TestClient::with(Invocation {
+            readonly: true, ..Default::default()
+        });
+        let response = client.req(json!({"type": "send_joules", "x": 0,
+                                         "y": 0, "joules": 5}));
+        assert_eq!(response["what"], "readonly");
+        assert_eq!(client.req(json!({"type": "recv_joules", "x": 0, "y": 0,
+                                     "max_joules": 5}))["type"],
+                   "got_joules");
+    }
+
+    #[test]
+    fn out_of_bounds_is_refused() {
+        let mut client = TestClient::with(Invocation {
+            max_coord: Some((10, 10, 0)), ..Default::default()
+        });
+        let response = client.req(json!({"type": "send_joules", "x": 11,
+                                         "y": 0, "joules": 5}));
+        assert_eq!(response["what"], "out_of_bounds");
+        assert_eq!(response["x"], 11);
+    }
+
+    #[test]
+    fn coordinate_bounds_are_inclusive() {
+        let mut client = TestClient::with(Invocation {
+            min_coord: Some((-10, -5, 0)), max_coord: Some((10, 10, 1)),
+            ..Default::default()
+        });
+        let joules = |x: i64, y: i64, z: i64| json!({
+            "type": "send_joules", "x": x, "y": y, "z": z, "joules": 5});
+        for &(x, y, z) in &[(-10, -5, 0), (10, 10, 1), (-10, 10, 1),
+                            (10, -5, 0)] {
+            assert_eq!(client.req(joules(x, y, z))["type"], "sent_joules",
+                       "{} {} {}", x, y, z);
+        }
+        for &(x, y, z) in &[(-11, 0, 0), (11, 0, 0), (0, -6, 0), (0, 11, 0),
+                            (0, 0, -1), (0, 0, 2),
+                            (i32::MIN as i64, i32::MIN as i64, 0),
+                            (i32::MAX as i64, 0, 0)] {
+            assert_eq!(client.req(joules(x, y, z))["what"], "out_of_bounds",
+                       "{} {} {}", x, y, z);
+        }
+        let register = |x: i64| json!({"type": "register", "x": x, "y": 0,
+                                       "what": "WirelessRecver"});
+        assert!(client.send(register(10)).unwrap().is_empty());
+        assert_eq!(client.req(register(11))["what"], "out_of_bounds");
+        let transfer = |x: i64| json!({
+            "type": "transfer", "kind": "joules", "amount": 1,
+            "from": {"x": 10, "y": 10, "z": 1}, "to": {"x": x, "y": 0}});
+        assert_eq!(client.req(transfer(-10))["type"], "transferred");
+        assert_eq!(client.req(transfer(-11))["what"], "out_of_bounds");
+        // taking things out is always allowed
+        assert_eq!(client.req(json!({"type": "recv_joules", "x": 11, "y": 0,
+                                     "max_joules": 5}))["type"],
+                   "got_joules");
+    }
+
+    #[test]
+    fn capabilities_lists_usable_types() {
+        let mut client = TestClient::with(Invocation {
+            readonly: true, ..Default::default()
+        });
+        let response = client.req(json!({"type": "capabilities"}));
+        let types = response["message_types"].as_array().unwrap();
+        assert!(types.contains(&json!("recv_joules")));
+        assert!(!types.contains(&json!("send_joules")));
+        assert!(!types.contains(&json!("mass_audit")));
+        assert_eq!(response["readonly"], true);
+    }
+
+    #[test]
+    fn joules_round_trip() {
+        let mut client = TestClient::new();
+        let response = client.req(json!({"type": "send_joules", "x": 1,
+                                         "y": 2, "joules": 12000}));
+        assert_eq!(response["type"], "sent_joules");
+        assert_eq!(response["spare"].as_f64(), Some(2000.0));
+        let response = client.req(json!({"type": "recv_joules", "x": 1,
+                                         "y": 2, "max_joules": 15000}));
+        assert_eq!(response["joules"].as_f64(), Some(10000.0));
+        assert_eq!(client.refused(json!({"type": "send_joules", "x": 1,
+                                         "y": 2, "joules": -1})),
+                   "bad_amount");
+    }
+
+    #[test]
+    fn packets_round_trip() {
+        let mut client = TestClient::new();
+        let response = client.req(json!({"type": "send_packet", "x": 0,
+                                         "y": 0, "phase": "Gas",
+                                         "packet": packet(5, 0.5)}));
+        assert_eq!(response["type"], "sent_packet");
+        assert_eq!(response["accepted"], true);
+        let response = client.req(json!({"type": "recv_packet", "x": 0,
+                                         "y": 0, "phase": "Gas"}));
+        assert_eq!(response["packet"]["element"], 5);
+        let response = client.req(json!({"type": "recv_packet", "x": 0,
+                                         "y": 0, "phase": "Gas"}));
+        assert!(response["packet"].is_null());
+    }
+
+    #[test]
+    fn packets_stay_on_their_z() {
+        let mut client = TestClient::new();
+        let response = client.req(json!({"type": "send_packet", "x": 1,
+                                         "y": 2, "z": 3, "phase": "Gas",
+                                         "packet": packet(5, 0.5)}));
+        assert_eq!(response["z"], 3);
+        let recv = |z: i64| json!({"type": "recv_packet", "x": 1, "y": 2,
+                                   "z": z, "phase": "Gas"});
+        assert!(client.req(recv(0))["packet"].is_null());
+        assert_eq!(client.req(recv(3))["packet"]["element"], 5);
+        // no z at all is z 0
+        client.send(json!({"type": "send_packet", "x": 1, "y": 2,
+                           "phase": "Gas", "packet": packet(6, 0.5)}))
+            .unwrap();
+        assert!(client.req(recv(3))["packet"].is_null());
+        assert_eq!(client.req(recv(0))["packet"]["element"], 6);
+    }
+
+    #[test]
+    fn nonsense_packets_are_refused() {
+        let mut client = TestClient::new();
+        let send = |packet: Value| json!({"type": "send_packet", "x": 0,
+                                          "y": 0, "phase": "Gas",
+                                          "packet": packet});
+        let bad = [
+            json!({"element": 5, "mass": -0.5, "temperature": 300.0,
+                   "germs": null}),
+            // (too big for an `f32`, so it comes out infinite)
+            json!({"element": 5, "mass": 0.5, "temperature": 1e39,
+                   "germs": null}),
+            json!({"element": 5, "mass": 0.5, "temperature": -1.0,
+                   "germs": null}),
+            json!({"element": 5, "mass": 0.5, "temperature": 300.0,
+                   "germs": {"id": 1, "count": 0}}),
+        ];
+        for packet in bad.iter() {
+            let error = client.refusal(send(packet.clone()));
+            assert_eq!(error["what"], "bad_message");
+            assert!(error["reason"].as_str().unwrap().contains("nonsensical"),
+                    "{}", packet);
+        }
+        // too much mass is only a refusal
+        let response = client.req(send(packet(5, 2.0)));
+        assert_eq!(response["accepted"], false);
+        assert_eq!(response["reason"], "too_large");
+        assert!(client.req(json!({"type": "recv_packet", "x": 0, "y": 0,
+                                  "phase": "Gas"}))["packet"].is_null());
+    }
+
+    #[test]
+    fn stack_sizes_are_configurable() {
+        let mut invocation = Invocation::default();
+        invocation.map_limits.phase_limits.liquid_stack = 50.0;
+        let mut client = TestClient::with(invocation);
+        let send = |mass: f32| json!({"type": "send_packet", "x": 0, "y": 0,
+                                      "phase": "Liquid",
+                                      "packet": packet(5, mass)});
+        assert_eq!(client.req(send(30.0))["accepted"], true);
+        assert_eq!(client.req(send(60.0))["reason"], "too_large");
+        // tops up the first to 50, and the rest is a packet of its own
+        assert_eq!(client.req(send(30.0))["accepted"], true);
+        let recv = json!({"type": "recv_packet", "x": 0, "y": 0,
+                          "phase": "Liquid"});
+        assert_eq!(client.req(recv.clone())["packet"]["mass"], 50.0);
+        assert_eq!(client.req(recv)["packet"]["mass"], 10.0);
+    }
+
+    #[test]
+    fn objects_round_trip() {
+        let mut client = TestClient::new();
+        let object = base64::encode(b"an object");
+        let response = client.req(json!({"type": "send_object", "x": 0,
+                                         "y": 0, "object": object}));
+        assert_eq!(response["accepted"], true);
+        let response = client.req(json!({"type": "recv_object", "x": 0,
+                                         "y": 0}));
+        assert_eq!(response["object"], object);
+        let big = base64::encode(vec![0; MAX_OBJECT_SIZE + 1]);
+        let response = client.req(json!({"type": "send_object", "x": 0,
+                                         "y": 0, "object": big}));
+        assert_eq!(response["accepted"], false);
+        assert_eq!(response["reason"], "too_large");
+    }
+
+    /// Sends an object in pieces of `chunk` bytes, and returns the
+    /// `sent_object`.
+    fn send_in_pieces(client: &mut TestClient, object: &[u8], chunk: usize)
+                      -> Value {
+        let response = client.req(json!({"type": "begin_object", "x": 1,
+                                         "y": 2, "z": 3, "transfer": 9,
+                                         "length": object.len()}));
+        assert_eq!(response["type"], "object_begun");
+        for piece in object.chunks(chunk) {
+            let response = client.req(json!({
+                "type": "object_chunk", "transfer": 9,
+                "data": base64::encode(piece),
+            }));
+            assert_eq!(response["type"], "object_chunk_ok");
+        }
+        client.req(json!({"type": "end_object", "transfer": 9}))
+    }
+
+    /// Receives an object in pieces, and returns it.
+    fn recv_in_pieces(client: &mut TestClient) -> Option<Vec<u8>> {
+        let response = client.req(json!({"type": "begin_recv_object",
+                                         "x": 1, "y": 2, "z": 3,
+                                         "transfer": 5}));
+        assert_eq!(response["type"], "recv_object_begun");
+        let length = response["length"].as_u64()? as usize;
+        let mut object = Vec::new();
+        loop {
+            let response = client.req(json!({"type": "recv_object_chunk",
+                                             "transfer": 5}));
+            assert_eq!(response["offset"].as_u64(), Some(object.len() as u64));
+            let data = base64::decode(response["data"].as_str().unwrap())
+                .unwrap();
+            assert!(data.len() <= OBJECT_CHUNK_SIZE);
+            object.extend(data);
+            if response["done"] == true { break }
+        }
+        assert_eq!(object.len(), length);
+        Some(object)
+    }
+
+    #[test]
+    fn chunked_objects_round_trip() {
+        let mut client = TestClient::new();
+        let object: Vec<u8> = (0 .. MAX_OBJECT_SIZE_LIMIT * 3)
+            .map(|x| x as u8).collect();
+        let response = send_in_pieces(&mut client, &object, 3000);
+        assert_eq!(response, json!({"type": "sent_object", "x": 1, "y": 2,
+                                    "z": 3, "transfer": 9,
+                                    "accepted": true}));
+        assert_eq!(recv_in_pieces(&mut client), Some(object));
+        assert_eq!(recv_in_pieces(&mut client), None);
+        assert!(client.transfers.is_empty());
+    }
+
+    #[test]
+    fn chunked_objects_have_their_own_limit() {
+        let mut client = TestClient::with(Invocation {
+            map_limits: MapLimits { max_chunked_object_size: 10,
+                                    ..Default::default() },
+            ..Default::default()
+        });
+        assert_eq!(send_in_pieces(&mut client, &[1; 10], 4)["accepted"],
+                   true);
+        assert_eq!(client.refused(json!({"type": "begin_object", "x": 0,
+                                         "y": 0, "transfer": 1,
+                                         "length": 11})),
+                   "object_too_large");
+        // (whole objects are still held to max_object_size)
+        let response = client.req(json!({
+            "type": "send_object", "x": 0, "y": 0,
+            "object": base64::encode(vec![0; 100]),
+        }));
+        assert_eq!(response["accepted"], true);
+    }
+
+    #[test]
+    fn chunked_object_mistakes() {
+        let mut client = TestClient::new();
+        let begin = json!({"type": "begin_object", "x": 0, "y": 0,
+                           "transfer": 1, "length": 4});
+        client.req(begin.clone());
+        assert_eq!(client.refused(begin), "transfer_in_use");
+        assert_eq!(client.refused(json!({"type": "recv_object_chunk",
+                                         "transfer": 1})),
+                   "unknown_transfer");
+        assert_eq!(client.refused(json!({"type": "object_chunk",
+                                         "transfer": 1,
+                                         "data": base64::encode(b"12345")})),
+                   "object_too_large");
+        // (going past the length throws the transfer away)
+        assert_eq!(client.refused(json!({"type": "end_object",
+                                         "transfer": 1})),
+                   "unknown_transfer");
+        client.req(json!({"type": "begin_object", "x": 0, "y": 0,
+                          "transfer": 1, "length": 4}));
+        client.req(json!({"type": "object_chunk", "transfer": 1,
+                          "data": base64::encode(b"12")}));
+        assert_eq!(client.refused(json!({"type": "end_object",
+                                         "transfer": 1})),
+                   "transfer_incomplete");
+        for id in 0 .. MAX_OBJECT_TRANSFERS as u64 {
+            client.req(json!({"type": "begin_object", "x": 0, "y": 0,
+                              "transfer": id, "length": 4}));
+        }
+        assert_eq!(client.refused(json!({"type": "begin_recv_object",
+                                         "x": 0, "y": 0, "transfer": 99})),
+                   "too_many_transfers");
+    }
+
+    #[test]
+    fn query_tile_leaves_things_alone() {
+        let mut client = TestClient::new();
+        client.req(json!({"type": "send_joules", "x": 3, "y": 4,
+                          "joules": 50}));
+        for _ in 0 .. 2 {
+            let response = client.req(json!({"type": "query_tile", "x": 3,
+                                             "y": 4}));
+            assert_eq!(response["joules"].as_f64(), Some(50.0));
+        }
+    }
+
+    #[test]
+    fn query_region_finds_occupied_tiles() {
+        let mut client = TestClient::new();
+        client.req(json!({"type": "send_joules", "x": 3, "y": 4,
+                          "joules": 50}));
+        client.req(json!({"type": "send_joules", "x": 30, "y": 4,
+                          "joules": 50}));
+        let responses = client.send(json!({
+            "type": "query_region", "min_x": 0, "min_y": 0, "max_x": 10,
+            "max_y": 10,
+        })).unwrap();
+        let tiles: usize = responses.iter()
+            .map(|x| x["tiles"].as_array().map(Vec::len)
+                 .or_else(|| x["tiles"].as_object().map(|x| x.len()))
+                 .unwrap_or(0))
+            .sum();
+        assert_eq!(tiles, 1);
+        let response = client.req(json!({
+            "type": "query_region", "min_x": 0, "min_y": 0,
+            "max_x": 100000, "max_y": 100000,
+        }));
+        assert_eq!(response["what"], "region_too_big");
+    }
+
+    #[test]
+    fn dump_map_ends_with_done() {
+        let mut client = TestClient::new();
+        for x in 0 .. 3 {
+            client.req(json!({"type": "send_joules", "x": x, "y": 0,
+                              "joules": 1}));
+        }
+        let responses = client.send(json!({"type": "dump_map"})).unwrap();
+        let last = responses.last().unwrap();
+        assert_eq!(last["done"], true);
+        let tiles: usize = responses.iter()
+            .map(|x| x["tiles"].as_object().unwrap().len()).sum();
+        assert_eq!(tiles, 3);
+    }
+
+    #[test]
+    fn subscriptions_add_up() {
+        let mut client = TestClient::new();
+        let response = client.req(json!({
+            "type": "subscribe", "points": [{"x": 0, "y": 0},
+                                            {"x": 1, "y": 0}],
+        }));
+        assert_eq!(response["count"], 2);
+        let response = client.req(json!({
+            "type": "unsubscribe", "points": [{"x": 0, "y": 0}],
+        }));
+        assert_eq!(response["count"], 1);
+        let response = client.req(json!({"type": "unsubscribe"}));
+        assert_eq!(response["count"], 0);
+        let too_many: Vec<Value> = (0 .. MAX_SUBSCRIPTIONS as i32 + 1)
+            .map(|x| json!({"x": x, "y": 0})).collect();
+        client.refused(json!({"type": "subscribe", "points": too_many}));
+    }
+
+    #[test]
+    fn clear_tile_empties_it() {
+        let mut client = TestClient::new();
+        client.req(json!({"type": "send_joules", "x": 0, "y": 0,
+                          "joules": 50}));
+        assert_eq!(client.req(json!({"type": "clear_tile", "x": 0,
+                                     "y": 0}))["type"], "cleared_tile");
+        let response = client.req(json!({"type": "query_tile", "x": 0,
+                                         "y": 0}));
+        assert_eq!(response["joules"].as_f64(), Some(0.0));
+    }
+
+    #[test]
+    fn reset_map_is_admin_only() {
+        let mut client = TestClient::new();
+        client.req(json!({"type": "send_joules", "x": 0, "y": 0,
+                          "joules": 50}));
+        let response = client.req(json!({"type": "reset_map"}));
+        if cfg!(feature = "auth") {
+            // (no authentication configured, so nobody is an admin)
+            assert_eq!(response["what"], "auth_required");
+            return
+        }
+        assert_eq!(response["type"], "map_reset");
+        assert_eq!(client.shared.map.read().unwrap().occupied_tile_count(),
+                   0);
+    }
+
+    #[test]
+    fn save_now_needs_a_save_file() {
+        let mut client = TestClient::new();
+        let response = client.req(json!({"type": "save_now"}));
+        if cfg!(feature = "auth") {
+            assert_eq!(response["what"], "auth_required");
+        }
+        else {
+            assert_eq!(response["what"], "saving_disabled");
+        }
+    }
+
+    #[test]
+    fn mass_audit_needs_the_option() {
+        let mut client = TestClient::new();
+        assert_eq!(client.req(json!({"type": "mass_audit"}))["what"],
+                   "mass_audit_disabled");
+        let mut client = TestClient::with(Invocation {
+            map_limits: MapLimits { mass_audit: true, ..Default::default() },
+            ..Default::default()
+        });
+        assert_eq!(client.req(json!({"type": "mass_audit"}))["type"],
+                   "mass_audit");
+    }
+
+    #[test]
+    fn kick_finds_nobody() {
+        let mut client = TestClient::new();
+        let response = client.req(json!({"type": "kick", "client_id": 99}));
+        if cfg!(feature = "auth") {
+            assert_eq!(response["what"], "auth_required");
+        }
+        else {
+            assert_eq!(response["type"], "kicked");
+            assert_eq!(response["found"], false);
+        }
+    }
+
+    #[test]
+    fn transfer_moves_things() {
+        let mut client = TestClient::new();
+        client.req(json!({"type": "send_joules", "x": 0, "y": 0,
+                          "joules": 50}));
+        let response = client.req(json!({
+            "type": "transfer", "kind": "joules", "amount": 30,
+            "from": {"x": 0, "y": 0}, "to": {"x": 1, "y": 0},
+        }));
+        assert_eq!(response["type"], "transferred");
+        assert_eq!(response["moved"].as_f64(), Some(30.0));
+        assert_eq!(client.refused(json!({
+            "type": "transfer", "kind": "nonsense",
+            "from": {"x": 0, "y": 0}, "to": {"x": 1, "y": 0},
+        })), "bad_message");
+    }
+
+    #[test]
+    fn bulk_send_is_all_or_nothing() {
+        let mut client = TestClient::new();
+        let response = client.req(json!({"type": "bulk_send", "ops": [
+            {"type": "send_joules", "x": 0, "y": 0, "joules": 5},
+            {"type": "send_object", "x": 0, "y": 0,
+             "object": base64::encode(b"thing")},
+        ]}));
+        assert_eq!(response["type"], "bulk_sent");
+        assert_eq!(response["results"][1]["accepted"], true);
+        let response = client.req(json!({"type": "bulk_send", "ops": [
+            {"type": "send_joules", "x": 5, "y": 5, "joules": 5},
+            {"type": "nonsense"},
+        ]}));
+        assert_eq!(response["what"], "bulk_send_rejected");
+        assert_eq!(client.req(json!({"type": "query_tile", "x": 5,
+                                     "y": 5}))["joules"].as_f64(),
+                   Some(0.0));
+    }
+
+    #[test]
+    fn registrations_come_and_go() {
+        let mut client = TestClient::new();
+        let register = json!({"type": "register", "x": 0, "y": 0,
+                              "what": "WirelessRecver"});
+        for _ in 0 .. MAX_REGISTRATIONS {
+            assert!(client.send(register.clone()).unwrap().is_empty());
+        }
+        assert_eq!(client.req(register.clone())["what"],
+                   "too_many_registrations");
+        assert!(client.send(json!({"type": "unregister", "x": 0, "y": 0,
+                                   "what": "WirelessRecver"}))
+                .unwrap().is_empty());
+        assert!(client.send(register).unwrap().is_empty());
+    }
+
+    #[test]
+    fn registration_refusals_say_which_limit() {
+        let mut invocation = Invocation::default();
+        invocation.map_limits.max_total_registrations = Some(1);
+        let mut client = TestClient::with(invocation);
+        client.shared.map.read().unwrap()
+            .register(Point::new(0, 0, 0), 2, None, "WirelessRecver".into())
+            .unwrap();
+        let register = json!({"type": "register", "x": 0, "y": 0,
+                              "what": "WirelessRecver"});
+        let response = client.req(register.clone());
+        assert_eq!(response["what"], "too_many_registrations");
+        assert_eq!(response["reason"], "too_many_total");
+        let mut client = TestClient::new();
+        for _ in 0 .. MAX_REGISTRATIONS {
+            client.send(register.clone()).unwrap();
+        }
+        let response = client.req(register);
+        assert_eq!(response["what"], "too_many_registrations");
+        assert_eq!(response["reason"], "too_many_for_client");
+    }
+
+    #[test]
+    fn move_registration_needs_something_to_move() {
+        let mut client = TestClient::new();
+        let move_it = json!({"type": "move_registration",
+                             "from": {"x": 0, "y": 0},
+                             "to": {"x": 1, "y": 0},
+                             "what": "WirelessRecver"});
+        assert_eq!(client.req(move_it.clone())["what"], "not_registered");
+        client.send(json!({"type": "register", "x": 0, "y": 0,
+                           "what": "WirelessRecver"})).unwrap();
+        assert!(client.send(move_it.clone()).unwrap().is_empty());
+        assert_eq!(client.req(move_it)["what"], "not_registered");
+    }
+
+    #[test]
+    fn max_string_bytes_is_one_encoded_object() {
+        assert_eq!(MAX_STRING_BYTES, 5464);
+        assert_eq!(max_encoded_size(MAX_OBJECT_SIZE), Some(MAX_STRING_BYTES));
+    }
+
+    #[test]
+    fn strings_are_limited_per_field() {
+        let mut client = TestClient::new();
+        let register = |len| json!({"type": "register", "x": 0, "y": 0,
+                                    "what": "R".repeat(len)});
+        assert!(client.send(register(MAX_STRING_BYTES)).unwrap().is_empty());
+        assert_eq!(client.refused(register(MAX_STRING_BYTES + 1)),
+                   "string_too_long");
+        // (it's bytes that count, not characters)
+        let what = "é".repeat(MAX_STRING_BYTES / 2 + 1);
+        assert_eq!(client.refused(json!({"type": "register", "x": 0, "y": 0,
+                                         "what": what})),
+                   "string_too_long");
+    }
+
+    #[tokio::test]
+    async fn connecting_stops_for_shutdown() {
+        let (shutdown, mut rx) = broadcast::channel(1);
+        assert_eq!(connecting_step(&mut rx, "nothing", async { 5 }).await,
+                   Ok(5));
+        shutdown.send(()).unwrap();
+        let forever = futures::future::pending::<()>();
+        assert_eq!(connecting_step(&mut rx, "handshake", forever).await,
+                   Err("server shut down while waiting for handshake"
+                       .to_owned()));
+    }
+
+    #[test]
+    fn nesting_is_limited_per_message() {
+        use codec::Decoder;
+        let (tx, _log) = mpsc::unbounded_channel();
+        let mut coder = MessageCoder {
+            verbosity: 0, log_json: false, peer: String::new(),
+            out: Outputter::channel(tx), stats: Arc::new(ClientStats::new()),
+            max_message_bytes: 1 << 20,
+        };
+        // the message itself is one level
+        let nested = |depth: usize| format!(
+            "{{\"type\":\"ping\",\"cookie\":{}0{}}}\n",
+            "[".repeat(depth - 1), "]".repeat(depth - 1));
+        let mut src = BytesMut::new();
+        src.extend_from_slice(nested(MAX_MESSAGE_DEPTH).as_bytes());
+        src.extend_from_slice(nested(MAX_MESSAGE_DEPTH + 1).as_bytes());
+        src.extend_from_slice(nested(100000).as_bytes());
+        // brackets in strings don't count
+        let quoted = json!({"type": "ping", "cookie": "[".repeat(100)});
+        src.extend_from_slice(format!("{}\n", quoted).as_bytes());
+        assert!(coder.decode(&mut src).unwrap().is_some());
+        for _ in 0 .. 2 {
+            let err = coder.decode(&mut src).unwrap_err();
+            assert!(is_nested_too_deep(&err));
+        }
+        // and the ones after are still read
+        let last = coder.decode(&mut src).unwrap().unwrap();
+        assert_eq!(last["cookie"].as_str().unwrap().len(), 100);
+        assert!(src.is_empty());
+    }
 }
@@ -18,113 +18,962 @@
  */
 
 use std::{
-    collections::hash_map::{HashMap,Entry},
+    collections::{HashSet, VecDeque, hash_map::{HashMap,Entry,DefaultHasher}},
+    fmt::{Display, Formatter},
     fs::File,
+    hash::{Hash,Hasher},
+    io::{BufRead, BufReader, BufWriter, Read, Write},
+    sync::{Arc,Mutex,MutexGuard,atomic::{AtomicBool,AtomicU64,AtomicUsize,
+                                         Ordering}},
+    time::Duration,
+};
+use flate2::{Compression, bufread::GzDecoder, write::GzEncoder};
+use tokio::{
+    sync::mpsc::{self, error::TrySendError},
+    time::Instant,
 };
-use tokio::sync::mpsc;
 use std::io::Result as IoResult;
 
 use crate::*;
+use crate::savefile::*;
+
+/// An amount of energy, in joules.
+///
+/// Normally this is a whole number of joules, which is what ZTransport clients
+/// send and expect to receive. With the `float_energy` feature, fractional
+/// joules are stored and transmitted instead, so a client computing fractional
+/// wattages doesn't lose the fraction on every transfer. The catch is that the
+/// protocol then carries floating point numbers where older clients expect
+/// integers, and a map saved with fractional energy will have its fractions
+/// discarded when loaded by a server built without the feature.
+#[cfg(not(feature = "float_energy"))]
+pub type Joules = u32;
+/// An amount of energy, in joules. (See above.)
+#[cfg(feature = "float_energy")]
+pub type Joules = f64;
 
-/// Maximum amount of energy, in joules, that can be stored in one point on the
-/// map. This will limit the maximum transmission rate of energy, related to
+/// Default maximum amount of energy, in joules, that can be stored in one point
+/// on the map. This will limit the maximum transmission rate of energy, related to
 /// ping. ONI's energy processing happens 5 times per game second, so the
 /// maximum transmission rate in watts is five times this amount.
 pub const MAX_STORED_ENERGY: u32 = 10000;
-/// Maximum number of "packets" that can be stored in one point on the map.
+/// The most points energy will spill through (see `MapLimits::energy_spill_to`)
+/// after the one it was sent to. Whatever hasn't found room by then is handed
+/// back to the sender as usual. This also stops an offset that wraps all the
+/// way around the map from going around forever.
+pub const MAX_SPILL_STEPS: usize = 16;
+/// Default maximum number of "packets" that can be stored in one point on the
+/// map.
 /// This will limit the maximum transmission rate of materials, related to
 /// ping. Similar packets will be merged, so as long as mixed pipes aren't in
 /// use, things should be okay.
 pub const MAX_STORED_PACKETS: usize = 10; // probably too high
-/// Maximum number of registrations allowed with the same `ClientID` at one
-/// point on the map.
+/// Default maximum number of registrations allowed with the same `ClientID` at
+/// one point on the map.
 pub const MAX_REGISTRATIONS: usize = 7;
-/// Maximum number of opaque objects that can be stored in one point on the
-/// map. This will limit the maximum transmission rate of solid objects,
+/// Default maximum number of opaque objects that can be stored in one point on
+/// the map. This will limit the maximum transmission rate of solid objects,
 /// related to ping. Unlike energy and packets, we can't combine "stackable"
-/// objects. Hopefully that doesn't end up being much of a problem.
+/// objects in general. Objects that are identical byte-for-byte can be stacked
+/// into one slot if `MapLimits::stack_objects` is set.
+///
+/// Hopefully that doesn't end up being much of a problem.
 pub const MAX_STORED_OBJECTS: usize = 3;
 
-struct RegSender {
-    vec: Vec<mpsc::UnboundedSender<(bool, Point, String)>>
+/// The storage limits for a particular `Map`. The defaults are the constants
+/// above.
+#[derive(Debug,Clone)]
+pub struct MapLimits {
+    /// See `MAX_STORED_ENERGY`.
+    pub max_stored_energy: u32,
+    /// See `MAX_STORED_PACKETS`.
+    pub max_stored_packets: usize,
+    /// See `MAX_REGISTRATIONS`.
+    pub max_registrations: usize,
+    /// See `MAX_STORED_OBJECTS`.
+    pub max_stored_objects: usize,
+    /// See `MAX_OBJECT_SIZE`. Never more than `MAX_OBJECT_SIZE_LIMIT`.
+    pub max_object_size: usize,
+    /// The most bytes an object sent in pieces can be. See
+    /// `DEFAULT_MAX_CHUNKED_OBJECT_SIZE`. Never more than
+    /// `MAX_CHUNKED_OBJECT_SIZE_LIMIT`.
+    pub max_chunked_object_size: usize,
+    /// Maximum number of opaque objects stored across all points. `None`
+    /// means unlimited.
+    pub max_total_objects: Option<usize>,
+    /// Maximum number of bytes of opaque objects stored across all points.
+    /// `None` means unlimited.
+    pub max_total_object_bytes: Option<usize>,
+    /// Maximum number of distinct points that can have something stored at
+    /// them. Once reached, only already-occupied points can accept more.
+    /// `None` means unlimited.
+    pub max_tiles: Option<usize>,
+    /// Maximum number of registrations at one point, from all clients
+    /// together (on top of `max_registrations` for each client). `None`
+    /// means unlimited.
+    pub max_total_registrations: Option<usize>,
+    /// If `true`, an object identical to one already stored at a point joins
+    /// that object's slot instead of taking up a new one. Clients that expect
+    /// every object to take its own slot won't want this.
+    pub stack_objects: bool,
+    /// How much mass a gas or liquid packet can have.
+    pub phase_limits: PhaseLimits,
+    /// If `true`, keep count of how much packet mass goes into and out of
+    /// the map, for `Map::mass_audit`.
+    pub mass_audit: bool,
+    /// If given, energy that doesn't fit at a point spills into the point this
+    /// far away from it, and from there into the next, and so on, up to
+    /// `MAX_SPILL_STEPS` times. `None` means spare energy is just handed back.
+    pub energy_spill_to: Option<(i32, i32, i32)>,
+}
+
+impl Default for MapLimits {
+    fn default() -> MapLimits {
+        MapLimits {
+            max_stored_energy: MAX_STORED_ENERGY,
+            max_stored_packets: MAX_STORED_PACKETS,
+            max_registrations: MAX_REGISTRATIONS,
+            max_stored_objects: MAX_STORED_OBJECTS,
+            max_object_size: MAX_OBJECT_SIZE,
+            max_chunked_object_size: DEFAULT_MAX_CHUNKED_OBJECT_SIZE,
+            max_total_objects: None,
+            max_total_object_bytes: None,
+            max_tiles: None,
+            max_total_registrations: None,
+            stack_objects: false,
+            phase_limits: PhaseLimits::default(),
+            mass_audit: false,
+            energy_spill_to: None,
+        }
+    }
+}
+
+impl MapLimits {
+    /// Returns the biggest an object stored on the map can be, whether it was
+    /// sent whole or in pieces.
+    pub fn max_stored_object_size(&self) -> usize {
+        self.max_object_size.max(self.max_chunked_object_size)
+    }
+}
+
+/// Why `Map::add_packet` couldn't store (all of) a packet.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum PacketRefusal {
+    /// The map already has things stored at as many points as it's allowed.
+    MapFull,
+    /// The point already has as many packets queued as it's allowed, and
+    /// none of them could take (all of) this one.
+    QueueFull,
+}
+
+impl PacketRefusal {
+    /// How we tell clients about it, in a `reason` field.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PacketRefusal::MapFull => "map_full",
+            PacketRefusal::QueueFull => "queue_full",
+        }
+    }
+}
+
+/// Why `Map::register` (or `Map::move_registration`) refused a
+/// registration.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum RegistrationRefusal {
+    /// The client already has `max_registrations` buildings at the point.
+    TooManyForClient,
+    /// The point already has `max_total_registrations` buildings, from all
+    /// clients together.
+    TooManyTotal,
+    /// The client has no such building at the point it's moving it from.
+    NotRegistered,
+}
+
+impl RegistrationRefusal {
+    /// How we tell clients about it, in a `reason` field.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RegistrationRefusal::TooManyForClient => "too_many_for_client",
+            RegistrationRefusal::TooManyTotal => "too_many_total",
+            RegistrationRefusal::NotRegistered => "not_registered",
+        }
+    }
+}
+
+/// How much mass of one phase has gone into and out of the map, as counted
+/// when `MapLimits::mass_audit` is set.
+#[derive(Debug,Clone,Copy,Default,Serialize)]
+pub struct MassTotals {
+    pub added: f64,
+    pub removed: f64,
+}
+
+/// What `Map::mass_audit` found for one phase.
+#[derive(Debug,Clone,Copy,Serialize)]
+pub struct PhaseAudit {
+    pub added: f64,
+    pub removed: f64,
+    pub stored: f64,
+    /// `added - removed - stored`, which should be zero (give or take
+    /// rounding). Positive means mass went missing, negative means it came
+    /// from nowhere.
+    pub unaccounted: f64,
+}
+
+impl PhaseAudit {
+    /// Returns `true` if more mass is unaccounted for than rounding could
+    /// explain.
+    pub fn is_leaking(&self) -> bool {
+        self.unaccounted.abs()
+            > (self.added * MASS_AUDIT_TOLERANCE).max(MASS_AUDIT_TOLERANCE)
+    }
+}
+
+/// What `Map::mass_audit` found.
+#[derive(Debug,Clone,Copy,Serialize)]
+pub struct MassAudit {
+    pub gas: PhaseAudit,
+    pub liquid: PhaseAudit,
+}
+
+/// A snapshot of everything stored at one point on the map, as returned by
+/// `Map::peek_tile`.
+#[derive(Debug,Clone,Serialize)]
+pub struct TileState {
+    pub joules: Joules,
+    pub gas_packets: Vec<MatPacket>,
+    pub liquid_packets: Vec<MatPacket>,
+    pub object_count: usize,
+}
+
+/// Counts of what `Map::try_load` had to leave out of a saved map, because it
+/// didn't make sense.
+#[derive(Debug,Clone,Default)]
+pub struct LoadReport {
+    /// Tiles whose coordinates couldn't be parsed.
+    pub bad_points: usize,
+    /// Tiles that weren't JSON objects.
+    pub bad_tiles: usize,
+    /// Amounts of energy that weren't valid.
+    pub bad_energy: usize,
+    /// Packets that couldn't be parsed, or that failed `MatPacket::validate`.
+    pub bad_packets: usize,
+    /// Objects that weren't Base64 strings.
+    pub bad_objects: usize,
+    /// Objects bigger than `MapLimits::max_stored_object_size`.
+    pub oversized_objects: usize,
+    /// Registrations without a string identity and building.
+    pub bad_registrations: usize,
 }
 
-impl RegSender {
-    pub fn new() -> RegSender { RegSender { vec: Vec::new() } }
-    pub fn send(&mut self, was: (bool, Point, &str)) {
+impl LoadReport {
+    /// Returns the total number of things that were left out.
+    pub fn skipped(&self) -> usize {
+        self.bad_points + self.bad_tiles + self.bad_energy + self.bad_packets
+            + self.bad_objects + self.oversized_objects
+            + self.bad_registrations
+    }
+}
+
+impl Display for LoadReport {
+    fn fmt(&self, fmt: &mut Formatter) -> std::fmt::Result {
+        let counts = [
+            (self.bad_points, "tiles with bad coordinates"),
+            (self.bad_tiles, "malformed tiles"),
+            (self.bad_energy, "bad amounts of energy"),
+            (self.bad_packets, "bad packets"),
+            (self.bad_objects, "bad objects"),
+            (self.oversized_objects, "oversized objects"),
+            (self.bad_registrations, "bad registrations"),
+        ];
+        let mut first = true;
+        for (count, what) in counts.iter().filter(|(count, _)| *count > 0) {
+            if !first { fmt.write_str(", ")?; }
+            write!(fmt, "{} {}", count, what)?;
+            first = false;
+        }
+        Ok(())
+    }
+}
+
+/// Something that happened on the map, as reported to the receivers returned
+/// by `Map::get_events`.
+#[derive(Debug,Clone)]
+pub enum MapEvent {
+    /// A building was registered at a point.
+    Registered(Point, String),
+    /// A building was unregistered from a point.
+    Unregistered(Point, String),
+    /// A building was moved from the first point to the second.
+    Moved(Point, Point, String),
+    /// The energy, packets, or objects stored at a point changed.
+    TileChanged(Point),
+    /// Not something that happened on the map, but to this receiver: it fell
+    /// too far behind, and missed some registration events. It will keep
+    /// getting this until it's caught up with `Map::resync_events`.
+    Overflowed,
+}
+
+/// Bookkeeping shared between one receiver and the `EventSender`.
+#[derive(Default)]
+struct QueueState {
+    /// `TileChanged` events sent, but not yet received.
+    queued_tile_changes: AtomicUsize,
+    /// `TileChanged` events dropped because too many were queued.
+    dropped_tile_changes: AtomicUsize,
+    /// Registration events have been dropped because the queue was full.
+    /// None will be sent until `Map::resync_events` clears this.
+    needs_resync: AtomicBool,
+    /// The tiles that this receiver gets `TileChanged` events for, as boxes
+    /// (inclusive). See `EventReceiver::watch`.
+    watched: Mutex<Vec<(Point, Point)>>,
+}
+
+impl QueueState {
+    fn is_watching(&self, loc: Point) -> bool {
+        self.watched.lock().unwrap().iter()
+            .any(|&(min, max)| loc.is_within(min, max))
+    }
+}
+
+struct EventSender {
+    vec: Vec<(mpsc::Sender<MapEvent>, Arc<QueueState>)>
+}
+
+impl EventSender {
+    pub fn new() -> EventSender { EventSender { vec: Vec::new() } }
+    pub fn send(&mut self, event: MapEvent) {
+        let tile_change = match event {
+            MapEvent::TileChanged(loc) => Some(loc),
+            _ => None,
+        };
+        let is_tile_change = tile_change.is_some();
         for i in (0..self.vec.len()).rev() {
-            match self.vec[i].send((was.0, was.1, was.2.to_owned())) {
+            let (tx, state) = &mut self.vec[i];
+            if let Some(loc) = tile_change {
+                // (nobody needs to hear about tiles they aren't watching)
+                if !state.is_watching(loc) { continue }
+                if state.queued_tile_changes.load(Ordering::Relaxed)
+                    >= MAX_QUEUED_TILE_CHANGES {
+                    state.dropped_tile_changes.fetch_add(1, Ordering::Relaxed);
+                    continue
+                }
+                state.queued_tile_changes.fetch_add(1, Ordering::Relaxed);
+            }
+            else if state.needs_resync.load(Ordering::Relaxed) {
+                // (it'll hear about this in the resync anyway)
+                continue
+            }
+            match tx.try_send(event.clone()) {
                 Ok(_) => (),
-                Err(_) => { self.vec.remove(i); },
+                // the receiver is still there, just too far behind
+                Err(TrySendError::Full(_)) => {
+                    if is_tile_change {
+                        state.queued_tile_changes
+                            .fetch_sub(1, Ordering::Relaxed);
+                        state.dropped_tile_changes
+                            .fetch_add(1, Ordering::Relaxed);
+                    }
+                    else {
+                        state.needs_resync.store(true, Ordering::Relaxed);
+                    }
+                },
+                // the receiver is gone (its client disconnected)
+                Err(TrySendError::Closed(_)) => { self.vec.remove(i); },
             }
         }
     }
-    pub fn push(&mut self, was: mpsc::UnboundedSender<(bool, Point, String)>) {
-        self.vec.push(was)
+    fn push(&mut self, was: mpsc::Sender<MapEvent>,
+            state: Arc<QueueState>) {
+        self.vec.push((was, state))
+    }
+}
+
+/// The receiving end of `Map::get_events`.
+pub struct EventReceiver {
+    /// Events to deliver before any from `rx`: the registrations that were
+    /// already there when this receiver was made, and any tile changes that
+    /// were set aside during a resync.
+    backlog: VecDeque<MapEvent>,
+    rx: mpsc::Receiver<MapEvent>,
+    state: Arc<QueueState>,
+}
+
+impl EventReceiver {
+    fn received(&self, event: Option<MapEvent>) -> Option<MapEvent> {
+        if let Some(MapEvent::TileChanged(_)) = event {
+            self.state.queued_tile_changes.fetch_sub(1, Ordering::Relaxed);
+        }
+        event
+    }
+    /// Waits for the next event. Returns `None` if the map is gone.
+    pub async fn recv(&mut self) -> Option<MapEvent> {
+        if self.state.needs_resync.load(Ordering::Relaxed) {
+            return Some(MapEvent::Overflowed)
+        }
+        let event = match self.backlog.pop_front() {
+            Some(x) => Some(x),
+            None => self.rx.recv().await,
+        };
+        self.received(event)
+    }
+    /// Returns the next event, if one is already waiting.
+    pub fn try_recv(&mut self) -> Option<MapEvent> {
+        if self.state.needs_resync.load(Ordering::Relaxed) {
+            return Some(MapEvent::Overflowed)
+        }
+        let event = match self.backlog.pop_front() {
+            Some(x) => Some(x),
+            None => self.rx.try_recv().ok(),
+        };
+        self.received(event)
+    }
+    /// Sets which tiles this receiver gets `TileChanged` events for, as boxes
+    /// between two corners (inclusive). A receiver starts out watching none.
+    /// Events that were already queued are still delivered.
+    pub fn watch(&self, tiles: Vec<(Point, Point)>) {
+        *self.state.watched.lock().unwrap() = tiles;
+    }
+    /// Returns how many `TileChanged` events were dropped since the last call,
+    /// because this receiver had fallen too far behind.
+    pub fn take_dropped(&self) -> usize {
+        self.state.dropped_tile_changes.swap(0, Ordering::Relaxed)
+    }
+}
+
+/// The most `TileChanged` events that may be waiting for one receiver. A
+/// receiver that falls further behind than this (because its client is
+/// reading slowly) misses the changes that don't fit, instead of the queue
+/// growing without bound.
+pub const MAX_QUEUED_TILE_CHANGES: usize = 4096;
+/// Room for at least this many registration events is left in each
+/// receiver's queue, on top of `MAX_QUEUED_TILE_CHANGES`. Those can't just
+/// be missed, so a receiver that falls further behind than this gets
+/// `MapEvent::Overflowed`, and has to start over from a fresh snapshot.
+pub const MAX_QUEUED_REGISTRATION_EVENTS: usize = 4096;
+
+/// The default number of shards a `Map` is split into.
+pub const DEFAULT_SHARDS: usize = 16;
+/// How much of the mass that went into the map can go unaccounted for (and
+/// how many kg, on a map that's barely been used) before `Map::mass_audit`
+/// calls it a leak, rather than rounding.
+pub const MASS_AUDIT_TOLERANCE: f64 = 0.0001;
+
+/// A building registered at a point.
+struct Registration {
+    /// The client that registered it. `None` if its owner isn't connected:
+    /// it was restored from a saved map, or kept when the server shut down,
+    /// and nobody has claimed it by registering it again yet.
+    client_id: Option<ClientID>,
+    /// The owner's identity, if it authenticated with one. Only
+    /// registrations with an identity are saved, since there's no other way
+    /// to tell who they belong to afterward.
+    identity: Option<String>,
+    what: String,
+}
+
+impl Registration {
+    /// Returns `true` if this registration belongs to the given client: it
+    /// registered it, or it has the identity of an unclaimed one.
+    fn belongs_to(&self, client_id: ClientID, identity: Option<&str>) -> bool {
+        match self.client_id {
+            Some(x) => x == client_id,
+            None => identity.is_some() && self.identity.as_deref() == identity,
+        }
+    }
+}
+
+/// An object, how many identical copies of it there are, and when the newest
+/// copy was stored (for `Map::expire_objects`). The count is always 1 unless
+/// `stack_objects` is set.
+type ObjectSlot = (Vec<u8>, u32, Instant);
+
+/// Everything stored at the points that belong to one shard of a `Map`.
+#[derive(Default)]
+struct MapShard {
+    energy: HashMap<Point, Joules>,
+    /// Packets are added at the back and popped from the front, so they come
+    /// out in the order they went in.
+    gas_packets: HashMap<Point, VecDeque<MatPacket>>,
+    liquid_packets: HashMap<Point, VecDeque<MatPacket>>,
+    objects: HashMap<Point, Vec<ObjectSlot>>,
+    registrations: HashMap<Point, Vec<Registration>>,
+    /// Gas and liquid mass in and out of this shard, if
+    /// `MapLimits::mass_audit` is set.
+    mass: [MassTotals; 2],
+}
+
+impl MapShard {
+    fn mass_totals(&mut self, phase: Phase) -> &mut MassTotals {
+        match phase {
+            Phase::Gas => &mut self.mass[0],
+            Phase::Liquid => &mut self.mass[1],
+        }
+    }
+    fn packets(&mut self, phase: Phase)
+               -> &mut HashMap<Point, VecDeque<MatPacket>> {
+        match phase {
+            Phase::Gas => &mut self.gas_packets,
+            Phase::Liquid => &mut self.liquid_packets,
+        }
+    }
+    /// Possibly prune Energy/MatPacket for the given location
+    fn prune(&mut self, loc: Point) {
+        match self.energy.entry(loc) {
+            Entry::Vacant(_) => (),
+            Entry::Occupied(entry) =>
+                if *entry.get() == 0 as Joules { entry.remove(); }
+        }
+        match self.gas_packets.entry(loc) {
+            Entry::Vacant(_) => (),
+            Entry::Occupied(entry) =>
+                if entry.get().is_empty() { entry.remove(); }
+        }
+        match self.liquid_packets.entry(loc) {
+            Entry::Vacant(_) => (),
+            Entry::Occupied(entry) =>
+                if entry.get().is_empty() { entry.remove(); }
+        }
+    }
+    /// Returns `true` if the given point has something stored at it.
+    fn is_occupied(&self, loc: Point) -> bool {
+        self.energy.get(&loc).map(|x| *x > 0 as Joules).unwrap_or(false)
+            || self.gas_packets.get(&loc).map(|x| !x.is_empty())
+                .unwrap_or(false)
+            || self.liquid_packets.get(&loc).map(|x| !x.is_empty())
+                .unwrap_or(false)
+            || self.objects.get(&loc).map(|x| !x.is_empty()).unwrap_or(false)
+    }
+    /// Returns the points in this shard that have something stored at them.
+    fn occupied_points(&self) -> HashSet<Point> {
+        let mut points = HashSet::new();
+        points.extend(self.energy.iter()
+                      .filter(|(_, joules)| **joules > 0 as Joules)
+                      .map(|(loc, _)| *loc));
+        for storage in &[&self.gas_packets, &self.liquid_packets] {
+            points.extend(storage.iter().filter(|(_, vec)| !vec.is_empty())
+                          .map(|(loc, _)| *loc));
+        }
+        points.extend(self.objects.iter().filter(|(_, vec)| !vec.is_empty())
+                      .map(|(loc, _)| *loc));
+        points
+    }
+    fn tile_state(&self, loc: Point) -> TileState {
+        TileState {
+            joules: self.energy.get(&loc).copied().unwrap_or(0 as Joules),
+            gas_packets: self.gas_packets.get(&loc)
+                .map(|x| x.iter().copied().collect()).unwrap_or_else(Vec::new),
+            liquid_packets: self.liquid_packets.get(&loc)
+                .map(|x| x.iter().copied().collect()).unwrap_or_else(Vec::new),
+            object_count: self.objects.get(&loc)
+                .map(|x| count_objects(x)).unwrap_or(0),
+        }
+    }
+}
+
+/// Running totals for the global object limits.
+#[derive(Default)]
+struct ObjectBudget {
+    total_objects: usize,
+    total_object_bytes: usize,
+    /// Set when an object is turned away because of the global limits, and
+    /// cleared once an object has been removed.
+    exhausted: bool,
+    /// Set when `exhausted` becomes set, cleared when somebody calls
+    /// `take_object_budget_warning`.
+    warning: bool,
+}
+
+impl ObjectBudget {
+    fn remove(&mut self, slots: &[ObjectSlot]) {
+        if slots.is_empty() { return }
+        for (object, count, _) in slots.iter() {
+            self.total_objects -= *count as usize;
+            self.total_object_bytes -= object.len() * *count as usize;
+        }
+        self.exhausted = false;
     }
 }
 
+/// Returns the number of objects in the given slots, counting every copy in a
+/// stack.
+fn count_objects(slots: &[ObjectSlot]) -> usize {
+    slots.iter().map(|(_, count, _)| *count as usize).sum()
+}
+
+/// Returns the total mass of the given packets.
+fn total_mass(packets: &[MatPacket]) -> f64 {
+    packets.iter().map(|x| x.get_mass() as f64).sum()
+}
+
+/// Returns every registration in the given shards, as its point and building.
+fn registrations_in(shards: &[MutexGuard<'_, MapShard>])
+                    -> Vec<(Point, String)> {
+    let mut registrations = Vec::new();
+    for shard in shards.iter() {
+        for (loc, vec) in shard.registrations.iter() {
+            for el in vec.iter() {
+                registrations.push((*loc, el.what.clone()));
+            }
+        }
+    }
+    registrations
+}
+
 /// Contains all the state for the "interlayer" map. Incorporates temporary
 /// storage for energy, solids, liquids, and gases.
+///
+/// The points are divided between several shards, each with its own lock, so
+/// that clients working on different points don't have to wait for each
+/// other. Operations on a single point only need `&self`. When more than one
+/// lock is held at once, they're always taken in this order: shards (in
+/// ascending order), then `event_senders`, then `object_budget`.
 pub struct Map {
-    energy: HashMap<Point, u32>,
-    gas_packets: HashMap<Point, Vec<MatPacket>>,
-    liquid_packets: HashMap<Point, Vec<MatPacket>>,
-    objects: HashMap<Point, Vec<Vec<u8>>>,
-    registrations: HashMap<Point, Vec<(ClientID, String)>>,
-    registration_senders: RegSender,
+    shards: Vec<Mutex<MapShard>>,
+    event_senders: Mutex<EventSender>,
+    limits: MapLimits,
+    object_budget: Mutex<ObjectBudget>,
+    /// Counts changes to what's stored on the map (including registrations,
+    /// but only the ones that get saved).
+    changes: AtomicU64,
+    /// The value of `changes` when the map was last saved or loaded.
+    saved_changes: AtomicU64,
+    /// How many points have something stored at them, so that `max_tiles`
+    /// can be checked without counting them. See `occupy` and `settle`.
+    occupied_tiles: AtomicUsize,
 }
 
 impl Map {
-    /// Creates a new, blank map.
-    pub fn new() -> Map {
+    /// Creates a new, blank map, with the default number of shards.
+    pub fn new(limits: MapLimits) -> Map {
+        Map::with_shards(limits, DEFAULT_SHARDS)
+    }
+    /// Creates a new, blank map, split into the given number of shards.
+    pub fn with_shards(limits: MapLimits, shards: usize) -> Map {
+        assert!(shards > 0, "a map needs at least one shard");
         Map {
-            energy: HashMap::new(),
-            gas_packets: HashMap::new(),
-            liquid_packets: HashMap::new(),
-            objects: HashMap::new(),
-            registrations: HashMap::new(),
-            registration_senders: RegSender::new(),
-        }
-    }
-    /// Attempts to insert energy into the map at a given point. Returns the
-    /// amount left over, i.e. the amount that DID NOT fit.
-    pub fn add_joules(&mut self, loc: Point, amt: u32) -> u32 {
-        let slot = self.energy.entry(loc).or_insert(0);
+            shards: (0 .. shards).map(|_| Mutex::new(MapShard::default()))
+                .collect(),
+            event_senders: Mutex::new(EventSender::new()),
+            limits,
+            object_budget: Mutex::new(ObjectBudget::default()),
+            changes: AtomicU64::new(0),
+            saved_changes: AtomicU64::new(0),
+            occupied_tiles: AtomicUsize::new(0),
+        }
+    }
+    /// Returns the index of the shard the given point belongs to.
+    fn shard_index(&self, loc: Point) -> usize {
+        let mut hasher = DefaultHasher::new();
+        loc.hash(&mut hasher);
+        (hasher.finish() % self.shards.len() as u64) as usize
+    }
+    /// Locks and returns the shard the given point belongs to.
+    fn shard(&self, loc: Point) -> MutexGuard<'_, MapShard> {
+        self.shards[self.shard_index(loc)].lock().unwrap()
+    }
+    /// Lets everyone listening know that something at the given point changed.
+    fn tile_changed(&self, loc: Point) {
+        self.changes.fetch_add(1, Ordering::Relaxed);
+        self.event_senders.lock().unwrap().send(MapEvent::TileChanged(loc));
+    }
+    /// Returns `true` if something could be stored at the given point without
+    /// going over `max_tiles`, right now. Points that are already occupied
+    /// always have room. (Only `occupy` actually sets the room aside.)
+    ///
+    /// This takes (and releases) the point's shard lock itself, so it must be
+    /// called before the caller locks that shard.
+    fn room_for_tile(&self, loc: Point) -> bool {
+        match self.limits.max_tiles {
+            None => true,
+            Some(max) => self.shard(loc).is_occupied(loc)
+                || self.occupied_tiles.load(Ordering::Relaxed) < max,
+        }
+    }
+    /// Call before storing something at a point, with its shard locked.
+    /// Returns `false` if the point isn't occupied yet, and there's no room
+    /// for another occupied point under `max_tiles`. Otherwise, the point is
+    /// counted as occupied from now on; call `settle` when done, in case
+    /// nothing got stored after all.
+    fn occupy(&self, shard: &MapShard, loc: Point) -> bool {
+        if shard.is_occupied(loc) { return true }
+        let max = self.limits.max_tiles.unwrap_or(usize::MAX);
+        self.occupied_tiles.fetch_update(Ordering::Relaxed, Ordering::Relaxed,
+                                         |n| if n < max { Some(n + 1) }
+                                         else { None })
+            .is_ok()
+    }
+    /// Call after changing what's stored at a point, with its shard still
+    /// locked, to keep `occupied_tiles` up to date. `counted` is whether the
+    /// point was counted as occupied beforehand: it was occupied, or `occupy`
+    /// said yes.
+    fn settle(&self, shard: &MapShard, loc: Point, counted: bool) {
+        match (counted, shard.is_occupied(loc)) {
+            (true, false) => {
+                self.occupied_tiles.fetch_sub(1, Ordering::Relaxed);
+            },
+            (false, true) => {
+                self.occupied_tiles.fetch_add(1, Ordering::Relaxed);
+            },
+            _ => (),
+        }
+    }
+    /// Locks and returns every shard, in order.
+    fn all_shards(&self) -> Vec<MutexGuard<'_, MapShard>> {
+        self.shards.iter().map(|x| x.lock().unwrap()).collect()
+    }
+    /// Attempts to insert energy into the map at a given point, spilling what
+    /// doesn't fit onward if `MapLimits::energy_spill_to` says to. Returns
+    /// the amount left over, i.e. the amount that DID NOT fit anywhere.
+    pub fn add_joules(&self, loc: Point, amt: Joules) -> Joules {
+        let mut spare = self.store_joules(loc, amt);
+        if let Some(offset) = self.limits.energy_spill_to {
+            let mut loc = loc;
+            for _ in 0 .. MAX_SPILL_STEPS {
+                if spare <= 0 as Joules { break }
+                loc = loc.offset_by(offset);
+                spare = self.store_joules(loc, spare);
+            }
+        }
+        spare
+    }
+    /// Attempts to insert energy into the map at exactly the given point.
+    /// Returns the amount left over, i.e. the amount that DID NOT fit.
+    #[cfg(not(feature = "float_energy"))]
+    fn store_joules(&self, loc: Point, amt: Joules) -> Joules {
+        let mut shard = self.shard(loc);
+        if !self.occupy(&shard, loc) { return amt }
+        let slot = shard.energy.entry(loc).or_insert(0);
         let new_amount = *slot as u64 + amt as u64;
-        let capped = (MAX_STORED_ENERGY as u64).min(new_amount);
+        let capped = (self.limits.max_stored_energy as u64).min(new_amount);
         let spill = new_amount.saturating_sub(capped);
         *slot = capped as u32;
+        self.settle(&shard, loc, true);
+        if spill < amt as u64 { self.tile_changed(loc) }
         spill as u32
     }
+    /// Attempts to insert energy into the map at exactly the given point.
+    /// Returns the amount left over, i.e. the amount that DID NOT fit.
+    ///
+    /// Negative or non-finite amounts are treated as zero.
+    #[cfg(feature = "float_energy")]
+    fn store_joules(&self, loc: Point, amt: Joules) -> Joules {
+        let amt = sanitize_joules(amt);
+        let mut shard = self.shard(loc);
+        if !self.occupy(&shard, loc) { return amt }
+        let slot = shard.energy.entry(loc).or_insert(0.0);
+        let new_amount = *slot + amt;
+        let capped = (self.limits.max_stored_energy as f64).min(new_amount);
+        let spill = (new_amount - capped).max(0.0);
+        *slot = capped;
+        self.settle(&shard, loc, true);
+        if spill < amt { self.tile_changed(loc) }
+        spill
+    }
     /// Attempts to remove energy from the map at a given point. Returns the
     /// amount that was successfully "removed".
-    pub fn sub_joules(&mut self, loc: Point, amt: u32) -> u32 {
-        match self.energy.get_mut(&loc) {
+    #[cfg(not(feature = "float_energy"))]
+    pub fn sub_joules(&self, loc: Point, amt: Joules) -> Joules {
+        let mut shard = self.shard(loc);
+        let was = shard.is_occupied(loc);
+        let slosh = match shard.energy.get_mut(&loc) {
             None => 0,
             Some(slot) => {
                 let slosh = (*slot).min(amt);
                 *slot = slot.saturating_sub(amt);
                 slosh
             },
+        };
+        self.settle(&shard, loc, was);
+        if slosh > 0 { self.tile_changed(loc) }
+        slosh
+    }
+    /// Attempts to remove energy from the map at a given point. Returns the
+    /// amount that was successfully "removed".
+    ///
+    /// Negative or non-finite amounts are treated as zero.
+    #[cfg(feature = "float_energy")]
+    pub fn sub_joules(&self, loc: Point, amt: Joules) -> Joules {
+        let amt = sanitize_joules(amt);
+        let mut shard = self.shard(loc);
+        let was = shard.is_occupied(loc);
+        let slosh = match shard.energy.get_mut(&loc) {
+            None => 0.0,
+            Some(slot) => {
+                let slosh = (*slot).min(amt);
+                *slot = (*slot - slosh).max(0.0);
+                slosh
+            },
+        };
+        self.settle(&shard, loc, was);
+        if slosh > 0.0 { self.tile_changed(loc) }
+        slosh
+    }
+    /// Moves up to `amt` joules from one point to another, no more than the
+    /// destination has room for. Returns the amount that moved.
+    ///
+    /// As with `transfer_packet`, hold the map's write lock if nobody may see
+    /// the energy in between.
+    pub fn transfer_joules(&self, from: Point, to: Point, amt: Joules)
+                           -> Joules {
+        // find out how much room there is first, so that nothing has to be
+        // put back
+        let room = if self.room_for_tile(to) {
+            let max = self.limits.max_stored_energy as Joules;
+            let stored = self.shard(to).energy.get(&to).copied()
+                .unwrap_or(0 as Joules);
+            if stored < max { max - stored } else { 0 as Joules }
+        } else { 0 as Joules };
+        let taken = self.sub_joules(from, if amt < room { amt } else { room });
+        // (not `add_joules`; a transfer moves energy to `to`, and nowhere else)
+        let spare = self.store_joules(to, taken);
+        // (only if someone else filled `to` up in the meantime, which can't
+        // happen under the write lock)
+        if spare > 0 as Joules { self.store_joules(from, spare); }
+        taken - spare
+    }
+    /// Multiplies the energy stored at every point by `factor`, which should be
+    /// between 0 and 1. Points that end up with no energy are dropped, unless
+    /// something is registered there. Points that already had no energy are
+    /// left alone.
+    ///
+    /// Only one shard is locked at a time.
+    pub fn decay_energy(&self, factor: f64) {
+        for shard in self.shards.iter() {
+            let shard = &mut *shard.lock().unwrap();
+            let registrations = &shard.registrations;
+            let mut changed = Vec::new();
+            shard.energy.retain(|loc, joules| {
+                if *joules == 0 as Joules { return true }
+                let decayed = decayed_joules(*joules, factor);
+                if decayed != *joules { changed.push(*loc) }
+                *joules = decayed;
+                *joules != 0 as Joules || registrations.contains_key(loc)
+            });
+            // (every changed point had energy, so it was occupied)
+            for loc in changed.into_iter() {
+                self.settle(shard, loc, true);
+                self.tile_changed(loc)
+            }
+        }
+    }
+    /// Removes the object slots whose newest copy has been stored for at least
+    /// `ttl`, along with any points left with no objects. Returns how many
+    /// objects (counting every copy in a stack) were removed.
+    ///
+    /// Only one shard is locked at a time.
+    pub fn expire_objects(&self, ttl: Duration) -> usize {
+        let now = Instant::now();
+        let mut total = 0;
+        for shard in self.shards.iter() {
+            let shard = &mut *shard.lock().unwrap();
+            let mut expired = Vec::new();
+            let mut changed = Vec::new();
+            shard.objects.retain(|loc, slots| {
+                let (old, kept): (Vec<ObjectSlot>, Vec<ObjectSlot>)
+                    = std::mem::take(slots).into_iter()
+                    .partition(|(_, _, stored_at)|
+                               now.duration_since(*stored_at) >= ttl);
+                *slots = kept;
+                if !old.is_empty() {
+                    changed.push(*loc);
+                    expired.extend(old);
+                }
+                !slots.is_empty()
+            });
+            // (every changed point had objects, so it was occupied)
+            for loc in changed.into_iter() {
+                self.settle(shard, loc, true);
+                self.tile_changed(loc)
+            }
+            total += count_objects(&expired);
+            self.object_budget.lock().unwrap().remove(&expired);
+        }
+        total
+    }
+    /// Evens out the temperatures of the packets stored at each point, gases
+    /// and liquids separately. See `MatPacket::mix_temperatures`.
+    ///
+    /// Only one shard is locked at a time.
+    pub fn mix_packet_temperatures(&self) {
+        for shard in self.shards.iter() {
+            let shard = &mut *shard.lock().unwrap();
+            let mut changed = HashSet::new();
+            for storage in &mut [&mut shard.gas_packets,
+                                 &mut shard.liquid_packets] {
+                for (loc, packets) in storage.iter_mut() {
+                    if MatPacket::mix_temperatures(packets.iter_mut()) {
+                        changed.insert(*loc);
+                    }
+                }
+            }
+            for loc in changed.into_iter() { self.tile_changed(loc) }
+        }
+    }
+    /// Multiplies the germ count of every stored packet by `factor`, which
+    /// should be between 0 and 1. See `MatPacket::decay_germs`.
+    ///
+    /// Only one shard is locked at a time.
+    pub fn decay_germs(&self, factor: f64) {
+        for shard in self.shards.iter() {
+            let shard = &mut *shard.lock().unwrap();
+            let mut changed = HashSet::new();
+            for storage in &mut [&mut shard.gas_packets,
+                                 &mut shard.liquid_packets] {
+                for (loc, packets) in storage.iter_mut() {
+                    for packet in packets.iter_mut() {
+                        if packet.decay_germs(factor) { changed.insert(*loc); }
+                    }
+                }
+            }
+            for loc in changed.into_iter() { self.tile_changed(loc) }
         }
     }
     /// Attempts to add a MatPacket of the given phase to the map at the given
-    /// point. Returns only `true` (the packet was entirely accepted) or
-    /// `false` (the packet was entirely rejected).
-    pub fn add_packet(&mut self, loc: Point, packet: &MatPacket, phase: Phase)
-                      -> bool {
-        let map = match phase {
-            Phase::Gas => &mut self.gas_packets,
-            Phase::Liquid => &mut self.liquid_packets,
+    /// point. Returns the mass left over, i.e. the amount that DID NOT fit,
+    /// and why it didn't. Part of a packet may be accepted, if it can be
+    /// merged into a packet that's already there but the rest has no room of
+    /// its own. Massless packets are never stored.
+    pub fn add_packet(&self, loc: Point, packet: &MatPacket, phase: Phase)
+                      -> (f32, Option<PacketRefusal>) {
+        // an empty packet is useless, and would only make trouble later
+        if !packet.has_mass() { return (0.0, None) }
+        let (spare, why) = match self.store_packet(loc, packet, phase) {
+            None => (0.0, None),
+            Some((x, why)) => (x.get_mass(), Some(why)),
         };
-        let entry = map.entry(loc);
+        if spare < packet.get_mass() { self.tile_changed(loc) }
+        (spare, why)
+    }
+    /// The guts of `add_packet`. Returns whatever didn't fit, and why.
+    fn store_packet(&self, loc: Point, packet: &MatPacket, phase: Phase)
+                    -> Option<(MatPacket, PacketRefusal)> {
+        let mut shard = self.shard(loc);
+        if !self.occupy(&shard, loc) {
+            return Some((*packet, PacketRefusal::MapFull))
+        }
+        let ret = self.store_packet_in(&mut shard, loc, packet, phase);
+        self.settle(&shard, loc, true);
+        if self.limits.mass_audit {
+            let spare = ret.map(|x| x.0.get_mass()).unwrap_or(0.0);
+            shard.mass_totals(phase).added
+                += (packet.get_mass() - spare) as f64;
+        }
+        ret
+    }
+    /// The guts of `store_packet`, with the shard already locked.
+    fn store_packet_in(&self, shard: &mut MapShard, loc: Point,
+                       packet: &MatPacket, phase: Phase)
+                       -> Option<(MatPacket, PacketRefusal)> {
+        let max_stored_packets = self.limits.max_stored_packets;
+        let phase_limits = &self.limits.phase_limits;
+        let entry = shard.packets(phase).entry(loc);
         match entry {
             Entry::Vacant(entry) => {
-                let mut vec = Vec::with_capacity(MAX_STORED_PACKETS);
-                vec.push(*packet);
-                entry.insert(vec);
-                return true;
+                let mut queue = VecDeque::new();
+                queue.push_back(*packet);
+                entry.insert(queue);
+                return None;
             },
             Entry::Occupied(mut entry) => {
                 // we assume that there cannot be more than one NON-FULL packet
@@ -138,85 +987,210 @@ impl Map {
                 // - It is fully merged into an existing packet
                 // - Part is merged into an existing packet (which becomes
                 //   full), and the rest is  put into EXACTLY ONE new packet
+                // - Part is merged into an existing packet (which becomes
+                //   full), and the rest is rejected
                 // - It is entirely rejected
                 //
                 // None of those three possibilities can result in there being
-                // more than one FULL packet of a given element.
-                let vec = entry.get_mut();
-                let len = vec.len();
-                for el in vec.iter_mut() {
-                    if !el.has_room(phase) { continue }
-                    match el.merge(&packet, phase) {
+                // more than one NON-FULL packet of a given element. Popping
+                // from the front only ever removes packets, so it can't
+                // either, and where in the queue that one packet sits
+                // doesn't matter, since we look through the whole thing.
+                let queue = entry.get_mut();
+                let len = queue.len();
+                for el in queue.iter_mut() {
+                    if !el.has_room(phase, phase_limits) { continue }
+                    match el.merge(&packet, phase, phase_limits) {
                         None => continue,
                         Some((merged, None)) => {
                             *el = merged;
-                            return true;
+                            return None;
                         },
                         Some((merged, Some(spare))) => {
-                            if len >= MAX_STORED_PACKETS { return false }
                             *el = merged;
-                            vec.push(spare);
-                            return true;
+                            if len >= max_stored_packets {
+                                return Some((spare, PacketRefusal::QueueFull))
+                            }
+                            queue.push_back(spare);
+                            return None;
                         },
                     }
                 }
                 // merging with an existing stack failed. try adding it to the
                 // end.
-                if len >= MAX_STORED_PACKETS { return false }
-                vec.push(*packet);
-                return true;
+                if len >= max_stored_packets {
+                    return Some((*packet, PacketRefusal::QueueFull))
+                }
+                queue.push_back(*packet);
+                return None;
             }
         }
     }
     /// Attempts to remove a MatPacket of the given phase from the map at the
     /// given point. Returns `None` if there were no packets left, or `Some` if
     /// a packet was successfully removed.
-    pub fn pop_packet(&mut self, loc: Point, phase: Phase) -> Option<MatPacket> {
-        let map = match phase {
-            Phase::Gas => &mut self.gas_packets,
-            Phase::Liquid => &mut self.liquid_packets,
-        };
-        let entry = map.entry(loc);
-        match entry {
+    pub fn pop_packet(&self, loc: Point, phase: Phase) -> Option<MatPacket> {
+        let mut shard = self.shard(loc);
+        let was = shard.is_occupied(loc);
+        let entry = shard.packets(phase).entry(loc);
+        let ret = match entry {
             Entry::Vacant(_) => None,
-            Entry::Occupied(mut entry) => {
-                let vec = entry.get_mut();
-                if vec.is_empty() { None }
-                else { Some(vec.remove(0)) }
+            Entry::Occupied(mut entry) => entry.get_mut().pop_front(),
+        };
+        self.settle(&shard, loc, was);
+        if let Some(packet) = ret {
+            if self.limits.mass_audit {
+                shard.mass_totals(phase).removed += packet.get_mass() as f64;
+            }
+            self.tile_changed(loc)
+        }
+        ret
+    }
+    /// Puts a packet back at the front of a point's queue, as though it had
+    /// never been popped. There's always room for it, since that's where it
+    /// came from.
+    fn return_packet(&self, loc: Point, packet: MatPacket, phase: Phase) {
+        let phase_limits = &self.limits.phase_limits;
+        let mut shard = self.shard(loc);
+        let was = shard.is_occupied(loc);
+        if self.limits.mass_audit {
+            shard.mass_totals(phase).added += packet.get_mass() as f64;
+        }
+        let queue = shard.packets(phase).entry(loc)
+            .or_insert_with(VecDeque::new);
+        // keep to the rule that at most one packet of an element isn't full
+        // (see `store_packet`)
+        let mut rest = Some(packet);
+        for el in queue.iter_mut() {
+            if !el.has_room(phase, phase_limits) { continue }
+            if let Some((merged, spare)) = el.merge(&packet, phase,
+                                                    phase_limits) {
+                *el = merged;
+                rest = spare;
+                break
             }
         }
+        if let Some(rest) = rest { queue.push_front(rest) }
+        // (this can go over `max_tiles`, but only by as much as it went under
+        // when the packet left)
+        self.settle(&shard, loc, was);
+    }
+    /// Moves the packet at the front of one point's queue to another point,
+    /// or as much of it as fits there. Whatever doesn't fit goes back where
+    /// it came from. Returns the mass that moved, and the mass that was left
+    /// behind; both are zero if there was nothing to move.
+    ///
+    /// This takes more than one lock, one after another; the caller should
+    /// hold the map's write lock if nobody may see the packet in between.
+    pub fn transfer_packet(&self, from: Point, to: Point, phase: Phase)
+                           -> (f32, f32) {
+        let packet = match self.pop_packet(from, phase) {
+            None => return (0.0, 0.0),
+            Some(x) => x,
+        };
+        let leftover = self.store_packet(to, &packet, phase).map(|x| x.0);
+        let left = leftover.map(|x| x.get_mass()).unwrap_or(0.0);
+        if left < packet.get_mass() { self.tile_changed(to) }
+        if let Some(leftover) = leftover {
+            self.return_packet(from, leftover, phase);
+        }
+        (packet.get_mass() - left, left)
+    }
+    /// Returns what's stored at the given point, without removing any of it.
+    /// A point with nothing stored at it gives zeroes and empty lists.
+    pub fn peek_tile(&self, loc: Point) -> TileState {
+        self.shard(loc).tile_state(loc)
+    }
+    /// Returns what's stored at every occupied point between two corners,
+    /// inclusive, in order. Points with nothing stored at them are left out.
+    /// Only one shard is locked at a time.
+    pub fn query_region(&self, min: Point, max: Point)
+                        -> Vec<(Point, TileState)> {
+        let inside = |loc: &Point|
+            loc.get_x() >= min.get_x() && loc.get_x() <= max.get_x()
+            && loc.get_y() >= min.get_y() && loc.get_y() <= max.get_y()
+            && loc.get_z() >= min.get_z() && loc.get_z() <= max.get_z();
+        let mut tiles = Vec::new();
+        for shard in self.shards.iter() {
+            let shard = shard.lock().unwrap();
+            tiles.extend(shard.occupied_points().into_iter()
+                         .filter(inside)
+                         .map(|loc| (loc, shard.tile_state(loc))));
+        }
+        tiles.sort_by_key(|(loc, _)| *loc);
+        tiles
+    }
+    /// Returns the number of distinct points that currently have something
+    /// (energy, packets, or objects) stored at them.
+    pub fn occupied_tile_count(&self) -> usize {
+        self.occupied_tiles.load(Ordering::Relaxed)
     }
     /// Attempts to register a given client's building at the given point.
-    /// Returns `true` if the registration was OK, `false` if the client had
-    /// too many registrations at that point.
-    pub fn register(&mut self, loc: Point, client_id: ClientID,
-                    what: String) -> bool {
-        let slot = self.registrations.entry(loc).or_insert(Vec::new());
-        let count = slot.iter().map(|x| x.0 == client_id)
-            .fold(0, |a,b| if b { a + 1 } else { a });
-        if count >= MAX_REGISTRATIONS { false }
-        else {
-            self.registration_senders.send((true, loc, &what));
-            slot.push((client_id, what));
-            true
+    /// Returns why not, if the client had too many registrations at that
+    /// point, or the point had too many altogether.
+    ///
+    /// `identity` is the client's identity, if it authenticated with one. If
+    /// the same building was registered at the same point under the same
+    /// identity before, and nobody has claimed it yet, this claims it
+    /// instead of registering it again.
+    pub fn register(&self, loc: Point, client_id: ClientID,
+                    identity: Option<&str>, what: String)
+                    -> Result<(), RegistrationRefusal> {
+        let mut shard = self.shard(loc);
+        let slot = shard.registrations.entry(loc).or_insert(Vec::new());
+        let count = slot.iter().filter(|x| x.client_id == Some(client_id))
+            .count();
+        if count >= self.limits.max_registrations {
+            return Err(RegistrationRefusal::TooManyForClient)
+        }
+        if let Some(unclaimed) = slot.iter_mut().find(|x| {
+            x.client_id.is_none() && x.belongs_to(client_id, identity)
+                && x.what == what
+        }) {
+            // (everyone already knows it's there)
+            unclaimed.client_id = Some(client_id);
+            return Ok(())
         }
+        // (claiming one doesn't add to the pile, so that's allowed even at
+        // the limit)
+        if self.limits.max_total_registrations
+        .map(|x| slot.len() >= x).unwrap_or(false) {
+            return Err(RegistrationRefusal::TooManyTotal)
+        }
+        self.event_senders.lock().unwrap()
+            .send(MapEvent::Registered(loc, what.clone()));
+        if identity.is_some() {
+            self.changes.fetch_add(1, Ordering::Relaxed);
+        }
+        slot.push(Registration { client_id: Some(client_id),
+                                 identity: identity.map(str::to_owned),
+                                 what });
+        Ok(())
     }
     /// Attempts to unregister a given client's building at the given point.
-    /// Unconditionally succeeds.
+    /// Unconditionally succeeds. An unclaimed registration with the client's
+    /// `identity` counts as the client's.
     ///
     /// This may also trigger removal of empty Energy/MatPacket storage at
     /// the given point, saving some memory.
-    pub fn unregister(&mut self, loc: Point, client_id: ClientID,
-                      what: &str) {
-        let entry = self.registrations.entry(loc);
+    pub fn unregister(&self, loc: Point, client_id: ClientID,
+                      identity: Option<&str>, what: &str) {
+        let mut shard = self.shard(loc);
+        let entry = shard.registrations.entry(loc);
         let prune = match entry {
             Entry::Vacant(_) => true,
             Entry::Occupied(mut entry) => {
                 let vec = entry.get_mut();
                 for i in (0..vec.len()).rev() {
-                    if vec[i].0 == client_id && vec[i].1 == what {
-                        vec.remove(i);
-                        self.registration_senders.send((false, loc, what));
+                    if vec[i].belongs_to(client_id, identity)
+                    && vec[i].what == what {
+                        let removed = vec.remove(i);
+                        if removed.identity.is_some() {
+                            self.changes.fetch_add(1, Ordering::Relaxed);
+                        }
+                        self.event_senders.lock().unwrap()
+                            .send(MapEvent::Unregistered(loc,
+                                                         what.to_owned()));
                     }
                 }
                 if vec.is_empty() {
@@ -226,144 +1200,490 @@ impl Map {
             }
         };
         if prune {
-            self.prune(loc);
+            shard.prune(loc);
         }
     }
-    /// Unregister *all* buildings from a given client.
+    /// Moves a given client's building from one point to another. It's the
+    /// same as unregistering it from one and registering it at the other,
+    /// except that it happens all at once: listeners get a single
+    /// `MapEvent::Moved`, and never see the building missing in between.
     ///
-    /// This may trigger removal of empty Energy/MatPackets.
-    pub fn unregister_all(&mut self, client_id: ClientID) {
-        let mut prunes = Vec::new();
-        let registration_senders = &mut self.registration_senders;
-        self.registrations.retain(|loc, vec| {
-            for i in (0..vec.len()).rev() {
-                if vec[i].0 == client_id {
-                    registration_senders.send((false, *loc, &vec[i].1));
-                    vec.remove(i);
-                }
-            }
-            if vec.is_empty() {
-                prunes.push(*loc);
-                false
-            } else { true }
-        });
-        for loc in prunes.into_iter() { self.prune(loc) }
-    }
-    /// Possibly prune Energy/MatPacket for the given location
-    fn prune(&mut self, loc: Point) {
-        match self.energy.entry(loc) {
-            Entry::Vacant(_) => (),
-            Entry::Occupied(entry) =>
-                if *entry.get() == 0 { entry.remove(); }
+    /// Refused for the same reasons as `register`, or if the client doesn't
+    /// have the building at `from`. As with `unregister`, an unclaimed
+    /// registration with the client's `identity` counts as the client's (and
+    /// is claimed by moving it).
+    pub fn move_registration(&self, from: Point, to: Point,
+                             client_id: ClientID, identity: Option<&str>,
+                             what: &str) -> Result<(), RegistrationRefusal> {
+        // lock both points' shards (in order) for the whole move
+        let from_index = self.shard_index(from);
+        let to_index = self.shard_index(to);
+        let mut indices = vec![from_index, to_index];
+        indices.sort_unstable();
+        indices.dedup();
+        let mut shards: Vec<MutexGuard<'_, MapShard>> = indices.iter()
+            .map(|x| self.shards[*x].lock().unwrap()).collect();
+        let from_shard = indices.iter().position(|x| *x == from_index)
+            .unwrap();
+        let to_shard = indices.iter().position(|x| *x == to_index).unwrap();
+        let index = shards[from_shard].registrations.get(&from)
+            .and_then(|vec| vec.iter().position(|x| {
+                x.belongs_to(client_id, identity) && x.what == what
+            }))
+            .ok_or(RegistrationRefusal::NotRegistered)?;
+        if from == to {
+            // (nowhere to go, but it's claimed all the same)
+            shards[from_shard].registrations.get_mut(&from).unwrap()[index]
+                .client_id = Some(client_id);
+            return Ok(())
         }
-        match self.gas_packets.entry(loc) {
-            Entry::Vacant(_) => (),
-            Entry::Occupied(entry) =>
-                if entry.get().is_empty() { entry.remove(); }
+        let (count, total) = shards[to_shard].registrations.get(&to)
+            .map(|slot| {
+                (slot.iter().filter(|x| x.client_id == Some(client_id))
+                 .count(), slot.len())
+            })
+            .unwrap_or((0, 0));
+        if count >= self.limits.max_registrations {
+            return Err(RegistrationRefusal::TooManyForClient)
         }
-        match self.liquid_packets.entry(loc) {
-            Entry::Vacant(_) => (),
-            Entry::Occupied(entry) =>
-                if entry.get().is_empty() { entry.remove(); }
+        if self.limits.max_total_registrations.map(|x| total >= x)
+        .unwrap_or(false) {
+            return Err(RegistrationRefusal::TooManyTotal)
+        }
+        let shard = &mut shards[from_shard];
+        let vec = shard.registrations.get_mut(&from).unwrap();
+        let mut registration = vec.remove(index);
+        if vec.is_empty() {
+            shard.registrations.remove(&from);
+            shard.prune(from);
+        }
+        registration.client_id = Some(client_id);
+        if registration.identity.is_some() {
+            self.changes.fetch_add(1, Ordering::Relaxed);
+        }
+        self.event_senders.lock().unwrap()
+            .send(MapEvent::Moved(from, to, what.to_owned()));
+        shards[to_shard].registrations.entry(to).or_insert_with(Vec::new)
+            .push(registration);
+        Ok(())
+    }
+    /// Unregister *all* buildings from a given client, including any
+    /// unclaimed ones with its `identity`. (Those weren't registered again
+    /// this time around, so they're probably gone.)
+    ///
+    /// If `keep_identified` is set, registrations with an identity are left
+    /// unclaimed instead of being removed. This is for when the server is
+    /// shutting down, so that they get saved, and their owners can claim them
+    /// after a restart.
+    ///
+    /// This may trigger removal of empty Energy/MatPackets. Only one shard is
+    /// locked at a time.
+    pub fn unregister_all(&self, client_id: ClientID, identity: Option<&str>,
+                          keep_identified: bool) {
+        for shard in self.shards.iter() {
+            let mut shard = shard.lock().unwrap();
+            let mut prunes = Vec::new();
+            let mut event_senders = self.event_senders.lock().unwrap();
+            shard.registrations.retain(|loc, vec| {
+                for i in (0..vec.len()).rev() {
+                    if !vec[i].belongs_to(client_id, identity) { continue }
+                    if keep_identified && vec[i].identity.is_some() {
+                        vec[i].client_id = None;
+                        continue
+                    }
+                    let removed = vec.remove(i);
+                    if removed.identity.is_some() {
+                        self.changes.fetch_add(1, Ordering::Relaxed);
+                    }
+                    event_senders.send(MapEvent::Unregistered(*loc,
+                                                              removed.what));
+                }
+                if vec.is_empty() {
+                    prunes.push(*loc);
+                    false
+                } else { true }
+            });
+            drop(event_senders);
+            for loc in prunes.into_iter() { shard.prune(loc) }
         }
     }
+    /// Puts back a registration from a saved map, unclaimed. Too many with
+    /// the same identity at the same point are quietly left out, the same as
+    /// if the owner had tried to register them.
+    fn restore_registration(&self, loc: Point, identity: String,
+                            what: String) {
+        let mut shard = self.shard(loc);
+        let slot = shard.registrations.entry(loc).or_insert(Vec::new());
+        let count = slot.iter()
+            .filter(|x| x.identity.as_ref() == Some(&identity)).count();
+        if count >= self.limits.max_registrations { return }
+        self.event_senders.lock().unwrap()
+            .send(MapEvent::Registered(loc, what.clone()));
+        slot.push(Registration { client_id: None, identity: Some(identity),
+                                 what });
+    }
     /// Get a queue that will receive all registrations and unregistratinos
-    /// that take place on the map, pre-filled with all currently-active registrations.
-    pub fn get_registrations(&mut self)
-                             -> mpsc::UnboundedReceiver<(bool, Point, String)>{
-        let (tx, rx) = mpsc::unbounded_channel();
-        for (loc, vec) in self.registrations.iter() {
-            for el in vec.iter() {
-                tx.send((true, *loc, el.1.clone()))
-                    .expect("Couldn't send? We should be able to send!");
+    /// that take place on the map, pre-filled with all currently-active
+    /// registrations, as well as every change to what's stored on the map.
+    pub fn get_events(&self) -> EventReceiver {
+        // Hold every shard while taking the snapshot, so that nothing can be
+        // registered or unregistered between the snapshot and the sender
+        // being added.
+        let shards = self.all_shards();
+        let backlog = registrations_in(&shards).into_iter()
+            .map(|(loc, what)| MapEvent::Registered(loc, what))
+            .collect();
+        self.add_receiver(backlog)
+    }
+    /// Like `get_events`, but instead of being put in the queue one by one,
+    /// the currently-active registrations are returned all together.
+    pub fn get_registrations_and_events(&self)
+        -> (Vec<(Point, String)>, EventReceiver) {
+        // (same as above: the snapshot and the new sender go together)
+        let shards = self.all_shards();
+        (registrations_in(&shards), self.add_receiver(VecDeque::new()))
+    }
+    fn add_receiver(&self, backlog: VecDeque<MapEvent>) -> EventReceiver {
+        let (tx, rx) = mpsc::channel(MAX_QUEUED_TILE_CHANGES
+                                     + MAX_QUEUED_REGISTRATION_EVENTS);
+        let state = Arc::new(QueueState::default());
+        self.event_senders.lock().unwrap().push(tx, state.clone());
+        EventReceiver { backlog, rx, state }
+    }
+    /// Catches up a receiver that got `MapEvent::Overflowed`. Returns all
+    /// currently-active registrations, which replace everything it was told
+    /// before. Registration events that were still queued are thrown away
+    /// (the snapshot covers them), but tile changes are kept.
+    pub fn resync_events(&self, events: &mut EventReceiver)
+        -> Vec<(Point, String)> {
+        // (same as in `get_events`: no registrations can change while the
+        // queue is emptied and the snapshot is taken)
+        let shards = self.all_shards();
+        events.backlog.retain(|x| matches!(x, MapEvent::TileChanged(_)));
+        while let Ok(event) = events.rx.try_recv() {
+            if let MapEvent::TileChanged(_) = event {
+                events.backlog.push_back(event);
             }
         }
-        self.registration_senders.push(tx);
-        rx
+        events.state.needs_resync.store(false, Ordering::Relaxed);
+        registrations_in(&shards)
     }
     /// Attempts to add an opaque object to the map at the given point. Returns
     /// only `true` (the object was entirely accepted) or `false` (the object
     /// was entirely rejected).
-    pub fn add_object(&mut self, loc: Point, object: Vec<u8>) -> bool {
-        let entry = self.objects.entry(loc);
+    ///
+    /// Objects are rejected if there are too many at this point, or if the
+    /// global object or tile limits in `MapLimits` have been reached. With
+    /// `stack_objects`, an object identical to one already here is never
+    /// rejected for want of a slot.
+    pub fn add_object(&self, loc: Point, object: Vec<u8>) -> bool {
+        let mut shard = self.shard(loc);
+        if !self.occupy(&shard, loc) { return false }
+        let stored = self.store_object_in(&mut shard, loc, object);
+        self.settle(&shard, loc, true);
+        if stored { self.tile_changed(loc) }
+        stored
+    }
+    /// The guts of `add_object`, with the shard already locked.
+    fn store_object_in(&self, shard: &mut MapShard, loc: Point,
+                       object: Vec<u8>) -> bool {
+        let mut budget = self.object_budget.lock().unwrap();
+        let over_count = self.limits.max_total_objects
+            .map(|max| budget.total_objects >= max).unwrap_or(false);
+        let over_bytes = self.limits.max_total_object_bytes
+            .map(|max| budget.total_object_bytes.saturating_add(object.len())
+                 > max).unwrap_or(false);
+        if over_count || over_bytes {
+            if !budget.exhausted {
+                budget.exhausted = true;
+                budget.warning = true;
+            }
+            return false
+        }
+        let len = object.len();
+        let now = Instant::now();
+        let entry = shard.objects.entry(loc);
         match entry {
             Entry::Vacant(entry) => {
-                let mut vec = Vec::with_capacity(MAX_STORED_OBJECTS);
-                vec.push(object);
+                let mut vec = Vec::new();
+                vec.push((object, 1, now));
                 entry.insert(vec);
-                return true;
             },
             Entry::Occupied(mut entry) => {
                 let vec = entry.get_mut();
-                let len = vec.len();
-                if len >= MAX_STORED_OBJECTS { return false }
-                vec.push(object);
-                return true;
+                let stack = if self.limits.stack_objects {
+                    vec.iter_mut().find(|(x, count, _)| *x == object
+                                        && *count < u32::MAX)
+                } else { None };
+                match stack {
+                    Some((_, count, stored_at)) => {
+                        *count += 1;
+                        *stored_at = now;
+                    },
+                    None => {
+                        if vec.len() >= self.limits.max_stored_objects {
+                            return false
+                        }
+                        vec.push((object, 1, now));
+                    },
+                }
             }
         }
+        budget.total_objects += 1;
+        budget.total_object_bytes += len;
+        true
+    }
+    /// Returns `true` if objects have started being rejected because of the
+    /// global object limits since the last time this was called. Used to log
+    /// that fact once, rather than on every rejection.
+    pub fn take_object_budget_warning(&self) -> bool {
+        std::mem::replace(&mut self.object_budget.lock().unwrap().warning,
+                          false)
     }
     /// Attempts to remove an opaque object from the map at the given point.
     /// Returns `None` if there was no object, or `Some(...)` if there was.
-    pub fn pop_object(&mut self, loc: Point) -> Option<Vec<u8>> {
-        let entry = self.objects.entry(loc);
+    pub fn pop_object(&self, loc: Point) -> Option<Vec<u8>> {
+        let mut shard = self.shard(loc);
+        let was = shard.is_occupied(loc);
+        let ret = self.take_object_in(&mut shard, loc);
+        self.settle(&shard, loc, was);
+        ret
+    }
+    /// The guts of `pop_object`, with the shard already locked.
+    fn take_object_in(&self, shard: &mut MapShard, loc: Point)
+                      -> Option<Vec<u8>> {
+        let entry = shard.objects.entry(loc);
         match entry {
             Entry::Vacant(_) => None,
             Entry::Occupied(mut entry) => {
                 let vec = entry.get_mut();
                 if vec.is_empty() { None }
-                else { Some(vec.remove(0)) }
+                else {
+                    let object = if vec[0].1 > 1 {
+                        vec[0].1 -= 1;
+                        vec[0].0.clone()
+                    }
+                    else { vec.remove(0).0 };
+                    self.tile_changed(loc);
+                    let mut budget = self.object_budget.lock().unwrap();
+                    budget.total_objects -= 1;
+                    budget.total_object_bytes -= object.len();
+                    budget.exhausted = false;
+                    Some(object)
+                }
             }
         }
     }
+    /// Puts an object back at the front of a point's slots, as though it had
+    /// never been popped.
+    fn return_object(&self, loc: Point, object: Vec<u8>) {
+        let mut shard = self.shard(loc);
+        let was = shard.is_occupied(loc);
+        let mut budget = self.object_budget.lock().unwrap();
+        budget.total_objects += 1;
+        budget.total_object_bytes += object.len();
+        let vec = shard.objects.entry(loc).or_insert_with(Vec::new);
+        match vec.first_mut() {
+            // it came off the top of this stack
+            Some((x, count, _)) if self.limits.stack_objects && *x == object
+                && *count < u32::MAX => *count += 1,
+            _ => vec.insert(0, (object, 1, Instant::now())),
+        }
+        drop(budget);
+        // (as with `return_packet`, this can't go over `max_tiles` by more
+        // than it went under)
+        self.settle(&shard, loc, was);
+    }
+    /// Moves the first object stored at one point to another point, if
+    /// there's room for it there. Returns `true` if it moved.
+    ///
+    /// As with `transfer_packet`, hold the map's write lock if nobody may see
+    /// the object in between.
+    pub fn transfer_object(&self, from: Point, to: Point) -> bool {
+        let object = match self.pop_object(from) {
+            None => return false,
+            Some(x) => x,
+        };
+        // (the object was just taken out of the global budget, so only the
+        // destination's own limits can turn it away)
+        if self.add_object(to, object.clone()) { return true }
+        self.return_object(from, object);
+        false
+    }
+    /// Removes all energy, packets, and objects stored at the given point,
+    /// leaving its registrations alone. Returns what was removed.
+    pub fn clear_tile(&self, loc: Point) -> TileState {
+        let mut shard = self.shard(loc);
+        let was = shard.is_occupied(loc);
+        let objects = shard.objects.remove(&loc).unwrap_or_else(Vec::new);
+        self.tile_changed(loc);
+        self.object_budget.lock().unwrap().remove(&objects);
+        let ret = TileState {
+            joules: shard.energy.remove(&loc).unwrap_or(0 as Joules),
+            gas_packets: shard.gas_packets.remove(&loc).map(Vec::from)
+                .unwrap_or_else(Vec::new),
+            liquid_packets: shard.liquid_packets.remove(&loc).map(Vec::from)
+                .unwrap_or_else(Vec::new),
+            object_count: count_objects(&objects),
+        };
+        self.settle(&shard, loc, was);
+        if self.limits.mass_audit {
+            shard.mass_totals(Phase::Gas).removed
+                += total_mass(&ret.gas_packets);
+            shard.mass_totals(Phase::Liquid).removed
+                += total_mass(&ret.liquid_packets);
+        }
+        ret
+    }
+    /// Compares how much packet mass is on the map with how much has been
+    /// added and removed since the map was loaded (or cleared, or reset).
+    /// Returns `None` unless `MapLimits::mass_audit` is set.
+    ///
+    /// Every shard is locked at once, so that mass that's on its way from
+    /// one point to another isn't counted twice, or not at all.
+    pub fn mass_audit(&self) -> Option<MassAudit> {
+        if !self.limits.mass_audit { return None }
+        let mut shards = self.all_shards();
+        let mut audit = |phase| {
+            let (mut added, mut removed, mut stored) = (0.0, 0.0, 0.0);
+            for shard in shards.iter_mut() {
+                let totals = *shard.mass_totals(phase);
+                added += totals.added;
+                removed += totals.removed;
+                stored += shard.packets(phase).values()
+                    .map(|x| x.iter().map(|x| x.get_mass() as f64)
+                         .sum::<f64>())
+                    .sum::<f64>();
+            }
+            PhaseAudit { added, removed, stored,
+                         unaccounted: added - removed - stored }
+        };
+        Some(MassAudit { gas: audit(Phase::Gas), liquid: audit(Phase::Liquid) })
+    }
+    /// Returns a number that goes up every time something stored on the map
+    /// changes.
+    pub fn change_count(&self) -> u64 {
+        self.changes.load(Ordering::Relaxed)
+    }
+    /// Records that the map, as of the given `change_count`, is safely saved.
+    ///
+    /// To get a `change_count` that matches what was saved, read it while
+    /// holding the map's write lock, the same as `snapshot`.
+    pub fn mark_saved(&self, change_count: u64) {
+        self.saved_changes.store(change_count, Ordering::Relaxed);
+    }
+    /// Returns `true` if something has changed since the map was last marked
+    /// as saved.
+    pub fn is_dirty(&self) -> bool {
+        self.change_count() != self.saved_changes.load(Ordering::Relaxed)
+    }
     /// Clears everything on the map.
     pub fn clear(&mut self) {
-        self.energy = HashMap::new();
-        self.gas_packets = HashMap::new();
-        self.liquid_packets = HashMap::new();
-        self.objects = HashMap::new();
-        self.registrations = HashMap::new();
+        for shard in self.shards.iter_mut() {
+            *shard.get_mut().unwrap() = MapShard::default();
+        }
+        *self.object_budget.get_mut().unwrap() = ObjectBudget::default();
+        *self.occupied_tiles.get_mut() = 0;
+    }
+    /// Clears everything on the map, like `clear`, but with clients still
+    /// around to hear about it: every registration gets an `Unregistered`
+    /// event, and every point that had something stored at it gets a
+    /// `TileChanged`. Returns how many points had something stored, and how
+    /// many registrations there were.
+    pub fn reset(&self) -> (usize, usize) {
+        let mut shards = self.all_shards();
+        let mut event_senders = self.event_senders.lock().unwrap();
+        let mut tile_count = 0;
+        let mut registration_count = 0;
+        for shard in shards.iter_mut() {
+            let old = std::mem::take(&mut **shard);
+            let occupied: HashSet<Point> = old.energy.keys()
+                .chain(old.gas_packets.keys())
+                .chain(old.liquid_packets.keys())
+                .chain(old.objects.keys())
+                .copied().collect();
+            tile_count += occupied.len();
+            for loc in occupied.into_iter() {
+                event_senders.send(MapEvent::TileChanged(loc));
+            }
+            for (loc, vec) in old.registrations.into_iter() {
+                for el in vec.into_iter() {
+                    registration_count += 1;
+                    event_senders.send(MapEvent::Unregistered(loc, el.what));
+                }
+            }
+        }
+        *self.object_budget.lock().unwrap() = ObjectBudget::default();
+        self.occupied_tiles.store(0, Ordering::Relaxed);
+        self.changes.fetch_add(1, Ordering::Relaxed);
+        (tile_count, registration_count)
     }
     /// Attempts to initialize the map with saved data from the given path.
     /// May leave the map in a partly-populated state on failure; you should
     /// call `clear` if that happens.
-    pub fn try_load(&mut self, path: &str) -> IoResult<()> {
+    ///
+    /// Either save format can be loaded, gzipped or not; which one the file
+    /// is in is decided by its first bytes.
+    ///
+    /// Anything in the file that doesn't make sense is left out, and counted
+    /// in the returned `LoadReport`.
+    pub fn try_load(&mut self, path: &str) -> IoResult<LoadReport> {
         self.clear();
-        let mut file = File::open(path)?;
-        let value = serde_json::from_reader(&mut file)?;
-        drop(file);
+        let mut file = BufReader::new(File::open(path)?);
+        if file.fill_buf()?.starts_with(GZIP_MAGIC) {
+            self.load_uncompressed(&mut BufReader::new(GzDecoder::new(file)))
+        }
+        else {
+            self.load_uncompressed(&mut file)
+        }
+    }
+    fn load_uncompressed(&mut self, file: &mut impl BufRead)
+                         -> IoResult<LoadReport> {
+        if file.fill_buf()?.first() == Some(&BINARY_MAGIC[0]) {
+            self.load_binary(file)
+        }
+        else {
+            self.load_json(file)
+        }
+    }
+    fn load_json(&mut self, file: &mut impl Read) -> IoResult<LoadReport> {
+        let mut report = LoadReport::default();
+        let max_object_size = self.limits.max_stored_object_size();
+        let value = serde_json::from_reader(file)?;
         let value = match value {
             Value::Object(x) => x,
             _ => return Err(errorize("saved map is not a JSON object"))
         };
         for (k,v) in value.into_iter() {
             let mut kit = k.split(",");
-            let (x, y) = match (kit.next(), kit.next(), kit.next()) {
-                (Some(x), Some(y), None) => (x, y),
-                _ => continue, // skip invalid points
+            // maps saved before the z coordinate existed have only x and y
+            let (x, y, z) = match (kit.next(), kit.next(), kit.next(),
+                                   kit.next()) {
+                (Some(x), Some(y), None, None) => (x, y, "0"),
+                (Some(x), Some(y), Some(z), None) => (x, y, z),
+                _ => { report.bad_points += 1; continue },
             };
-            let (x, y) = match (x.parse::<i32>(), y.parse::<i32>()) {
-                (Ok(x), Ok(y)) => (x, y),
-                _ => continue,
+            let (x, y, z) = match (x.parse::<i32>(), y.parse::<i32>(),
+                                   z.parse::<i32>()) {
+                (Ok(x), Ok(y), Ok(z)) => (x, y, z),
+                _ => { report.bad_points += 1; continue },
             };
-            let point = Point::new(x, y);
+            let point = Point::new(x, y, z);
             let tile = match v {
                 Value::Object(x) => x,
-                _ => continue, // skip invalid tiles
+                _ => { report.bad_tiles += 1; continue },
             };
-            match tile.get("energy") {
-                Some(Value::Number(x)) if x.is_u64() =>
-                    match x.as_u64().unwrap().try_into() {
-                        Ok(x) => { self.add_joules(point, x); },
-                        _ => (),
-                    },
-                _ => (),
+            match tile.get("energy").map(joules_from_value) {
+                Some(Some(x)) => { self.store_joules(point, x); },
+                Some(None) => report.bad_energy += 1,
+                None => (),
             };
             match tile.get("gas_packets") {
                 Some(Value::Array(x)) => {
                     for packet in x.iter() {
                         let packet = match serde_json::from_value::<MatPacket>(packet.clone()) {
-                            Ok(x) => x,
-                            Err(_) => continue,
+                            Ok(x) if x.validate(Phase::Gas, &self.limits.phase_limits).is_ok() => x,
+                            _ => { report.bad_packets += 1; continue },
                         };
                         self.add_packet(point, &packet, Phase::Gas);
                     }
@@ -374,8 +1694,8 @@ impl Map {
                 Some(Value::Array(x)) => {
                     for packet in x.iter() {
                         let packet = match serde_json::from_value::<MatPacket>(packet.clone()) {
-                            Ok(x) => x,
-                            Err(_) => continue,
+                            Ok(x) if x.validate(Phase::Liquid, &self.limits.phase_limits).is_ok() => x,
+                            _ => { report.bad_packets += 1; continue },
                         };
                         self.add_packet(point, &packet, Phase::Liquid);
                     }
@@ -387,66 +1707,281 @@ impl Map {
                     for object in x.iter() {
                         let object = match object {
                             Value::String(x) => x,
-                            _ => continue,
+                            _ => { report.bad_objects += 1; continue },
                         };
-                        if object.len() > MAX_OBJECT_ENCODED_SIZE { continue }
+                        if max_encoded_size(max_object_size)
+                        .map(|x| object.len() > x).unwrap_or(false) {
+                            report.oversized_objects += 1;
+                            continue
+                        }
                         let decoded = match base64::decode(object) {
-                            Ok(x) if x.len() <= MAX_OBJECT_SIZE => { x },
-                            _ => continue,
+                            Ok(x) if x.len() <= max_object_size => { x },
+                            Ok(_) => {
+                                report.oversized_objects += 1;
+                                continue
+                            },
+                            Err(_) => { report.bad_objects += 1; continue },
                         };
                         self.add_object(point, decoded);
                     }
                 },
                 _ => (),
             };
+            match tile.get("registrations") {
+                Some(Value::Array(x)) => {
+                    for registration in x.iter() {
+                        match (registration["identity"].as_str(),
+                               registration["what"].as_str()) {
+                            (Some(identity), Some(what)) =>
+                                self.restore_registration(point,
+                                                          identity.to_owned(),
+                                                          what.to_owned()),
+                            _ => report.bad_registrations += 1,
+                        }
+                    }
+                },
+                _ => (),
+            };
         }
-        Ok(())
+        Ok(report)
+    }
+    fn load_binary(&mut self, file: &mut impl Read) -> IoResult<LoadReport> {
+        let mut report = LoadReport::default();
+        let max_object_size = self.limits.max_stored_object_size();
+        let mut magic = [0; 8];
+        file.read_exact(&mut magic)?;
+        let has_registrations = if &magic == BINARY_MAGIC { true }
+        else if &magic == BINARY_MAGIC_V1 { false }
+        else {
+            return Err(errorize("saved map is not in a format we understand"))
+        };
+        let joules_format = read_u8(file)?;
+        if joules_format != JOULES_WHOLE && joules_format != JOULES_FRACTIONAL {
+            return Err(errorize("saved map is not in a format we understand"))
+        }
+        for _ in 0 .. read_u32(file)? {
+            let point = read_point(file)?;
+            let joules = if joules_format == JOULES_WHOLE {
+                read_u32(file)? as f64
+            } else { read_f64(file)? };
+            match joules_from_f64(joules) {
+                Some(x) => { self.store_joules(point, x); },
+                None => report.bad_energy += 1,
+            }
+        }
+        for &phase in &[Phase::Gas, Phase::Liquid] {
+            for _ in 0 .. read_u32(file)? {
+                let point = read_point(file)?;
+                for _ in 0 .. read_u32(file)? {
+                    let packet = MatPacket::read_binary(file)?;
+                    if packet.validate(phase, &self.limits.phase_limits)
+                    .is_err() {
+                        report.bad_packets += 1;
+                        continue
+                    }
+                    self.add_packet(point, &packet, phase);
+                }
+            }
+        }
+        for _ in 0 .. read_u32(file)? {
+            let point = read_point(file)?;
+            for _ in 0 .. read_u32(file)? {
+                let object = read_blob(file, max_object_size)?;
+                for _ in 0 .. read_u32(file)? {
+                    // (a stack too big to fit stops at the limit, instead of
+                    // trying every last copy)
+                    if !self.add_object(point, object.clone()) { break }
+                }
+            }
+        }
+        if has_registrations {
+            for _ in 0 .. read_u32(file)? {
+                let point = read_point(file)?;
+                for _ in 0 .. read_u32(file)? {
+                    let identity = read_string(file, MAX_SAVED_NAME)?;
+                    let what = read_string(file, MAX_SAVED_NAME)?;
+                    self.restore_registration(point, identity, what);
+                }
+            }
+        }
+        Ok(report)
+    }
+    /// Saves the map, in the given format, into memory. This is the only
+    /// part of saving that needs the map to hold still; `write_save` can put
+    /// the result on disk afterward.
+    pub fn snapshot(&self, format: SaveFormat) -> IoResult<Vec<u8>> {
+        let mut ret = Vec::new();
+        match format {
+            SaveFormat::Json => self.save_json(&mut ret)?,
+            SaveFormat::Binary => self.save_binary(&mut ret)?,
+        }
+        Ok(ret)
+    }
+    /// Returns everything on the map, in the same form as a JSON save: an
+    /// object with a `"x,y,z"` key for every occupied point. Registrations
+    /// are left out; they're only saved so their owners can claim them
+    /// again, and they're nobody else's business.
+    pub fn to_json(&self) -> IoResult<serde_json::Map<String, Value>> {
+        self.tiles_to_json(false)
     }
-    /// Attempt to save the map to the given path.
-    pub fn try_save(&self, path: &str) -> IoResult<()> {
+    fn tiles_to_json(&self, with_registrations: bool)
+                     -> IoResult<serde_json::Map<String, Value>> {
         let mut saved: serde_json::Map<String, Value> = serde_json::Map::new();
-        for (k, v) in self.energy.iter() {
-            if *v > 0 {
-                set_tile_key(&mut saved, *k, "energy",
-                             Value::Number((*v).into()))
+        // lock everything up front, so the save is a consistent snapshot
+        let shards = self.all_shards();
+        for shard in shards.iter() {
+            for (k, v) in shard.energy.iter() {
+                if *v > 0 as Joules {
+                    set_tile_key(&mut saved, *k, "energy", json!(*v))
+                }
+            }
+            for (k, v) in shard.gas_packets.iter() {
+                if v.len() > 0 {
+                    let mut arr = Vec::new();
+                    for packet in v.iter() {
+                        arr.push(serde_json::to_value(packet)?);
+                    }
+                    set_tile_key(&mut saved, *k, "gas_packets",
+                                 Value::Array(arr))
+                }
+            }
+            for (k, v) in shard.liquid_packets.iter() {
+                if v.len() > 0 {
+                    let mut arr = Vec::new();
+                    for packet in v.iter() {
+                        arr.push(serde_json::to_value(packet)?);
+                    }
+                    set_tile_key(&mut saved, *k, "liquid_packets",
+                                 Value::Array(arr))
+                }
+            }
+            for (k, v) in shard.objects.iter() {
+                if v.len() > 0 {
+                    let mut arr = Vec::new();
+                    // stacks are saved as that many copies, so the file
+                    // format doesn't care whether stacking is on
+                    for (object, count, _) in v.iter() {
+                        let encoded = base64::encode(object);
+                        for _ in 0 .. *count {
+                            arr.push(Value::String(encoded.clone()));
+                        }
+                    }
+                    set_tile_key(&mut saved, *k, "objects",
+                                 Value::Array(arr))
+                }
+            }
+            if !with_registrations { continue }
+            for (k, v) in shard.registrations.iter() {
+                let arr: Vec<Value> = v.iter().filter_map(|x| {
+                    x.identity.as_ref().map(|identity| json!({
+                        "identity": identity,
+                        "what": x.what,
+                    }))
+                }).collect();
+                if !arr.is_empty() {
+                    set_tile_key(&mut saved, *k, "registrations",
+                                 Value::Array(arr))
+                }
             }
         }
-        for (k, v) in self.gas_packets.iter() {
-            if v.len() > 0 {
-                let mut arr = Vec::new();
-                for packet in v.iter() {
-                    arr.push(serde_json::to_value(packet)?);
+        drop(shards);
+        Ok(saved)
+    }
+    fn save_json(&self, file: &mut impl Write) -> IoResult<()> {
+        serde_json::to_writer(file, &Value::Object(self.tiles_to_json(true)?))?;
+        Ok(())
+    }
+    fn save_binary(&self, file: &mut impl Write) -> IoResult<()> {
+        // lock everything up front, so the save is a consistent snapshot
+        let shards = self.all_shards();
+        file.write_all(BINARY_MAGIC)?;
+        write_u8(file, if cfg!(feature = "float_energy") { JOULES_FRACTIONAL }
+                 else { JOULES_WHOLE })?;
+        write_len(file, shards.iter().map(|shard| {
+            shard.energy.values().filter(|x| **x > 0 as Joules).count()
+        }).sum())?;
+        for shard in shards.iter() {
+            for (k, v) in shard.energy.iter() {
+                if *v > 0 as Joules {
+                    write_point(file, *k)?;
+                    write_joules(file, *v)?;
                 }
-                set_tile_key(&mut saved, *k, "gas_packets",
-                             Value::Array(arr))
             }
         }
-        for (k, v) in self.liquid_packets.iter() {
-            if v.len() > 0 {
-                let mut arr = Vec::new();
-                for packet in v.iter() {
-                    arr.push(serde_json::to_value(packet)?);
+        for &phase in &[Phase::Gas, Phase::Liquid] {
+            let storages: Vec<_> = shards.iter().map(|shard| match phase {
+                Phase::Gas => &shard.gas_packets,
+                Phase::Liquid => &shard.liquid_packets,
+            }).collect();
+            write_len(file, storages.iter().map(|storage| {
+                storage.values().filter(|x| !x.is_empty()).count()
+            }).sum())?;
+            for storage in storages.iter() {
+                for (k, v) in storage.iter() {
+                    if v.is_empty() { continue }
+                    write_point(file, *k)?;
+                    write_len(file, v.len())?;
+                    for packet in v.iter() {
+                        packet.write_binary(file)?;
+                    }
                 }
-                set_tile_key(&mut saved, *k, "liquid_packets",
-                             Value::Array(arr))
             }
         }
-        for (k, v) in self.objects.iter() {
-            if v.len() > 0 {
-                let mut arr = Vec::new();
-                for object in v.iter() {
-                    arr.push(Value::String(base64::encode(object)));
+        write_len(file, shards.iter().map(|shard| {
+            shard.objects.values().filter(|x| !x.is_empty()).count()
+        }).sum())?;
+        for shard in shards.iter() {
+            for (k, v) in shard.objects.iter() {
+                if v.is_empty() { continue }
+                write_point(file, *k)?;
+                write_len(file, v.len())?;
+                for (object, count, _) in v.iter() {
+                    write_blob(file, object)?;
+                    write_u32(file, *count)?;
+                }
+            }
+        }
+        let saved_registrations = |v: &Vec<Registration>| {
+            v.iter().filter(|x| x.identity.is_some()).count()
+        };
+        write_len(file, shards.iter().map(|shard| {
+            shard.registrations.values()
+                .filter(|x| saved_registrations(x) > 0).count()
+        }).sum())?;
+        for shard in shards.iter() {
+            for (k, v) in shard.registrations.iter() {
+                let count = saved_registrations(v);
+                if count == 0 { continue }
+                write_point(file, *k)?;
+                write_len(file, count)?;
+                for registration in v.iter() {
+                    if let Some(identity) = &registration.identity {
+                        write_blob(file, identity.as_bytes())?;
+                        write_blob(file, registration.what.as_bytes())?;
+                    }
                 }
-                set_tile_key(&mut saved, *k, "objects",
-                             Value::Array(arr))
             }
         }
-        let mut file = File::create(path)?;
-        serde_json::to_writer(&mut file, &Value::Object(saved))?;
         Ok(())
     }
 }
 
+/// Writes a `Map::snapshot` to the given path, gzipped if `compress` is
+/// `true`.
+pub fn write_save(path: &str, snapshot: &[u8], compress: bool)
+                  -> IoResult<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    if compress {
+        let mut gz = GzEncoder::new(file, Compression::default());
+        gz.write_all(snapshot)?;
+        file = gz.finish()?;
+    }
+    else {
+        file.write_all(snapshot)?;
+    }
+    file.flush()
+}
+
 fn set_tile_key(saved: &mut serde_json::Map<String, Value>, point: Point,
                 key: &str, value: Value) {
     let point = point.as_string();
@@ -465,3 +2000,497 @@ fn set_tile_key(saved: &mut serde_json::Map<String, Value>, point: Point,
         },
     }
 }
+
+/// Reads an amount of energy out of a saved map.
+#[cfg(not(feature = "float_energy"))]
+fn joules_from_value(value: &Value) -> Option<Joules> {
+    match value {
+        Value::Number(x) if x.is_u64() => x.as_u64().unwrap().try_into().ok(),
+        // a map saved with fractional energy; keep the whole joules
+        Value::Number(x) => x.as_f64().and_then(joules_from_f64),
+        _ => None,
+    }
+}
+
+/// Reads an amount of energy out of a saved map.
+#[cfg(feature = "float_energy")]
+fn joules_from_value(value: &Value) -> Option<Joules> {
+    value.as_f64().and_then(joules_from_f64)
+}
+
+/// Converts an amount of energy from a saved map, which may have been made
+/// with or without `float_energy`.
+#[cfg(not(feature = "float_energy"))]
+fn joules_from_f64(x: f64) -> Option<Joules> {
+    if x.is_finite() && x >= 0.0 && x <= u32::MAX as f64 { Some(x as u32) }
+    else { None }
+}
+
+/// Converts an amount of energy from a saved map, which may have been made
+/// with or without `float_energy`.
+#[cfg(feature = "float_energy")]
+fn joules_from_f64(x: f64) -> Option<Joules> {
+    if x.is_finite() && x >= 0.0 { Some(x) } else { None }
+}
+
+#[cfg(not(feature = "float_energy"))]
+fn write_joules(w: &mut impl Write, amt: Joules) -> IoResult<()> {
+    write_u32(w, amt)
+}
+
+#[cfg(feature = "float_energy")]
+fn write_joules(w: &mut impl Write, amt: Joules) -> IoResult<()> {
+    write_f64(w, amt)
+}
+
+/// Turns negative or non-finite amounts of energy into zero.
+#[cfg(feature = "float_energy")]
+fn sanitize_joules(amt: Joules) -> Joules {
+    if amt.is_finite() { amt.max(0.0) } else { 0.0 }
+}
+
+/// Applies one step of energy decay. Partial joules are rounded down, so every
+/// point eventually runs dry.
+#[cfg(not(feature = "float_energy"))]
+fn decayed_joules(joules: Joules, factor: f64) -> Joules {
+    (joules as f64 * factor) as u32
+}
+
+/// Applies one step of energy decay. Amounts too small to matter are rounded
+/// down to zero, so every point eventually runs dry.
+#[cfg(feature = "float_energy")]
+fn decayed_joules(joules: Joules, factor: f64) -> Joules {
+    let ret = joules * factor;
+    if ret < MIN_DECAYED_JOULES { 0.0 } else { ret }
+}
+
+/// Below this many joules, decaying energy is considered gone.
+#[cfg(feature = "float_energy")]
+const MIN_DECAYED_JOULES: f64 = 0.001;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn packet(element: i32, mass: f32) -> MatPacket {
+        serde_json::from_value(serde_json::json!({
+            "element": element, "mass": mass, "temperature": 300.0,
+            "germs": null,
+        })).unwrap()
+    }
+
+    #[test]
+    fn chunked_sized_objects_survive_saving() {
+        let limits = MapLimits { max_object_size: 10,
+                                 max_chunked_object_size: 100,
+                                 ..Default::default() };
+        let loc = Point::new(0, 0, 0);
+        for &format in &[SaveFormat::Json, SaveFormat::Binary] {
+            let map = Map::new(limits.clone());
+            assert!(map.add_object(loc, vec![7; 100]));
+            let path = std::env::temp_dir()
+                .join(format!("onizd-test-{}-{:?}", std::process::id(),
+                              format));
+            std::fs::write(&path, map.snapshot(format).unwrap()).unwrap();
+            let mut loaded = Map::new(limits.clone());
+            let report = loaded.try_load(path.to_str().unwrap());
+            let _ = std::fs::remove_file(&path);
+            assert_eq!(report.unwrap().oversized_objects, 0);
+            assert_eq!(loaded.pop_object(loc), Some(vec![7; 100]));
+        }
+    }
+
+    #[test]
+    fn huge_limits_are_not_allocated_up_front() {
+        let map = Map::new(MapLimits {
+            max_stored_packets: usize::MAX,
+            max_stored_objects: usize::MAX,
+            ..Default::default()
+        });
+        let loc = Point::new(0, 0, 0);
+        assert_eq!(map.add_packet(loc, &packet(1, 0.5), Phase::Gas).1, None);
+        assert!(map.add_object(loc, vec![1, 2, 3]));
+        assert_eq!(map.pop_object(loc), Some(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn massless_packets_are_not_stored() {
+        let map = Map::new(MapLimits::default());
+        let a = Point::new(0, 0, 0);
+        assert_eq!(map.add_packet(a, &packet(1, 0.0), Phase::Gas), (0.0, None));
+        assert_eq!(map.add_packet(a, &packet(1, 0.0), Phase::Gas), (0.0, None));
+        assert_eq!(map.occupied_tile_count(), 0);
+        assert!(map.pop_packet(a, Phase::Gas).is_none());
+        // and one with mass doesn't merge with anything that would poison it
+        map.add_packet(a, &packet(1, 0.5), Phase::Gas);
+        let popped = map.pop_packet(a, Phase::Gas).unwrap();
+        assert_eq!(popped.get_mass(), 0.5);
+        assert!(serde_json::to_value(popped).unwrap()["temperature"].is_f64());
+    }
+
+    fn element_of(packet: &MatPacket) -> i64 {
+        serde_json::to_value(packet).unwrap()["element"].as_i64().unwrap()
+    }
+
+    #[test]
+    fn packets_come_out_in_order() {
+        let map = Map::new(MapLimits::default());
+        let a = Point::new(0, 0, 0);
+        let pop = || map.pop_packet(a, Phase::Gas)
+            .map(|x| (element_of(&x), x.get_mass()));
+        for element in 1 ..= 3 {
+            map.add_packet(a, &packet(element, 0.75), Phase::Gas);
+        }
+        // tops up the first packet, and the rest goes on the end
+        map.add_packet(a, &packet(1, 0.5), Phase::Gas);
+        assert_eq!(pop(), Some((1, 1.0)));
+        map.add_packet(a, &packet(2, 0.5), Phase::Gas);
+        assert_eq!(pop(), Some((2, 1.0)));
+        assert_eq!(pop(), Some((3, 0.75)));
+        assert_eq!(pop(), Some((1, 0.25)));
+        assert_eq!(pop(), Some((2, 0.25)));
+        assert_eq!(pop(), None);
+    }
+
+    #[test]
+    fn packets_churn_fairly() {
+        let map = Map::new(MapLimits::default());
+        let a = Point::new(0, 0, 0);
+        let (mut added, mut popped) = (0.0f64, 0.0f64);
+        let mut pops = [0usize; 3];
+        for n in 0 .. 3000 {
+            let element = n % 3;
+            let (spare, why) = map.add_packet(a, &packet(element, 0.375),
+                                              Phase::Gas);
+            assert_eq!((spare, why), (0.0, None));
+            added += 0.375;
+            if n % 2 == 1 {
+                let packet = map.pop_packet(a, Phase::Gas).unwrap();
+                popped += packet.get_mass() as f64;
+                pops[element_of(&packet) as usize] += 1;
+            }
+            // at most one non-full packet of each element
+            let mut shard = map.shard(a);
+            let queue = shard.packets(Phase::Gas).get(&a).unwrap();
+            let mut partial = [0; 3];
+            for packet in queue.iter().filter(|x| x.get_mass() < 1.0) {
+                partial[element_of(packet) as usize] += 1;
+            }
+            assert!(partial.iter().all(|&x| x <= 1), "{:?}", partial);
+        }
+        // nobody got starved
+        assert!(pops.iter().all(|&x| x >= 400), "{:?}", pops);
+        while let Some(packet) = map.pop_packet(a, Phase::Gas) {
+            popped += packet.get_mass() as f64;
+        }
+        assert_eq!(added, popped);
+    }
+
+    #[test]
+    fn mixing_stays_within_a_point_and_phase() {
+        let map = Map::new(MapLimits::default());
+        let (a, b) = (Point::new(0, 0, 0), Point::new(1, 0, 0));
+        let hot = |element, mass| {
+            let mut json = serde_json::to_value(packet(element, mass))
+                .unwrap();
+            json["temperature"] = serde_json::json!(400.0);
+            serde_json::from_value::<MatPacket>(json).unwrap()
+        };
+        map.add_packet(a, &packet(1, 0.5), Phase::Gas);
+        map.add_packet(a, &hot(2, 0.5), Phase::Gas);
+        map.add_packet(a, &hot(3, 0.5), Phase::Liquid);
+        map.add_packet(b, &hot(1, 0.5), Phase::Gas);
+        map.mix_packet_temperatures();
+        let temperature = |loc, phase| {
+            let packet = map.pop_packet(loc, phase).unwrap();
+            serde_json::to_value(packet).unwrap()["temperature"].as_f64()
+                .unwrap()
+        };
+        assert_eq!(temperature(a, Phase::Gas), 350.0);
+        assert_eq!(temperature(a, Phase::Gas), 350.0);
+        assert_eq!(temperature(a, Phase::Liquid), 400.0);
+        assert_eq!(temperature(b, Phase::Gas), 400.0);
+    }
+
+    #[test]
+    fn stored_germs_decay() {
+        let map = Map::new(MapLimits::default());
+        let a = Point::new(0, 0, 0);
+        let germy: MatPacket = serde_json::from_value(serde_json::json!({
+            "element": 1, "mass": 0.5, "temperature": 300.0,
+            "germs": {"id": 3, "count": 1000},
+        })).unwrap();
+        map.add_packet(a, &germy, Phase::Liquid);
+        map.decay_germs(0.5);
+        let packet = map.pop_packet(a, Phase::Liquid).unwrap();
+        assert_eq!(serde_json::to_value(packet).unwrap()["germs"],
+                   serde_json::json!({"id": 3, "count": 500}));
+        map.add_packet(a, &packet, Phase::Liquid);
+        for _ in 0 .. 10 { map.decay_germs(0.5) }
+        let packet = map.pop_packet(a, Phase::Liquid).unwrap();
+        assert!(serde_json::to_value(packet).unwrap()["germs"].is_null());
+        assert_eq!(packet.get_mass(), 0.5);
+    }
+
+    #[test]
+    fn registrations_are_limited_per_client_and_in_total() {
+        let map = Map::new(MapLimits {
+            max_registrations: 2,
+            max_total_registrations: Some(3),
+            ..MapLimits::default()
+        });
+        let (a, b) = (Point::new(0, 0, 0), Point::new(1, 0, 0));
+        let register = |loc, client_id| map.register(loc, client_id, None,
+                                                      "WirelessRecver".into());
+        assert_eq!(register(a, 1), Ok(()));
+        assert_eq!(register(a, 1), Ok(()));
+        assert_eq!(register(a, 1), Err(RegistrationRefusal::TooManyForClient));
+        assert_eq!(register(a, 2), Ok(()));
+        assert_eq!(register(a, 2), Err(RegistrationRefusal::TooManyTotal));
+        assert_eq!(register(a, 3), Err(RegistrationRefusal::TooManyTotal));
+        // moving one in counts the same as registering it there
+        assert_eq!(register(b, 3), Ok(()));
+        assert_eq!(map.move_registration(b, a, 3, None, "WirelessRecver"),
+                   Err(RegistrationRefusal::TooManyTotal));
+        map.unregister(a, 2, None, "WirelessRecver");
+        assert_eq!(map.move_registration(b, a, 3, None, "WirelessRecver"),
+                   Ok(()));
+    }
+
+    #[test]
+    fn old_objects_expire() {
+        let map = Map::new(MapLimits { stack_objects: true,
+                                       ..MapLimits::default() });
+        let (a, b) = (Point::new(0, 0, 0), Point::new(1, 0, 0));
+        let ttl = Duration::from_millis(50);
+        map.add_object(a, vec![1]);
+        map.add_object(a, vec![1]);
+        map.add_object(a, vec![2]);
+        std::thread::sleep(ttl);
+        map.add_object(b, vec![3]);
+        assert_eq!(map.expire_objects(Duration::from_secs(3600)), 0);
+        assert_eq!(map.expire_objects(ttl), 3);
+        assert_eq!(map.pop_object(a), None);
+        assert_eq!(map.occupied_tile_count(), 1);
+        assert_eq!(map.pop_object(b), Some(vec![3]));
+        assert_eq!(map.occupied_tile_count(), 0);
+    }
+
+    #[test]
+    fn energy_spills_down_the_chain() {
+        let j = |x: u16| Joules::from(x);
+        let map = Map::new(MapLimits { max_stored_energy: 100,
+                                       energy_spill_to: Some((0, 1, 0)),
+                                       ..MapLimits::default() });
+        let at = |y| Point::new(0, y, 0);
+        assert_eq!(map.add_joules(at(0), j(250)), j(0));
+        assert_eq!(map.sub_joules(at(2), j(1000)), j(50));
+        assert_eq!(map.sub_joules(at(1), j(1000)), j(100));
+        assert_eq!(map.sub_joules(at(0), j(1000)), j(100));
+        // (only onward, never back)
+        assert_eq!(map.add_joules(at(5), j(150)), j(0));
+        assert_eq!(map.sub_joules(at(4), j(1000)), j(0));
+        assert_eq!(map.sub_joules(at(6), j(1000)), j(50));
+        // without spilling, the spare comes straight back
+        let map = Map::new(MapLimits { max_stored_energy: 100,
+                                       ..MapLimits::default() });
+        assert_eq!(map.add_joules(at(0), j(250)), j(150));
+        assert_eq!(map.sub_joules(at(1), j(1000)), j(0));
+    }
+
+    #[test]
+    fn energy_spills_only_so_far() {
+        let j = |x: u16| Joules::from(x);
+        let map = Map::new(MapLimits { max_stored_energy: 100,
+                                       energy_spill_to: Some((1, 0, 0)),
+                                       ..MapLimits::default() });
+        let steps = MAX_SPILL_STEPS as u16;
+        let sent = j(100 * (steps + 1) + 30);
+        assert_eq!(map.add_joules(Point::new(0, 0, 0), sent), j(30));
+        assert_eq!(map.occupied_tile_count(), MAX_SPILL_STEPS + 1);
+        // once every point along the way is full, it all comes back
+        assert_eq!(map.add_joules(Point::new(0, 0, 0), j(500)), j(500));
+        assert_eq!(map.sub_joules(Point::new(steps as i32, 0, 0), j(1000)),
+                   j(100));
+        assert_eq!(map.add_joules(Point::new(0, 0, 0), j(500)), j(400));
+    }
+
+    /// Counts the occupied points the slow way, to check the fast way
+    /// against.
+    fn count_occupied(map: &Map) -> usize {
+        map.all_shards().iter().map(|x| x.occupied_points().len()).sum()
+    }
+
+    #[test]
+    fn occupied_tiles_are_counted() {
+        let map = Map::new(MapLimits::default());
+        let check = |map: &Map| assert_eq!(map.occupied_tile_count(),
+                                           count_occupied(map));
+        let (a, b, c) = (Point::new(0, 0, 0), Point::new(1, 0, 0),
+                         Point::new(2, 0, 0));
+        map.add_joules(a, Joules::from(5u8));
+        map.add_joules(a, Joules::from(5u8));
+        check(&map);
+        assert_eq!(map.occupied_tile_count(), 1);
+        map.add_packet(b, &packet(1, 0.5), Phase::Gas);
+        map.add_object(c, vec![1]);
+        check(&map);
+        assert_eq!(map.occupied_tile_count(), 3);
+        map.transfer_packet(b, c, Phase::Gas);
+        check(&map);
+        assert_eq!(map.occupied_tile_count(), 2);
+        map.transfer_object(c, b);
+        map.transfer_joules(a, b, Joules::from(10u8));
+        check(&map);
+        map.sub_joules(b, Joules::from(10u8));
+        map.pop_packet(c, Phase::Gas);
+        check(&map);
+        assert_eq!(map.occupied_tile_count(), 1);
+        map.clear_tile(b);
+        check(&map);
+        assert_eq!(map.occupied_tile_count(), 0);
+        map.add_joules(a, Joules::from(1u8));
+        map.decay_energy(0.0);
+        check(&map);
+        map.add_object(a, vec![1]);
+        map.expire_objects(Duration::from_secs(0));
+        check(&map);
+        map.add_joules(a, Joules::from(1u8));
+        map.reset();
+        check(&map);
+    }
+
+    #[test]
+    fn max_tiles_turns_away_new_points_only() {
+        let map = Map::new(MapLimits { max_tiles: Some(2),
+                                       ..Default::default() });
+        let (a, b, c) = (Point::new(0, 0, 0), Point::new(1, 0, 0),
+                         Point::new(2, 0, 0));
+        let one = Joules::from(1u8);
+        assert_eq!(map.add_joules(a, one), Joules::from(0u8));
+        assert!(map.add_object(b, vec![1]));
+        assert_eq!(map.add_joules(c, one), one);
+        assert_eq!(map.add_packet(c, &packet(1, 0.5), Phase::Gas).1,
+                   Some(PacketRefusal::MapFull));
+        assert!(!map.add_object(c, vec![1]));
+        // (points that are already occupied can still take more)
+        assert_eq!(map.add_joules(a, one), Joules::from(0u8));
+        assert!(map.add_object(b, vec![2]));
+        assert_eq!(map.add_packet(a, &packet(1, 0.5), Phase::Gas).1, None);
+        assert_eq!(map.transfer_joules(a, c, one), Joules::from(0u8));
+        // once a point empties out, there's room for another
+        map.clear_tile(a);
+        assert_eq!(map.add_joules(c, one), Joules::from(0u8));
+        assert_eq!(map.occupied_tile_count(), 2);
+    }
+
+    #[test]
+    fn max_tiles_holds_under_contention() {
+        let map = Arc::new(Map::new(MapLimits { max_tiles: Some(10),
+                                                ..Default::default() }));
+        let threads: Vec<_> = (0 .. 8).map(|n| {
+            let map = map.clone();
+            std::thread::spawn(move || {
+                for x in 0 .. 100 {
+                    map.add_joules(Point::new(x, n, 0), Joules::from(1u8));
+                }
+            })
+        }).collect();
+        for thread in threads { thread.join().unwrap() }
+        assert_eq!(map.occupied_tile_count(), 10);
+        assert_eq!(count_occupied(&map), 10);
+    }
+
+    fn tile_changes(events: &mut EventReceiver) -> Vec<Point> {
+        let mut ret = Vec::new();
+        while let Some(event) = events.try_recv() {
+            if let MapEvent::TileChanged(loc) = event { ret.push(loc) }
+        }
+        ret
+    }
+
+    #[test]
+    fn tile_changes_only_go_to_watchers() {
+        let map = Map::new(MapLimits::default());
+        let mut nobody = map.get_events();
+        let mut one = map.get_events();
+        let mut boxed = map.get_events();
+        let here = Point::new(1, 1, 0);
+        let there = Point::new(50, 50, 0);
+        one.watch(vec![(here, here)]);
+        boxed.watch(vec![(Point::new(0, 0, 0), Point::new(10, 10, 0))]);
+        map.add_joules(here, Joules::from(5u8));
+        map.add_joules(there, Joules::from(5u8));
+        assert_eq!(tile_changes(&mut nobody), vec![]);
+        assert_eq!(tile_changes(&mut one), vec![here]);
+        assert_eq!(tile_changes(&mut boxed), vec![here]);
+        // (nothing queued for tiles it isn't watching counts against it)
+        for _ in 0 .. MAX_QUEUED_TILE_CHANGES + 1 {
+            map.add_joules(there, Joules::from(1u8));
+        }
+        assert_eq!(one.take_dropped(), 0);
+        one.watch(vec![]);
+        map.add_joules(here, Joules::from(5u8));
+        assert_eq!(tile_changes(&mut one), vec![]);
+    }
+
+    #[test]
+    fn stalled_receivers_drop_tile_changes() {
+        let map = Map::new(MapLimits::default());
+        let mut stalled = map.get_events();
+        let here = Point::new(0, 0, 0);
+        stalled.watch(vec![(here, here)]);
+        for _ in 0 .. MAX_QUEUED_TILE_CHANGES + 10 {
+            map.add_joules(here, Joules::from(1u8));
+        }
+        assert_eq!(stalled.take_dropped(), 10);
+        assert_eq!(stalled.take_dropped(), 0);
+        // registrations still get through, after the tile changes
+        map.register(here, 1, None, "WirelessRecver".into()).unwrap();
+        assert_eq!(tile_changes(&mut stalled).len(), MAX_QUEUED_TILE_CHANGES);
+        // (`tile_changes` throws away anything else)
+        map.unregister(here, 1, None, "WirelessRecver");
+        assert!(matches!(stalled.try_recv(),
+                         Some(MapEvent::Unregistered(_, _))));
+        assert!(stalled.try_recv().is_none());
+    }
+
+    #[test]
+    fn stalled_receivers_resync_registrations() {
+        let map = Map::new(MapLimits::default());
+        let mut stalled = map.get_events();
+        let mut keeping_up = map.get_events();
+        let here = Point::new(0, 0, 0);
+        let there = Point::new(1, 0, 0);
+        let limit = MAX_QUEUED_TILE_CHANGES + MAX_QUEUED_REGISTRATION_EVENTS;
+        for _ in 0 .. limit / 2 + 1 {
+            map.register(here, 1, None, "WirelessRecver".into()).unwrap();
+            map.unregister(here, 1, None, "WirelessRecver");
+            while keeping_up.try_recv().is_some() {}
+        }
+        map.register(there, 1, None, "WirelessSender".into()).unwrap();
+        assert!(matches!(keeping_up.try_recv(),
+                         Some(MapEvent::Registered(_, _))));
+        // it keeps hearing about it until it resyncs
+        for _ in 0 .. 2 {
+            assert!(matches!(stalled.try_recv(), Some(MapEvent::Overflowed)));
+        }
+        assert_eq!(map.resync_events(&mut stalled),
+                   vec![(there, "WirelessSender".to_owned())]);
+        assert!(stalled.try_recv().is_none());
+        map.unregister(there, 1, None, "WirelessSender");
+        assert!(matches!(stalled.try_recv(),
+                         Some(MapEvent::Unregistered(_, _))));
+    }
+
+    #[test]
+    fn closed_receivers_are_forgotten() {
+        let map = Map::new(MapLimits::default());
+        let _kept = map.get_events();
+        drop(map.get_events());
+        assert_eq!(map.event_senders.lock().unwrap().vec.len(), 2);
+        map.register(Point::new(0, 0, 0), 1, None, "WirelessRecver".into())
+            .unwrap();
+        assert_eq!(map.event_senders.lock().unwrap().vec.len(), 1);
+    }
+}
@@ -18,11 +18,28 @@
  */
 
 use std::fmt::{Debug,Display,Formatter};
+use std::io::{Read, Write, Result as IoResult};
 use serde::{Serialize,Deserialize};
 use crate::*;
+use crate::savefile::*;
+
+/// The default most mass, in kg, a gas packet can have. (The game's own
+/// limit, unless it's been modded.)
+pub const DEFAULT_GAS_STACK: f32 = 1.0;
+/// The default most mass, in kg, a liquid packet can have.
+pub const DEFAULT_LIQUID_STACK: f32 = 10.0;
 
 #[derive(Clone,Copy,Debug,PartialEq,Eq,Serialize,Deserialize)]
 pub enum Phase { Gas, Liquid }
+/// How much mass a packet of each phase can have. The defaults are the
+/// constants above.
+#[derive(Clone,Copy,Debug,PartialEq)]
+pub struct PhaseLimits {
+    /// See `DEFAULT_GAS_STACK`.
+    pub gas_stack: f32,
+    /// See `DEFAULT_LIQUID_STACK`.
+    pub liquid_stack: f32,
+}
 #[derive(Clone,Copy,Debug,PartialEq,Serialize,Deserialize)]
 pub struct MatPacket {
     element: i32,
@@ -36,11 +53,20 @@ pub struct Germs {
     count: i32,
 }
 
-impl Phase {
-    pub fn get_max_stack_size(&self) -> f32 {
-        match self {
-            &Phase::Gas => 1.0,
-            &Phase::Liquid => 10.0,
+impl Default for PhaseLimits {
+    fn default() -> PhaseLimits {
+        PhaseLimits {
+            gas_stack: DEFAULT_GAS_STACK,
+            liquid_stack: DEFAULT_LIQUID_STACK,
+        }
+    }
+}
+
+impl PhaseLimits {
+    pub fn get_max_stack_size(&self, phase: Phase) -> f32 {
+        match phase {
+            Phase::Gas => self.gas_stack,
+            Phase::Liquid => self.liquid_stack,
         }
     }
 }
@@ -52,12 +78,22 @@ impl MatPacket {
     /// - `None`: The merge was impossible
     /// - `Some((MatPacket, None))`: Merging resulted in one packet
     /// - `Some((MatPacket, Some(MatPacket)))`: Merging resulted in two packets
-    pub fn merge(&self, other: &MatPacket, phase: Phase)
+    ///
+    /// Germs go along with the mass that carries them. When all of `other`'s
+    /// mass merges, there's no second packet to hold germs that didn't make it
+    /// into the first one, so they're dropped. That happens to the losing
+    /// stack when two kinds of germ meet, and to whatever wouldn't fit under
+    /// `i32::MAX`.
+    pub fn merge(&self, other: &MatPacket, phase: Phase,
+                 limits: &PhaseLimits)
                  -> Option<(MatPacket,Option<MatPacket>)> {
         // can't merge different elements
         if self.element != other.element { return None }
+        // can't merge massless packets (the temperature and germ calculations
+        // below would divide by zero)
+        if !self.has_mass() || !other.has_mass() { return None }
         let element = self.element;
-        let max = phase.get_max_stack_size();
+        let max = limits.get_max_stack_size(phase);
         let room = max - self.mass;
         // can't merge above max mass
         if room <= 0.0 { return None }
@@ -71,6 +107,7 @@ impl MatPacket {
                           + other.temperature * buff) / (self.mass + buff),
             germs: germs.0,
         };
+        // (if `leftover` is zero, `germs.1` goes nowhere; see above)
         let rest = if leftover > 0.0 {
             Some(MatPacket {
                 element,
@@ -81,15 +118,113 @@ impl MatPacket {
         } else { None };
         Some((merged, rest))
     }
+    pub fn get_mass(&self) -> f32 { self.mass }
+    /// Brings the given packets to the same temperature, as if they had been
+    /// sharing a tile for a while: the mass-weighted average of their
+    /// temperatures. Nothing else about them changes. Returns `true` if any
+    /// temperatures changed.
+    pub fn mix_temperatures<'a, I>(packets: I) -> bool
+    where I: IntoIterator<Item=&'a mut MatPacket> {
+        let mut packets: Vec<&mut MatPacket> = packets.into_iter()
+            .filter(|x| x.has_mass()).collect();
+        let first = match packets.first() {
+            None => return false,
+            Some(x) => x.temperature,
+        };
+        // (averaging already-equal temperatures could still nudge them, by
+        // rounding, and we'd go on "changing" them forever)
+        if packets.iter().all(|x| x.temperature == first) { return false }
+        let (mass, heat) = packets.iter().fold((0.0f64, 0.0f64), |(m, h), x| {
+            (m + x.mass as f64, h + x.mass as f64 * x.temperature as f64)
+        });
+        let temperature = (heat / mass) as f32;
+        for packet in packets.iter_mut() { packet.temperature = temperature }
+        true
+    }
+    /// Multiplies this packet's germ count by `factor`, which should be
+    /// between 0 and 1, rounding toward zero. Once no germs are left, the
+    /// packet has no germs at all. Nothing else about it changes. Returns
+    /// `true` if the germs changed.
+    pub fn decay_germs(&mut self, factor: f64) -> bool {
+        let germs = match self.germs {
+            None => return false,
+            Some(x) => x,
+        };
+        self.germs = germs.decayed(factor);
+        self.germs != Some(germs)
+    }
+    /// Returns `true` if this packet has some mass, `false` if it's empty (or
+    /// its mass is nonsensical).
+    pub fn has_mass(&self) -> bool {
+        self.mass > 0.0
+    }
+    /// Returns `false` if the loaded element table (see `--element-names`)
+    /// says this packet's element isn't in the given phase. Elements it
+    /// doesn't give a state for, or no table at all, get the benefit of the
+    /// doubt.
+    pub fn is_element_in_phase(&self, phase: Phase) -> bool {
+        match get_element_state(self.element) {
+            None => true,
+            Some(state) => state == match phase {
+                Phase::Gas => ElementState::Gas,
+                Phase::Liquid => ElementState::Liquid,
+            },
+        }
+    }
     /// Returns `true` if more mass could be added to this packet, `false`
     /// otherwise.
-    pub fn has_room(&self, phase: Phase) -> bool {
-        return self.mass < phase.get_max_stack_size();
+    pub fn has_room(&self, phase: Phase, limits: &PhaseLimits) -> bool {
+        return self.mass < limits.get_max_stack_size(phase);
     }
     /// Returns `true` if this packet is *larger* than it is allowed to be,
     /// false otherwise/
-    pub fn is_oversized(&self, phase: Phase) -> bool {
-        return self.mass > phase.get_max_stack_size();
+    pub fn is_oversized(&self, phase: Phase, limits: &PhaseLimits) -> bool {
+        return self.mass > limits.get_max_stack_size(phase);
+    }
+    /// Checks that a packet received from a client makes sense: its mass and
+    /// temperature must be finite and non-negative, it must not be oversized,
+    /// and if it has germs, there must be at least one of them. Returns a
+    /// description of the problem if there is one.
+    pub fn validate(&self, phase: Phase, limits: &PhaseLimits)
+                    -> Result<(), &'static str> {
+        if !self.mass.is_finite() || self.mass < 0.0 {
+            Err("Received `MatPacket` had a nonsensical mass")
+        }
+        else if self.is_oversized(phase, limits) {
+            Err("Received `MatPacket` had too much mass")
+        }
+        else if !self.temperature.is_finite() || self.temperature < 0.0 {
+            Err("Received `MatPacket` had a nonsensical temperature")
+        }
+        else if self.germs.map(|x| x.count <= 0).unwrap_or(false) {
+            Err("Received `MatPacket` had a nonsensical germ count")
+        }
+        else { Ok(()) }
+    }
+    /// Writes this packet in the binary save format.
+    pub fn write_binary(&self, w: &mut impl Write) -> IoResult<()> {
+        write_i32(w, self.element)?;
+        write_f32(w, self.mass)?;
+        write_f32(w, self.temperature)?;
+        match self.germs {
+            None => write_u8(w, 0),
+            Some(germs) => {
+                write_u8(w, 1)?;
+                write_i32(w, germs.id)?;
+                write_i32(w, germs.count)
+            },
+        }
+    }
+    /// Reads a packet written by `write_binary`.
+    pub fn read_binary(r: &mut impl Read) -> IoResult<MatPacket> {
+        let element = read_i32(r)?;
+        let mass = read_f32(r)?;
+        let temperature = read_f32(r)?;
+        let germs = match read_u8(r)? {
+            0 => None,
+            _ => Some(Germs { id: read_i32(r)?, count: read_i32(r)? }),
+        };
+        Ok(MatPacket { element, mass, temperature, germs })
     }
 }
 
@@ -103,6 +238,11 @@ impl Germs {
     /// - `(Some(Germs), None)`: 100% of the germs were merged
     /// - `(Some(Germs), Some(Germs))`: Not all germs were merged (either
     ///   the stacks were incompatible, or frac was less than one)
+    ///
+    /// No germs are lost here: between them, the two stacks returned hold as
+    /// many germs as the two that went in. What happens to the second stack is
+    /// up to the caller; `MatPacket::merge` drops it if there's no mass left
+    /// over to carry it.
     pub fn merge(a: Option<Germs>, b: Option<Germs>, frac: f32)
                  -> (Option<Germs>, Option<Germs>) {
         let b = match (a, b) {
@@ -139,7 +279,11 @@ impl Germs {
         let mut b = b.split(frac);
         if let Some(a) = a {
             assert_eq!(a.id, b.0.id);
-            b.0.count += a.count;
+            // (a stack can only count so high; whatever doesn't fit stays
+            // behind with the rest, instead of wrapping around)
+            let moved = b.0.count.min(i32::MAX.saturating_sub(a.count));
+            b.1.count += b.0.count - moved;
+            b.0.count = a.count + moved;
         }
         (b.0.maybe(), b.1.maybe())
     }
@@ -158,6 +302,13 @@ impl Germs {
             )
         }
     }
+    /// Returns what's left after multiplying our count by `factor`, rounded
+    /// toward zero. With `factor` below 1, the count always gets closer to
+    /// zero, so repeated decay always reaches `None` eventually.
+    pub fn decayed(self, factor: f64) -> Option<Germs> {
+        let count = (self.count as f64 * factor) as i32;
+        Germs { id: self.id, count }.maybe()
+    }
     /// Returns `None` if there aren't any actual germs in us, or `Some(self)`
     /// if there are.
     pub fn maybe(self) -> Option<Germs> {
@@ -174,13 +325,13 @@ impl Display for Phase {
 
 impl Display for Germs {
     fn fmt(&self, fmt: &mut Formatter) -> std::fmt::Result {
-        fmt.write_fmt(format_args!("{}({}) x{}", get_germ_name(self.id).unwrap_or("???"), self.id, self.count))
+        fmt.write_fmt(format_args!("{}({}) x{}", get_germ_name(self.id).as_deref().unwrap_or("???"), self.id, self.count))
     }
 }
 
 impl Display for MatPacket {
     fn fmt(&self, fmt: &mut Formatter) -> std::fmt::Result {
-        fmt.write_fmt(format_args!("{:.2}kg of {}({}) at {:.1}°C", self.mass, get_element_name(self.element).unwrap_or("???"), self.element, self.temperature - 273.15))?;
+        fmt.write_fmt(format_args!("{:.2}kg of {}({}) at {:.1}°C", self.mass, get_element_name(self.element).as_deref().unwrap_or("???"), self.element, self.temperature - 273.15))?;
         match self.germs {
             None => (),
             Some(ref germs) => {
@@ -192,3 +343,255 @@ impl Display for MatPacket {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn germs(id: i32, count: i32) -> Option<Germs> {
+        Some(Germs { id, count })
+    }
+    fn total(stacks: (Option<Germs>, Option<Germs>)) -> i64 {
+        stacks.0.map(|x| x.count as i64).unwrap_or(0)
+            + stacks.1.map(|x| x.count as i64).unwrap_or(0)
+    }
+    fn packet(mass: f32, temperature: f32, germs: Option<Germs>)
+    -> MatPacket {
+        MatPacket { element: 1, mass, temperature, germs }
+    }
+
+    #[test]
+    fn no_germs() {
+        assert_eq!(Germs::merge(None, None, 0.5), (None, None));
+    }
+
+    #[test]
+    fn germs_only_on_the_left() {
+        assert_eq!(Germs::merge(germs(1, 10), None, 0.5), (germs(1, 10), None));
+    }
+
+    #[test]
+    fn germs_only_on_the_right() {
+        assert_eq!(Germs::merge(None, germs(1, 10), 1.0), (germs(1, 10), None));
+        assert_eq!(Germs::merge(None, germs(1, 10), 0.0), (None, germs(1, 10)));
+        let partial = Germs::merge(None, germs(1, 10), 0.25);
+        assert_eq!(partial, (germs(1, 3), germs(1, 7)));
+    }
+
+    #[test]
+    fn same_kind() {
+        assert_eq!(Germs::merge(germs(1, 10), germs(1, 20), 1.0),
+                   (germs(1, 30), None));
+        assert_eq!(Germs::merge(germs(1, 10), germs(1, 20), 0.5),
+                   (germs(1, 20), germs(1, 10)));
+        assert_eq!(Germs::merge(germs(1, 10), germs(1, 20), 0.0),
+                   (germs(1, 10), germs(1, 20)));
+    }
+
+    #[test]
+    fn same_kind_overflow_stays_behind() {
+        let merged = Germs::merge(germs(1, i32::MAX - 5), germs(1, 20), 1.0);
+        assert_eq!(merged, (germs(1, i32::MAX), germs(1, 15)));
+        let merged = Germs::merge(germs(1, i32::MAX), germs(1, i32::MAX), 0.5);
+        assert_eq!(total(merged), i32::MAX as i64 * 2);
+        assert_eq!(merged.0, germs(1, i32::MAX));
+    }
+
+    #[test]
+    fn incompatible_partial_keeps_both() {
+        for &frac in &[0.0, 0.001, 0.5, 0.999] {
+            assert_eq!(Germs::merge(germs(1, 10), germs(2, 1000), frac),
+                       (germs(1, 10), germs(2, 1000)));
+        }
+    }
+
+    #[test]
+    fn incompatible_full_bigger_wins() {
+        assert_eq!(Germs::merge(germs(1, 10), germs(2, 20), 1.0),
+                   (germs(2, 20), germs(1, 10)));
+        assert_eq!(Germs::merge(germs(1, 20), germs(2, 10), 1.0),
+                   (germs(1, 20), germs(2, 10)));
+    }
+
+    #[test]
+    fn incompatible_full_tie_goes_to_lower_id() {
+        assert_eq!(Germs::merge(germs(1, 10), germs(2, 10), 1.0),
+                   (germs(1, 10), germs(2, 10)));
+        assert_eq!(Germs::merge(germs(2, 10), germs(1, 10), 1.0),
+                   (germs(1, 10), germs(2, 10)));
+    }
+
+    #[test]
+    fn merge_conserves_germs() {
+        let fracs = [-1.0, 0.0, 1e-9, 0.1, 0.3333, 0.5, 0.9, 0.99999, 1.0,
+                     2.0];
+        let counts = [1, 2, 3, 7, 1000, i32::MAX - 1, i32::MAX];
+        for &frac in &fracs {
+            for &a in &counts {
+                for &b in &counts {
+                    for &b_id in &[1, 2] {
+                        let before = a as i64 + b as i64;
+                        let after = total(Germs::merge(germs(1, a),
+                                                       germs(b_id, b), frac));
+                        assert_eq!(before, after, "{} {} {} {}",
+                                   a, b, b_id, frac);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn packet_merge_refusals() {
+        let limits = PhaseLimits::default();
+        let a = packet(0.5, 300.0, None);
+        let other = MatPacket { element: 2, ..a };
+        assert_eq!(a.merge(&other, Phase::Gas, &limits), None);
+        let empty = packet(0.0, 300.0, None);
+        assert_eq!(a.merge(&empty, Phase::Gas, &limits), None);
+        assert_eq!(empty.merge(&a, Phase::Gas, &limits), None);
+        let full = packet(1.0, 300.0, None);
+        assert_eq!(full.merge(&a, Phase::Gas, &limits), None);
+    }
+
+    #[test]
+    fn packet_merge_tiny_masses() {
+        let limits = PhaseLimits::default();
+        let a = packet(f32::MIN_POSITIVE, 300.0, None);
+        let b = packet(f32::MIN_POSITIVE, 330.0, None);
+        let (merged, rest) = a.merge(&b, Phase::Gas, &limits).unwrap();
+        assert!(merged.temperature.is_finite());
+        assert!(merged.temperature >= 300.0 && merged.temperature <= 330.0);
+        assert_eq!(rest, None);
+        let empty = packet(0.0, 300.0, None);
+        assert_eq!(empty.merge(&empty, Phase::Gas, &limits), None);
+    }
+
+    #[test]
+    fn nonsense_packets_are_invalid() {
+        let limits = PhaseLimits::default();
+        let valid = |x: MatPacket| x.validate(Phase::Gas, &limits).is_ok();
+        assert!(valid(packet(0.5, 300.0, germs(1, 1))));
+        assert!(valid(packet(0.0, 0.0, None)));
+        for &mass in &[f32::NAN, f32::INFINITY, f32::NEG_INFINITY, -0.5] {
+            assert!(!valid(packet(mass, 300.0, None)), "mass {}", mass);
+        }
+        for &temperature in &[f32::NAN, f32::INFINITY, -1.0] {
+            assert!(!valid(packet(0.5, temperature, None)),
+                    "temperature {}", temperature);
+        }
+        assert!(!valid(packet(0.5, 300.0, germs(1, 0))));
+        assert!(!valid(packet(0.5, 300.0, germs(1, -5))));
+        assert!(!valid(packet(1.5, 300.0, None)));
+    }
+
+    #[test]
+    fn mixing_conserves_heat() {
+        let mut packets = [packet(0.25, 300.0, germs(1, 10)),
+                           MatPacket { element: 2,
+                                       ..packet(0.75, 400.0, None) },
+                           packet(1.0, 273.15, germs(2, 5))];
+        let before = packets;
+        let heat = |packets: &[MatPacket]| packets.iter()
+            .map(|x| x.mass as f64 * x.temperature as f64).sum::<f64>();
+        assert!(MatPacket::mix_temperatures(packets.iter_mut()));
+        assert!((heat(&packets) - heat(&before)).abs() < 1e-3);
+        for (after, before) in packets.iter().zip(before.iter()) {
+            assert_eq!(after.temperature, packets[0].temperature);
+            assert_eq!(MatPacket { temperature: before.temperature, ..*after },
+                       *before);
+        }
+        // already mixed
+        assert!(!MatPacket::mix_temperatures(packets.iter_mut()));
+    }
+
+    #[test]
+    fn mixing_ignores_massless_packets() {
+        let mut packets = [packet(0.5, 300.0, None), packet(0.0, 1000.0, None)];
+        assert!(!MatPacket::mix_temperatures(packets.iter_mut()));
+        assert_eq!(packets[1].temperature, 1000.0);
+        assert!(!MatPacket::mix_temperatures(std::iter::empty()));
+    }
+
+    #[test]
+    fn germs_decay_away() {
+        for &factor in &[0.5, 0.9, 0.999] {
+            let start = packet(0.5, 300.0, germs(1, i32::MAX));
+            let mut packet = start;
+            let mut last = i32::MAX;
+            let mut rounds = 0;
+            while let Some(germs) = packet.germs {
+                rounds += 1;
+                assert!(rounds < 100000, "factor {} never finished", factor);
+                assert!(packet.decay_germs(factor));
+                let count = packet.germs.map(|x| x.count).unwrap_or(0);
+                assert!(count < last, "{} after {}", count, last);
+                assert_eq!(germs.id, 1);
+                last = count;
+                assert_eq!(MatPacket { germs: None, ..packet },
+                           MatPacket { germs: None, ..start });
+            }
+            assert!(!packet.decay_germs(factor));
+        }
+    }
+
+    #[test]
+    fn stack_sizes_come_from_the_limits() {
+        let limits = PhaseLimits { liquid_stack: 50.0, ..Default::default() };
+        let a = packet(30.0, 300.0, None);
+        assert!(!a.is_oversized(Phase::Liquid, &limits));
+        assert!(a.is_oversized(Phase::Liquid, &PhaseLimits::default()));
+        assert!(a.is_oversized(Phase::Gas, &limits));
+        assert!(packet(60.0, 300.0, None).is_oversized(Phase::Liquid, &limits));
+        let (merged, rest) = a.merge(&a, Phase::Liquid, &limits).unwrap();
+        assert_eq!(merged.mass, 50.0);
+        assert!(!merged.has_room(Phase::Liquid, &limits));
+        assert_eq!(rest.unwrap().mass, 10.0);
+        let small = PhaseLimits { liquid_stack: 5.0, ..Default::default() };
+        let b = packet(4.0, 300.0, None);
+        let (merged, rest) = b.merge(&b, Phase::Liquid, &small).unwrap();
+        assert_eq!((merged.mass, rest.unwrap().mass), (5.0, 3.0));
+    }
+
+    #[test]
+    fn packet_merge_whole() {
+        let limits = PhaseLimits::default();
+        let a = packet(0.25, 300.0, germs(1, 10));
+        let b = packet(0.5, 330.0, germs(1, 20));
+        let (merged, rest) = a.merge(&b, Phase::Gas, &limits).unwrap();
+        assert_eq!(rest, None);
+        assert_eq!(merged.mass, 0.75);
+        assert!((merged.temperature - 320.0).abs() < 0.001);
+        assert_eq!(merged.germs, germs(1, 30));
+    }
+
+    #[test]
+    fn packet_merge_with_leftover_carries_germs() {
+        let limits = PhaseLimits::default();
+        let a = packet(0.5, 300.0, germs(1, 10));
+        let b = packet(1.0, 300.0, germs(2, 1000));
+        let (merged, rest) = a.merge(&b, Phase::Gas, &limits).unwrap();
+        let rest = rest.unwrap();
+        assert_eq!(merged.mass, 1.0);
+        assert_eq!(rest.mass, 0.5);
+        assert_eq!(merged.germs, germs(1, 10));
+        assert_eq!(rest.germs, germs(2, 1000));
+        let b = packet(1.0, 300.0, germs(1, 1000));
+        let (merged, rest) = a.merge(&b, Phase::Gas, &limits).unwrap();
+        assert_eq!(merged.germs, germs(1, 510));
+        assert_eq!(rest.unwrap().germs, germs(1, 500));
+    }
+
+    #[test]
+    fn packet_merge_without_leftover_drops_the_loser() {
+        let limits = PhaseLimits::default();
+        let a = packet(0.5, 300.0, germs(1, 10));
+        let b = packet(0.5, 300.0, germs(2, 20));
+        let (merged, rest) = a.merge(&b, Phase::Gas, &limits).unwrap();
+        assert_eq!(rest, None);
+        assert_eq!(merged.germs, germs(2, 20));
+        let b = packet(0.5, 300.0, germs(1, i32::MAX));
+        let (merged, rest) = a.merge(&b, Phase::Gas, &limits).unwrap();
+        assert_eq!(rest, None);
+        assert_eq!(merged.germs, germs(1, i32::MAX));
+    }
+}
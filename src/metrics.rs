@@ -0,0 +1,335 @@
+/*
+ *
+ * This file is part of onizd, copyright ©2020 Solra Bizna.
+ *
+ * onizd is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or (at your option) any later
+ * version.
+ *
+ * onizd is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * onizd. If not, see <https://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Server-wide counters, and a tiny HTTP server that reports them in the
+//! Prometheus text format. Also per-client counters, which just get logged.
+
+use std::{
+    fmt::Write,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+
+use crate::*;
+
+/// The longest HTTP request we're willing to read before answering.
+const MAX_REQUEST_SIZE: usize = 8192;
+
+#[derive(Default)]
+struct Counters {
+    connected_clients: u64,
+    joules_sent: f64,
+    joules_received: f64,
+    gas_packets_sent: u64,
+    gas_packets_received: u64,
+    liquid_packets_sent: u64,
+    liquid_packets_received: u64,
+    objects_sent: u64,
+    objects_received: u64,
+    /// From every client that has disconnected.
+    compression: CompressionTotals,
+}
+
+/// How much a compressed connection's traffic was compressed. Only the
+/// deflate data itself is counted, not any headers or trailers around it.
+#[derive(Default,Clone,Copy)]
+pub struct CompressionTotals {
+    pub compressed_in: u64,
+    pub uncompressed_in: u64,
+    pub compressed_out: u64,
+    pub uncompressed_out: u64,
+}
+
+impl CompressionTotals {
+    fn is_empty(&self) -> bool {
+        self.uncompressed_in == 0 && self.uncompressed_out == 0
+    }
+    /// Sums things up in one line, for the log.
+    pub fn summary(&self) -> String {
+        // how much smaller compression made something, in percent
+        let saved = |compressed: u64, uncompressed: u64| {
+            if uncompressed == 0 { 0.0 }
+            else { 100.0 - compressed as f64 * 100.0 / uncompressed as f64 }
+        };
+        format!("{} bytes in ({} uncompressed, {:.1}% saved), {} bytes out \
+                 ({} uncompressed, {:.1}% saved)",
+                self.compressed_in, self.uncompressed_in,
+                saved(self.compressed_in, self.uncompressed_in),
+                self.compressed_out, self.uncompressed_out,
+                saved(self.compressed_out, self.uncompressed_out))
+    }
+}
+
+/// Keeps track of what's been happening on the server, for the metrics
+/// endpoint's benefit.
+#[derive(Default)]
+pub struct Metrics {
+    counters: Mutex<Counters>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics { Default::default() }
+    pub fn client_connected(&self) {
+        self.counters.lock().unwrap().connected_clients += 1;
+    }
+    pub fn client_disconnected(&self) {
+        let mut counters = self.counters.lock().unwrap();
+        counters.connected_clients = counters.connected_clients
+            .saturating_sub(1);
+    }
+    /// Energy was put into the map.
+    pub fn joules_sent(&self, joules: Joules) {
+        self.counters.lock().unwrap().joules_sent += joules as f64;
+    }
+    /// Energy was taken out of the map.
+    pub fn joules_received(&self, joules: Joules) {
+        self.counters.lock().unwrap().joules_received += joules as f64;
+    }
+    /// A packet was put into the map.
+    pub fn packet_sent(&self, phase: Phase) {
+        let mut counters = self.counters.lock().unwrap();
+        match phase {
+            Phase::Gas => counters.gas_packets_sent += 1,
+            Phase::Liquid => counters.liquid_packets_sent += 1,
+        }
+    }
+    /// A packet was taken out of the map.
+    pub fn packet_received(&self, phase: Phase) {
+        let mut counters = self.counters.lock().unwrap();
+        match phase {
+            Phase::Gas => counters.gas_packets_received += 1,
+            Phase::Liquid => counters.liquid_packets_received += 1,
+        }
+    }
+    /// An object was put into the map.
+    pub fn object_sent(&self) {
+        self.counters.lock().unwrap().objects_sent += 1;
+    }
+    /// An object was taken out of the map.
+    pub fn object_received(&self) {
+        self.counters.lock().unwrap().objects_received += 1;
+    }
+    /// Adds a disconnected client's compression totals to the server-wide
+    /// ones.
+    pub fn add_compression(&self, totals: CompressionTotals) {
+        let compression = &mut self.counters.lock().unwrap().compression;
+        compression.compressed_in += totals.compressed_in;
+        compression.uncompressed_in += totals.uncompressed_in;
+        compression.compressed_out += totals.compressed_out;
+        compression.uncompressed_out += totals.uncompressed_out;
+    }
+    /// The compression totals of every client that has disconnected so far,
+    /// or `None` if none of them used compression.
+    pub fn compression(&self) -> Option<CompressionTotals> {
+        Some(self.counters.lock().unwrap().compression)
+            .filter(|x| !x.is_empty())
+    }
+    /// Renders the current values in the Prometheus text exposition format.
+    pub fn render(&self, occupied_tiles: usize) -> String {
+        let c = self.counters.lock().unwrap();
+        let mut ret = String::new();
+        // (writing to a `String` can't fail)
+        writeln!(ret, "# HELP onizd_connected_clients Number of clients \
+                       currently connected.\n\
+                       # TYPE onizd_connected_clients gauge\n\
+                       onizd_connected_clients {}",
+                 c.connected_clients).unwrap();
+        writeln!(ret, "# HELP onizd_joules_total Energy moved through the \
+                       map, in joules.\n\
+                       # TYPE onizd_joules_total counter\n\
+                       onizd_joules_total{{direction=\"sent\"}} {}\n\
+                       onizd_joules_total{{direction=\"received\"}} {}",
+                 c.joules_sent, c.joules_received).unwrap();
+        writeln!(ret, "# HELP onizd_packets_total Gas and liquid packets \
+                       moved through the map.\n\
+                       # TYPE onizd_packets_total counter\n\
+                       onizd_packets_total{{phase=\"gas\",direction=\"sent\"}} {}\n\
+                       onizd_packets_total{{phase=\"gas\",direction=\"received\"}} {}\n\
+                       onizd_packets_total{{phase=\"liquid\",direction=\"sent\"}} {}\n\
+                       onizd_packets_total{{phase=\"liquid\",direction=\"received\"}} {}",
+                 c.gas_packets_sent, c.gas_packets_received,
+                 c.liquid_packets_sent, c.liquid_packets_received).unwrap();
+        writeln!(ret, "# HELP onizd_objects_total Objects moved through the \
+                       map.\n\
+                       # TYPE onizd_objects_total counter\n\
+                       onizd_objects_total{{direction=\"sent\"}} {}\n\
+                       onizd_objects_total{{direction=\"received\"}} {}",
+                 c.objects_sent, c.objects_received).unwrap();
+        writeln!(ret, "# HELP onizd_occupied_tiles Number of points on the \
+                       map with something stored at them.\n\
+                       # TYPE onizd_occupied_tiles gauge\n\
+                       onizd_occupied_tiles {}",
+                 occupied_tiles).unwrap();
+        ret
+    }
+}
+
+#[derive(Default)]
+struct ClientCounters {
+    messages_in: u64,
+    messages_out: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+    joules_sent: f64,
+    joules_received: f64,
+    packets_sent: u64,
+    packets_received: u64,
+    objects_sent: u64,
+    objects_received: u64,
+    compression: CompressionTotals,
+}
+
+/// Keeps track of what one client has been doing, so that we can say so when
+/// it disconnects. Bytes are counted as they cross the wire, after
+/// compression; a compressed connection's compression is counted separately.
+#[derive(Default)]
+pub struct ClientStats {
+    counters: Mutex<ClientCounters>,
+}
+
+impl ClientStats {
+    pub fn new() -> ClientStats { Default::default() }
+    pub fn message_received(&self) {
+        self.counters.lock().unwrap().messages_in += 1;
+    }
+    pub fn message_sent(&self) {
+        self.counters.lock().unwrap().messages_out += 1;
+    }
+    pub fn bytes_received(&self, bytes: usize) {
+        self.counters.lock().unwrap().bytes_in += bytes as u64;
+    }
+    pub fn bytes_sent(&self, bytes: usize) {
+        self.counters.lock().unwrap().bytes_out += bytes as u64;
+    }
+    /// `compressed` bytes from the client were decompressed into
+    /// `uncompressed` bytes.
+    pub fn decompressed(&self, compressed: usize, uncompressed: usize) {
+        let compression = &mut self.counters.lock().unwrap().compression;
+        compression.compressed_in += compressed as u64;
+        compression.uncompressed_in += uncompressed as u64;
+    }
+    /// `uncompressed` bytes for the client were compressed into `compressed`
+    /// bytes.
+    pub fn compressed(&self, uncompressed: usize, compressed: usize) {
+        let compression = &mut self.counters.lock().unwrap().compression;
+        compression.compressed_out += compressed as u64;
+        compression.uncompressed_out += uncompressed as u64;
+    }
+    /// The client put energy into the map.
+    pub fn joules_sent(&self, joules: Joules) {
+        self.counters.lock().unwrap().joules_sent += joules as f64;
+    }
+    /// The client took energy out of the map.
+    pub fn joules_received(&self, joules: Joules) {
+        self.counters.lock().unwrap().joules_received += joules as f64;
+    }
+    /// The client put a packet into the map.
+    pub fn packet_sent(&self) {
+        self.counters.lock().unwrap().packets_sent += 1;
+    }
+    /// The client took a packet out of the map.
+    pub fn packet_received(&self) {
+        self.counters.lock().unwrap().packets_received += 1;
+    }
+    /// The client put an object into the map.
+    pub fn object_sent(&self) {
+        self.counters.lock().unwrap().objects_sent += 1;
+    }
+    /// The client took an object out of the map.
+    pub fn object_received(&self) {
+        self.counters.lock().unwrap().objects_received += 1;
+    }
+    /// Sums everything up in one line, for the log.
+    pub fn summary(&self) -> String {
+        let c = self.counters.lock().unwrap();
+        format!("{} messages ({} bytes) in, {} messages ({} bytes) out; \
+                 sent {}J, {} packets, {} objects; \
+                 received {}J, {} packets, {} objects",
+                c.messages_in, c.bytes_in, c.messages_out, c.bytes_out,
+                c.joules_sent, c.packets_sent, c.objects_sent,
+                c.joules_received, c.packets_received, c.objects_received)
+    }
+    /// How much compression did for this client, or `None` if it didn't use
+    /// compression.
+    pub fn compression(&self) -> Option<CompressionTotals> {
+        Some(self.counters.lock().unwrap().compression)
+            .filter(|x| !x.is_empty())
+    }
+}
+
+/// Answers HTTP requests on the given listener, forever. Every request gets
+/// the metrics, regardless of what was actually requested.
+pub async fn serve_metrics(mut listener: TcpListener, shared: Arc<Shared>,
+                           mut out: Outputter) {
+    loop {
+        let (socket, peer) = match listener.accept().await {
+            Ok(x) => x,
+            Err(x) => {
+                writeln!(out, "Metrics listener error: {}", x).unwrap();
+                continue
+            },
+        };
+        let shared = shared.clone();
+        let mut out = out.clone();
+        tokio::spawn(async move {
+            match metrics_request(socket, &shared).await {
+                Ok(_) => (),
+                Err(x) if shared.invocation.verbosity >= 1 => {
+                    writeln!(out, "{} metrics request error: {}", peer, x)
+                        .unwrap();
+                },
+                Err(_) => (),
+            }
+        });
+    }
+}
+
+async fn metrics_request(mut socket: TcpStream, shared: &Shared)
+                         -> std::io::Result<()> {
+    // read (and ignore) the request, up to the blank line that ends it
+    let mut request = Vec::with_capacity(512);
+    let mut buf = [0u8; 512];
+    while !request.windows(4).any(|x| x == b"\r\n\r\n") {
+        if request.len() > MAX_REQUEST_SIZE {
+            return Err(errorize("request too long"))
+        }
+        let red = match timeout(Duration::from_secs(10),
+                                socket.read(&mut buf[..])).await {
+            Ok(x) => x?,
+            Err(_) => return Err(errorize("timed out")),
+        };
+        if red == 0 { break }
+        request.extend_from_slice(&buf[..red]);
+    }
+    let occupied_tiles = shared.map.read().unwrap().occupied_tile_count();
+    let body = shared.metrics.render(occupied_tiles);
+    let response = format!("HTTP/1.0 200 OK\r\n\
+                            Content-Type: text/plain; version=0.0.4\r\n\
+                            Content-Length: {}\r\n\
+                            Connection: close\r\n\
+                            \r\n{}", body.len(), body);
+    socket.write_all(response.as_bytes()).await?;
+    socket.shutdown(std::net::Shutdown::Write)?;
+    Ok(())
+}
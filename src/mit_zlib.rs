@@ -19,22 +19,79 @@
 
 //! `flate2`'s tokio support is too old and/or not applicable, so I get to roll
 //! my own. Lovely.
+//!
+//! Gzip is the same deflate data as zlib, with a different header and
+//! trailer. `flate2` can only do those itself with the C zlib backend, so we
+//! do raw deflate and handle them here.
 
-use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
-use flate2::{Compress, Decompress, Status, FlushCompress, FlushDecompress};
+use tokio::io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf};
+use flate2::{Compress, Crc, Decompress, Status, FlushCompress,
+             FlushDecompress};
 use std::{
     convert::TryInto,
     pin::Pin,
     mem::MaybeUninit,
+    sync::Arc,
     task::{Context, Poll},
 };
-use crate::errorize;
+use crate::{errorize, ClientStats, CompressionType, Transport};
 
-/// An `AsyncWrite` implementation that wraps `OwnedWriteHalf` and compresses
-/// all data before being sent.
+/// The compression level used unless `--compression-level` says otherwise.
+pub const DEFAULT_COMPRESSION_LEVEL: u32 = 6;
+
+/// The header we start a gzip stream with: deflate, no flags, no timestamp,
+/// no extra flags, unknown OS.
+const GZIP_HEADER: &[u8] = &[0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 255];
+const GZIP_FHCRC: u8 = 0x02;
+const GZIP_FEXTRA: u8 = 0x04;
+const GZIP_FNAME: u8 = 0x08;
+const GZIP_FCOMMENT: u8 = 0x10;
+/// A gzip header longer than this (which would take a very long file name or
+/// comment) is assumed to be garbage.
+const MAX_GZIP_HEADER: usize = 4096;
+
+/// Works out how long the gzip header at the start of `header` is. Returns
+/// `Ok(None)` if more bytes are needed to tell, and `Err` if it isn't a gzip
+/// header at all.
+fn gzip_header_len(header: &[u8]) -> Result<Option<usize>, ()> {
+    if header.iter().zip(GZIP_HEADER[..3].iter()).any(|(a, b)| a != b) {
+        return Err(())
+    }
+    if header.len() < GZIP_HEADER.len() { return Ok(None) }
+    let flags = header[3];
+    if flags & 0xE0 != 0 { return Err(()) }
+    let mut len = GZIP_HEADER.len();
+    if flags & GZIP_FEXTRA != 0 {
+        if header.len() < len + 2 { return Ok(None) }
+        len += 2 + u16::from_le_bytes([header[len], header[len+1]]) as usize;
+    }
+    for &flag in &[GZIP_FNAME, GZIP_FCOMMENT] {
+        if flags & flag == 0 { continue }
+        // a zero-terminated string
+        match header.get(len..).and_then(|x| x.iter().position(|&x| x == 0)) {
+            None => return Ok(None),
+            Some(n) => len += n + 1,
+        }
+    }
+    if flags & GZIP_FHCRC != 0 { len += 2 }
+    if header.len() < len { Ok(None) } else { Ok(Some(len)) }
+}
+
+/// Where a gzip reader is in the current stream.
+enum GzipState {
+    /// Collecting the header; holds what we have of it so far.
+    Header(Vec<u8>),
+    /// Decompressing, keeping track of the CRC of what comes out.
+    Body(Crc),
+    /// Collecting the trailer, which should match the `Crc`.
+    Trailer(Crc, Vec<u8>),
+}
+
+/// An `AsyncWrite` implementation that wraps the write half of a `Transport`
+/// and compresses all data before being sent.
 pub struct MitZlibWriter {
-    inner: OwnedWriteHalf,
+    inner: WriteHalf<Transport>,
+    stats: Arc<ClientStats>,
     zlib: Compress,
     buf: Vec<u8>,
     cursor: usize,
@@ -59,6 +116,38 @@ impl MitZlibWriter {
         self.cursor = 0;
         Poll::Ready(Ok(()))
     }
+    /// Compress all of `input` into the buffer, growing it as needed.
+    /// `compress_vec` only ever writes into spare capacity, so we keep going
+    /// until it has eaten all the input and still left some room unused.
+    fn compress_all(&mut self, input: &[u8], flush: FlushCompress)
+                    -> std::io::Result<()> {
+        let mut consumed = 0;
+        let total_in_at_start = self.zlib.total_in();
+        let total_out_at_start = self.zlib.total_out();
+        loop {
+            if self.buf.capacity() - self.buf.len() < 64 {
+                self.buf.reserve(self.buf.capacity().max(256));
+            }
+            let total_in_before = self.zlib.total_in();
+            match self.zlib.compress_vec(&input[consumed..], &mut self.buf,
+                                         flush) {
+                Ok(Status::Ok) | Ok(Status::BufError) => (),
+                // This should not happen
+                _ => return Err(errorize("compression error")),
+            }
+            let wrote: usize = (self.zlib.total_in() - total_in_before)
+                .try_into().unwrap();
+            consumed += wrote;
+            if consumed == input.len() && self.buf.len() < self.buf.capacity() {
+                self.stats.compressed(
+                    (self.zlib.total_in() - total_in_at_start)
+                        .try_into().unwrap(),
+                    (self.zlib.total_out() - total_out_at_start)
+                        .try_into().unwrap());
+                return Ok(())
+            }
+        }
+    }
 }
 
 impl AsyncWrite for MitZlibWriter {
@@ -72,18 +161,15 @@ impl AsyncWrite for MitZlibWriter {
         }
         if buf.is_empty() { return Poll::Ready(Ok(0)) }
         me.unflushed_data_sent = true;
-        match me.zlib.compress_vec(buf, &mut me.buf,
-                                     FlushCompress::None) {
-            Ok(Status::Ok) => (),
-            // This should not happen
-            _ => return Poll::Ready(Err(errorize("compression \
-                                                  error")))
+        if let Err(x) = me.compress_all(buf, FlushCompress::None) {
+            return Poll::Ready(Err(x))
         }
-        // assume everything got buffered o_O
+        // Everything in `buf` is ours now, so we have to say so even if it
+        // can't all be sent yet; returning `Pending` here would get it
+        // written again. Whatever's left goes out on the next write or flush.
         match me.soft_flush(cx) {
-            Poll::Pending => return Poll::Pending,
-            Poll::Ready(Err(x)) => return Poll::Ready(Err(x)),
-            Poll::Ready(Ok(_)) => Poll::Ready(Ok(buf.len())),
+            Poll::Ready(Err(x)) => Poll::Ready(Err(x)),
+            _ => Poll::Ready(Ok(buf.len())),
         }
     }
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context)
@@ -95,12 +181,8 @@ impl AsyncWrite for MitZlibWriter {
                 Poll::Ready(Err(x)) => return Poll::Ready(Err(x)),
                 _ => (),
             }
-            match me.zlib.compress_vec(&[], &mut me.buf,
-                                       FlushCompress::Sync) {
-                Ok(Status::Ok) | Ok(Status::BufError) => (),
-                // This should not happen
-                _ => return Poll::Ready(Err(errorize("compression \
-                                                      error")))
+            if let Err(x) = me.compress_all(&[], FlushCompress::Sync) {
+                return Poll::Ready(Err(x))
             }
             me.unflushed_data_sent = false;
         }
@@ -117,13 +199,26 @@ impl AsyncWrite for MitZlibWriter {
     }
 }
 
-/// An `AsyncRead` implementation that wraps a `OwnedReadHalf` and decompresses
-/// any data that is received.
+/// An `AsyncRead` implementation that wraps the read half of a `Transport` and
+/// decompresses any data that is received.
+///
+/// If the client ends its zlib stream and then keeps sending, whatever comes
+/// next is taken to be a new stream. If it doesn't make sense as one, it's
+/// taken to be trailing garbage, and the connection reads as ended.
 pub struct MitZlibReader {
-    inner: OwnedReadHalf,
+    inner: ReadHalf<Transport>,
+    stats: Arc<ClientStats>,
     zlib: Decompress,
+    /// `None` for zlib.
+    gzip: Option<GzipState>,
     buf: Vec<u8>,
     cursor: usize,
+    /// The last stream ended, and we haven't started a new one yet.
+    stream_ended: bool,
+    /// We started a new stream, and it hasn't given us anything yet.
+    new_stream: bool,
+    /// We found garbage after the end of a stream. Nothing more will be read.
+    finished: bool,
 }
 
 impl AsyncRead for MitZlibReader {
@@ -132,17 +227,55 @@ impl AsyncRead for MitZlibReader {
         if buf.is_empty() { return Poll::Ready(Ok(0)) }
         let me = Pin::into_inner(self);
         loop {
+            if me.finished { return Poll::Ready(Ok(0)) }
             if me.cursor < me.buf.len() {
+                if me.stream_ended {
+                    me.zlib.reset(me.gzip.is_none());
+                    me.stream_ended = false;
+                    me.new_stream = true;
+                }
+                match me.gzip.as_mut() {
+                    Some(GzipState::Header(header)) => {
+                        let had = header.len();
+                        header.extend_from_slice(&me.buf[me.cursor..]);
+                        match gzip_header_len(&header) {
+                            Ok(Some(len)) => {
+                                me.cursor += len - had;
+                                me.gzip = Some(GzipState::Body(Crc::new()));
+                            },
+                            Ok(None) if header.len() <= MAX_GZIP_HEADER =>
+                                me.cursor = me.buf.len(),
+                            _ if me.new_stream => {
+                                me.finished = true;
+                                return Poll::Ready(Ok(0))
+                            },
+                            _ => return Poll::Ready(Err(errorize("bad gzip \
+                                                                  header"))),
+                        }
+                        continue
+                    },
+                    Some(GzipState::Trailer(crc, trailer)) => {
+                        let wanted = (8 - trailer.len())
+                            .min(me.buf.len() - me.cursor);
+                        trailer.extend_from_slice(&me.buf[me.cursor ..
+                                                          me.cursor + wanted]);
+                        me.cursor += wanted;
+                        if trailer.len() < 8 { continue }
+                        if trailer[..4] != crc.sum().to_le_bytes()
+                        || trailer[4..] != crc.amount().to_le_bytes() {
+                            return Poll::Ready(Err(errorize("gzip CRC \
+                                                             mismatch")))
+                        }
+                        me.gzip = Some(GzipState::Header(Vec::new()));
+                        me.stream_ended = true;
+                        continue
+                    },
+                    _ => (),
+                }
                 let total_in_before = me.zlib.total_in();
                 let total_out_before = me.zlib.total_out();
-                match me.zlib.decompress(&me.buf[me.cursor..],
-                                         buf, FlushDecompress::None) {
-                    Ok(Status::Ok) => (),
-                    Ok(Status::StreamEnd) => (), // ?????
-                    // This should not happen
-                    _ => return Poll::Ready(Err(errorize("decompression \
-                                                          error 2")))
-                }
+                let status = me.zlib.decompress(&me.buf[me.cursor..],
+                                                buf, FlushDecompress::None);
                 let total_in_after = me.zlib.total_in();
                 let total_out_after = me.zlib.total_out();
                 let read: usize = (total_out_after - total_out_before)
@@ -150,7 +283,39 @@ impl AsyncRead for MitZlibReader {
                 let wrote: usize = (total_in_after - total_in_before)
                     .try_into().unwrap();
                 me.cursor += wrote;
-                return Poll::Ready(Ok(read))
+                me.stats.decompressed(wrote, read);
+                if let Some(GzipState::Body(crc)) = me.gzip.as_mut() {
+                    crc.update(&buf[..read]);
+                }
+                match status {
+                    // (`BufError` only means it wants more input)
+                    Ok(Status::Ok) | Ok(Status::BufError) => (),
+                    Ok(Status::StreamEnd) => match me.gzip.take() {
+                        // the trailer comes before the end, for gzip
+                        Some(GzipState::Body(crc)) => me.gzip = Some(
+                            GzipState::Trailer(crc, Vec::with_capacity(8))),
+                        _ => me.stream_ended = true,
+                    },
+                    Err(_) if me.new_stream && read == 0 => {
+                        me.finished = true;
+                        return Poll::Ready(Ok(0))
+                    },
+                    Err(_) => return Poll::Ready(Err(errorize("decompression \
+                                                               error 2"))),
+                }
+                if read > 0 {
+                    me.new_stream = false;
+                    return Poll::Ready(Ok(read))
+                }
+                // Zero bytes would look like the end of the connection, so
+                // don't return until we have some. Input that gave no output
+                // (a header, a sync flush...) has been eaten, so either
+                // there's more to decompress or we need to read more.
+                if wrote == 0 && me.cursor < me.buf.len() {
+                    // This should not happen
+                    return Poll::Ready(Err(errorize("decompression error 3")))
+                }
+                continue
             }
             me.cursor = 0;
             me.buf.clear();
@@ -168,17 +333,182 @@ impl AsyncRead for MitZlibReader {
     }
 }
 
-/// Wraps an `OwnedWriteHalf`, compressing data before it's sent.
-pub fn make_writer(inner: OwnedWriteHalf) -> MitZlibWriter {
-    let zlib = Compress::new(flate2::Compression::best(), true);
-    MitZlibWriter { zlib, inner, buf: Vec::with_capacity(256), cursor: 0,
+/// Wraps a write half, compressing data before it's sent. `level` is a
+/// compression level, 0 (none) through 9 (best).
+///
+/// Almost everything we send is a small JSON message that gets flushed on its
+/// own, so there's very little for the higher levels to find; they mostly
+/// burn CPU. Hence `DEFAULT_COMPRESSION_LEVEL`.
+pub fn make_writer(inner: WriteHalf<Transport>, stats: Arc<ClientStats>,
+                   typ: CompressionType, level: u32) -> MitZlibWriter {
+    let gzip = typ == CompressionType::Gzip;
+    let zlib = Compress::new(flate2::Compression::new(level), !gzip);
+    let mut buf = Vec::with_capacity(256);
+    // (it goes out along with the first thing we send)
+    if gzip { buf.extend_from_slice(GZIP_HEADER) }
+    MitZlibWriter { zlib, inner, stats, buf, cursor: 0,
                     unflushed_data_sent: false }
 }
 
-/// Wraps an `OwnedReadHalf`, decompressing data after it's received.
-pub fn make_reader(inner: OwnedReadHalf, slice: &[u8]) -> MitZlibReader {
-    let zlib = Decompress::new(true);
+/// Wraps a read half, decompressing data after it's received.
+pub fn make_reader(inner: ReadHalf<Transport>, stats: Arc<ClientStats>,
+                   typ: CompressionType, slice: &[u8]) -> MitZlibReader {
+    let gzip = match typ {
+        CompressionType::Zlib => None,
+        CompressionType::Gzip => Some(GzipState::Header(Vec::new())),
+    };
+    let zlib = Decompress::new(gzip.is_none());
     let mut buf = Vec::with_capacity(256.max(slice.len()));
     buf.extend_from_slice(slice);
-    MitZlibReader { zlib, inner, buf, cursor: 0 }
+    MitZlibReader { zlib, gzip, inner, stats, buf, cursor: 0,
+                    stream_ended: false, new_stream: false, finished: false }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        time::delay_for,
+    };
+    use std::time::Duration;
+
+    /// Makes a connection to ourselves. Returns our end as a `Transport`, and
+    /// the other end as it is.
+    async fn connection() -> (Transport, TcpStream) {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (theirs, ours) = tokio::join!(TcpStream::connect(addr),
+                                          listener.accept());
+        (Transport::plain(ours.unwrap().0, Arc::new(ClientStats::new())),
+         theirs.unwrap())
+    }
+
+    fn zlib(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::ZlibEncoder::new(
+            Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(
+            Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    /// Sends `input` a few bytes at a time, waiting a little between each
+    /// bit so that they arrive separately, then hangs up. Returns everything
+    /// the reader makes of it.
+    async fn dribble(typ: CompressionType, input: Vec<u8>) -> Vec<u8> {
+        let (transport, mut theirs) = connection().await;
+        let (read, _write) = tokio::io::split(transport);
+        let mut reader = make_reader(read, Arc::new(ClientStats::new()), typ,
+                                     &[]);
+        let sender = tokio::spawn(async move {
+            for chunk in input.chunks(3) {
+                theirs.write_all(chunk).await.unwrap();
+                theirs.flush().await.unwrap();
+                delay_for(Duration::from_millis(1)).await;
+            }
+        });
+        let mut output = Vec::new();
+        reader.read_to_end(&mut output).await.unwrap();
+        sender.await.unwrap();
+        output
+    }
+
+    const TEXT: &[u8] = b"{\"type\":\"ping\",\"cookie\":1}\n\
+                          {\"type\":\"ping\",\"cookie\":2}\n";
+
+    #[tokio::test]
+    async fn zlib_in_dribs_and_drabs() {
+        assert_eq!(dribble(CompressionType::Zlib, zlib(TEXT)).await, TEXT);
+    }
+
+    #[tokio::test]
+    async fn gzip_in_dribs_and_drabs() {
+        assert_eq!(dribble(CompressionType::Gzip, gzip(TEXT)).await, TEXT);
+    }
+
+    #[tokio::test]
+    async fn one_stream_after_another() {
+        let mut input = zlib(b"first\n");
+        input.extend(zlib(b"second\n"));
+        assert_eq!(dribble(CompressionType::Zlib, input).await,
+                   b"first\nsecond\n");
+        let mut input = gzip(b"first\n");
+        input.extend(gzip(b"second\n"));
+        assert_eq!(dribble(CompressionType::Gzip, input).await,
+                   b"first\nsecond\n");
+    }
+
+    #[tokio::test]
+    async fn garbage_after_the_end_is_the_end() {
+        let mut input = zlib(TEXT);
+        input.extend_from_slice(b"\0\0\xFF\xFFpadding");
+        assert_eq!(dribble(CompressionType::Zlib, input).await, TEXT);
+        let mut input = gzip(TEXT);
+        input.extend_from_slice(b"\0\0\xFF\xFFpadding");
+        assert_eq!(dribble(CompressionType::Gzip, input).await, TEXT);
+    }
+
+    #[tokio::test]
+    async fn sync_flushes_are_not_the_end() {
+        let mut encoder = flate2::write::ZlibEncoder::new(
+            Vec::new(), flate2::Compression::default());
+        encoder.write_all(b"first\n").unwrap();
+        encoder.flush().unwrap();
+        // (an empty flush gives bytes, but no data)
+        encoder.flush().unwrap();
+        encoder.write_all(b"second\n").unwrap();
+        let input = encoder.finish().unwrap();
+        assert_eq!(dribble(CompressionType::Zlib, input).await,
+                   b"first\nsecond\n");
+    }
+
+    /// Bytes that don't compress much, so that there's plenty of output.
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state = 12345u32;
+        (0 .. len).map(|_| {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            (state >> 16) as u8
+        }).collect()
+    }
+
+    /// Writes each of `writes` through a writer, flushing after each, and
+    /// reads them back through a reader on the other end.
+    async fn round_trip(typ: CompressionType, writes: Vec<Vec<u8>>) {
+        let (ours, theirs) = connection().await;
+        let theirs = Transport::plain(theirs, Arc::new(ClientStats::new()));
+        let (_read, write) = tokio::io::split(ours);
+        let (read, _write) = tokio::io::split(theirs);
+        let mut writer = make_writer(write, Arc::new(ClientStats::new()),
+                                     typ, DEFAULT_COMPRESSION_LEVEL);
+        let mut reader = make_reader(read, Arc::new(ClientStats::new()),
+                                     typ, &[]);
+        let expected = writes.concat();
+        let sender = tokio::spawn(async move {
+            for data in writes.iter() {
+                writer.write_all(data).await.unwrap();
+                writer.flush().await.unwrap();
+            }
+        });
+        let mut output = vec![0; expected.len()];
+        reader.read_exact(&mut output).await.unwrap();
+        sender.await.unwrap();
+        assert!(output == expected);
+    }
+
+    #[tokio::test]
+    async fn big_writes_arrive_whole() {
+        for &typ in &[CompressionType::Zlib, CompressionType::Gzip] {
+            round_trip(typ, vec![noise(100000)]).await;
+            round_trip(typ, vec![b"small\n".to_vec(), noise(5000),
+                                 TEXT.repeat(1000), noise(1)]).await;
+        }
+    }
 }
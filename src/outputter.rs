@@ -17,26 +17,244 @@
  *
  */
 
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufWriter, Write},
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+#[cfg(all(unix, feature = "syslog"))]
+use std::os::unix::net::UnixDatagram;
+use serde_json::Value;
 use tokio::sync::mpsc;
 
-/// Abstracts out the writing of log messages. Either uses `eprint!` or an
-/// MPSC channel to send the messages out.
+/// How many old log files to keep around when rotating. The oldest one is
+/// `PATH.LOG_FILES_KEPT`.
+const LOG_FILES_KEPT: u32 = 5;
+/// Default size, in bytes, a log file may reach before it's rotated.
+pub const DEFAULT_LOG_MAX_SIZE: u64 = 10_000_000;
+/// Where the local syslog daemon might be listening, in the order we try
+/// them. (The second one is where macOS keeps it.)
+#[cfg(all(unix, feature = "syslog"))]
+const SYSLOG_PATHS: &[&str] = &["/dev/log", "/var/run/syslog"];
+/// The syslog facility we log as, `LOG_DAEMON`, already shifted into place.
+#[cfg(all(unix, feature = "syslog"))]
+const SYSLOG_FACILITY: u8 = 3 << 3;
+#[cfg(all(unix, feature = "syslog"))]
+const SYSLOG_WARNING: u8 = 4;
+#[cfg(all(unix, feature = "syslog"))]
+const SYSLOG_INFO: u8 = 6;
+
+/// A log file that gets rotated once it grows too big.
+struct LogFile {
+    path: String,
+    file: BufWriter<File>,
+    size: u64,
+    max_size: u64,
+}
+
+impl LogFile {
+    fn open(path: &str, max_size: u64) -> std::io::Result<LogFile> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+        Ok(LogFile { path: path.to_owned(), file: BufWriter::new(file), size,
+                     max_size })
+    }
+    fn write_line(&mut self, line: &str) {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            if let Err(x) = self.rotate() {
+                eprintln!("Unable to rotate log file {:?}: {}", self.path, x);
+            }
+        }
+        match self.file.write_all(line.as_bytes())
+            .and_then(|_| self.file.flush()) {
+            Ok(_) => self.size += line.len() as u64,
+            Err(x) => eprintln!("Unable to write to log file {:?}: {}\n{}",
+                                self.path, x, line.trim_end()),
+        }
+    }
+    /// Moves `PATH` to `PATH.1`, `PATH.1` to `PATH.2`, and so on, and starts
+    /// a new, empty `PATH`.
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+        for n in (1 .. LOG_FILES_KEPT).rev() {
+            match fs::rename(format!("{}.{}", self.path, n),
+                             format!("{}.{}", self.path, n+1)) {
+                Err(x) if x.kind() != std::io::ErrorKind::NotFound
+                    => return Err(x),
+                _ => (),
+            }
+        }
+        fs::rename(&self.path, format!("{}.1", self.path))?;
+        *self = LogFile::open(&self.path, self.max_size)?;
+        Ok(())
+    }
+}
+
+/// Sends one line to the syslog daemon. Errors and warnings are logged with
+/// the warning priority, everything else (connects, disconnects, and so on)
+/// as info. Blank lines are left out.
+#[cfg(all(unix, feature = "syslog"))]
+fn write_syslog(socket: &UnixDatagram, line: &str) {
+    let line = line.trim_end();
+    if line.is_empty() { return }
+    let severity = if line.contains("ERROR") || line.contains("WARNING") {
+        SYSLOG_WARNING
+    } else { SYSLOG_INFO };
+    let record = format!("<{}>onizd[{}]: {}", SYSLOG_FACILITY | severity,
+                         std::process::id(), line);
+    if let Err(x) = socket.send(record.as_bytes()) {
+        eprintln!("Unable to write to syslog: {}\n{}", x, line);
+    }
+}
+
+/// Where an `Outputter`'s log lines end up.
 #[derive(Clone)]
-pub enum Outputter {
+enum Sink {
     /// Uses `eprint!`
     Stderr,
     /// Uses an MPSC channel
     Channel(mpsc::UnboundedSender<String>),
+    /// Appends to a (rotating) file
+    File(Arc<Mutex<LogFile>>),
+    /// Sends to the local syslog daemon
+    #[cfg(all(unix, feature = "syslog"))]
+    Syslog(Arc<UnixDatagram>),
 }
 
-impl std::fmt::Write for Outputter {
-    fn write_str(&mut self, s: &str) -> Result<(), std::fmt::Error> {
+impl Sink {
+    /// Returns `false` if the other end puts its own timestamps on lines.
+    fn wants_timestamps(&self) -> bool {
         match self {
-            Outputter::Stderr => eprint!("{}", s),
-            Outputter::Channel(sender) => {
+            #[cfg(all(unix, feature = "syslog"))]
+            Sink::Syslog(_) => false,
+            _ => true,
+        }
+    }
+}
+
+/// Abstracts out the writing of log messages. Uses `eprint!`, an MPSC
+/// channel, a log file, or syslog to send the messages out.
+///
+/// Output is collected until a whole line has been written, and then sent on
+/// with a UTC timestamp in front of it (except to syslog, which adds its
+/// own). Each clone has its own line buffer, so
+/// lines written by different tasks don't get mixed together.
+pub struct Outputter {
+    sink: Sink,
+    line: String,
+}
+
+impl Outputter {
+    /// An `Outputter` that writes to standard error.
+    pub fn stderr() -> Outputter {
+        Outputter { sink: Sink::Stderr, line: String::new() }
+    }
+    /// An `Outputter` that sends each line through the given channel.
+    pub fn channel(sender: mpsc::UnboundedSender<String>) -> Outputter {
+        Outputter { sink: Sink::Channel(sender), line: String::new() }
+    }
+    /// An `Outputter` that appends to the given file, rotating it whenever it
+    /// would grow past `max_size` bytes.
+    pub fn file(path: &str, max_size: u64) -> std::io::Result<Outputter> {
+        let file = LogFile::open(path, max_size)?;
+        Ok(Outputter { sink: Sink::File(Arc::new(Mutex::new(file))),
+                       line: String::new() })
+    }
+    /// An `Outputter` that sends each line to the local syslog daemon.
+    #[cfg(all(unix, feature = "syslog"))]
+    pub fn syslog() -> std::io::Result<Outputter> {
+        let mut last_error = None;
+        for path in SYSLOG_PATHS {
+            let socket = UnixDatagram::unbound()?;
+            match socket.connect(path) {
+                Ok(_) => return Ok(Outputter {
+                    sink: Sink::Syslog(Arc::new(socket)),
+                    line: String::new(),
+                }),
+                Err(x) => last_error = Some(x),
+            }
+        }
+        Err(last_error.unwrap())
+    }
+    fn emit(&mut self, s: &str) {
+        match &self.sink {
+            Sink::Stderr => eprint!("{}", s),
+            Sink::Channel(sender) => {
                 let _ = sender.send(s.to_owned());
+            },
+            Sink::File(file) => file.lock().unwrap().write_line(s),
+            #[cfg(all(unix, feature = "syslog"))]
+            Sink::Syslog(socket) => write_syslog(socket, s),
+        }
+    }
+    /// Sends out a JSON object as a line of its own. Instead of the usual
+    /// timestamp in front, it gets a `"timestamp"` field.
+    pub fn write_json(&mut self, mut event: Value) {
+        event["timestamp"] = Value::String(timestamp());
+        self.emit(&format!("{}\n", event));
+    }
+    /// Sends out every complete line in the buffer.
+    fn emit_lines(&mut self) {
+        while let Some(n) = self.line.find('\n') {
+            let rest = self.line.split_off(n+1);
+            let line = std::mem::replace(&mut self.line, rest);
+            // (blank lines are just spacing, they don't need a timestamp)
+            if line == "\n" || !self.sink.wants_timestamps() {
+                self.emit(&line)
             }
+            else { self.emit(&format!("{} {}", timestamp(), line)) }
         }
+    }
+}
+
+impl Clone for Outputter {
+    fn clone(&self) -> Outputter {
+        Outputter { sink: self.sink.clone(), line: String::new() }
+    }
+}
+
+impl Drop for Outputter {
+    fn drop(&mut self) {
+        if !self.line.is_empty() {
+            self.line.push('\n');
+            self.emit_lines();
+        }
+    }
+}
+
+impl std::fmt::Write for Outputter {
+    fn write_str(&mut self, s: &str) -> Result<(), std::fmt::Error> {
+        self.line.push_str(s);
+        self.emit_lines();
         Ok(())
     }
 }
+
+/// Returns the current time, in ISO 8601 format, in UTC.
+fn timestamp() -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let secs = now.as_secs();
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let secs = secs % 86400;
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            year, month, day, secs / 3600, secs / 60 % 60, secs % 60,
+            now.subsec_millis())
+}
+
+/// Converts a number of days since 1970-01-01 into a (year, month, day) in
+/// the proleptic Gregorian calendar. This is Howard Hinnant's
+/// `civil_from_days`.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe/1460 + doe/36524 - doe/146096) / 365;
+    let doy = doe - (365*yoe + yoe/4 - yoe/100);
+    let mp = (5*doy + 2) / 153;
+    let day = (doy - (153*mp + 2)/5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
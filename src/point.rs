@@ -23,6 +23,7 @@ use std::fmt::Display;
 pub struct Point {
     x: i32,
     y: i32,
+    z: i32,
 }
 
 impl Display for Point {
@@ -31,16 +32,52 @@ impl Display for Point {
         self.x.fmt(fmt)?;
         fmt.write_str(",")?;
         self.y.fmt(fmt)?;
+        fmt.write_str(",")?;
+        self.z.fmt(fmt)?;
         fmt.write_str("}")?;
         Ok(())
     }
 }
 
 impl Point {
-    pub fn new(x: i32, y: i32) -> Point {
-        Point { x, y }
+    pub fn new(x: i32, y: i32, z: i32) -> Point {
+        Point { x, y, z }
     }
     pub fn get_x(&self) -> i32 { self.x }
     pub fn get_y(&self) -> i32 { self.y }
-    pub fn as_string(&self) -> String { format!("{},{}", self.x, self.y) }
+    pub fn get_z(&self) -> i32 { self.z }
+    /// Returns this point moved by the given amount along each axis.
+    pub fn offset_by(&self, (dx, dy, dz): (i32, i32, i32)) -> Point {
+        Point::new(self.x.wrapping_add(dx), self.y.wrapping_add(dy),
+                   self.z.wrapping_add(dz))
+    }
+    /// Returns whether this point is in the box between two corners,
+    /// inclusive.
+    pub fn is_within(&self, min: Point, max: Point) -> bool {
+        self.x >= min.x && self.x <= max.x
+            && self.y >= min.y && self.y <= max.y
+            && self.z >= min.z && self.z <= max.z
+    }
+    /// Makes a point for a client that packs a z layer into the top `z_bits`
+    /// bits of `y`, instead of sending a separate `z`. The rest of `y` is sign
+    /// extended, so negative coordinates still work. With `z_bits` of 0, this
+    /// is just the point at z = 0.
+    pub fn unpack_z(x: i32, y: i32, z_bits: u32) -> Point {
+        if z_bits == 0 { return Point::new(x, y, 0) }
+        let z = ((y as u32) >> (32 - z_bits)) as i32;
+        Point::new(x, (y << z_bits) >> z_bits, z)
+    }
+    /// The reverse of `unpack_z`: returns `y` with this point's z layer packed
+    /// into its top `z_bits` bits, or `None` if either one doesn't fit.
+    pub fn packed_y(&self, z_bits: u32) -> Option<i32> {
+        if z_bits == 0 { return if self.z == 0 { Some(self.y) } else { None } }
+        let fits = (self.y << z_bits) >> z_bits == self.y
+            && self.z >= 0 && (self.z as u32) >> z_bits == 0;
+        if !fits { return None }
+        Some(((self.z as u32) << (32 - z_bits)
+              | (self.y as u32) << z_bits >> z_bits) as i32)
+    }
+    pub fn as_string(&self) -> String {
+        format!("{},{},{}", self.x, self.y, self.z)
+    }
 }
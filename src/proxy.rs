@@ -0,0 +1,148 @@
+/*
+ *
+ * This file is part of onizd, copyright ©2020 Solra Bizna.
+ *
+ * onizd is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or (at your option) any later
+ * version.
+ *
+ * onizd is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * onizd. If not, see <https://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Support for the PROXY protocol (versions 1 and 2), which lets a TCP proxy
+//! or load balancer tell us who is *really* on the other end of a connection.
+//! See <https://www.haproxy.org/download/2.0/doc/proxy-protocol.txt>.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::{
+    io::AsyncReadExt,
+    net::TcpStream,
+};
+use crate::errorize;
+
+/// The twelve bytes that start every version 2 header.
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+/// The longest a version 1 header is allowed to be, including the CRLF.
+const V1_MAX_LENGTH: usize = 107;
+
+/// Reads a PROXY protocol header from the very beginning of the connection,
+/// consuming exactly the bytes that belong to the header. Returns the address
+/// of the original client, or `None` if the proxy declined to give one (as it
+/// does for its own health checks).
+///
+/// Anything that isn't a well-formed header is an error.
+pub async fn read_proxy_header(socket: &mut TcpStream)
+                               -> std::io::Result<Option<SocketAddr>> {
+    // The shortest possible v1 header ("PROXY UNKNOWN\r\n") is longer than
+    // the v2 signature, so it's safe to read this much either way.
+    let mut buf = [0u8; 12];
+    socket.read_exact(&mut buf[..]).await?;
+    if buf[..] == V2_SIGNATURE[..] {
+        read_v2(socket).await
+    }
+    else if buf.starts_with(b"PROXY ") {
+        read_v1(socket, &buf[..]).await
+    }
+    else {
+        Err(errorize("missing PROXY protocol header"))
+    }
+}
+
+async fn read_v1(socket: &mut TcpStream, start: &[u8])
+                 -> std::io::Result<Option<SocketAddr>> {
+    let mut line = Vec::with_capacity(V1_MAX_LENGTH);
+    line.extend_from_slice(start);
+    // one byte at a time, so that we don't eat any of the real handshake
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            return Err(errorize("PROXY header was too long"))
+        }
+        line.push(socket.read_u8().await?);
+    }
+    let line = match std::str::from_utf8(&line[..line.len()-2]) {
+        Ok(x) => x,
+        Err(_) => return Err(errorize("PROXY header was not ASCII")),
+    };
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4", src, _dst, sport, _dport] => {
+            let ip = parse_v1_ip::<Ipv4Addr>(src)?;
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), parse_v1_port(sport)?)))
+        },
+        ["PROXY", "TCP6", src, _dst, sport, _dport] => {
+            let ip = parse_v1_ip::<Ipv6Addr>(src)?;
+            Ok(Some(SocketAddr::new(IpAddr::V6(ip), parse_v1_port(sport)?)))
+        },
+        _ => Err(errorize("malformed PROXY header")),
+    }
+}
+
+fn parse_v1_ip<T: std::str::FromStr>(text: &str) -> std::io::Result<T> {
+    text.parse().map_err(|_| errorize("malformed address in PROXY header"))
+}
+
+fn parse_v1_port(text: &str) -> std::io::Result<u16> {
+    // `u16::from_str` would also accept a leading `+`, which the spec doesn't
+    if text.is_empty() || text.len() > 5
+    || !text.bytes().all(|x| x.is_ascii_digit())
+    || (text.len() > 1 && text.starts_with('0')) {
+        return Err(errorize("malformed port in PROXY header"))
+    }
+    text.parse().map_err(|_| errorize("malformed port in PROXY header"))
+}
+
+async fn read_v2(socket: &mut TcpStream)
+                 -> std::io::Result<Option<SocketAddr>> {
+    let mut buf = [0u8; 4];
+    socket.read_exact(&mut buf[..]).await?;
+    let version_command = buf[0];
+    let family = buf[1];
+    let len = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+    if version_command >> 4 != 2 {
+        return Err(errorize("unknown PROXY protocol version"))
+    }
+    let mut body = vec![0u8; len];
+    socket.read_exact(&mut body[..]).await?;
+    match version_command & 15 {
+        // LOCAL: the proxy is talking to us on its own behalf
+        0 => return Ok(None),
+        // PROXY
+        1 => (),
+        _ => return Err(errorize("unknown PROXY protocol command")),
+    }
+    match family {
+        // UNSPEC
+        0x00 => Ok(None),
+        // TCP over IPv4
+        0x11 => {
+            if body.len() < 12 {
+                return Err(errorize("PROXY header was too short"))
+            }
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let port = u16::from_be_bytes([body[8], body[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        },
+        // TCP over IPv6
+        0x21 => {
+            if body.len() < 36 {
+                return Err(errorize("PROXY header was too short"))
+            }
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&body[..16]);
+            let ip = Ipv6Addr::from(octets);
+            let port = u16::from_be_bytes([body[32], body[33]]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(ip), port)))
+        },
+        // UDP and UNIX sockets make no sense in front of us
+        _ => Err(errorize("unsupported address family in PROXY header")),
+    }
+}
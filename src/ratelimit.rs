@@ -0,0 +1,58 @@
+/*
+ *
+ * This file is part of onizd, copyright ©2020 Solra Bizna.
+ *
+ * onizd is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or (at your option) any later
+ * version.
+ *
+ * onizd is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * onizd. If not, see <https://www.gnu.org/licenses/>.
+ *
+ */
+
+//! A token bucket, used to keep any one client from hogging the map.
+
+use std::time::Duration;
+use tokio::time::{Instant, delay_for};
+
+pub struct RateLimiter {
+    /// Tokens regained per second. This is also the size of the bucket, so a
+    /// client can burst up to one second's worth of messages.
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(messages_per_second: u32) -> RateLimiter {
+        let rate = messages_per_second as f64;
+        RateLimiter { rate, tokens: rate, last_refill: Instant::now() }
+    }
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last_refill = now;
+    }
+    /// Takes one token from the bucket, waiting for it to refill first if it's
+    /// empty. Returns `true` if we had to wait.
+    pub async fn take(&mut self) -> bool {
+        self.refill();
+        let throttled = if self.tokens < 1.0 {
+            let shortfall = 1.0 - self.tokens;
+            delay_for(Duration::from_secs_f64(shortfall / self.rate)).await;
+            self.refill();
+            true
+        }
+        else { false };
+        self.tokens = (self.tokens - 1.0).max(0.0);
+        throttled
+    }
+}
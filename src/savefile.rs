@@ -0,0 +1,155 @@
+/*
+ *
+ * This file is part of onizd, copyright ©2020 Solra Bizna.
+ *
+ * onizd is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or (at your option) any later
+ * version.
+ *
+ * onizd is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * onizd. If not, see <https://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Bits and pieces of the binary save format.
+//!
+//! A binary save starts with `BINARY_MAGIC`, then a byte saying how energy is
+//! stored (`JOULES_WHOLE` or `JOULES_FRACTIONAL`), then five sections: energy,
+//! gas packets, liquid packets, objects, and registrations. Each section
+//! starts with the number of points in it. Every point is three `i32`s,
+//! followed by the energy at that point, or a count and that many packets,
+//! object slots, or registrations. Object slots are a length-prefixed blob and
+//! the number of copies in the stack. Registrations are two length-prefixed
+//! strings, the owner's identity and the building. Everything is
+//! little-endian.
+//!
+//! Saves that start with `BINARY_MAGIC_V1` are the same, but without the
+//! registrations section.
+//!
+//! Either format may also be gzipped as a whole, which is noticed by the
+//! `GZIP_MAGIC` at the start.
+
+use std::{
+    convert::TryInto,
+    io::{Read, Write, Result as IoResult},
+};
+
+use crate::{Point, errorize};
+
+/// The first bytes of a binary save. (A JSON save starts with `{`, so the
+/// first byte alone is enough to tell them apart.)
+pub const BINARY_MAGIC: &[u8; 8] = b"ONIZMAP\x02";
+/// The first bytes of a binary save from before registrations were saved.
+pub const BINARY_MAGIC_V1: &[u8; 8] = b"ONIZMAP\x01";
+/// The first bytes of a gzipped save (of either format).
+pub const GZIP_MAGIC: &[u8; 2] = b"\x1f\x8b";
+/// No saved identity or building name is believed to be longer than this.
+pub const MAX_SAVED_NAME: usize = 65536;
+/// Energy is stored as a `u32` per point.
+pub const JOULES_WHOLE: u8 = 0;
+/// Energy is stored as an `f64` per point.
+pub const JOULES_FRACTIONAL: u8 = 1;
+
+/// Which format to save the map in. Either one can be loaded, no matter which
+/// one is selected.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum SaveFormat { Json, Binary }
+
+impl SaveFormat {
+    /// Picks a format based on a save file's name: binary if it ends in
+    /// `.bin` (or `.bin.gz`), JSON otherwise.
+    pub fn for_path(path: &str) -> SaveFormat {
+        let path = path.strip_suffix(".gz").unwrap_or(path);
+        if path.ends_with(".bin") { SaveFormat::Binary }
+        else { SaveFormat::Json }
+    }
+}
+
+pub fn write_u8(w: &mut impl Write, x: u8) -> IoResult<()> {
+    w.write_all(&[x])
+}
+pub fn write_u32(w: &mut impl Write, x: u32) -> IoResult<()> {
+    w.write_all(&x.to_le_bytes())
+}
+pub fn write_i32(w: &mut impl Write, x: i32) -> IoResult<()> {
+    w.write_all(&x.to_le_bytes())
+}
+pub fn write_f32(w: &mut impl Write, x: f32) -> IoResult<()> {
+    w.write_all(&x.to_le_bytes())
+}
+#[cfg(feature = "float_energy")]
+pub fn write_f64(w: &mut impl Write, x: f64) -> IoResult<()> {
+    w.write_all(&x.to_le_bytes())
+}
+/// Writes a count. Nothing we save comes anywhere near `u32::MAX` of anything.
+pub fn write_len(w: &mut impl Write, x: usize) -> IoResult<()> {
+    write_u32(w, x.try_into().map_err(|_| errorize("too many to save"))?)
+}
+pub fn write_point(w: &mut impl Write, point: Point) -> IoResult<()> {
+    write_i32(w, point.get_x())?;
+    write_i32(w, point.get_y())?;
+    write_i32(w, point.get_z())
+}
+/// Writes a length-prefixed blob.
+pub fn write_blob(w: &mut impl Write, blob: &[u8]) -> IoResult<()> {
+    write_len(w, blob.len())?;
+    w.write_all(blob)
+}
+
+pub fn read_u8(r: &mut impl Read) -> IoResult<u8> {
+    let mut buf = [0; 1];
+    r.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+pub fn read_u32(r: &mut impl Read) -> IoResult<u32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+pub fn read_i32(r: &mut impl Read) -> IoResult<i32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(i32::from_le_bytes(buf))
+}
+pub fn read_f32(r: &mut impl Read) -> IoResult<f32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(f32::from_le_bytes(buf))
+}
+pub fn read_f64(r: &mut impl Read) -> IoResult<f64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(f64::from_le_bytes(buf))
+}
+pub fn read_point(r: &mut impl Read) -> IoResult<Point> {
+    let x = read_i32(r)?;
+    let y = read_i32(r)?;
+    let z = read_i32(r)?;
+    Ok(Point::new(x, y, z))
+}
+/// Reads a length-prefixed blob, refusing to believe one longer than `max`.
+pub fn read_blob(r: &mut impl Read, max: usize) -> IoResult<Vec<u8>> {
+    let len = read_u32(r)? as usize;
+    if len > max { return Err(errorize("saved map contains an oversized \
+                                        object")) }
+    let mut ret = vec![0; len];
+    r.read_exact(&mut ret)?;
+    Ok(ret)
+}
+/// Reads a length-prefixed UTF-8 string, refusing to believe one longer than
+/// `max` bytes.
+pub fn read_string(r: &mut impl Read, max: usize) -> IoResult<String> {
+    let len = read_u32(r)? as usize;
+    if len > max { return Err(errorize("saved map contains an oversized \
+                                        string")) }
+    let mut ret = vec![0; len];
+    r.read_exact(&mut ret)?;
+    String::from_utf8(ret)
+        .map_err(|_| errorize("saved map contains a string that isn't UTF-8"))
+}
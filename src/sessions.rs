@@ -0,0 +1,213 @@
+/*
+ *
+ * This file is part of onizd, copyright ©2020 Solra Bizna.
+ *
+ * onizd is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or (at your option) any later
+ * version.
+ *
+ * onizd is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * onizd. If not, see <https://www.gnu.org/licenses/>.
+ *
+ */
+
+
+//! Keeps track of which connection each authenticated identity is using, so
+//! that an identity can be kept from having two connections (and two sets of
+//! registrations) at once. See `--duplicate-identity`.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::Duration,
+};
+use tokio::{sync::oneshot, time::timeout};
+
+use crate::ClientID;
+
+/// How long a replaced connection gets to say goodbye and clean up after
+/// itself before it's hung up on, and how long after that we wait for it to be
+/// gone before letting the new connection in anyway.
+#[cfg(not(test))]
+const REPLACE_GRACE: Duration = Duration::from_secs(5);
+#[cfg(test)]
+const REPLACE_GRACE: Duration = Duration::from_millis(50);
+
+/// What to do when an identity that already has a connection connects again.
+#[derive(Debug,Clone,Copy,PartialEq,Eq)]
+pub enum DuplicateIdentity {
+    /// Let both connections be. (Each has its own registrations.)
+    Allow,
+    /// Hang up on the old connection, with a `replaced` message, and clean up
+    /// after it before letting the new one in. Good for clients that
+    /// reconnect before the server notices the old connection is dead.
+    Replace,
+    /// Refuse the new connection.
+    Reject,
+}
+
+struct Session {
+    client_id: ClientID,
+    /// Tells the connection that it has been replaced.
+    replace: oneshot::Sender<()>,
+    /// Hangs up on the connection, if it takes too long to leave on its own.
+    hang_up: oneshot::Sender<()>,
+    /// Completes (with an error) once the connection, and everything it
+    /// registered, is gone.
+    gone: oneshot::Receiver<()>,
+}
+
+pub struct Sessions {
+    policy: DuplicateIdentity,
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+impl Sessions {
+    pub fn new(policy: DuplicateIdentity) -> Sessions {
+        Sessions { policy, sessions: Mutex::new(HashMap::new()) }
+    }
+    /// Makes the given connection the one for `identity`. `gone` should be
+    /// the other end of something the connection drops once it, and its
+    /// registrations, are gone. `hang_up` should make the connection drop
+    /// whatever it's doing and go.
+    ///
+    /// If another connection already has this identity, what happens depends
+    /// on the policy: with `Allow`, nothing; with `Reject`, this returns
+    /// `Err(())`; with `Replace`, the other connection is told to leave, and
+    /// this waits until it has. (If it doesn't leave within `REPLACE_GRACE`,
+    /// it's hung up on; if it's still not gone `REPLACE_GRACE` after that, we
+    /// stop waiting.) Otherwise, returns something that completes if this
+    /// connection is replaced in its turn.
+    pub async fn claim(&self, identity: &str, client_id: ClientID,
+                       gone: oneshot::Receiver<()>,
+                       hang_up: oneshot::Sender<()>)
+                       -> Result<Option<oneshot::Receiver<()>>, ()> {
+        if self.policy == DuplicateIdentity::Allow { return Ok(None) }
+        let (replace, replaced) = oneshot::channel();
+        let session = Session { client_id, replace, hang_up, gone };
+        let old = {
+            let mut sessions = self.sessions.lock().unwrap();
+            if self.policy == DuplicateIdentity::Reject
+            && sessions.contains_key(identity) {
+                return Err(())
+            }
+            sessions.insert(identity.to_owned(), session)
+        };
+        if let Some(mut old) = old {
+            // (it might be on its way out already, which is fine)
+            let _ = old.replace.send(());
+            if timeout(REPLACE_GRACE, &mut old.gone).await.is_err() {
+                let _ = old.hang_up.send(());
+                let _ = timeout(REPLACE_GRACE, old.gone).await;
+            }
+        }
+        Ok(Some(replaced))
+    }
+    /// Forgets about a connection that has gone away, unless it had already
+    /// been replaced.
+    pub fn release(&self, identity: &str, client_id: ClientID) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if sessions.get(identity).map(|x| x.client_id == client_id)
+        .unwrap_or(false) {
+            sessions.remove(identity);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The ends of a connection's `gone` and `hang_up` that the connection
+    /// itself would hold...
+    type Mine = (oneshot::Sender<()>, oneshot::Receiver<()>);
+    /// ...and the ends that go to `claim`.
+    type Theirs = (oneshot::Receiver<()>, oneshot::Sender<()>);
+
+    fn connection() -> (Mine, Theirs) {
+        let (gone_tx, gone_rx) = oneshot::channel();
+        let (hang_up_tx, hang_up_rx) = oneshot::channel();
+        ((gone_tx, hang_up_rx), (gone_rx, hang_up_tx))
+    }
+
+    #[tokio::test]
+    async fn allow_lets_everyone_in() {
+        let sessions = Sessions::new(DuplicateIdentity::Allow);
+        for client_id in 0 .. 2 {
+            let (_mine, (gone, hang_up)) = connection();
+            assert!(matches!(sessions.claim("a", client_id, gone, hang_up)
+                             .await, Ok(None)));
+        }
+    }
+
+    #[tokio::test]
+    async fn reject_keeps_the_first() {
+        let sessions = Sessions::new(DuplicateIdentity::Reject);
+        let (_first, (gone, hang_up)) = connection();
+        assert!(sessions.claim("a", 1, gone, hang_up).await.is_ok());
+        let (_second, (gone, hang_up)) = connection();
+        assert!(sessions.claim("a", 2, gone, hang_up).await.is_err());
+        let (_other, (gone, hang_up)) = connection();
+        assert!(sessions.claim("b", 3, gone, hang_up).await.is_ok());
+        sessions.release("a", 1);
+        let (_third, (gone, hang_up)) = connection();
+        assert!(sessions.claim("a", 4, gone, hang_up).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn replace_waits_for_the_old_one_to_leave() {
+        let sessions = Sessions::new(DuplicateIdentity::Replace);
+        let ((gone_tx, mut hung_up), (gone, hang_up)) = connection();
+        let replaced = sessions.claim("a", 1, gone, hang_up).await
+            .unwrap().unwrap();
+        // (the old connection leaves as soon as it's asked)
+        tokio::spawn(async move {
+            replaced.await.unwrap();
+            drop(gone_tx);
+        });
+        let (_second, (gone, hang_up)) = connection();
+        assert!(sessions.claim("a", 2, gone, hang_up).await.is_ok());
+        assert!(hung_up.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn replace_hangs_up_on_a_stuck_connection() {
+        let sessions = Sessions::new(DuplicateIdentity::Replace);
+        let ((gone_tx, hung_up), (gone, hang_up)) = connection();
+        let _replaced = sessions.claim("a", 1, gone, hang_up).await.unwrap();
+        // (the old connection only leaves when it's hung up on)
+        tokio::spawn(async move {
+            hung_up.await.unwrap();
+            drop(gone_tx);
+        });
+        let (_second, (gone, hang_up)) = connection();
+        assert!(sessions.claim("a", 2, gone, hang_up).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn replace_gives_up_on_a_connection_that_never_leaves() {
+        let sessions = Sessions::new(DuplicateIdentity::Replace);
+        let (_first, (gone, hang_up)) = connection();
+        let _replaced = sessions.claim("a", 1, gone, hang_up).await.unwrap();
+        let (_second, (gone, hang_up)) = connection();
+        assert!(timeout(REPLACE_GRACE * 4,
+                        sessions.claim("a", 2, gone, hang_up)).await
+                .unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn release_only_forgets_its_own_session() {
+        let sessions = Sessions::new(DuplicateIdentity::Reject);
+        let (_first, (gone, hang_up)) = connection();
+        assert!(sessions.claim("a", 1, gone, hang_up).await.is_ok());
+        sessions.release("a", 2);
+        let (_second, (gone, hang_up)) = connection();
+        assert!(sessions.claim("a", 2, gone, hang_up).await.is_err());
+    }
+}
@@ -0,0 +1,62 @@
+/*
+ *
+ * This file is part of onizd, copyright ©2020 Solra Bizna.
+ *
+ * onizd is free software: you can redistribute it and/or modify it under the
+ * terms of the GNU General Public License as published by the Free Software
+ * Foundation, either version 3 of the License, or (at your option) any later
+ * version.
+ *
+ * onizd is distributed in the hope that it will be useful, but WITHOUT ANY
+ * WARRANTY; without even the implied warranty of MERCHANTABILITY or FITNESS
+ * FOR A PARTICULAR PURPOSE. See the GNU General Public License for more
+ * details.
+ *
+ * You should have received a copy of the GNU General Public License along with
+ * onizd. If not, see <https://www.gnu.org/licenses/>.
+ *
+ */
+
+//! Native TLS termination, for servers that face the open internet.
+
+use std::{
+    fs::File,
+    io::BufReader,
+    sync::Arc,
+};
+use anyhow::anyhow;
+use tokio_rustls::{
+    TlsAcceptor,
+    rustls::{
+        NoClientAuth, ServerConfig,
+        internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys},
+    },
+};
+
+/// The first byte of every TLS connection: the record type of a handshake. A
+/// plaintext client will send `{` instead.
+pub const TLS_HANDSHAKE_RECORD: u8 = 0x16;
+
+/// Loads a PEM certificate chain and private key (PKCS #8 or RSA), and makes
+/// an acceptor that will use them.
+pub fn load_acceptor(cert_path: &str, key_path: &str)
+                     -> anyhow::Result<TlsAcceptor> {
+    let chain = certs(&mut BufReader::new(File::open(cert_path)?))
+        .map_err(|_| anyhow!("{}: not a valid PEM certificate file",
+                             cert_path))?;
+    if chain.is_empty() {
+        return Err(anyhow!("{}: no certificates found", cert_path))
+    }
+    let bad_key = |_| anyhow!("{}: not a valid PEM key file", key_path);
+    let mut keys = pkcs8_private_keys(&mut BufReader::new(
+        File::open(key_path)?)).map_err(bad_key)?;
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut BufReader::new(File::open(key_path)?))
+            .map_err(bad_key)?;
+    }
+    let key = keys.into_iter().next()
+        .ok_or_else(|| anyhow!("{}: no private key found", key_path))?;
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config.set_single_cert(chain, key)?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
@@ -18,21 +18,255 @@
  */
 
 use tokio::{
-    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
-    net::{TcpStream, tcp::{OwnedReadHalf, OwnedWriteHalf}},
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf},
+    net::TcpStream,
 };
 use tokio_util::codec;
 use std::{
     mem::MaybeUninit,
     pin::Pin,
+    sync::Arc,
     task::{Context, Poll},
 };
 use bytes::{Buf,BufMut};
+#[cfg(feature = "websocket")]
+use futures::{ready, Sink, Stream as _};
+#[cfg(feature = "websocket")]
+use tokio_tungstenite::{
+    WebSocketStream,
+    tungstenite::{Error as WsError, Message, protocol::WebSocketConfig},
+};
+
+use crate::{CompressionType, MessageCoder, Client, MitZlibReader, MitZlibWriter,
+            ClientStats};
+#[cfg(feature = "websocket")]
+use crate::errorize;
+
+/// The connection underneath everything else: either a plain TCP socket, or
+/// one with TLS on top of it, or a WebSocket on top of one of those. Counts
+/// the bytes that go through it.
+pub struct Transport {
+    stream: Stream,
+    stats: Arc<ClientStats>,
+}
+
+enum Stream {
+    Plain(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<tokio_rustls::server::TlsStream<TcpStream>>),
+    #[cfg(feature = "websocket")]
+    WebSocket(Box<WsStream>),
+}
+
+impl Transport {
+    pub fn plain(socket: TcpStream, stats: Arc<ClientStats>) -> Transport {
+        Transport { stream: Stream::Plain(socket), stats }
+    }
+    #[cfg(feature = "tls")]
+    pub fn tls(socket: tokio_rustls::server::TlsStream<TcpStream>,
+               stats: Arc<ClientStats>) -> Transport {
+        Transport { stream: Stream::Tls(Box::new(socket)), stats }
+    }
+    /// Wraps a connection that has finished its WebSocket handshake.
+    #[cfg(feature = "websocket")]
+    pub fn websocket(ws: WebSocketStream<Transport>) -> Transport {
+        let stats = ws.get_ref().stats.clone();
+        Transport { stream: Stream::WebSocket(Box::new(WsStream {
+            ws, incoming: Vec::new(), outgoing: Vec::new(),
+        })), stats }
+    }
+    /// The stats for the client on the other end of this connection.
+    pub fn stats(&self) -> &Arc<ClientStats> { &self.stats }
+    pub fn is_websocket(&self) -> bool {
+        #[cfg(feature = "websocket")]
+        { matches!(self.stream, Stream::WebSocket(_)) }
+        #[cfg(not(feature = "websocket"))]
+        { false }
+    }
+}
+
+impl AsyncRead for Transport {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut[u8])
+                 -> Poll<std::io::Result<usize>> {
+        let this = Pin::into_inner(self);
+        let ret = match this.stream {
+            Stream::Plain(ref mut x) => Pin::new(x).poll_read(cx, buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref mut x) => Pin::new(x).poll_read(cx, buf),
+            // (the transport underneath already counted these)
+            #[cfg(feature = "websocket")]
+            Stream::WebSocket(ref mut x) =>
+                return Pin::new(&mut **x).poll_read(cx, buf),
+        };
+        if let Poll::Ready(Ok(n)) = ret { this.stats.bytes_received(n) }
+        ret
+    }
+    unsafe fn prepare_uninitialized_buffer(&self, buf: &mut[MaybeUninit<u8>])
+                                           -> bool {
+        match self.stream {
+            Stream::Plain(ref x) => x.prepare_uninitialized_buffer(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref x) => x.prepare_uninitialized_buffer(buf),
+            #[cfg(feature = "websocket")]
+            Stream::WebSocket(ref x) => x.prepare_uninitialized_buffer(buf),
+        }
+    }
+}
+
+impl AsyncWrite for Transport {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8])
+                  -> Poll<std::io::Result<usize>> {
+        let this = Pin::into_inner(self);
+        let ret = match this.stream {
+            Stream::Plain(ref mut x) => Pin::new(x).poll_write(cx, buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref mut x) => Pin::new(x).poll_write(cx, buf),
+            #[cfg(feature = "websocket")]
+            Stream::WebSocket(ref mut x) =>
+                return Pin::new(&mut **x).poll_write(cx, buf),
+        };
+        if let Poll::Ready(Ok(n)) = ret { this.stats.bytes_sent(n) }
+        ret
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context)
+                  -> Poll<std::io::Result<()>> {
+        match Pin::into_inner(self).stream {
+            Stream::Plain(ref mut x) => Pin::new(x).poll_flush(cx),
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref mut x) => Pin::new(x).poll_flush(cx),
+            #[cfg(feature = "websocket")]
+            Stream::WebSocket(ref mut x) => Pin::new(&mut **x).poll_flush(cx),
+        }
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context)
+                  -> Poll<std::io::Result<()>> {
+        match Pin::into_inner(self).stream {
+            Stream::Plain(ref mut x) => Pin::new(x).poll_shutdown(cx),
+            #[cfg(feature = "tls")]
+            Stream::Tls(ref mut x) => Pin::new(x).poll_shutdown(cx),
+            #[cfg(feature = "websocket")]
+            Stream::WebSocket(ref mut x) =>
+                Pin::new(&mut **x).poll_shutdown(cx),
+        }
+    }
+}
 
-use crate::{CompressionType, MessageCoder, Client, MitZlibReader, MitZlibWriter};
+/// A WebSocket connection, made to look like the newline-delimited stream the
+/// rest of the protocol expects. Each text message received reads as one
+/// line, and each line written is sent as one text message.
+#[cfg(feature = "websocket")]
+pub struct WsStream {
+    ws: WebSocketStream<Transport>,
+    /// Received lines that haven't been read yet.
+    incoming: Vec<u8>,
+    /// Written bytes that haven't been sent yet. Only a complete line is ever
+    /// sent.
+    outgoing: Vec<u8>,
+}
+
+/// The settings to accept a WebSocket connection with, when messages may be
+/// up to `max_message_bytes` long. Each message received becomes one line, so
+/// this is what holds a `WsStream`'s lines to the same limit as the
+/// `MessageCoder` on top of it. It has to be done here: by the time a line
+/// reaches the coder, the whole message has already been pieced together
+/// from its frames, however big it was.
+#[cfg(feature = "websocket")]
+pub fn websocket_config(max_message_bytes: usize) -> WebSocketConfig {
+    WebSocketConfig {
+        max_send_queue: None,
+        max_message_size: Some(max_message_bytes),
+        max_frame_size: Some(max_message_bytes),
+    }
+}
+
+#[cfg(feature = "websocket")]
+fn ws_error(x: WsError) -> std::io::Error {
+    match x {
+        WsError::Io(x) => x,
+        x => std::io::Error::new(std::io::ErrorKind::Other, x),
+    }
+}
+
+#[cfg(feature = "websocket")]
+impl WsStream {
+    /// Sends every complete line that has been written so far.
+    fn poll_send_lines(&mut self, cx: &mut Context)
+                       -> Poll<std::io::Result<()>> {
+        while let Some(n) = self.outgoing.iter().position(|x| *x == b'\n') {
+            ready!(Pin::new(&mut self.ws).poll_ready(cx)).map_err(ws_error)?;
+            let mut line: Vec<u8> = self.outgoing.drain(..=n).collect();
+            line.pop();
+            let line = String::from_utf8(line)
+                .map_err(|_| errorize("tried to send a line that isn't UTF-8"))?;
+            Pin::new(&mut self.ws).start_send(Message::Text(line))
+                .map_err(ws_error)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(feature = "websocket")]
+impl AsyncRead for WsStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut[u8])
+                 -> Poll<std::io::Result<usize>> {
+        let this = Pin::into_inner(self);
+        while this.incoming.is_empty() {
+            match ready!(Pin::new(&mut this.ws).poll_next(cx)) {
+                None => return Poll::Ready(Ok(0)),
+                Some(Ok(Message::Text(text))) => {
+                    // one message is one line, even if it was pretty-printed
+                    this.incoming.extend(text.bytes().map(|x| match x {
+                        b'\n' | b'\r' => b' ',
+                        x => x,
+                    }));
+                    this.incoming.push(b'\n');
+                },
+                Some(Ok(Message::Binary(_))) =>
+                    return Poll::Ready(Err(errorize("received a binary \
+                                                     WebSocket message"))),
+                // (pings are answered for us, and so is a close, after which
+                // the stream ends)
+                Some(Ok(_)) => (),
+                Some(Err(x)) => return Poll::Ready(Err(ws_error(x))),
+            }
+        }
+        let n = buf.len().min(this.incoming.len());
+        buf[..n].copy_from_slice(&this.incoming[..n]);
+        this.incoming.drain(..n);
+        Poll::Ready(Ok(n))
+    }
+}
+
+#[cfg(feature = "websocket")]
+impl AsyncWrite for WsStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8])
+                  -> Poll<std::io::Result<usize>> {
+        let this = Pin::into_inner(self);
+        // don't take any more until the lines we already have are on their way
+        ready!(this.poll_send_lines(cx))?;
+        this.outgoing.extend_from_slice(buf);
+        // (whatever can't go out yet will go out on the next write or flush)
+        if let Poll::Ready(Err(x)) = this.poll_send_lines(cx) {
+            return Poll::Ready(Err(x))
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context)
+                  -> Poll<std::io::Result<()>> {
+        let this = Pin::into_inner(self);
+        ready!(this.poll_send_lines(cx))?;
+        Pin::new(&mut this.ws).poll_flush(cx).map_err(ws_error)
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context)
+                  -> Poll<std::io::Result<()>> {
+        let this = Pin::into_inner(self);
+        ready!(this.poll_send_lines(cx))?;
+        Pin::new(&mut this.ws).poll_close(cx).map_err(ws_error)
+    }
+}
 
 pub enum WrappedSocket {
-    Uncompressed(OwnedReadHalf, OwnedWriteHalf),
+    Uncompressed(ReadHalf<Transport>, WriteHalf<Transport>),
     Zlib(MitZlibReader, MitZlibWriter),
 }
 
@@ -107,23 +341,80 @@ impl AsyncWrite for WrappedSocket {
     }
 }
 
-pub async fn wrap_client(orig: codec::Framed<TcpStream, MessageCoder>,
-                              typ: Option<CompressionType>)
+pub async fn wrap_client(orig: codec::Framed<Transport, MessageCoder>,
+                              typ: Option<CompressionType>,
+                              compression_level: u32)
                               -> std::io::Result<Client> {
     let codec::FramedParts { io, codec, mut read_buf, write_buf, ..}
       = orig.into_parts();
-    let (reader, mut writer) = io.into_split();
+    let stats = io.stats().clone();
+    let (reader, mut writer) = tokio::io::split(io);
     writer.write_all(&write_buf[..]).await?;
     let wrapped_sock = match typ {
         None => WrappedSocket::Uncompressed(reader, writer),
-        Some(CompressionType::Zlib) => {
+        Some(typ) => {
             let splat = read_buf.split_to(read_buf.len());
-            WrappedSocket::Zlib(crate::mit_zlib::make_reader(reader,
-                                                             &splat[..]),
-                                crate::mit_zlib::make_writer(writer))
+            WrappedSocket::Zlib(crate::mit_zlib::make_reader(
+                                    reader, stats.clone(), typ, &splat[..]),
+                                crate::mit_zlib::make_writer(
+                                    writer, stats, typ, compression_level))
         }
     };
     let mut new_parts = codec::FramedParts::new(wrapped_sock, codec);
     new_parts.read_buf.put(&read_buf[..]);
     Ok(codec::Framed::from_parts(new_parts))
 }
+
+#[cfg(all(test, feature = "websocket"))]
+mod tests {
+    use super::*;
+    use futures::SinkExt;
+    use tokio::{io::AsyncReadExt, net::TcpListener};
+
+    /// Makes a WebSocket connection to ourselves. Returns our end, the way
+    /// `client` would have it, and the other end.
+    async fn connection(max_message_bytes: usize)
+                        -> (Transport, WebSocketStream<TcpStream>) {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let ours = async {
+            let socket = listener.accept().await.unwrap().0;
+            let socket = Transport::plain(socket,
+                                          Arc::new(ClientStats::new()));
+            tokio_tungstenite::accept_async_with_config(
+                socket, Some(websocket_config(max_message_bytes))).await
+                .unwrap()
+        };
+        let theirs = async {
+            let socket = TcpStream::connect(addr).await.unwrap();
+            tokio_tungstenite::client_async("ws://localhost/", socket).await
+                .unwrap().0
+        };
+        let (ours, theirs) = tokio::join!(ours, theirs);
+        (Transport::websocket(ours), theirs)
+    }
+
+    #[tokio::test]
+    async fn each_message_is_one_line() {
+        let (mut ours, mut theirs) = connection(1024).await;
+        theirs.send(Message::Text("{\n  \"a\": 1\r\n}".to_owned())).await
+            .unwrap();
+        theirs.send(Message::Text("{}".to_owned())).await.unwrap();
+        let mut lines = [0; 17];
+        ours.read_exact(&mut lines).await.unwrap();
+        assert_eq!(&lines, b"{   \"a\": 1  }\n{}\n");
+    }
+
+    #[tokio::test]
+    async fn messages_are_held_to_the_line_limit() {
+        let (mut ours, mut theirs) = connection(1024).await;
+        theirs.send(Message::Text("x".repeat(1024))).await.unwrap();
+        let mut line = vec![0; 1025];
+        ours.read_exact(&mut line).await.unwrap();
+        assert_eq!(line.pop(), Some(b'\n'));
+        assert!(line.iter().all(|&x| x == b'x'));
+        theirs.send(Message::Text("x".repeat(1025))).await.unwrap();
+        let mut buf = [0; 16];
+        assert!(ours.read(&mut buf).await.is_err());
+    }
+}